@@ -152,6 +152,18 @@ async fn run(event_loop: EventLoop<UserEvent>, window: Window, args: Args) {
             _ => {}
         },
         Event::MainEventsCleared => {
+            if surface.is_lost() {
+                // The output of the window may have been disconnected, in
+                // which case recreating the surface is retried later
+                if let Ok(true) =
+                    pollster::block_on(render_cx.recreate_surface(&mut surface, &window))
+                {
+                    let device = &render_cx.devices[surface.dev_id].device;
+                    renderer
+                        .migrate(device)
+                        .expect("failed to migrate renderer");
+                }
+            }
             window.request_redraw();
         }
         Event::RedrawRequested(_) => {
@@ -161,7 +173,7 @@ async fn run(event_loop: EventLoop<UserEvent>, window: Window, args: Args) {
             let device_handle = &render_cx.devices[surface.dev_id];
             let mut builder = SceneBuilder::for_scene(&mut scene);
 
-            const N_SCENES: i32 = 6;
+            const N_SCENES: i32 = 7;
             // Allow looping forever
             scene_ix = scene_ix.rem_euclid(N_SCENES);
             // Remainder operation allows negative results, which isn't the right semantics
@@ -181,13 +193,18 @@ async fn run(event_loop: EventLoop<UserEvent>, window: Window, args: Args) {
                 3 => test_scene::render_brush_transform(&mut builder, current_frame),
                 4 => test_scene::render_funky_paths(&mut builder),
                 5 => test_scene::render_scene(&mut builder),
+                6 => test_scene::render_compose_grid(&mut builder),
                 _ => unreachable!("N_SCENES is too large"),
             }
             builder.finish();
-            let surface_texture = surface
-                .surface
-                .get_current_texture()
-                .expect("failed to get surface texture");
+            let surface_texture = match render_cx.acquire_texture(&mut surface) {
+                Ok(surface_texture) => surface_texture,
+                Err(vello::util::SurfaceError::OutOfMemory) => {
+                    panic!("out of memory acquiring surface texture")
+                }
+                // Skip the frame; lost surfaces are recreated below
+                Err(_) => return,
+            };
             #[cfg(not(target_arch = "wasm32"))]
             {
                 block_on_wgpu(
@@ -222,6 +222,68 @@ pub fn render_blend_grid(sb: &mut SceneBuilder) {
     }
 }
 
+pub fn render_compose_grid(sb: &mut SceneBuilder) {
+    const COMPOSE_MODES: &[Compose] = &[
+        Compose::Clear,
+        Compose::Copy,
+        Compose::Dest,
+        Compose::SrcOver,
+        Compose::DestOver,
+        Compose::SrcIn,
+        Compose::DestIn,
+        Compose::SrcOut,
+        Compose::DestOut,
+        Compose::SrcAtop,
+        Compose::DestAtop,
+        Compose::Xor,
+        Compose::Plus,
+        Compose::PlusLighter,
+    ];
+    for (ix, &compose) in COMPOSE_MODES.iter().enumerate() {
+        let i = ix % 4;
+        let j = ix / 4;
+        let transform = Affine::translate((i as f64 * 225., j as f64 * 225.));
+        render_compose_square(sb, compose, transform);
+    }
+}
+
+fn render_compose_square(sb: &mut SceneBuilder, compose: Compose, transform: Affine) {
+    // The classic Porter-Duff diagram: the destination is a circle and the
+    // source a square that overlaps it, composed in an isolated group over
+    // a checkerboard.
+    let rect = Rect::from_origin_size(Point::new(0., 0.), (200., 200.));
+    for y in 0..10 {
+        for x in 0..10 {
+            let color = if (x + y) % 2 == 0 {
+                Color::rgb8(204, 204, 204)
+            } else {
+                Color::WHITE
+            };
+            let cell =
+                Rect::from_origin_size(Point::new(x as f64 * 20., y as f64 * 20.), (20., 20.));
+            sb.fill(Fill::NonZero, transform, color, None, &cell);
+        }
+    }
+    sb.push_layer(Mix::Normal, 1.0, transform, &rect);
+    sb.fill(
+        Fill::NonZero,
+        transform,
+        Color::rgba8(0, 128, 255, 208),
+        None,
+        &Ellipse::new((80., 80.), (60., 60.), 0.),
+    );
+    sb.push_layer(BlendMode::new(Mix::Normal, compose), 1.0, transform, &rect);
+    sb.fill(
+        Fill::NonZero,
+        transform,
+        Color::rgba8(255, 64, 0, 208),
+        None,
+        &Rect::new(80., 80., 180., 180.),
+    );
+    sb.pop_layer();
+    sb.pop_layer();
+}
+
 fn render_blend_square(sb: &mut SceneBuilder, blend: BlendMode, transform: Affine) {
     // Inspired by https://developer.mozilla.org/en-US/docs/Web/CSS/mix-blend-mode
     let rect = Rect::from_origin_size(Point::new(0., 0.), (200., 200.));
@@ -64,6 +64,14 @@ var<workgroup> sh_tile_base: array<u32, WG_SIZE>;
 var<private> cmd_offset: u32;
 var<private> cmd_limit: u32;
 
+// Scissor rectangle of the current draw object, written after the path if
+// it does not contain the whole tile.
+var<private> scissor_rect: vec4<f32>;
+var<private> has_scissor: bool;
+
+// Whether the current draw object is composited additively.
+var<private> is_additive: bool;
+
 // Make sure there is space for a command of given size, plus a jump if needed
 fn alloc_cmd(size: u32) {
     if cmd_offset + size >= cmd_limit {
@@ -108,9 +116,61 @@ fn write_path(tile: Tile, linewidth: f32) -> bool {
         ptcl[cmd_offset + 2u] = bitcast<u32>(stroke.half_width);
         cmd_offset += 3u;
     }
+    write_modifiers();
     return true;
 }
 
+// Writes the commands that modify how the area of the path just written
+// is composited.
+fn write_modifiers() {
+    if has_scissor {
+        write_scissor(CmdScissor(scissor_rect));
+    }
+    if is_additive {
+        alloc_cmd(1u);
+        ptcl[cmd_offset] = CMD_ADDITIVE;
+        cmd_offset += 1u;
+    }
+}
+
+fn write_scissor(scissor: CmdScissor) {
+    alloc_cmd(5u);
+    ptcl[cmd_offset] = CMD_SCISSOR;
+    ptcl[cmd_offset + 1u] = bitcast<u32>(scissor.rect.x);
+    ptcl[cmd_offset + 2u] = bitcast<u32>(scissor.rect.y);
+    ptcl[cmd_offset + 3u] = bitcast<u32>(scissor.rect.z);
+    ptcl[cmd_offset + 4u] = bitcast<u32>(scissor.rect.w);
+    cmd_offset += 5u;
+}
+
+// Determines whether the scissor of a draw object clips the tile at the
+// given coordinates, in tiles. Clips are not affected by scissors.
+fn setup_scissor(drawobj_ix: u32, drawtag: u32, x: u32, y: u32) {
+    has_scissor = false;
+    if config.n_scissor == 0u || (drawtag & 1u) != 0u {
+        return;
+    }
+    let scissor = scene[config.draw_scissor_base + drawobj_ix];
+    if scissor >= config.n_scissor {
+        return;
+    }
+    let base = config.scissor_base + scissor * 4u;
+    scissor_rect = bitcast<vec4<f32>>(vec4(scene[base], scene[base + 1u], scene[base + 2u], scene[base + 3u]));
+    let tile_min = vec2(f32(x * TILE_WIDTH), f32(y * TILE_HEIGHT));
+    let tile_max = tile_min + vec2(f32(TILE_WIDTH), f32(TILE_HEIGHT));
+    has_scissor = any(scissor_rect.xy > tile_min) || any(scissor_rect.zw < tile_max);
+}
+
+// Reads the flags of a draw object. Clips do not have flags.
+fn setup_draw_flags(drawobj_ix: u32, drawtag: u32) {
+    is_additive = false;
+    if config.n_draw_flags == 0u || (drawtag & 1u) != 0u {
+        return;
+    }
+    let flags = scene[config.draw_flags_base + drawobj_ix];
+    is_additive = (flags & DRAW_FLAG_ADDITIVE) != 0u;
+}
+
 fn write_color(color: CmdColor) {
     alloc_cmd(2u);
     ptcl[cmd_offset] = CMD_COLOR;
@@ -118,6 +178,13 @@ fn write_color(color: CmdColor) {
     cmd_offset += 2u;
 }
 
+fn write_erase(erase: CmdErase) {
+    alloc_cmd(2u);
+    ptcl[cmd_offset] = CMD_ERASE;
+    ptcl[cmd_offset + 1u] = bitcast<u32>(erase.alpha);
+    cmd_offset += 2u;
+}
+
 fn write_grad(ty: u32, index: u32, info_offset: u32) {
     alloc_cmd(3u);
     ptcl[cmd_offset] = ty;
@@ -126,6 +193,60 @@ fn write_grad(ty: u32, index: u32, info_offset: u32) {
     cmd_offset += 3u;
 }
 
+fn write_image(info_offset: u32) {
+    alloc_cmd(2u);
+    ptcl[cmd_offset] = CMD_IMAGE;
+    ptcl[cmd_offset + 1u] = info_offset;
+    cmd_offset += 2u;
+}
+
+fn write_mesh_grad(info_offset: u32) {
+    alloc_cmd(2u);
+    ptcl[cmd_offset] = CMD_MESH_GRAD;
+    ptcl[cmd_offset + 1u] = info_offset;
+    cmd_offset += 2u;
+}
+
+fn write_procedural(info_offset: u32) {
+    alloc_cmd(2u);
+    ptcl[cmd_offset] = CMD_PROCEDURAL;
+    ptcl[cmd_offset + 1u] = info_offset;
+    cmd_offset += 2u;
+}
+
+fn write_shadow(info_offset: u32) {
+    alloc_cmd(2u);
+    ptcl[cmd_offset] = CMD_SHADOW;
+    ptcl[cmd_offset + 1u] = info_offset;
+    cmd_offset += 2u;
+}
+
+fn write_rounded_rect(info_offset: u32) {
+    alloc_cmd(2u);
+    ptcl[cmd_offset] = CMD_ROUNDED_RECT;
+    ptcl[cmd_offset + 1u] = info_offset;
+    cmd_offset += 2u;
+}
+
+fn write_ellipse(info_offset: u32) {
+    alloc_cmd(2u);
+    ptcl[cmd_offset] = CMD_ELLIPSE;
+    ptcl[cmd_offset + 1u] = info_offset;
+    cmd_offset += 2u;
+}
+
+// Writes the outline band of a stroked path, whose coverage falls off with
+// the distance to the path like a blur.
+fn write_soft_stroke(tile: Tile, linewidth: f32) {
+    alloc_cmd(3u);
+    let stroke = CmdStroke(tile.segments, 0.5 * linewidth);
+    ptcl[cmd_offset] = CMD_SOFT_STROKE;
+    ptcl[cmd_offset + 1u] = stroke.tile;
+    ptcl[cmd_offset + 2u] = bitcast<u32>(stroke.half_width);
+    cmd_offset += 3u;
+    write_modifiers();
+}
+
 fn write_begin_clip() {
     alloc_cmd(1u);
     ptcl[cmd_offset] = CMD_BEGIN_CLIP;
@@ -306,14 +427,17 @@ fn main(
             let tile = tiles[tile_ix];
             let is_clip = (tag & 1u) != 0u;
             var is_blend = false;
+            var zero_backdrop = tile.backdrop == 0;
             if is_clip {
                 let BLEND_CLIP = (128u << 8u) | 3u;
                 let scene_offset = draw_monoids[drawobj_ix].scene_offset;
                 let dd = config.drawdata_base + scene_offset;
                 let blend = scene[dd];
-                is_blend = blend != BLEND_CLIP;
+                is_blend = (blend & ~CLIP_EVEN_ODD) != BLEND_CLIP;
+                let even_odd = (blend & CLIP_EVEN_ODD) != 0u;
+                zero_backdrop = zero_backdrop || (even_odd && (tile.backdrop & 1) == 0);
             }
-            let include_tile = tile.segments != 0u || (tile.backdrop == 0) == is_clip || is_blend;
+            let include_tile = tile.segments != 0u || zero_backdrop == is_clip || is_blend;
             if include_tile {
                 let el_slice = el_ix / 32u;
                 let el_mask = 1u << (el_ix & 31u);
@@ -350,6 +474,8 @@ fn main(
             if clip_zero_depth == 0u {
                 let tile_ix = sh_tile_base[el_ix] + sh_tile_stride[el_ix] * tile_y + tile_x;
                 let tile = tiles[tile_ix];
+                setup_scissor(drawobj_ix, drawtag, bin_tile_x + tile_x, bin_tile_y + tile_y);
+                setup_draw_flags(drawobj_ix, drawtag);
                 switch drawtag {
                     // DRAWTAG_FILL_COLOR
                     case 0x44u: {
@@ -359,6 +485,26 @@ fn main(
                             write_color(CmdColor(rgba_color));
                         }
                     }
+                    // DRAWTAG_FILL_PALETTE_COLOR
+                    case 0x444u: {
+                        let linewidth = bitcast<f32>(info_bin_data[di]);
+                        if write_path(tile, linewidth) {
+                            let slot = scene[dd];
+                            var rgba_color = 0u;
+                            if slot < config.n_palette {
+                                rgba_color = scene[config.palette_base + slot];
+                            }
+                            write_color(CmdColor(rgba_color));
+                        }
+                    }
+                    // DRAWTAG_ERASE
+                    case 0x46u: {
+                        let linewidth = bitcast<f32>(info_bin_data[di]);
+                        if write_path(tile, linewidth) {
+                            let alpha = bitcast<f32>(scene[dd]);
+                            write_erase(CmdErase(alpha));
+                        }
+                    }
                     // DRAWTAG_FILL_LIN_GRADIENT
                     case 0x114u: {
                         let linewidth = bitcast<f32>(info_bin_data[di]);
@@ -377,9 +523,66 @@ fn main(
                             write_grad(CMD_RAD_GRAD, index, info_offset);
                         }
                     }
+                    // DRAWTAG_FILL_SWEEP_GRADIENT
+                    case 0x298u: {
+                        let linewidth = bitcast<f32>(info_bin_data[di]);
+                        if write_path(tile, linewidth) {
+                            let index = scene[dd];
+                            let info_offset = di + 1u;
+                            write_grad(CMD_SWEEP_GRAD, index, info_offset);
+                        }
+                    }
+                    // DRAWTAG_FILL_IMAGE
+                    case 0x2d0u: {
+                        let linewidth = bitcast<f32>(info_bin_data[di]);
+                        if write_path(tile, linewidth) {
+                            write_image(di + 1u);
+                        }
+                    }
+                    // DRAWTAG_FILL_MESH_GRADIENT
+                    case 0x204u: {
+                        let linewidth = bitcast<f32>(info_bin_data[di]);
+                        if write_path(tile, linewidth) {
+                            write_mesh_grad(di + 1u);
+                        }
+                    }
+                    // DRAWTAG_FILL_PROCEDURAL
+                    case 0x314u: {
+                        let linewidth = bitcast<f32>(info_bin_data[di]);
+                        if write_path(tile, linewidth) {
+                            write_procedural(di + 1u);
+                        }
+                    }
+                    // DRAWTAG_SHADOW
+                    case 0x714u: {
+                        let linewidth = bitcast<f32>(info_bin_data[di]);
+                        if linewidth >= 0.0 {
+                            write_soft_stroke(tile, linewidth);
+                            write_color(CmdColor(scene[dd]));
+                        } else if write_path(tile, linewidth) {
+                            write_shadow(di + 1u);
+                        }
+                    }
+                    // DRAWTAG_ROUNDED_RECT
+                    case 0x39cu: {
+                        let linewidth = bitcast<f32>(info_bin_data[di]);
+                        if write_path(tile, linewidth) {
+                            write_rounded_rect(di + 1u);
+                        }
+                    }
+                    // DRAWTAG_ELLIPSE
+                    case 0x316u: {
+                        let linewidth = bitcast<f32>(info_bin_data[di]);
+                        if write_path(tile, linewidth) {
+                            write_ellipse(di + 1u);
+                        }
+                    }
                     // DRAWTAG_BEGIN_CLIP
                     case 0x9u: {
-                        if tile.segments == 0u && tile.backdrop == 0 {
+                        let even_odd = (scene[dd] & CLIP_EVEN_ODD) != 0u;
+                        let zero_backdrop = tile.backdrop == 0 ||
+                            (even_odd && (tile.backdrop & 1) == 0);
+                        if tile.segments == 0u && zero_backdrop {
                             clip_zero_depth = clip_depth + 1u;
                         } else {
                             write_begin_clip();
@@ -391,10 +594,11 @@ fn main(
                     // DRAWTAG_END_CLIP
                     case 0x21u: {
                         clip_depth -= 1u;
-                        write_path(tile, -1.0);
                         let blend = scene[dd];
+                        let even_odd = (blend & CLIP_EVEN_ODD) != 0u;
+                        write_path(tile, select(-1.0, -2.0, even_odd));
                         let alpha = bitcast<f32>(scene[dd + 1u]);
-                        write_end_clip(CmdEndClip(blend, alpha));
+                        write_end_clip(CmdEndClip(blend & ~CLIP_EVEN_ODD, alpha));
                         render_blend_depth -= 1u;
                     }
                     default: {}
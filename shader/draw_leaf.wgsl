@@ -17,7 +17,7 @@ var<storage> scene: array<u32>;
 var<storage> reduced: array<DrawMonoid>;
 
 @group(0) @binding(3)
-var<storage> path_bbox: array<PathBbox>;
+var<storage, read_write> path_bbox: array<PathBbox>;
 
 @group(0) @binding(4)
 var<storage, read_write> draw_monoid: array<DrawMonoid>;
@@ -51,6 +51,26 @@ fn read_transform(transform_base: u32, ix: u32) -> Transform {
 
 var<workgroup> sh_scratch: array<DrawMonoid, WG_SIZE>;
 
+fn is_hidden(ix: u32) -> bool {
+    if config.n_visibility == 0u {
+        return false;
+    }
+    let group = scene[config.draw_group_base + ix];
+    if group >= config.n_visibility * 32u {
+        return false;
+    }
+    return ((scene[config.visibility_base + group / 32u] >> (group % 32u)) & 1u) != 0u;
+}
+
+// Returns the index of the scissor rectangle of a draw object. The index is
+// out of range if the draw object does not have a scissor.
+fn scissor_ix(ix: u32) -> u32 {
+    if config.n_scissor == 0u {
+        return ~0u;
+    }
+    return scene[config.draw_scissor_base + ix];
+}
+
 @compute @workgroup_size(256)
 fn main(
     @builtin(global_invocation_id) global_id: vec3<u32>,
@@ -98,11 +118,40 @@ fn main(
     draw_monoid[ix] = m;
     let dd = config.drawdata_base + m.scene_offset;
     let di = m.info_offset;
-    if tag_word == DRAWTAG_FILL_COLOR || tag_word == DRAWTAG_FILL_LIN_GRADIENT ||
-        tag_word == DRAWTAG_FILL_RAD_GRADIENT || tag_word == DRAWTAG_FILL_IMAGE ||
-        tag_word == DRAWTAG_BEGIN_CLIP
+    if tag_word == DRAWTAG_FILL_COLOR || tag_word == DRAWTAG_FILL_PALETTE_COLOR ||
+        tag_word == DRAWTAG_ERASE || tag_word == DRAWTAG_FILL_LIN_GRADIENT ||
+        tag_word == DRAWTAG_FILL_RAD_GRADIENT || tag_word == DRAWTAG_FILL_SWEEP_GRADIENT ||
+        tag_word == DRAWTAG_FILL_IMAGE ||
+        tag_word == DRAWTAG_FILL_MESH_GRADIENT || tag_word == DRAWTAG_FILL_PROCEDURAL ||
+        tag_word == DRAWTAG_SHADOW || tag_word == DRAWTAG_ROUNDED_RECT ||
+        tag_word == DRAWTAG_ELLIPSE || tag_word == DRAWTAG_BEGIN_CLIP
     {
         let bbox = path_bbox[m.path_ix];
+        if is_hidden(ix) {
+            // Collapse the bbox so that the draw object is not binned. For
+            // a clip, this also collapses the bboxes of the contents and the
+            // matching end clip.
+            path_bbox[m.path_ix].x1 = bbox.x0;
+            path_bbox[m.path_ix].y1 = bbox.y0;
+        }
+        let scissor = scissor_ix(ix);
+        if scissor < config.n_scissor && tag_word != DRAWTAG_BEGIN_CLIP {
+            // Restrict the bbox so that the draw object is only binned and
+            // allocated tiles inside the scissor. Coarse rasterization
+            // clips the coverage of tiles on the boundary.
+            let base = config.scissor_base + scissor * 4u;
+            let rect = vec4(
+                i32(floor(bitcast<f32>(scene[base]))),
+                i32(floor(bitcast<f32>(scene[base + 1u]))),
+                i32(ceil(bitcast<f32>(scene[base + 2u]))),
+                i32(ceil(bitcast<f32>(scene[base + 3u]))),
+            );
+            let cur = path_bbox[m.path_ix];
+            path_bbox[m.path_ix].x0 = max(cur.x0, rect.x);
+            path_bbox[m.path_ix].y0 = max(cur.y0, rect.y);
+            path_bbox[m.path_ix].x1 = min(cur.x1, rect.z);
+            path_bbox[m.path_ix].y1 = min(cur.y1, rect.w);
+        }
         // TODO: bbox is mostly yagni here, sort that out. Maybe clips?
         // let x0 = f32(bbox.x0);
         // let y0 = f32(bbox.y0);
@@ -113,7 +162,11 @@ fn main(
         var matrx: vec4<f32>;
         var translate: vec2<f32>;
         var linewidth = bbox.linewidth;
-        if linewidth >= 0.0 || tag_word == DRAWTAG_FILL_LIN_GRADIENT || tag_word == DRAWTAG_FILL_RAD_GRADIENT {
+        if linewidth >= 0.0 || tag_word == DRAWTAG_FILL_LIN_GRADIENT || tag_word == DRAWTAG_FILL_RAD_GRADIENT ||
+            tag_word == DRAWTAG_FILL_SWEEP_GRADIENT || tag_word == DRAWTAG_FILL_IMAGE || tag_word == DRAWTAG_FILL_MESH_GRADIENT ||
+            tag_word == DRAWTAG_FILL_PROCEDURAL || tag_word == DRAWTAG_SHADOW ||
+            tag_word == DRAWTAG_ROUNDED_RECT || tag_word == DRAWTAG_ELLIPSE
+        {
             let transform = read_transform(config.transform_base, bbox.trans_ix);
             matrx = transform.matrx;
             translate = transform.translate;
@@ -123,8 +176,8 @@ fn main(
             linewidth *= sqrt(abs(matrx.x * matrx.w - matrx.y * matrx.z));
         }
         switch tag_word {
-            // DRAWTAG_FILL_COLOR, DRAWTAG_FILL_IMAGE
-            case 0x44u, 0x48u: {
+            // DRAWTAG_FILL_COLOR, DRAWTAG_FILL_PALETTE_COLOR, DRAWTAG_ERASE
+            case 0x44u, 0x444u, 0x46u: {
                 info[di] = bitcast<u32>(linewidth);
             }
             // DRAWTAG_FILL_LIN_GRADIENT
@@ -170,6 +223,80 @@ fn main(
                 info[di + 9u] = bitcast<u32>(ra);
                 info[di + 10u] = bitcast<u32>(roff);
             }
+            // DRAWTAG_FILL_SWEEP_GRADIENT
+            case 0x298u: {
+                info[di] = bitcast<u32>(linewidth);
+                let p0 = bitcast<vec2<f32>>(vec2(scene[dd + 1u], scene[dd + 2u]));
+                let t0 = bitcast<f32>(scene[dd + 3u]);
+                let t1 = bitcast<f32>(scene[dd + 4u]);
+                // Inverse transform maps device space to gradient space
+                // relative to the center.
+                let inv_det = 1.0 / (matrx.x * matrx.w - matrx.y * matrx.z);
+                let inv_mat = inv_det * vec4(matrx.w, -matrx.y, -matrx.z, matrx.x);
+                var inv_tr = inv_mat.xz * translate.x + inv_mat.yw * translate.y;
+                inv_tr += p0;
+                info[di + 1u] = bitcast<u32>(inv_mat.x);
+                info[di + 2u] = bitcast<u32>(inv_mat.y);
+                info[di + 3u] = bitcast<u32>(inv_mat.z);
+                info[di + 4u] = bitcast<u32>(inv_mat.w);
+                info[di + 5u] = bitcast<u32>(inv_tr.x);
+                info[di + 6u] = bitcast<u32>(inv_tr.y);
+                info[di + 7u] = bitcast<u32>(t0);
+                // An empty range of angles draws the color at the start of
+                // the ramp rather than dividing by zero.
+                let t_range = t1 - t0;
+                let t_scale = select(0.0, 1.0 / t_range, t_range != 0.0);
+                info[di + 8u] = bitcast<u32>(t_scale);
+                info[di + 9u] = scene[dd + 5u];
+            }
+            // DRAWTAG_FILL_IMAGE
+            case 0x2d0u: {
+                info[di] = bitcast<u32>(linewidth);
+                // Inverse transform maps device space to image space.
+                let inv_det = 1.0 / (matrx.x * matrx.w - matrx.y * matrx.z);
+                let inv_mat = inv_det * vec4(matrx.w, -matrx.y, -matrx.z, matrx.x);
+                let inv_tr = inv_mat.xy * translate.x + inv_mat.zw * translate.y;
+                info[di + 1u] = bitcast<u32>(inv_mat.x);
+                info[di + 2u] = bitcast<u32>(inv_mat.y);
+                info[di + 3u] = bitcast<u32>(inv_mat.z);
+                info[di + 4u] = bitcast<u32>(inv_mat.w);
+                info[di + 5u] = bitcast<u32>(inv_tr.x);
+                info[di + 6u] = bitcast<u32>(inv_tr.y);
+                info[di + 7u] = scene[dd];
+                info[di + 8u] = scene[dd + 1u];
+                info[di + 9u] = scene[dd + 2u];
+                info[di + 10u] = scene[dd + 3u];
+            }
+            // DRAWTAG_FILL_MESH_GRADIENT
+            case 0x204u: {
+                info[di] = bitcast<u32>(linewidth);
+                info[di + 1u] = bitcast<u32>(matrx.x);
+                info[di + 2u] = bitcast<u32>(matrx.y);
+                info[di + 3u] = bitcast<u32>(matrx.z);
+                info[di + 4u] = bitcast<u32>(matrx.w);
+                info[di + 5u] = bitcast<u32>(translate.x);
+                info[di + 6u] = bitcast<u32>(translate.y);
+                info[di + 7u] = scene[dd];
+            }
+            // DRAWTAG_FILL_PROCEDURAL, DRAWTAG_SHADOW, DRAWTAG_ROUNDED_RECT,
+            // DRAWTAG_ELLIPSE
+            case 0x314u, 0x714u, 0x39cu, 0x316u: {
+                info[di] = bitcast<u32>(linewidth);
+                // Inverse transform maps device space to brush space.
+                let inv_det = 1.0 / (matrx.x * matrx.w - matrx.y * matrx.z);
+                let inv_mat = inv_det * vec4(matrx.w, -matrx.y, -matrx.z, matrx.x);
+                let inv_tr = inv_mat.xy * translate.x + inv_mat.zw * translate.y;
+                info[di + 1u] = bitcast<u32>(inv_mat.x);
+                info[di + 2u] = bitcast<u32>(inv_mat.y);
+                info[di + 3u] = bitcast<u32>(inv_mat.z);
+                info[di + 4u] = bitcast<u32>(inv_mat.w);
+                info[di + 5u] = bitcast<u32>(inv_tr.x);
+                info[di + 6u] = bitcast<u32>(inv_tr.y);
+                let data_size = (tag_word >> 2u) & 0x7u;
+                for (var i = 0u; i < data_size; i += 1u) {
+                    info[di + 7u + i] = scene[dd + i];
+                }
+            }
             default: {}
         }
     }
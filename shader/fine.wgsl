@@ -40,6 +40,12 @@ var gradients: texture_2d<f32>;
 @group(0) @binding(6)
 var<storage> info: array<u32>;
 
+@group(0) @binding(7)
+var<storage> scene: array<u32>;
+
+@group(0) @binding(8)
+var image_atlas: texture_2d<f32>;
+
 fn read_fill(cmd_ix: u32) -> CmdFill {
     let tile = ptcl[cmd_ix + 1u];
     let backdrop = i32(ptcl[cmd_ix + 2u]);
@@ -57,6 +63,11 @@ fn read_color(cmd_ix: u32) -> CmdColor {
     return CmdColor(rgba_color);
 }
 
+fn read_erase(cmd_ix: u32) -> CmdErase {
+    let alpha = bitcast<f32>(ptcl[cmd_ix + 1u]);
+    return CmdErase(alpha);
+}
+
 fn read_lin_grad(cmd_ix: u32) -> CmdLinGrad {
     let index = ptcl[cmd_ix + 1u];
     let info_offset = ptcl[cmd_ix + 2u];
@@ -81,12 +92,509 @@ fn read_rad_grad(cmd_ix: u32) -> CmdRadGrad {
     return CmdRadGrad(index, matrx, xlat, c1, ra, roff);
 }
 
+fn read_sweep_grad(cmd_ix: u32) -> CmdSweepGrad {
+    let index = ptcl[cmd_ix + 1u];
+    let info_offset = ptcl[cmd_ix + 2u];
+    let m0 = bitcast<f32>(info[info_offset]);
+    let m1 = bitcast<f32>(info[info_offset + 1u]);
+    let m2 = bitcast<f32>(info[info_offset + 2u]);
+    let m3 = bitcast<f32>(info[info_offset + 3u]);
+    let matrx = vec4(m0, m1, m2, m3);
+    let xlat = vec2(bitcast<f32>(info[info_offset + 4u]), bitcast<f32>(info[info_offset + 5u]));
+    let t0 = bitcast<f32>(info[info_offset + 6u]);
+    let t_scale = bitcast<f32>(info[info_offset + 7u]);
+    let extend = info[info_offset + 8u];
+    return CmdSweepGrad(index, matrx, xlat, t0, t_scale, extend);
+}
+
+// Applies a gradient extend mode to a position along the ramp.
+// Samples a gradient ramp at t in [0, 1]. Ramps with many stops span
+// several rows: the low 24 bits of the index are the first row and the high
+// bits are the number of additional rows.
+fn sample_ramp(index: u32, t: f32) -> vec4<f32> {
+    let n_rows = i32(index >> 24u) + 1;
+    let x = i32(round(t * f32(n_rows * GRADIENT_WIDTH - 1)));
+    let row = i32(index & 0xffffffu) + x / GRADIENT_WIDTH;
+    return textureLoad(gradients, vec2(x % GRADIENT_WIDTH, row), 0);
+}
+
+fn extend_mode(t: f32, mode: u32) -> f32 {
+    switch mode {
+        // Repeat
+        case 1u: {
+            return fract(t);
+        }
+        // Reflect
+        case 2u: {
+            return 1.0 - abs(fract(t * 0.5) * 2.0 - 1.0);
+        }
+        default: {
+            return clamp(t, 0.0, 1.0);
+        }
+    }
+}
+
+fn read_mesh_grad(cmd_ix: u32) -> CmdMeshGrad {
+    let info_offset = ptcl[cmd_ix + 1u];
+    let m0 = bitcast<f32>(info[info_offset]);
+    let m1 = bitcast<f32>(info[info_offset + 1u]);
+    let m2 = bitcast<f32>(info[info_offset + 2u]);
+    let m3 = bitcast<f32>(info[info_offset + 3u]);
+    let matrx = vec4(m0, m1, m2, m3);
+    let xlat = vec2(bitcast<f32>(info[info_offset + 4u]), bitcast<f32>(info[info_offset + 5u]));
+    let patch_ix = info[info_offset + 6u];
+    return CmdMeshGrad(matrx, xlat, patch_ix);
+}
+
+// Size of an encoded mesh patch in u32s: 16 points followed by 4 colors.
+let MESH_PATCH_SIZE = 36u;
+let MESH_NEWTON_ITERATIONS = 6u;
+
+// Device space control points of the current mesh patch.
+var<private> mesh_pts: array<vec2<f32>, 16>;
+
+struct MeshEval {
+    p: vec2<f32>,
+    du: vec2<f32>,
+    dv: vec2<f32>,
+}
+
+fn load_mesh_patch(mesh: CmdMeshGrad) -> u32 {
+    let base = config.mesh_base + mesh.patch_ix * MESH_PATCH_SIZE;
+    for (var i = 0u; i < 16u; i += 1u) {
+        let x = bitcast<f32>(scene[base + i * 2u]);
+        let y = bitcast<f32>(scene[base + i * 2u + 1u]);
+        mesh_pts[i] = mesh.matrx.xy * x + mesh.matrx.zw * y + mesh.xlat;
+    }
+    return base + 32u;
+}
+
+fn bernstein3(t: f32) -> vec4<f32> {
+    let s = 1.0 - t;
+    return vec4(s * s * s, 3.0 * s * s * t, 3.0 * s * t * t, t * t * t);
+}
+
+fn bernstein3_deriv(t: f32) -> vec4<f32> {
+    let s = 1.0 - t;
+    return vec4(-3.0 * s * s, 3.0 * s * (s - 2.0 * t), 3.0 * t * (2.0 * s - t), 3.0 * t * t);
+}
+
+// Evaluates the current tensor patch and its partial derivatives at uv.
+fn mesh_eval(uv: vec2<f32>) -> MeshEval {
+    let bu = bernstein3(uv.x);
+    let bv = bernstein3(uv.y);
+    let du = bernstein3_deriv(uv.x);
+    let dv = bernstein3_deriv(uv.y);
+    var p = vec2(0.0);
+    var pu = vec2(0.0);
+    var pv = vec2(0.0);
+    for (var j = 0u; j < 4u; j += 1u) {
+        for (var i = 0u; i < 4u; i += 1u) {
+            let cp = mesh_pts[j * 4u + i];
+            p += (bu[i] * bv[j]) * cp;
+            pu += (du[i] * bv[j]) * cp;
+            pv += (bu[i] * dv[j]) * cp;
+        }
+    }
+    return MeshEval(p, pu, pv);
+}
+
+// Finds the parametric coordinates of the point xy in the current patch
+// using Newton's method starting from the given initial guess.
+fn mesh_invert(xy: vec2<f32>, guess: vec2<f32>) -> vec2<f32> {
+    var uv = guess;
+    for (var k = 0u; k < MESH_NEWTON_ITERATIONS; k += 1u) {
+        let e = mesh_eval(uv);
+        let det = e.du.x * e.dv.y - e.du.y * e.dv.x;
+        if abs(det) < 1e-9 {
+            break;
+        }
+        let r = e.p - xy;
+        let delta = vec2(e.dv.y * r.x - e.dv.x * r.y, e.du.x * r.y - e.du.y * r.x) / det;
+        uv = clamp(uv - delta, vec2(0.0), vec2(1.0));
+    }
+    return uv;
+}
+
+fn read_image(cmd_ix: u32) -> CmdImage {
+    let info_offset = ptcl[cmd_ix + 1u];
+    let m0 = bitcast<f32>(info[info_offset]);
+    let m1 = bitcast<f32>(info[info_offset + 1u]);
+    let m2 = bitcast<f32>(info[info_offset + 2u]);
+    let m3 = bitcast<f32>(info[info_offset + 3u]);
+    let matrx = vec4(m0, m1, m2, m3);
+    let xlat = vec2(bitcast<f32>(info[info_offset + 4u]), bitcast<f32>(info[info_offset + 5u]));
+    let xy = info[info_offset + 6u];
+    let width_height = info[info_offset + 7u];
+    let alpha = bitcast<f32>(info[info_offset + 8u]);
+    let flags = info[info_offset + 9u];
+    let extend = flags & 3u;
+    let nearest = (flags & 4u) != 0u;
+    let yuv = (flags >> 3u) & 7u;
+    // The following are not intended to be bitcasts
+    let x = f32(xy >> 16u);
+    let y = f32(xy & 0xffffu);
+    let width = f32(width_height >> 16u);
+    let height = f32(width_height & 0xffffu);
+    return CmdImage(matrx, xlat, vec2(x, y), vec2(width, height), alpha, extend, nearest, yuv);
+}
+
+// Loads a texel from the image atlas, which holds straight alpha, and
+// premultiplies it.
+fn load_image_premul(coords: vec2<f32>) -> vec4<f32> {
+    let c = textureLoad(image_atlas, vec2<i32>(coords), 0);
+    return vec4(c.rgb * c.a, c.a);
+}
+
+// Maps integer texel coordinates outside of the image extents back into the
+// image according to its extend mode, returning atlas coordinates.
+fn image_texel(image: CmdImage, texel: vec2<f32>) -> vec2<f32> {
+    let n = image.extents;
+    var uv: vec2<f32>;
+    switch image.extend {
+        // Repeat
+        case 1u: {
+            uv = texel - n * floor(texel / n);
+        }
+        // Reflect
+        case 2u: {
+            let m = texel - 2.0 * n * floor(texel / (2.0 * n));
+            uv = select(m, 2.0 * n - 1.0 - m, m >= n);
+        }
+        default: {
+            uv = clamp(texel, vec2(0.0), n - 1.0);
+        }
+    }
+    return image.atlas_offset + uv;
+}
+
+// Bilinear or nearest neighbor sample of an image in the atlas, extended
+// beyond its bounds according to its extend mode.
+fn sample_image(image: CmdImage, p: vec2<f32>) -> vec4<f32> {
+    if image.nearest {
+        return load_image_premul(image_texel(image, floor(p)));
+    }
+    let uv = p - 0.5;
+    let uv0 = floor(uv);
+    let t = uv - uv0;
+    let a = load_image_premul(image_texel(image, uv0));
+    let b = load_image_premul(image_texel(image, uv0 + vec2(1.0, 0.0)));
+    let c = load_image_premul(image_texel(image, uv0 + vec2(0.0, 1.0)));
+    let d = load_image_premul(image_texel(image, uv0 + 1.0));
+    return mix(mix(a, b, t.x), mix(c, d, t.x), t.y);
+}
+
+// Converts premultiplied YUV samples in the red, green and blue channels to
+// RGB, using the BT.601 (bit 0) or BT.709 (bit 1) matrix and the limited
+// range unless bit 2 is set. The conversion is affine, so it can be applied
+// after filtering, with the offsets scaled by alpha.
+fn yuv_to_rgb(c: vec4<f32>, yuv: u32) -> vec4<f32> {
+    var y = c.r - (16.0 / 255.0) * c.a;
+    var u = c.g - (128.0 / 255.0) * c.a;
+    var v = c.b - (128.0 / 255.0) * c.a;
+    if (yuv & 4u) != 0u {
+        y = c.r;
+    } else {
+        y *= 255.0 / 219.0;
+        u *= 255.0 / 224.0;
+        v *= 255.0 / 224.0;
+    }
+    var rgb: vec3<f32>;
+    if (yuv & 1u) != 0u {
+        rgb = vec3(y + 1.402 * v, y - 0.344136 * u - 0.714136 * v, y + 1.772 * u);
+    } else {
+        rgb = vec3(y + 1.5748 * v, y - 0.187324 * u - 0.468124 * v, y + 1.8556 * u);
+    }
+    return vec4(clamp(rgb, vec3(0.0), vec3(c.a)), c.a);
+}
+
+// Maximum number of bilinear samples along each axis of the footprint of a
+// minified image.
+let MAX_IMAGE_TAPS = 8.0;
+
+// Sample of an image averaged over the footprint of a pixel, which is the
+// parallelogram spanned by the derivatives of the image coordinates. When the
+// image is minified or skewed, samples are spread evenly across the footprint
+// so that the result approximates the area average of the covered texels
+// instead of aliasing.
+fn sample_image_area(image: CmdImage, p: vec2<f32>) -> vec4<f32> {
+    let dx = image.matrx.xy;
+    let dy = image.matrx.zw;
+    let nx = clamp(ceil(length(dx)), 1.0, MAX_IMAGE_TAPS);
+    let ny = clamp(ceil(length(dy)), 1.0, MAX_IMAGE_TAPS);
+    if nx == 1.0 && ny == 1.0 {
+        return sample_image(image, p);
+    }
+    var sum = vec4(0.0);
+    for (var i = 0.0; i < nx; i += 1.0) {
+        for (var j = 0.0; j < ny; j += 1.0) {
+            let offset = dx * ((i + 0.5) / nx - 0.5) + dy * ((j + 0.5) / ny - 0.5);
+            sum += sample_image(image, p + offset);
+        }
+    }
+    return sum / (nx * ny);
+}
+
+fn read_procedural(cmd_ix: u32) -> CmdProcedural {
+    let info_offset = ptcl[cmd_ix + 1u];
+    let m0 = bitcast<f32>(info[info_offset]);
+    let m1 = bitcast<f32>(info[info_offset + 1u]);
+    let m2 = bitcast<f32>(info[info_offset + 2u]);
+    let m3 = bitcast<f32>(info[info_offset + 3u]);
+    let matrx = vec4(m0, m1, m2, m3);
+    let xlat = vec2(bitcast<f32>(info[info_offset + 4u]), bitcast<f32>(info[info_offset + 5u]));
+    let kind = info[info_offset + 6u];
+    let color0 = info[info_offset + 7u];
+    let color1 = info[info_offset + 8u];
+    let scale = bitcast<f32>(info[info_offset + 9u]);
+    let param = info[info_offset + 10u];
+    return CmdProcedural(matrx, xlat, kind, color0, color1, scale, param);
+}
+
+// Integer hash of a lattice point, returning a value in [0, 1].
+fn hash2(p: vec2<i32>, seed: u32) -> f32 {
+    var h = (u32(p.x) * 0x8da6b343u) ^ (u32(p.y) * 0xd8163841u) ^ (seed * 0xcb1ab31fu);
+    h = (h ^ (h >> 16u)) * 0x7feb352du;
+    h = (h ^ (h >> 15u)) * 0x846ca68bu;
+    h = h ^ (h >> 16u);
+    return f32(h) * (1.0 / 4294967295.0);
+}
+
+fn value_noise(p: vec2<f32>, seed: u32) -> f32 {
+    let i = vec2<i32>(floor(p));
+    let f = fract(p);
+    let u = f * f * (3.0 - 2.0 * f);
+    let a = hash2(i, seed);
+    let b = hash2(i + vec2(1, 0), seed);
+    let c = hash2(i + vec2(0, 1), seed);
+    let d = hash2(i + vec2(1, 1), seed);
+    return mix(mix(a, b, u.x), mix(c, d, u.x), u.y);
+}
+
+fn noise_gradient(i: vec2<i32>, seed: u32) -> vec2<f32> {
+    let a = hash2(i, seed) * 6.2831853;
+    return vec2(cos(a), sin(a));
+}
+
+fn simplex_noise(p: vec2<f32>, seed: u32) -> f32 {
+    // Skew factors for two dimensions: (sqrt(3) - 1) / 2 and (3 - sqrt(3)) / 6
+    let K1 = 0.366025404;
+    let K2 = 0.211324865;
+    let i = floor(p + (p.x + p.y) * K1);
+    let a = p - i + (i.x + i.y) * K2;
+    let o = select(vec2(0.0, 1.0), vec2(1.0, 0.0), a.x > a.y);
+    let b = a - o + K2;
+    let c = a - 1.0 + 2.0 * K2;
+    let h = max(0.5 - vec3(dot(a, a), dot(b, b), dot(c, c)), vec3(0.0));
+    let ii = vec2<i32>(i);
+    let n = h * h * h * h * vec3(
+        dot(a, noise_gradient(ii, seed)),
+        dot(b, noise_gradient(ii + vec2<i32>(o), seed)),
+        dot(c, noise_gradient(ii + vec2(1, 1), seed))
+    );
+    // Map from approximately [-1, 1] to [0, 1]
+    return clamp(0.5 + 35.0 * (n.x + n.y + n.z), 0.0, 1.0);
+}
+
+// Returns the interpolation factor between the two procedural colors for
+// a point in brush space.
+fn procedural_t(pattern: CmdProcedural, p: vec2<f32>) -> f32 {
+    let q = p / pattern.scale;
+    switch pattern.kind {
+        // PROCEDURAL_CHECKERBOARD
+        case 0u: {
+            let cell = vec2<i32>(floor(q));
+            return f32((cell.x + cell.y) & 1);
+        }
+        // PROCEDURAL_STRIPES
+        case 1u: {
+            return step(bitcast<f32>(pattern.param), fract(q.x));
+        }
+        // PROCEDURAL_VALUE_NOISE
+        case 2u: {
+            return value_noise(q, pattern.param);
+        }
+        // PROCEDURAL_SIMPLEX_NOISE
+        case 3u: {
+            return simplex_noise(q, pattern.param);
+        }
+        default: {
+            return 0.0;
+        }
+    }
+}
+
+fn read_shadow(cmd_ix: u32) -> CmdShadow {
+    let info_offset = ptcl[cmd_ix + 1u];
+    let m0 = bitcast<f32>(info[info_offset]);
+    let m1 = bitcast<f32>(info[info_offset + 1u]);
+    let m2 = bitcast<f32>(info[info_offset + 2u]);
+    let m3 = bitcast<f32>(info[info_offset + 3u]);
+    let matrx = vec4(m0, m1, m2, m3);
+    let xlat = vec2(bitcast<f32>(info[info_offset + 4u]), bitcast<f32>(info[info_offset + 5u]));
+    let color = info[info_offset + 6u];
+    let width = bitcast<f32>(info[info_offset + 7u]);
+    let height = bitcast<f32>(info[info_offset + 8u]);
+    let radius = bitcast<f32>(info[info_offset + 9u]);
+    let std_dev = bitcast<f32>(info[info_offset + 10u]);
+    return CmdShadow(matrx, xlat, color, width, height, radius, std_dev);
+}
+
+// Approximation of the error function with a maximum error of about 1e-4.
+fn erf7(x: f32) -> f32 {
+    // Scale by 2 / sqrt(pi)
+    let y = x * 1.1283791671;
+    let yy = y * y;
+    let z = y + (0.24295 + (0.03395 + 0.0104 * yy) * yy) * (y * yy);
+    return z / sqrt(1.0 + z * z);
+}
+
+// Returns the alpha of a Gaussian blurred rounded rectangle centered at the
+// origin for a point in brush space.
+//
+// This uses the approximation described in
+// https://raphlinus.github.io/graphics/2020/04/21/blurred-rounded-rects.html
+// where the corners are modeled as superellipses.
+fn shadow_alpha(shadow: CmdShadow, p: vec2<f32>) -> f32 {
+    let std_dev = max(shadow.std_dev, 1e-5);
+    let inv_std_dev = 1.0 / std_dev;
+    let min_edge = min(shadow.width, shadow.height);
+    let radius_max = 0.5 * min_edge;
+    let r0 = min(shadow.radius, radius_max);
+    let r1 = min(length(vec2(shadow.radius, std_dev * 1.15)), radius_max);
+    let exponent = clamp(2.0 * r1 / max(r0, 1e-5), 2.0, 16.0);
+    let inv_exponent = 1.0 / exponent;
+    // Pull in the long edge to make the shape less eccentric.
+    let ex = 0.5 * inv_std_dev * shadow.width;
+    let ey = 0.5 * inv_std_dev * shadow.height;
+    let delta = 1.25 * std_dev * (exp(-ex * ex) - exp(-ey * ey));
+    let width = shadow.width + min(delta, 0.0);
+    let height = shadow.height - max(delta, 0.0);
+    let scale = 0.5 * erf7(inv_std_dev * 0.5 * (max(width, height) - 0.5 * r0));
+    let d0 = abs(p) - (vec2(width, height) * 0.5 - r1);
+    let d1 = max(d0, vec2(0.0));
+    // Normalize before raising to the exponent to avoid overflow.
+    let m = max(max(d1.x, d1.y), 1e-5);
+    let d_pos = m * pow(pow(d1.x / m, exponent) + pow(d1.y / m, exponent), inv_exponent);
+    let d_neg = min(max(d0.x, d0.y), 0.0);
+    let d = d_pos + d_neg - r1;
+    return scale * (erf7(inv_std_dev * (min_edge + d)) - erf7(inv_std_dev * d));
+}
+
+fn read_rounded_rect(cmd_ix: u32) -> CmdRoundedRect {
+    let info_offset = ptcl[cmd_ix + 1u];
+    let m0 = bitcast<f32>(info[info_offset]);
+    let m1 = bitcast<f32>(info[info_offset + 1u]);
+    let m2 = bitcast<f32>(info[info_offset + 2u]);
+    let m3 = bitcast<f32>(info[info_offset + 3u]);
+    let matrx = vec4(m0, m1, m2, m3);
+    let xlat = vec2(bitcast<f32>(info[info_offset + 4u]), bitcast<f32>(info[info_offset + 5u]));
+    let color = info[info_offset + 6u];
+    let width = bitcast<f32>(info[info_offset + 7u]);
+    let height = bitcast<f32>(info[info_offset + 8u]);
+    let radii = vec4(
+        bitcast<f32>(info[info_offset + 9u]),
+        bitcast<f32>(info[info_offset + 10u]),
+        bitcast<f32>(info[info_offset + 11u]),
+        bitcast<f32>(info[info_offset + 12u])
+    );
+    return CmdRoundedRect(matrx, xlat, color, width, height, radii);
+}
+
+// Returns the coverage of a rounded rectangle centered at the origin for the
+// pixel whose center maps to the point in brush space.
+//
+// The signed distance to the outline is converted to device pixels with the
+// gradient of the distance, which accounts for the scale of the transform
+// in the direction of the outline.
+fn rounded_rect_alpha(rect: CmdRoundedRect, p: vec2<f32>) -> f32 {
+    let half_size = 0.5 * vec2(rect.width, rect.height);
+    // The radii of the corners on the side of the point, with y down.
+    let side = select(rect.radii.xw, rect.radii.yz, p.x > 0.0);
+    let corner = select(side.x, side.y, p.y > 0.0);
+    let radius = clamp(corner, 0.0, min(half_size.x, half_size.y));
+    let q = abs(p) - half_size + radius;
+    let q_pos = max(q, vec2(0.0));
+    let outside = length(q_pos);
+    let d = outside + min(max(q.x, q.y), 0.0) - radius;
+    var n = select(vec2(0.0, 1.0), vec2(1.0, 0.0), q.x > q.y);
+    if outside > 0.0 {
+        n = q_pos / outside;
+    }
+    n *= select(vec2(-1.0), vec2(1.0), p > vec2(0.0));
+    let grad = vec2(dot(rect.matrx.xy, n), dot(rect.matrx.zw, n));
+    return clamp(0.5 - d / max(length(grad), 1e-6), 0.0, 1.0);
+}
+
+fn read_ellipse(cmd_ix: u32) -> CmdEllipse {
+    let info_offset = ptcl[cmd_ix + 1u];
+    let m0 = bitcast<f32>(info[info_offset]);
+    let m1 = bitcast<f32>(info[info_offset + 1u]);
+    let m2 = bitcast<f32>(info[info_offset + 2u]);
+    let m3 = bitcast<f32>(info[info_offset + 3u]);
+    let matrx = vec4(m0, m1, m2, m3);
+    let xlat = vec2(bitcast<f32>(info[info_offset + 4u]), bitcast<f32>(info[info_offset + 5u]));
+    let color = info[info_offset + 6u];
+    let center = vec2(bitcast<f32>(info[info_offset + 7u]), bitcast<f32>(info[info_offset + 8u]));
+    let radii = vec2(bitcast<f32>(info[info_offset + 9u]), bitcast<f32>(info[info_offset + 10u]));
+    return CmdEllipse(matrx, xlat, color, center, radii);
+}
+
+// Returns the coverage of an ellipse for the pixel whose center maps to the
+// point in brush space.
+//
+// The distance is estimated from the implicit function |q| - 1, where q is
+// the point relative to the center divided by the radii, and its gradient
+// in device space. This is exact for circles with uniform scales and
+// accurate to first order at the outline of an ellipse.
+fn ellipse_alpha(ellipse: CmdEllipse, p: vec2<f32>) -> f32 {
+    let radii = max(abs(ellipse.radii), vec2(1e-6));
+    let q = (p - ellipse.center) / radii;
+    let len = max(length(q), 1e-6);
+    let f = len - 1.0;
+    let n = q / (radii * len);
+    let grad = vec2(dot(ellipse.matrx.xy, n), dot(ellipse.matrx.zw, n));
+    return clamp(0.5 - f / max(length(grad), 1e-6), 0.0, 1.0);
+}
+
+fn read_scissor(cmd_ix: u32) -> CmdScissor {
+    let x0 = bitcast<f32>(ptcl[cmd_ix + 1u]);
+    let y0 = bitcast<f32>(ptcl[cmd_ix + 2u]);
+    let x1 = bitcast<f32>(ptcl[cmd_ix + 3u]);
+    let y1 = bitcast<f32>(ptcl[cmd_ix + 4u]);
+    return CmdScissor(vec4(x0, y0, x1, y1));
+}
+
 fn read_end_clip(cmd_ix: u32) -> CmdEndClip {
     let blend = ptcl[cmd_ix + 1u];
     let alpha = bitcast<f32>(ptcl[cmd_ix + 2u]);
     return CmdEndClip(blend, alpha);
 }
 
+let COLOR_MATRIX_SIZE = 20u;
+
+// Applies a color matrix to the unpremultiplied color, as for feColorMatrix.
+fn apply_color_matrix(rgba: vec4<f32>, index: u32) -> vec4<f32> {
+    if index >= config.n_color_matrix {
+        return rgba;
+    }
+    let base = config.color_matrix_base + index * COLOR_MATRIX_SIZE;
+    let c = vec4(rgba.rgb / max(rgba.a, 1e-15), rgba.a);
+    var out = vec4(0.0);
+    for (var i = 0u; i < 4u; i += 1u) {
+        let row = base + i * 5u;
+        let m = vec4(
+            bitcast<f32>(scene[row]),
+            bitcast<f32>(scene[row + 1u]),
+            bitcast<f32>(scene[row + 2u]),
+            bitcast<f32>(scene[row + 3u])
+        );
+        out[i] = dot(m, c) + bitcast<f32>(scene[row + 4u]);
+    }
+    out = clamp(out, vec4(0.0), vec4(1.0));
+    return vec4(out.rgb * out.a, out.a);
+}
+
 #else
 
 @group(0) @binding(3)
@@ -135,6 +643,10 @@ fn fill_path(tile: Tile, xy: vec2<f32>, even_odd: bool) -> array<f32, PIXELS_PER
         }
         segment_ix = segment.next;
     }
+    if (config.flags & CONFIG_DEBUG_WINDING) != 0u {
+        // keep the signed winding number for visualization
+        return area;
+    }
     if even_odd {
         // even-odd winding rule
         for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
@@ -150,7 +662,8 @@ fn fill_path(tile: Tile, xy: vec2<f32>, even_odd: bool) -> array<f32, PIXELS_PER
     return area;
 }
 
-fn stroke_path(seg: u32, half_width: f32, xy: vec2<f32>) -> array<f32, PIXELS_PER_THREAD> {
+// Returns the distance from the center of each pixel to the nearest segment.
+fn stroke_distance(seg: u32, xy: vec2<f32>) -> array<f32, PIXELS_PER_THREAD> {
     var df: array<f32, PIXELS_PER_THREAD>;
     for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
         df[i] = 1e9;
@@ -169,6 +682,11 @@ fn stroke_path(seg: u32, half_width: f32, xy: vec2<f32>) -> array<f32, PIXELS_PE
         }
         segment_ix = segment.next;
     }
+    return df;
+}
+
+fn stroke_path(seg: u32, half_width: f32, xy: vec2<f32>) -> array<f32, PIXELS_PER_THREAD> {
+    var df = stroke_distance(seg, xy);
     for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
         // reuse array; return alpha rather than distance
         df[i] = clamp(half_width + 0.5 - df[i], 0.0, 1.0);
@@ -176,6 +694,47 @@ fn stroke_path(seg: u32, half_width: f32, xy: vec2<f32>) -> array<f32, PIXELS_PE
     return df;
 }
 
+// Returns the coverage of a blurred edge for each pixel of the band around a
+// path, where the half width of the band is three standard deviations.
+fn soft_stroke_path(seg: u32, half_width: f32, xy: vec2<f32>) -> array<f32, PIXELS_PER_THREAD> {
+    var df = stroke_distance(seg, xy);
+    // 1 / (sqrt(2) * std_dev)
+    let scale = 3.0 * 0.70710678 / max(half_width, 1e-5);
+    for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+        df[i] = 0.5 - 0.5 * erf7(df[i] * scale);
+    }
+    return df;
+}
+
+// Ordered dither offset of up to half of an 8 bit quantization step.
+fn dither(coords: vec2<u32>) -> f32 {
+    var bayer = array<u32, 16>(0u, 8u, 2u, 10u, 12u, 4u, 14u, 6u, 3u, 11u, 1u, 9u, 15u, 7u, 13u, 5u);
+    let ix = (coords.y & 3u) * 4u + (coords.x & 3u);
+    return (f32(bayer[ix]) / 16.0 - 0.46875) / 255.0;
+}
+
+// False color of a draw with the given winding number or coverage for the
+// debug views. Positive winding numbers are red and negative ones are blue,
+// with brighter colors for larger magnitudes.
+fn debug_color(area: f32) -> vec4<f32> {
+    if (config.flags & CONFIG_DEBUG_WINDING) != 0u {
+        let level = min(abs(area), 4.0) * 0.25;
+        let hue = select(vec3(0.2, 0.4, 1.0), vec3(1.0, 0.3, 0.2), area > 0.0);
+        return vec4(hue * level, level);
+    }
+    return vec4(area);
+}
+
+// Composites a premultiplied brush color over the background, or adds it
+// clamped as for plus-lighter if the draw object is additive. Adding skips
+// the blend stack entirely, which keeps heavily overlapping draws cheap.
+fn composite(bg: vec4<f32>, fg: vec4<f32>, additive: bool) -> vec4<f32> {
+    if additive {
+        return min(bg + fg, vec4(1.0));
+    }
+    return bg * (1.0 - fg.a) + fg;
+}
+
 // The X size should be 16 / PIXELS_PER_THREAD
 @compute @workgroup_size(4, 16)
 fn main(
@@ -190,6 +749,9 @@ fn main(
     var blend_stack: array<array<u32, PIXELS_PER_THREAD>, BLEND_STACK_SPLIT>;
     var clip_depth = 0u;
     var area: array<f32, PIXELS_PER_THREAD>;
+    var additive = false;
+    var debug_rgba: array<vec4<f32>, PIXELS_PER_THREAD>;
+    let debug = (config.flags & (CONFIG_DEBUG_WINDING | CONFIG_DEBUG_COVERAGE)) != 0u;
     var cmd_ix = tile_ix * PTCL_INITIAL_ALLOC;
     let blend_offset = ptcl[cmd_ix];
     cmd_ix += 1u;
@@ -199,6 +761,18 @@ fn main(
         if tag == CMD_END {
             break;
         }
+        // The debug views composite a false color for the area of every
+        // brush command in place of the brush itself.
+        let is_brush = tag == CMD_COLOR || (tag >= CMD_LIN_GRAD && tag <= CMD_IMAGE)
+            || tag == CMD_MESH_GRAD || tag == CMD_PROCEDURAL || tag == CMD_ERASE
+            || tag == CMD_SWEEP_GRAD || tag == CMD_SHADOW || tag == CMD_ROUNDED_RECT
+            || tag == CMD_ELLIPSE;
+        if debug && is_brush {
+            for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                let fg = debug_color(area[i]);
+                debug_rgba[i] = fg + debug_rgba[i] * (1.0 - fg.a);
+            }
+        }
         switch tag {
             // CMD_FILL
             case 1u: {
@@ -207,12 +781,21 @@ fn main(
                 let even_odd = (fill.tile & 1u) != 0u;
                 let tile = Tile(fill.backdrop, segments);
                 area = fill_path(tile, xy, even_odd);
+                additive = false;
                 cmd_ix += 3u;
             }
             // CMD_STROKE
             case 2u: {
                 let stroke = read_stroke(cmd_ix);
                 area = stroke_path(stroke.tile, stroke.half_width, xy);
+                additive = false;
+                cmd_ix += 3u;
+            }
+            // CMD_SOFT_STROKE
+            case 18u: {
+                let stroke = read_stroke(cmd_ix);
+                area = soft_stroke_path(stroke.tile, stroke.half_width, xy);
+                additive = false;
                 cmd_ix += 3u;
             }
             // CMD_SOLID
@@ -220,15 +803,43 @@ fn main(
                 for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
                     area[i] = 1.0;
                 }
+                additive = false;
+                cmd_ix += 1u;
+            }
+            // CMD_ADDITIVE
+            case 19u: {
+                additive = true;
                 cmd_ix += 1u;
             }
+            // CMD_SCISSOR
+            case 15u: {
+                let scissor = read_scissor(cmd_ix);
+                for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                    // Fractional coverage of the pixel by the rectangle
+                    let p0 = xy + vec2(f32(i), 0.0);
+                    let p1 = p0 + 1.0;
+                    let cover = clamp(min(p1, scissor.rect.zw) - max(p0, scissor.rect.xy), vec2(0.0), vec2(1.0));
+                    area[i] *= cover.x * cover.y;
+                }
+                cmd_ix += 5u;
+            }
             // CMD_COLOR
             case 5u: {
                 let color = read_color(cmd_ix);
                 let fg = unpack4x8unorm(color.rgba_color).wzyx;
                 for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
                     let fg_i = fg * area[i];
-                    rgba[i] = rgba[i] * (1.0 - fg_i.a) + fg_i;
+                    rgba[i] = composite(rgba[i], fg_i, additive);
+                }
+                cmd_ix += 2u;
+            }
+            // CMD_ERASE
+            case 14u: {
+                let erase = read_erase(cmd_ix);
+                for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                    // Destination-out: only the current layer is affected as
+                    // enclosing layers live on the blend stack.
+                    rgba[i] *= 1.0 - erase.alpha * area[i];
                 }
                 cmd_ix += 2u;
             }
@@ -238,10 +849,9 @@ fn main(
                 let d = lin.line_x * xy.x + lin.line_y * xy.y + lin.line_c;
                 for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
                     let my_d = d + lin.line_x * f32(i);
-                    let x = i32(round(clamp(my_d, 0.0, 1.0) * f32(GRADIENT_WIDTH - 1)));
-                    let fg_rgba = textureLoad(gradients, vec2(x, i32(lin.index)), 0);
+                    let fg_rgba = sample_ramp(lin.index, clamp(my_d, 0.0, 1.0));
                     let fg_i = fg_rgba * area[i];
-                    rgba[i] = rgba[i] * (1.0 - fg_i.a) + fg_i;
+                    rgba[i] = composite(rgba[i], fg_i, additive);
                 }
                 cmd_ix += 3u;
             }
@@ -255,13 +865,113 @@ fn main(
                     let ba = dot(xy_xformed, rad.c1);
                     let ca = rad.ra * dot(xy_xformed, xy_xformed);
                     let t = sqrt(ba * ba + ca) - ba - rad.roff;
-                    let x = i32(round(clamp(t, 0.0, 1.0) * f32(GRADIENT_WIDTH - 1)));
-                    let fg_rgba = textureLoad(gradients, vec2(x, i32(rad.index)), 0);
+                    let fg_rgba = sample_ramp(rad.index, clamp(t, 0.0, 1.0));
                     let fg_i = fg_rgba * area[i];
-                    rgba[i] = rgba[i] * (1.0 - fg_i.a) + fg_i;
+                    rgba[i] = composite(rgba[i], fg_i, additive);
                 }
                 cmd_ix += 3u;
             }
+            // CMD_SWEEP_GRAD
+            case 16u: {
+                let sweep = read_sweep_grad(cmd_ix);
+                for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                    let my_xy = vec2(xy.x + f32(i) + 0.5, xy.y + 0.5);
+                    let p = sweep.matrx.xz * my_xy.x + sweep.matrx.yw * my_xy.y - sweep.xlat;
+                    // Angle from the start angle, wrapped to a full turn.
+                    let angle = atan2(p.y, p.x) - sweep.t0;
+                    let turn = 6.283185307179586;
+                    let t = (angle - turn * floor(angle / turn)) * sweep.t_scale;
+                    let fg_rgba = sample_ramp(sweep.index, extend_mode(t, sweep.extend));
+                    let fg_i = fg_rgba * area[i];
+                    rgba[i] = composite(rgba[i], fg_i, additive);
+                }
+                cmd_ix += 3u;
+            }
+            // CMD_MESH_GRAD
+            case 12u: {
+                let mesh = read_mesh_grad(cmd_ix);
+                let color_base = load_mesh_patch(mesh);
+                let c00 = unpack4x8unorm(scene[color_base]).wzyx;
+                let c10 = unpack4x8unorm(scene[color_base + 1u]).wzyx;
+                let c11 = unpack4x8unorm(scene[color_base + 2u]).wzyx;
+                let c01 = unpack4x8unorm(scene[color_base + 3u]).wzyx;
+                var uv = vec2(0.5);
+                for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                    let my_xy = vec2(xy.x + f32(i) + 0.5, xy.y + 0.5);
+                    // Adjacent pixels are close in parameter space, so seed
+                    // each solve with the previous result.
+                    uv = mesh_invert(my_xy, uv);
+                    let fg_rgba = mix(mix(c00, c10, uv.x), mix(c01, c11, uv.x), uv.y);
+                    let fg_i = fg_rgba * area[i];
+                    rgba[i] = composite(rgba[i], fg_i, additive);
+                }
+                cmd_ix += 2u;
+            }
+            // CMD_IMAGE
+            case 8u: {
+                let image = read_image(cmd_ix);
+                for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                    let my_xy = vec2(xy.x + f32(i) + 0.5, xy.y + 0.5);
+                    let p = image.matrx.xy * my_xy.x + image.matrx.zw * my_xy.y - image.xlat;
+                    var texel = sample_image_area(image, p);
+                    if image.yuv != 0u {
+                        texel = yuv_to_rgb(texel, image.yuv);
+                    }
+                    let fg_i = texel * (image.alpha * area[i]);
+                    rgba[i] = composite(rgba[i], fg_i, additive);
+                }
+                cmd_ix += 2u;
+            }
+            // CMD_PROCEDURAL
+            case 13u: {
+                let pattern = read_procedural(cmd_ix);
+                let c0 = unpack4x8unorm(pattern.color0).wzyx;
+                let c1 = unpack4x8unorm(pattern.color1).wzyx;
+                for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                    let my_xy = vec2(xy.x + f32(i) + 0.5, xy.y + 0.5);
+                    let p = pattern.matrx.xy * my_xy.x + pattern.matrx.zw * my_xy.y - pattern.xlat;
+                    let fg_rgba = mix(c0, c1, procedural_t(pattern, p));
+                    let fg_i = fg_rgba * area[i];
+                    rgba[i] = composite(rgba[i], fg_i, additive);
+                }
+                cmd_ix += 2u;
+            }
+            // CMD_SHADOW
+            case 17u: {
+                let shadow = read_shadow(cmd_ix);
+                let fg = unpack4x8unorm(shadow.color).wzyx;
+                for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                    let my_xy = vec2(xy.x + f32(i) + 0.5, xy.y + 0.5);
+                    let p = shadow.matrx.xy * my_xy.x + shadow.matrx.zw * my_xy.y - shadow.xlat;
+                    let fg_i = fg * (shadow_alpha(shadow, p) * area[i]);
+                    rgba[i] = composite(rgba[i], fg_i, additive);
+                }
+                cmd_ix += 2u;
+            }
+            // CMD_ROUNDED_RECT
+            case 20u: {
+                let rect = read_rounded_rect(cmd_ix);
+                let fg = unpack4x8unorm(rect.color).wzyx;
+                for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                    let my_xy = vec2(xy.x + f32(i) + 0.5, xy.y + 0.5);
+                    let p = rect.matrx.xy * my_xy.x + rect.matrx.zw * my_xy.y - rect.xlat;
+                    let fg_i = fg * (rounded_rect_alpha(rect, p) * area[i]);
+                    rgba[i] = composite(rgba[i], fg_i, additive);
+                }
+                cmd_ix += 2u;
+            }
+            // CMD_ELLIPSE
+            case 21u: {
+                let ellipse = read_ellipse(cmd_ix);
+                let fg = unpack4x8unorm(ellipse.color).wzyx;
+                for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                    let my_xy = vec2(xy.x + f32(i) + 0.5, xy.y + 0.5);
+                    let p = ellipse.matrx.xy * my_xy.x + ellipse.matrx.zw * my_xy.y - ellipse.xlat;
+                    let fg_i = fg * (ellipse_alpha(ellipse, p) * area[i]);
+                    rgba[i] = composite(rgba[i], fg_i, additive);
+                }
+                cmd_ix += 2u;
+            }
             // CMD_BEGIN_CLIP
             case 9u: {
                 if clip_depth < BLEND_STACK_SPLIT {
@@ -287,8 +997,23 @@ fn main(
                         // load from memory
                     }
                     let bg = unpack4x8unorm(bg_rgba);
-                    let fg = rgba[i] * area[i] * end_clip.alpha;
-                    rgba[i] = blend_mix_compose(bg, fg, end_clip.blend);
+                    var fg = rgba[i];
+                    if (end_clip.blend & CLIP_COLOR_MATRIX) != 0u {
+                        fg = apply_color_matrix(fg, end_clip.blend >> CLIP_COLOR_MATRIX_SHIFT);
+                    }
+                    fg *= end_clip.alpha;
+                    if (end_clip.blend & CLIP_LUMINANCE) != 0u {
+                        // The color channels are premultiplied, so this is
+                        // the luminance times the alpha of the layer.
+                        fg = vec4(0.0, 0.0, 0.0, dot(fg.rgb, vec3(0.2125, 0.7154, 0.0721)));
+                    }
+                    // The coverage of the clip path is applied after
+                    // compositing, so that Porter-Duff modes that affect
+                    // the backdrop where the source is transparent, such
+                    // as source-in and copy, leave the backdrop outside of
+                    // the clip untouched.
+                    let composed = blend_mix_compose(bg, fg, end_clip.blend & 0xffffu);
+                    rgba[i] = mix(bg, composed, area[i]);
                 }
                 cmd_ix += 3u;
             }
@@ -303,10 +1028,16 @@ fn main(
     for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
         let coords = xy_uint + vec2(i, 0u);
         if coords.x < config.target_width && coords.y < config.target_height {
-            let fg = rgba[i];
+            var fg = rgba[i];
+            if debug {
+                fg = debug_rgba[i];
+            }
             // Max with a small epsilon to avoid NaNs
             let a_inv = 1.0 / max(fg.a, 1e-6);
-            let rgba_sep = vec4(fg.rgb * a_inv, fg.a);            
+            var rgba_sep = vec4(fg.rgb * a_inv, fg.a);
+            if (config.flags & CONFIG_DITHER) != 0u {
+                rgba_sep = vec4(rgba_sep.rgb + dither(coords), rgba_sep.a);
+            }
             textureStore(output, vec2<i32>(coords), rgba_sep);
         }
     } 
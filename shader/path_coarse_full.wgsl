@@ -132,7 +132,7 @@ fn main(
         let p3 = cubic.p3;
         let err_v = 3.0 * (p2 - p1) + p0 - p3;
         let err = dot(err_v, err_v);
-        let ACCURACY = 0.25;
+        let ACCURACY = config.tolerance;
         let Q_ACCURACY = ACCURACY * 0.1;
         let REM_ACCURACY = (ACCURACY - Q_ACCURACY);
         let MAX_HYPOT2 = 432.0 * Q_ACCURACY * Q_ACCURACY;
@@ -24,14 +24,38 @@ struct Config {
 
     transform_base: u32,
     linewidth_base: u32,
+    mesh_base: u32,
+    palette_base: u32,
+    n_palette: u32,
+    draw_group_base: u32,
+    visibility_base: u32,
+    n_visibility: u32,
+    draw_scissor_base: u32,
+    scissor_base: u32,
+    n_scissor: u32,
+    color_matrix_base: u32,
+    n_color_matrix: u32,
+    draw_flags_base: u32,
+    n_draw_flags: u32,
 
     // Sizes of bump allocated buffers (in element size units)
     binning_size: u32,
     tiles_size: u32,
     segments_size: u32,    
     ptcl_size: u32,
+
+    // Quality and debug flags, see the CONFIG_ constants
+    flags: u32,
+
+    // Flattening tolerance in pixels
+    tolerance: f32,
 }
 
+let CONFIG_DITHER = 1u;
+let CONFIG_SIMPLIFY_BLURS = 2u;
+let CONFIG_DEBUG_WINDING = 4u;
+let CONFIG_DEBUG_COVERAGE = 8u;
+
 // Geometry of tiles and bins
 
 let TILE_WIDTH = 16u;
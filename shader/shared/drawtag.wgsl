@@ -19,10 +19,25 @@ let DRAWTAG_NOP = 0u;
 let DRAWTAG_FILL_COLOR = 0x44u;
 let DRAWTAG_FILL_LIN_GRADIENT = 0x114u;
 let DRAWTAG_FILL_RAD_GRADIENT = 0x2dcu;
-let DRAWTAG_FILL_IMAGE = 0x48u;
+let DRAWTAG_FILL_SWEEP_GRADIENT = 0x298u;
+let DRAWTAG_FILL_IMAGE = 0x2d0u;
+let DRAWTAG_ERASE = 0x46u;
+let DRAWTAG_FILL_PALETTE_COLOR = 0x444u;
+let DRAWTAG_FILL_MESH_GRADIENT = 0x204u;
+let DRAWTAG_FILL_PROCEDURAL = 0x314u;
+let DRAWTAG_SHADOW = 0x714u;
+let DRAWTAG_ROUNDED_RECT = 0x39cu;
+let DRAWTAG_ELLIPSE = 0x316u;
 let DRAWTAG_BEGIN_CLIP = 0x9u;
 let DRAWTAG_END_CLIP = 0x21u;
 
+// Flag in the blend mode of a begin clip indicating that the clip path uses
+// the even-odd fill rule.
+let CLIP_EVEN_ODD = 0x10000u;
+
+// Draw flag indicating that a draw object is added to the content beneath it.
+let DRAW_FLAG_ADDITIVE = 1u;
+
 fn draw_monoid_identity() -> DrawMonoid {
     return DrawMonoid();
 }
@@ -19,6 +19,17 @@ let CMD_RAD_GRAD = 7u;
 let CMD_BEGIN_CLIP = 9u;
 let CMD_END_CLIP = 10u;
 let CMD_JUMP = 11u;
+let CMD_MESH_GRAD = 12u;
+let CMD_PROCEDURAL = 13u;
+let CMD_ERASE = 14u;
+let CMD_SCISSOR = 15u;
+let CMD_SWEEP_GRAD = 16u;
+let CMD_SHADOW = 17u;
+let CMD_SOFT_STROKE = 18u;
+let CMD_ADDITIVE = 19u;
+let CMD_ROUNDED_RECT = 20u;
+let CMD_ELLIPSE = 21u;
+let CMD_IMAGE = 8u;
 
 // The individual PTCL structs are written here, but read/write is by
 // hand in the relevant shaders
@@ -41,6 +52,10 @@ struct CmdColor {
     rgba_color: u32,
 }
 
+struct CmdErase {
+    alpha: f32,
+}
+
 struct CmdLinGrad {
     index: u32,
     line_x: f32,
@@ -57,6 +72,82 @@ struct CmdRadGrad {
     roff: f32,
 }
 
+struct CmdSweepGrad {
+    index: u32,
+    matrx: vec4<f32>,
+    xlat: vec2<f32>,
+    t0: f32,
+    t_scale: f32,
+    extend: u32,
+}
+
+struct CmdMeshGrad {
+    matrx: vec4<f32>,
+    xlat: vec2<f32>,
+    patch_ix: u32,
+}
+
+struct CmdProcedural {
+    matrx: vec4<f32>,
+    xlat: vec2<f32>,
+    kind: u32,
+    color0: u32,
+    color1: u32,
+    scale: f32,
+    param: u32,
+}
+
+struct CmdShadow {
+    matrx: vec4<f32>,
+    xlat: vec2<f32>,
+    color: u32,
+    width: f32,
+    height: f32,
+    radius: f32,
+    std_dev: f32,
+}
+
+struct CmdRoundedRect {
+    matrx: vec4<f32>,
+    xlat: vec2<f32>,
+    color: u32,
+    width: f32,
+    height: f32,
+    radii: vec4<f32>,
+}
+
+struct CmdEllipse {
+    matrx: vec4<f32>,
+    xlat: vec2<f32>,
+    color: u32,
+    center: vec2<f32>,
+    radii: vec2<f32>,
+}
+
+struct CmdImage {
+    matrx: vec4<f32>,
+    xlat: vec2<f32>,
+    atlas_offset: vec2<f32>,
+    extents: vec2<f32>,
+    alpha: f32,
+    extend: u32,
+    nearest: bool,
+    yuv: u32,
+}
+
+struct CmdScissor {
+    rect: vec4<f32>,
+}
+
+// Flag in the blend mode of an end clip command indicating that the layer is
+// replaced by its luminance as alpha before it is composited.
+let CLIP_LUMINANCE = 0x20000u;
+// Flag in the blend mode of an end clip command indicating that the colors of
+// the layer are transformed by the color matrix whose index is stored in the
+// bits above CLIP_COLOR_MATRIX_SHIFT.
+let CLIP_COLOR_MATRIX = 0x40000u;
+let CLIP_COLOR_MATRIX_SHIFT = 20u;
+
 struct CmdEndClip {
     blend: u32,
     alpha: f32,
@@ -0,0 +1,427 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Gaussian blur of layers for shadows and frosted panels.
+//!
+//! The fine shader composites layers one tile at a time, so a layer that
+//! needs the pixels of its neighbors is rendered into an intermediate
+//! texture first. [`LayerBlur::render`] renders the content of a layer,
+//! blurs it with two separable compute passes and registers the result as an
+//! image with [`Renderer::register_texture`], which is then composited like
+//! any other layer with [`BlurredLayer::draw`].
+//!
+//! The same passes blur the backdrops of layers pushed with
+//! [`SceneBuilder::push_backdrop_layer`], which the renderer resolves before
+//! rendering a scene.
+
+use peniko::kurbo::{Affine, Rect};
+use peniko::{BlendMode, BrushRef, Fill, Image};
+use wgpu::util::DeviceExt;
+use wgpu::{Device, Queue, TextureFormat};
+
+use crate::clip_cache::fragment_bounds;
+use crate::{Renderer, Result, Scene, SceneBuilder, SceneFragment};
+
+/// Largest supported blur radius in standard deviations, beyond which the
+/// blur is clamped.
+pub const MAX_SIGMA: f32 = 64.0;
+
+/// Layer that has been rendered and blurred.
+///
+/// The image is in the coordinate space of the scene, so the transforms
+/// pushed on the builder should be included in the transform passed to
+/// [`LayerBlur::render`] rather than applied when the layer is drawn.
+pub struct BlurredLayer {
+    image: Image,
+    bounds: Rect,
+}
+
+impl BlurredLayer {
+    /// Returns the blurred image, which is registered with the renderer.
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+
+    /// Returns the bounds of the image in scene coordinates, which include
+    /// the extent of the blur.
+    pub fn bounds(&self) -> Rect {
+        self.bounds
+    }
+
+    /// Composites the blurred layer with the specified blend mode and
+    /// opacity.
+    ///
+    /// The layer is drawn where it was rendered in the scene, so the
+    /// transforms pushed on the builder are undone. Nothing is drawn if they
+    /// are not invertible.
+    pub fn draw(&self, builder: &mut SceneBuilder, blend: impl Into<BlendMode>, alpha: f32) {
+        let stack = builder.transform();
+        if stack.determinant() == 0.0 {
+            return;
+        }
+        let transform = stack.inverse();
+        builder.push_layer(blend, alpha, transform, &self.bounds);
+        builder.fill(
+            Fill::NonZero,
+            transform,
+            BrushRef::Image(&self.image),
+            Some(Affine::translate(self.bounds.origin().to_vec2())),
+            &self.bounds,
+        );
+        builder.pop_layer();
+    }
+
+    /// Unregisters the image of the layer from the renderer.
+    pub fn release(self, renderer: &mut Renderer) {
+        renderer.unregister_texture(&self.image);
+    }
+}
+
+/// Compute pipeline for blurring layers.
+pub struct LayerBlur {
+    bind_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::ComputePipeline,
+}
+
+impl LayerBlur {
+    /// Creates the blur pipeline.
+    pub fn new(device: &Device) -> Self {
+        const SHADER: &str = r#"
+            struct Params {
+                direction: vec2<i32>,
+                radius: i32,
+            }
+
+            @group(0) @binding(0)
+            var<uniform> params: Params;
+
+            @group(0) @binding(1)
+            var<storage> weights: array<f32>;
+
+            @group(0) @binding(2)
+            var input: texture_2d<f32>;
+
+            @group(0) @binding(3)
+            var output: texture_storage_2d<rgba8unorm, write>;
+
+            // One dimensional pass of a separable Gaussian blur. The textures
+            // hold straight alpha, so texels are premultiplied before they are
+            // averaged.
+            @compute @workgroup_size(8, 8)
+            fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+                let size = vec2<i32>(textureDimensions(input));
+                let xy = vec2<i32>(id.xy);
+                if xy.x >= size.x || xy.y >= size.y {
+                    return;
+                }
+                var sum = vec4(0.0);
+                for (var i = -params.radius; i <= params.radius; i += 1) {
+                    let p = xy + params.direction * i;
+                    if all(p >= vec2(0)) && all(p < size) {
+                        let c = textureLoad(input, p, 0);
+                        sum += vec4(c.rgb * c.a, c.a) * weights[abs(i)];
+                    }
+                }
+                let rgb = select(vec3(0.0), sum.rgb / sum.a, sum.a > 0.0);
+                textureStore(output, xy, vec4(rgb, sum.a));
+            }
+        "#;
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("blur shader"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let bind_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::WriteOnly,
+                        format: TextureFormat::Rgba8Unorm,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&bind_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("blur"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "main",
+        });
+        Self {
+            bind_layout,
+            pipeline,
+        }
+    }
+
+    /// Renders the content of a layer with the specified transform and blurs
+    /// it with a Gaussian of standard deviation `sigma` in pixels.
+    ///
+    /// When [`RenderQuality::simplify_blurs`](crate::RenderQuality) is set,
+    /// the kernel is truncated at two standard deviations instead of three,
+    /// which takes fewer samples at the cost of slightly harder edges.
+    pub fn render(
+        &self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &Queue,
+        content: &SceneFragment,
+        transform: Affine,
+        sigma: f32,
+    ) -> Result<BlurredLayer> {
+        let sigma = sigma.clamp(0.0, MAX_SIGMA);
+        let radius = kernel_radius(sigma, renderer.quality().simplify_blurs);
+        let bounds = fragment_bounds(content, transform).inflate(radius as f64, radius as f64);
+        let width = (bounds.width().ceil() as u32).max(1);
+        let height = (bounds.height().ceil() as u32).max(1);
+        let mut scene = Scene::new();
+        let mut builder = SceneBuilder::for_scene(&mut scene);
+        builder.append(
+            content,
+            Some(Affine::translate(-bounds.origin().to_vec2()) * transform),
+        );
+        builder.finish();
+        let output = blur_texture(device, width, height, wgpu::TextureUsages::COPY_SRC);
+        let output_view = output.create_view(&wgpu::TextureViewDescriptor::default());
+        renderer.render_to_texture(device, queue, &scene, &output_view, width, height)?;
+        self.blur(device, queue, &output, sigma, radius);
+        let image = renderer
+            .register_texture(output)
+            .expect("blur textures can be registered");
+        Ok(BlurredLayer {
+            image,
+            bounds: bounds.with_size((width as f64, height as f64)),
+        })
+    }
+
+    /// Copies a region of a rendered texture and blurs it, for backdrop
+    /// layers. Parts of the region outside of the texture are transparent.
+    ///
+    /// The returned texture has the size of the region and can be registered
+    /// with the renderer.
+    pub(crate) fn blur_region(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        source: &wgpu::Texture,
+        region: Rect,
+        sigma: f32,
+        simplify: bool,
+    ) -> wgpu::Texture {
+        let sigma = sigma.clamp(0.0, MAX_SIGMA);
+        let radius = kernel_radius(sigma, simplify);
+        let width = (region.width().ceil() as u32).max(1);
+        let height = (region.height().ceil() as u32).max(1);
+        let output = blur_texture(
+            device,
+            width,
+            height,
+            wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::COPY_DST,
+        );
+        let size = source.size();
+        let (x0, y0) = (region.x0 as i64, region.y0 as i64);
+        let src_x0 = x0.clamp(0, size.width as i64);
+        let src_y0 = y0.clamp(0, size.height as i64);
+        let src_x1 = (x0 + width as i64).clamp(0, size.width as i64);
+        let src_y1 = (y0 + height as i64).clamp(0, size.height as i64);
+        if src_x1 > src_x0 && src_y1 > src_y0 {
+            let mut encoder =
+                device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            encoder.copy_texture_to_texture(
+                wgpu::ImageCopyTexture {
+                    texture: source,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: src_x0 as u32,
+                        y: src_y0 as u32,
+                        z: 0,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::ImageCopyTexture {
+                    texture: &output,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: (src_x0 - x0) as u32,
+                        y: (src_y0 - y0) as u32,
+                        z: 0,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::Extent3d {
+                    width: (src_x1 - src_x0) as u32,
+                    height: (src_y1 - src_y0) as u32,
+                    depth_or_array_layers: 1,
+                },
+            );
+            queue.submit(Some(encoder.finish()));
+        }
+        self.blur(device, queue, &output, sigma, radius);
+        output
+    }
+
+    /// Blurs a texture in place with a horizontal pass into a scratch
+    /// texture and a vertical pass back into the texture.
+    fn blur(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        texture: &wgpu::Texture,
+        sigma: f32,
+        radius: i32,
+    ) {
+        if radius <= 0 {
+            return;
+        }
+        let size = texture.size();
+        let scratch = blur_texture(
+            device,
+            size.width,
+            size.height,
+            wgpu::TextureUsages::empty(),
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let scratch_view = scratch.create_view(&wgpu::TextureViewDescriptor::default());
+        let weights = gaussian_weights(sigma, radius);
+        let weights = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("blur weights"),
+            contents: bytemuck::cast_slice(&weights),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        for (direction, input, output) in [
+            ([1, 0], &view, &scratch_view),
+            ([0, 1], &scratch_view, &view),
+        ] {
+            let params: [i32; 4] = [direction[0], direction[1], radius, 0];
+            let params = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("blur params"),
+                contents: bytemuck::cast_slice(&params),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &self.bind_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: params.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: weights.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(input),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::TextureView(output),
+                    },
+                ],
+            });
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups((size.width + 7) / 8, (size.height + 7) / 8, 1);
+        }
+        queue.submit(Some(encoder.finish()));
+    }
+}
+
+/// Creates a texture that can be rendered to and blurred.
+fn blur_texture(
+    device: &Device,
+    width: u32,
+    height: u32,
+    usage: wgpu::TextureUsages,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("blur"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING | usage,
+        format: TextureFormat::Rgba8Unorm,
+        view_formats: &[],
+    })
+}
+
+/// Returns the number of samples on each side of the center of the kernel,
+/// which covers three standard deviations, or two for simplified blurs.
+fn kernel_radius(sigma: f32, simplify: bool) -> i32 {
+    let extent = if simplify { 2.0 } else { 3.0 };
+    (sigma * extent).ceil() as i32
+}
+
+/// Returns the normalized weights of a Gaussian kernel from the center to
+/// `radius` samples away.
+fn gaussian_weights(sigma: f32, radius: i32) -> Vec<f32> {
+    let mut weights: Vec<f32> = (0..=radius)
+        .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum = weights[0] + 2.0 * weights[1..].iter().sum::<f32>();
+    for weight in &mut weights {
+        *weight /= sum;
+    }
+    weights
+}
@@ -0,0 +1,362 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Development time warnings for scenes that approach configured budgets,
+//! and limits on the work of a render that are enforced with an error.
+
+use crate::encoding::{Encoding, PathTag, Transform};
+
+/// Limits on the size and complexity of a scene.
+///
+/// When a scene with a budget is built, a warning is emitted for each limit
+/// that the scene comes within `threshold` of. By default, warnings are
+/// printed to stderr.
+pub struct SceneBudget {
+    /// Maximum size of the encoded scene in bytes.
+    pub encoding_bytes: Option<usize>,
+    /// Maximum nesting depth of layers.
+    pub layer_depth: Option<u32>,
+    /// Maximum number of distinct gradients.
+    pub gradients: Option<usize>,
+    /// Fraction of each limit at which warnings are emitted.
+    pub threshold: f32,
+    callback: Option<Box<dyn Fn(&BudgetWarning) + Send + Sync>>,
+}
+
+impl Default for SceneBudget {
+    fn default() -> Self {
+        Self {
+            encoding_bytes: None,
+            layer_depth: None,
+            gradients: None,
+            threshold: 0.8,
+            callback: None,
+        }
+    }
+}
+
+impl SceneBudget {
+    /// Creates a new budget without any limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builder method for setting the maximum size of the encoded scene.
+    pub fn with_encoding_bytes(mut self, bytes: usize) -> Self {
+        self.encoding_bytes = Some(bytes);
+        self
+    }
+
+    /// Builder method for setting the maximum nesting depth of layers.
+    pub fn with_layer_depth(mut self, depth: u32) -> Self {
+        self.layer_depth = Some(depth);
+        self
+    }
+
+    /// Builder method for setting the maximum number of gradients.
+    pub fn with_gradients(mut self, count: usize) -> Self {
+        self.gradients = Some(count);
+        self
+    }
+
+    /// Builder method for setting the fraction of each limit at which
+    /// warnings are emitted.
+    pub fn with_threshold(mut self, threshold: f32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Builder method for setting a callback that receives warnings instead
+    /// of printing them.
+    pub fn with_callback(
+        mut self,
+        callback: impl Fn(&BudgetWarning) + Send + Sync + 'static,
+    ) -> Self {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Checks the encoding against the budget and emits warnings.
+    pub(crate) fn check(&self, encoding: &Encoding, layer_depth: u32) {
+        if let Some(limit) = self.encoding_bytes {
+            let used = encoding.uploaded_size();
+            if self.exceeds(used as f64, limit as f64) {
+                self.warn(BudgetWarning::EncodingBytes { used, limit });
+            }
+        }
+        if let Some(limit) = self.layer_depth {
+            if self.exceeds(layer_depth as f64, limit as f64) {
+                self.warn(BudgetWarning::LayerDepth {
+                    used: layer_depth,
+                    limit,
+                });
+            }
+        }
+        if let Some(limit) = self.gradients {
+            let used = encoding.resources().gradients.len();
+            if self.exceeds(used as f64, limit as f64) {
+                self.warn(BudgetWarning::Gradients { used, limit });
+            }
+        }
+    }
+
+    fn exceeds(&self, used: f64, limit: f64) -> bool {
+        used >= limit * self.threshold as f64
+    }
+
+    fn warn(&self, warning: BudgetWarning) {
+        match &self.callback {
+            Some(callback) => callback(&warning),
+            None => eprintln!("vello: {warning}"),
+        }
+    }
+}
+
+/// Warning emitted when a scene approaches a limit of its budget.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BudgetWarning {
+    /// The encoded scene is approaching the maximum size in bytes.
+    EncodingBytes { used: usize, limit: usize },
+    /// Layers are approaching the maximum nesting depth.
+    LayerDepth { used: u32, limit: u32 },
+    /// The scene is approaching the maximum number of gradients.
+    Gradients { used: usize, limit: usize },
+}
+
+impl std::fmt::Display for BudgetWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::EncodingBytes { used, limit } => {
+                write!(f, "scene encoding uses {used} of {limit} bytes")
+            }
+            Self::LayerDepth { used, limit } => {
+                write!(f, "scene layer depth is {used} of {limit}")
+            }
+            Self::Gradients { used, limit } => {
+                write!(f, "scene uses {used} of {limit} gradients")
+            }
+        }
+    }
+}
+
+/// Limits on the work of a single render.
+///
+/// This protects renderers shared between documents, such as on a server,
+/// from scenes that would occupy the GPU for a long time. The limits are
+/// checked before the scene is submitted, and a render that exceeds one of
+/// them fails with a [`BudgetExceeded`] error instead.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct RenderBudget {
+    /// Maximum number of path segments.
+    pub segments: Option<u32>,
+    /// Maximum number of tiles covered by the bounding boxes of paths,
+    /// clipped to the target. This is an upper bound of the tiles that are
+    /// allocated by the pipeline.
+    pub tiles: Option<u64>,
+    /// Maximum number of compute dispatches, including those for the
+    /// backdrops of backdrop layers, which render the scene beneath them
+    /// separately.
+    pub dispatches: Option<u32>,
+}
+
+impl RenderBudget {
+    /// Creates a new budget without any limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builder method for setting the maximum number of path segments.
+    pub fn with_segments(mut self, segments: u32) -> Self {
+        self.segments = Some(segments);
+        self
+    }
+
+    /// Builder method for setting the maximum number of tiles.
+    pub fn with_tiles(mut self, tiles: u64) -> Self {
+        self.tiles = Some(tiles);
+        self
+    }
+
+    /// Builder method for setting the maximum number of dispatches.
+    pub fn with_dispatches(mut self, dispatches: u32) -> Self {
+        self.dispatches = Some(dispatches);
+        self
+    }
+
+    /// Checks the encoding of a scene rendered at the given size and scale.
+    /// The instances of the scene are expected to be expanded already, as
+    /// the renderer does once for each change of the scene.
+    pub(crate) fn check_scene(
+        &self,
+        encoding: &Encoding,
+        width: u32,
+        height: u32,
+        scale: f32,
+    ) -> Result<(), BudgetExceeded> {
+        if let Some(limit) = self.segments {
+            let used = encoding.n_path_segments;
+            if used > limit {
+                return Err(BudgetExceeded::Segments { used, limit });
+            }
+        }
+        if let Some(limit) = self.tiles {
+            let used = estimate_tiles(encoding, width, height, scale);
+            if used > limit {
+                return Err(BudgetExceeded::Tiles { used, limit });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the number of dispatches of a render.
+    pub(crate) fn check_dispatches(&self, used: u32) -> Result<(), BudgetExceeded> {
+        match self.dispatches {
+            Some(limit) if used > limit => Err(BudgetExceeded::Dispatches { used, limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Error returned when a render exceeds a limit of its [`RenderBudget`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BudgetExceeded {
+    /// The scene has too many path segments.
+    Segments { used: u32, limit: u32 },
+    /// The paths of the scene cover too many tiles.
+    Tiles { used: u64, limit: u64 },
+    /// The render needs too many dispatches.
+    Dispatches { used: u32, limit: u32 },
+}
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Segments { used, limit } => {
+                write!(
+                    f,
+                    "scene has {used} path segments, exceeding the limit of {limit}"
+                )
+            }
+            Self::Tiles { used, limit } => {
+                write!(
+                    f,
+                    "scene covers {used} tiles, exceeding the limit of {limit}"
+                )
+            }
+            Self::Dispatches { used, limit } => {
+                write!(
+                    f,
+                    "render needs {used} dispatches, exceeding the limit of {limit}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+/// Returns the number of tiles covered by the bounding boxes of the paths,
+/// clipped to the target.
+fn estimate_tiles(encoding: &Encoding, width: u32, height: u32, scale: f32) -> u64 {
+    const TILE_WIDTH: f32 = 16.0;
+    let width_in_tiles = (width as f32 / TILE_WIDTH).ceil();
+    let height_in_tiles = (height as f32 / TILE_WIDTH).ceil();
+    let mut tiles = 0;
+    let mut transform_ix = 0;
+    let mut linewidth_ix = 0;
+    let mut offset = 0;
+    let mut bbox = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+    let mut stroke_scale = 0.0f32;
+    for tag in &encoding.path_tags {
+        if *tag == PathTag::TRANSFORM {
+            transform_ix += 1;
+            continue;
+        }
+        if *tag == PathTag::LINEWIDTH {
+            linewidth_ix += 1;
+            continue;
+        }
+        if tag.is_path_segment() {
+            let transform = encoding
+                .transforms
+                .get(transform_ix)
+                .copied()
+                .unwrap_or(Transform::IDENTITY);
+            let n_points = tag.path_segment_type().0 as usize + tag.is_subpath_end() as usize;
+            let size = n_points * if tag.is_f32() { 8 } else { 4 };
+            let end = (offset + size).min(encoding.path_data.len());
+            let data = &encoding.path_data[offset.min(end)..end];
+            offset += size;
+            let [a, b, c, d] = transform.matrix;
+            let [e, f] = transform.translation;
+            // Strokes extend by half of the line width, scaled by the
+            // transform of their segments in the same way as on the GPU.
+            stroke_scale = (a * a + c * c).sqrt().max((b * b + d * d).sqrt());
+            let mut add_point = |x: f32, y: f32| {
+                let px = (a * x + c * y + e) * scale;
+                let py = (b * x + d * y + f) * scale;
+                bbox = [
+                    bbox[0].min(px),
+                    bbox[1].min(py),
+                    bbox[2].max(px),
+                    bbox[3].max(py),
+                ];
+            };
+            if tag.is_f32() {
+                for p in data.chunks_exact(8) {
+                    let x = f32::from_ne_bytes([p[0], p[1], p[2], p[3]]);
+                    let y = f32::from_ne_bytes([p[4], p[5], p[6], p[7]]);
+                    add_point(x, y);
+                }
+            } else {
+                for p in data.chunks_exact(4) {
+                    let x = i16::from_ne_bytes([p[0], p[1]]);
+                    let y = i16::from_ne_bytes([p[2], p[3]]);
+                    add_point(x as f32, y as f32);
+                }
+            }
+        }
+        if *tag == PathTag::PATH {
+            let linewidth = encoding
+                .linewidths
+                .get(linewidth_ix)
+                .copied()
+                .unwrap_or(-1.0);
+            let stroke = if linewidth >= 0.0 {
+                0.5 * linewidth * scale * stroke_scale
+            } else {
+                0.0
+            };
+            let x0 = ((bbox[0] - stroke) / TILE_WIDTH)
+                .floor()
+                .clamp(0.0, width_in_tiles);
+            let y0 = ((bbox[1] - stroke) / TILE_WIDTH)
+                .floor()
+                .clamp(0.0, height_in_tiles);
+            let x1 = ((bbox[2] + stroke) / TILE_WIDTH)
+                .ceil()
+                .clamp(0.0, width_in_tiles);
+            let y1 = ((bbox[3] + stroke) / TILE_WIDTH)
+                .ceil()
+                .clamp(0.0, height_in_tiles);
+            if x1 > x0 && y1 > y0 {
+                tiles += (x1 - x0) as u64 * (y1 - y0) as u64;
+            }
+            bbox = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+        }
+    }
+    tiles
+}
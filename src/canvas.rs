@@ -0,0 +1,258 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Stateful drawing in the style of the HTML Canvas 2D API.
+//!
+//! A [`Canvas`] wraps a [`SceneBuilder`] and keeps a current transform,
+//! fill and stroke styles and a global alpha, which are applied to each draw
+//! so that renderers written against Canvas 2D or Skia can be ported without
+//! threading these through every call.
+
+use peniko::kurbo::{Affine, Rect, Shape, Vec2};
+use peniko::{Brush, Color, Fill, Image, Mix, Stroke};
+
+use crate::glyph::TextBlob;
+use crate::image::Orientation;
+use crate::SceneBuilder;
+
+/// Drawing state that is saved and restored by [`Canvas::save`] and
+/// [`Canvas::restore`].
+#[derive(Clone)]
+struct CanvasState {
+    transform: Affine,
+    fill_style: Brush,
+    stroke_style: Brush,
+    stroke: Stroke,
+    fill_rule: Fill,
+    global_alpha: f32,
+}
+
+impl Default for CanvasState {
+    fn default() -> Self {
+        Self {
+            transform: Affine::IDENTITY,
+            fill_style: Color::BLACK.into(),
+            stroke_style: Color::BLACK.into(),
+            stroke: Stroke::new(1.0),
+            fill_rule: Fill::NonZero,
+            global_alpha: 1.0,
+        }
+    }
+}
+
+/// Stateful drawing interface over a [`SceneBuilder`].
+///
+/// The initial state matches that of a new Canvas 2D context: an identity
+/// transform, black fill and stroke styles, a line width of 1, the nonzero
+/// fill rule and a global alpha of 1.
+pub struct Canvas<'a, 'b> {
+    builder: &'b mut SceneBuilder<'a>,
+    state: CanvasState,
+    saved: Vec<CanvasState>,
+}
+
+impl<'a, 'b> Canvas<'a, 'b> {
+    /// Creates a canvas that draws into the builder.
+    pub fn new(builder: &'b mut SceneBuilder<'a>) -> Self {
+        Self {
+            builder,
+            state: CanvasState::default(),
+            saved: vec![],
+        }
+    }
+
+    /// Returns the underlying builder for draws that the canvas does not
+    /// cover. The current transform of the canvas is not applied to them.
+    pub fn builder(&mut self) -> &mut SceneBuilder<'a> {
+        self.builder
+    }
+
+    /// Saves the drawing state along with the clips of the builder.
+    pub fn save(&mut self) {
+        self.saved.push(self.state.clone());
+        self.builder.save();
+    }
+
+    /// Restores the drawing state and clips saved by the most recent
+    /// [`save`](Self::save). Does nothing if there is no saved state.
+    pub fn restore(&mut self) {
+        if let Some(state) = self.saved.pop() {
+            self.state = state;
+            self.builder.restore();
+        }
+    }
+
+    /// Returns the current transform.
+    pub fn current_transform(&self) -> Affine {
+        self.state.transform
+    }
+
+    /// Replaces the current transform.
+    pub fn set_transform(&mut self, transform: Affine) {
+        self.state.transform = transform;
+    }
+
+    /// Resets the current transform to the identity.
+    pub fn reset_transform(&mut self) {
+        self.state.transform = Affine::IDENTITY;
+    }
+
+    /// Multiplies the current transform by `transform`, so that it is
+    /// applied to subsequent draws before the current transform.
+    pub fn transform(&mut self, transform: Affine) {
+        self.state.transform *= transform;
+    }
+
+    /// Adds a translation to the current transform.
+    pub fn translate(&mut self, offset: impl Into<Vec2>) {
+        self.transform(Affine::translate(offset));
+    }
+
+    /// Adds a scale to the current transform.
+    pub fn scale(&mut self, x: f64, y: f64) {
+        self.transform(Affine::scale_non_uniform(x, y));
+    }
+
+    /// Adds a clockwise rotation by `angle` radians to the current transform.
+    pub fn rotate(&mut self, angle: f64) {
+        self.transform(Affine::rotate(angle));
+    }
+
+    /// Sets the brush used by [`fill`](Self::fill) and text.
+    pub fn set_fill_style(&mut self, brush: impl Into<Brush>) {
+        self.state.fill_style = brush.into();
+    }
+
+    /// Sets the brush used by [`stroke`](Self::stroke).
+    pub fn set_stroke_style(&mut self, brush: impl Into<Brush>) {
+        self.state.stroke_style = brush.into();
+    }
+
+    /// Sets the width, joins, caps and dashes of strokes.
+    pub fn set_stroke(&mut self, stroke: Stroke) {
+        self.state.stroke = stroke;
+    }
+
+    /// Sets the width of strokes.
+    pub fn set_line_width(&mut self, width: f32) {
+        self.state.stroke.width = width;
+    }
+
+    /// Sets the fill rule used by [`fill`](Self::fill) and
+    /// [`clip`](Self::clip).
+    pub fn set_fill_rule(&mut self, fill_rule: Fill) {
+        self.state.fill_rule = fill_rule;
+    }
+
+    /// Sets the opacity that is applied to each subsequent draw.
+    pub fn set_global_alpha(&mut self, alpha: f32) {
+        self.state.global_alpha = alpha.clamp(0.0, 1.0);
+    }
+
+    /// Fills a shape with the fill style.
+    pub fn fill(&mut self, shape: &impl Shape) {
+        let bounds = shape.bounding_box();
+        self.with_alpha(bounds, |builder, state| {
+            builder.fill(
+                state.fill_rule,
+                state.transform,
+                &state.fill_style,
+                None,
+                shape,
+            );
+        });
+    }
+
+    /// Strokes a shape with the stroke style.
+    pub fn stroke(&mut self, shape: &impl Shape) {
+        // Generous enough to account for miter joins.
+        let width = self.state.stroke.width as f64 * 2.0;
+        let bounds = shape.bounding_box().inset(width);
+        self.with_alpha(bounds, |builder, state| {
+            builder.stroke(
+                &state.stroke,
+                state.transform,
+                &state.stroke_style,
+                None,
+                shape,
+            );
+        });
+    }
+
+    /// Fills a rectangle with the fill style.
+    pub fn fill_rect(&mut self, rect: Rect) {
+        self.fill(&rect);
+    }
+
+    /// Strokes a rectangle with the stroke style.
+    pub fn stroke_rect(&mut self, rect: Rect) {
+        self.stroke(&rect);
+    }
+
+    /// Clears a rectangle to transparent.
+    pub fn clear_rect(&mut self, rect: Rect) {
+        self.builder
+            .erase(Fill::NonZero, self.state.transform, 1.0, &rect);
+    }
+
+    /// Intersects the clip with a shape until the next
+    /// [`restore`](Self::restore).
+    pub fn clip(&mut self, shape: &impl Shape) {
+        self.builder
+            .clip(self.state.fill_rule, self.state.transform, shape);
+    }
+
+    /// Draws an image with its top left corner at `origin`.
+    pub fn draw_image(&mut self, image: &Image, origin: impl Into<Vec2>) {
+        let offset = origin.into();
+        let bounds = Rect::new(0.0, 0.0, image.width as f64, image.height as f64) + offset;
+        self.with_alpha(bounds, |builder, state| {
+            builder.draw_image(
+                image,
+                Orientation::Normal,
+                state.transform * Affine::translate(offset),
+            );
+        });
+    }
+
+    /// Fills the glyphs of a blob with the fill style, with the origin of
+    /// the blob at `origin`.
+    pub fn fill_text(&mut self, blob: &TextBlob, origin: impl Into<Vec2>) {
+        let offset = origin.into();
+        let bounds = blob.bounds() + offset;
+        self.with_alpha(bounds, |builder, state| {
+            blob.draw_with_brush(
+                builder,
+                state.transform * Affine::translate(offset),
+                &state.fill_style,
+            );
+        });
+    }
+
+    /// Runs `f`, wrapping its draws in a layer bounded by `bounds` in user
+    /// space if the global alpha is less than one.
+    fn with_alpha(&mut self, bounds: Rect, f: impl FnOnce(&mut SceneBuilder, &CanvasState)) {
+        let alpha = self.state.global_alpha;
+        if alpha >= 1.0 {
+            f(self.builder, &self.state);
+        } else if alpha > 0.0 {
+            self.builder
+                .push_layer(Mix::Normal, alpha, self.state.transform, &bounds);
+            f(self.builder, &self.state);
+            self.builder.pop_layer();
+        }
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Caching of the coverage of complex clip paths across frames.
+//!
+//! The coverage is rendered into a [cached layer](crate::CachedLayer), so it
+//! stays on the GPU and is never read back.
+
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use peniko::kurbo::{Affine, Point, Rect};
+use peniko::{BlendMode, BrushRef, Compose, Fill, Image, Mix};
+use wgpu::{Device, Queue};
+
+use crate::{CachedLayer, Renderer, Result, SceneBuilder, SceneFragment};
+
+/// Coverage of a clip path rasterized into an image.
+///
+/// Applying the clip samples the image rather than rasterizing the path, so
+/// the cost of the path is paid only when it is cached. The image is in the
+/// coordinate space of the scene, i.e. the draws between
+/// [`push`](Self::push) and [`pop`](Self::pop) must not be appended to a
+/// scene with a transform.
+#[derive(Clone)]
+pub struct CachedClip {
+    layer: CachedLayer,
+    bounds: Rect,
+    transform: Affine,
+    hash: u64,
+}
+
+impl CachedClip {
+    /// Returns the image containing the coverage of the clip in the alpha
+    /// channel.
+    pub fn image(&self) -> &Image {
+        self.layer.image()
+    }
+
+    /// Returns the bounds of the clip in scene coordinates.
+    pub fn bounds(&self) -> Rect {
+        self.bounds
+    }
+
+    /// Pushes a layer that is clipped to the cached coverage when popped
+    /// with [`pop`](Self::pop).
+    pub fn push(&self, builder: &mut SceneBuilder) {
+        builder.push_layer(Mix::Clip, 1.0, Affine::IDENTITY, &self.bounds);
+    }
+
+    /// Applies the cached coverage to the content drawn since the matching
+    /// [`push`](Self::push) and pops the layer.
+    pub fn pop(&self, builder: &mut SceneBuilder) {
+        let dest_in = BlendMode::new(Mix::Normal, Compose::DestIn);
+        builder.push_layer(dest_in, 1.0, Affine::IDENTITY, &self.bounds);
+        builder.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            BrushRef::Image(self.image()),
+            Some(Affine::translate(self.bounds.origin().to_vec2())),
+            &self.bounds,
+        );
+        builder.pop_layer();
+        builder.pop_layer();
+    }
+}
+
+/// Cache of clip coverage images keyed by an application defined id.
+///
+/// An entry is rendered again when the transform or the encoding of its clip
+/// changes. The layers of entries are released with the renderer that
+/// rendered them when they are replaced or removed.
+pub struct ClipCache<K> {
+    entries: HashMap<K, CachedClip>,
+}
+
+impl<K> Default for ClipCache<K> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq> ClipCache<K> {
+    /// Creates a new empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the cached coverage of the clip, rendering it if it is not
+    /// cached or has changed.
+    ///
+    /// The clip is a fragment whose fills define the clip region, for
+    /// example a single fill of the clip path.
+    pub fn get(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &Queue,
+        key: K,
+        transform: Affine,
+        clip: &SceneFragment,
+    ) -> Result<&CachedClip> {
+        let hash = hash_fragment(clip);
+        match self.entries.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let cached = entry.get_mut();
+                if cached.transform != transform || cached.hash != hash {
+                    let (bounds, mask) = clip_mask(clip, transform);
+                    if bounds.size() == cached.bounds.size() {
+                        // The layer of the previous coverage is reused.
+                        renderer.invalidate_layer(&cached.layer);
+                        renderer.update_layer(device, queue, &cached.layer, &mask)?;
+                    } else {
+                        let layer = cache_mask(renderer, device, queue, bounds, &mask)?;
+                        renderer.release_layer(std::mem::replace(&mut cached.layer, layer));
+                    }
+                    cached.bounds = bounds;
+                    cached.transform = transform;
+                    cached.hash = hash;
+                }
+                Ok(entry.into_mut())
+            }
+            Entry::Vacant(entry) => {
+                let (bounds, mask) = clip_mask(clip, transform);
+                let layer = cache_mask(renderer, device, queue, bounds, &mask)?;
+                Ok(entry.insert(CachedClip {
+                    layer,
+                    bounds,
+                    transform,
+                    hash,
+                }))
+            }
+        }
+    }
+
+    /// Removes the cached coverage of a clip and releases its layer.
+    pub fn invalidate(&mut self, renderer: &mut Renderer, key: &K) {
+        if let Some(cached) = self.entries.remove(key) {
+            renderer.release_layer(cached.layer);
+        }
+    }
+
+    /// Removes all cached clips and releases their layers.
+    pub fn clear(&mut self, renderer: &mut Renderer) {
+        for (_, cached) in self.entries.drain() {
+            renderer.release_layer(cached.layer);
+        }
+    }
+
+    /// Returns the number of cached clips.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Returns the bounds of the clip in whole pixels of the scene and a
+/// fragment that draws its coverage in pixels of the bounds.
+fn clip_mask(clip: &SceneFragment, transform: Affine) -> (Rect, SceneFragment) {
+    let bounds = fragment_bounds(clip, transform);
+    let width = bounds.width().max(1.0);
+    let height = bounds.height().max(1.0);
+    let mask = SceneFragment::record(|builder| {
+        builder.append(
+            &clip.coverage_mask(),
+            Some(Affine::translate(-bounds.origin().to_vec2()) * transform),
+        );
+    });
+    (bounds.with_size((width, height)), mask)
+}
+
+fn cache_mask(
+    renderer: &mut Renderer,
+    device: &Device,
+    queue: &Queue,
+    bounds: Rect,
+    mask: &SceneFragment,
+) -> Result<CachedLayer> {
+    let (width, height) = (bounds.width() as u32, bounds.height() as u32);
+    renderer.cache_layer(device, queue, mask, width, height)
+}
+
+fn hash_fragment(fragment: &SceneFragment) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    fragment.data().hash_streams(&mut hasher);
+    hasher.finish()
+}
+
+/// Returns the bounds of the fragment in device space, rounded out to whole
+/// pixels.
+pub(crate) fn fragment_bounds(fragment: &SceneFragment, transform: Affine) -> Rect {
+    let bbox = fragment
+        .points()
+        .iter()
+        .map(|[x, y]| transform * Point::new(*x as f64, *y as f64))
+        .fold(None, |bbox: Option<Rect>, point| {
+            Some(bbox.map_or(Rect::from_points(point, point), |bbox| bbox.union_pt(point)))
+        })
+        .unwrap_or(Rect::ZERO);
+    // Include a pixel of padding for antialiasing.
+    bbox.inflate(1.0, 1.0).expand()
+}
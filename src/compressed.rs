@@ -0,0 +1,404 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Image brushes from block compressed textures.
+//!
+//! A [`CompressedImage`] holds BCn or ASTC blocks, for example map tiles
+//! stored in KTX2 containers. When the device supports the format,
+//! [`Renderer::load_compressed_image`](crate::Renderer::load_compressed_image)
+//! uploads the blocks as they are and decodes them on the GPU, so only the
+//! compressed data crosses the bus and is held in host memory. Otherwise
+//! BC1 and BC3 blocks are decompressed on the CPU with
+//! [`CompressedImage::decompress`].
+//!
+//! The image atlas holds uncompressed RGBA8 pixels, so a loaded image uses
+//! the same amount of atlas memory as an uncompressed one.
+
+use std::sync::Arc;
+
+use peniko::{Format, Image};
+use wgpu::{Device, Features, Queue, TextureFormat};
+
+/// Block compressed texture format.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum CompressedFormat {
+    /// BC1 (DXT1) with 1-bit alpha.
+    Bc1,
+    /// BC3 (DXT5) with interpolated alpha.
+    Bc3,
+    /// BC7 with high quality color and alpha.
+    Bc7,
+    /// ASTC with 4x4 blocks.
+    Astc4x4,
+}
+
+impl CompressedFormat {
+    /// Returns the width and height of a block in pixels.
+    pub fn block_dimensions(self) -> (u32, u32) {
+        (4, 4)
+    }
+
+    /// Returns the size of a block in bytes.
+    pub fn block_size(self) -> usize {
+        match self {
+            Self::Bc1 => 8,
+            Self::Bc3 | Self::Bc7 | Self::Astc4x4 => 16,
+        }
+    }
+
+    /// Returns the device feature that is required to sample textures of
+    /// the format.
+    pub fn required_features(self) -> Features {
+        match self {
+            Self::Bc1 | Self::Bc3 | Self::Bc7 => Features::TEXTURE_COMPRESSION_BC,
+            Self::Astc4x4 => Features::TEXTURE_COMPRESSION_ASTC_LDR,
+        }
+    }
+
+    /// Returns true if blocks of the format can be decompressed on the CPU.
+    pub fn can_decompress(self) -> bool {
+        matches!(self, Self::Bc1 | Self::Bc3)
+    }
+
+    fn texture_format(self) -> TextureFormat {
+        match self {
+            Self::Bc1 => TextureFormat::Bc1RgbaUnorm,
+            Self::Bc3 => TextureFormat::Bc3RgbaUnorm,
+            Self::Bc7 => TextureFormat::Bc7RgbaUnorm,
+            Self::Astc4x4 => TextureFormat::Astc {
+                block: wgpu::AstcBlock::B4x4,
+                channel: wgpu::AstcChannel::Unorm,
+            },
+        }
+    }
+}
+
+/// Image made of compressed blocks with straight alpha, stored in rows from
+/// top to bottom.
+#[derive(Clone, Debug)]
+pub struct CompressedImage {
+    format: CompressedFormat,
+    width: u32,
+    height: u32,
+    data: Arc<[u8]>,
+}
+
+impl CompressedImage {
+    /// Creates a compressed image, returning `None` if the length of the
+    /// data does not match the number of blocks covering the image.
+    pub fn new(format: CompressedFormat, width: u32, height: u32, data: Arc<[u8]>) -> Option<Self> {
+        let image = Self {
+            format,
+            width,
+            height,
+            data,
+        };
+        let (bx, by) = image.blocks();
+        if width == 0 || height == 0 || image.data.len() != bx * by * format.block_size() {
+            return None;
+        }
+        Some(image)
+    }
+
+    /// Returns the format of the blocks.
+    pub fn format(&self) -> CompressedFormat {
+        self.format
+    }
+
+    /// Returns the width of the image in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Returns the height of the image in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the compressed blocks.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the number of blocks along each axis.
+    fn blocks(&self) -> (usize, usize) {
+        let (bw, bh) = self.format.block_dimensions();
+        (
+            ((self.width + bw - 1) / bw) as usize,
+            ((self.height + bh - 1) / bh) as usize,
+        )
+    }
+
+    /// Decompresses the image on the CPU. Returns `None` if the format cannot
+    /// be decompressed on the CPU.
+    pub fn decompress(&self) -> Option<Image> {
+        if !self.format.can_decompress() {
+            return None;
+        }
+        let (w, h) = (self.width as usize, self.height as usize);
+        let (bx, by) = self.blocks();
+        let block_size = self.format.block_size();
+        let mut out = vec![0u8; w * h * 4];
+        let mut texels = [[0u8; 4]; 16];
+        for y in 0..by {
+            for x in 0..bx {
+                let block = &self.data[(y * bx + x) * block_size..][..block_size];
+                match self.format {
+                    CompressedFormat::Bc1 => decode_bc1(block, &mut texels, true),
+                    _ => {
+                        decode_bc1(&block[8..], &mut texels, false);
+                        decode_bc3_alpha(&block[..8], &mut texels);
+                    }
+                }
+                for (i, texel) in texels.iter().enumerate() {
+                    let (px, py) = (x * 4 + i % 4, y * 4 + i / 4);
+                    if px < w && py < h {
+                        out[(py * w + px) * 4..][..4].copy_from_slice(texel);
+                    }
+                }
+            }
+        }
+        Some(Image::new(
+            out.into(),
+            Format::Rgba8,
+            self.width,
+            self.height,
+        ))
+    }
+}
+
+/// Decodes the color part of a BC1 or BC3 block. In BC1 blocks with the
+/// first endpoint not greater than the second, index 3 is transparent.
+fn decode_bc1(block: &[u8], texels: &mut [[u8; 4]; 16], punch_through: bool) {
+    let c0 = u16::from_le_bytes([block[0], block[1]]);
+    let c1 = u16::from_le_bytes([block[2], block[3]]);
+    let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+    let (e0, e1) = (rgb565(c0), rgb565(c1));
+    let mix = |a: u32, b: u32, w: u32, d: u32| ((a * (d - w) + b * w + d / 2) / d) as u8;
+    let mut palette = [[0u8; 4]; 4];
+    palette[0] = [e0[0] as u8, e0[1] as u8, e0[2] as u8, 255];
+    palette[1] = [e1[0] as u8, e1[1] as u8, e1[2] as u8, 255];
+    if c0 > c1 || !punch_through {
+        for (k, w) in [(2, 1), (3, 2)] {
+            palette[k] = [
+                mix(e0[0], e1[0], w, 3),
+                mix(e0[1], e1[1], w, 3),
+                mix(e0[2], e1[2], w, 3),
+                255,
+            ];
+        }
+    } else {
+        palette[2] = [
+            mix(e0[0], e1[0], 1, 2),
+            mix(e0[1], e1[1], 1, 2),
+            mix(e0[2], e1[2], 1, 2),
+            255,
+        ];
+        palette[3] = [0, 0, 0, 0];
+    }
+    for (i, texel) in texels.iter_mut().enumerate() {
+        *texel = palette[((indices >> (i * 2)) & 3) as usize];
+    }
+}
+
+/// Decodes the alpha part of a BC3 block.
+fn decode_bc3_alpha(block: &[u8], texels: &mut [[u8; 4]; 16]) {
+    let (a0, a1) = (block[0] as u32, block[1] as u32);
+    let mut palette = [0u8; 8];
+    palette[0] = a0 as u8;
+    palette[1] = a1 as u8;
+    if a0 > a1 {
+        for k in 1..7 {
+            palette[k + 1] = ((a0 * (7 - k as u32) + a1 * k as u32 + 3) / 7) as u8;
+        }
+    } else {
+        for k in 1..5 {
+            palette[k + 1] = ((a0 * (5 - k as u32) + a1 * k as u32 + 2) / 5) as u8;
+        }
+        palette[6] = 0;
+        palette[7] = 255;
+    }
+    let mut bits = [0u8; 8];
+    bits[..6].copy_from_slice(&block[2..8]);
+    let indices = u64::from_le_bytes(bits);
+    for (i, texel) in texels.iter_mut().enumerate() {
+        texel[3] = palette[((indices >> (i * 3)) & 7) as usize];
+    }
+}
+
+fn rgb565(c: u16) -> [u32; 3] {
+    let r = (c >> 11) as u32 & 0x1f;
+    let g = (c >> 5) as u32 & 0x3f;
+    let b = c as u32 & 0x1f;
+    [
+        (r << 3) | (r >> 2),
+        (g << 2) | (g >> 4),
+        (b << 3) | (b >> 2),
+    ]
+}
+
+/// Uploads compressed images and decodes them into textures on the GPU.
+pub(crate) struct CompressedDecoder {
+    bind_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl CompressedDecoder {
+    pub fn new(device: &Device) -> Self {
+        const SHADERS: &str = r#"
+            @vertex
+            fn vs_main(@builtin(vertex_index) ix: u32) -> @builtin(position) vec4<f32> {
+                // Generate a full screen triangle in NDCs
+                let vertex = vec2(f32(ix / 2u) * 4.0 - 1.0, f32(ix % 2u) * 4.0 - 1.0);
+                return vec4(vertex, 0.0, 1.0);
+            }
+
+            @group(0) @binding(0)
+            var blocks: texture_2d<f32>;
+
+            @fragment
+            fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+                return textureLoad(blocks, vec2<i32>(position.xy), 0);
+            }
+        "#;
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("compressed image shaders"),
+            source: wgpu::ShaderSource::Wgsl(SHADERS.into()),
+        });
+        let bind_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                binding: 0,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&bind_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: TextureFormat::Rgba8Unorm,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+        Self {
+            bind_layout,
+            pipeline,
+        }
+    }
+
+    /// Uploads the blocks of the image and decodes them into an `Rgba8Unorm`
+    /// texture that can be registered as an external image.
+    pub fn decode(&self, device: &Device, queue: &Queue, image: &CompressedImage) -> wgpu::Texture {
+        let (bx, by) = image.blocks();
+        let (bw, bh) = image.format.block_dimensions();
+        // Compressed textures must cover whole blocks.
+        let blocks = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("compressed_image"),
+            size: wgpu::Extent3d {
+                width: bx as u32 * bw,
+                height: by as u32 * bh,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            format: image.format.texture_format(),
+            view_formats: &[],
+        });
+        queue.write_texture(
+            blocks.as_image_copy(),
+            image.data(),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new((bx * image.format.block_size()) as u32),
+                rows_per_image: None,
+            },
+            blocks.size(),
+        );
+        let decoded = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("decoded_image"),
+            size: wgpu::Extent3d {
+                width: image.width,
+                height: image.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: TextureFormat::Rgba8Unorm,
+            view_formats: &[],
+        });
+        let blocks_view = blocks.create_view(&wgpu::TextureViewDescriptor::default());
+        let decoded_view = decoded.create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.bind_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&blocks_view),
+            }],
+        });
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("compressed image decode"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &decoded_view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        queue.submit(Some(encoder.finish()));
+        decoded
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Underlines, strikethroughs and overlines for text.
+
+use peniko::kurbo::{Affine, BezPath, PathEl, Point, Rect};
+use peniko::{BrushRef, Fill};
+
+use crate::glyph::TextBlob;
+use crate::scene::SceneBuilder;
+
+/// Tolerance for flattening glyph outlines when skipping ink.
+const SKIP_INK_TOLERANCE: f64 = 0.25;
+
+/// Kind of text decoration.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DecorationKind {
+    /// Line below the baseline.
+    Underline,
+    /// Line through the middle of lowercase letters.
+    Strikethrough,
+    /// Line at the ascender.
+    Overline,
+}
+
+/// Position and thickness of text decorations in pixels.
+///
+/// Offsets are measured from the baseline to the top of the line, with
+/// positive values above the baseline, as in the `post` and `OS/2` tables.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct DecorationMetrics {
+    /// Offset of the underline.
+    pub underline_offset: f32,
+    /// Thickness of the underline.
+    pub underline_thickness: f32,
+    /// Offset of the strikethrough.
+    pub strikeout_offset: f32,
+    /// Thickness of the strikethrough.
+    pub strikeout_thickness: f32,
+    /// Ascender of the font, used for overlines.
+    pub ascent: f32,
+}
+
+impl DecorationMetrics {
+    /// Creates metrics from values in font units, i.e.
+    /// `post.underlinePosition`, `post.underlineThickness`,
+    /// `OS/2.yStrikeoutPosition`, `OS/2.yStrikeoutSize` and `hhea.ascender`.
+    pub fn from_font_units(
+        units_per_em: u16,
+        ppem: f32,
+        underline_position: i16,
+        underline_thickness: i16,
+        strikeout_position: i16,
+        strikeout_size: i16,
+        ascender: i16,
+    ) -> Self {
+        let scale = ppem / units_per_em.max(1) as f32;
+        let fallback = Self::fallback(ppem);
+        // Fonts with missing tables report zero thickness.
+        let thickness = |size: i16, fallback: f32| {
+            if size > 0 {
+                size as f32 * scale
+            } else {
+                fallback
+            }
+        };
+        Self {
+            underline_offset: underline_position as f32 * scale,
+            underline_thickness: thickness(underline_thickness, fallback.underline_thickness),
+            strikeout_offset: if strikeout_size > 0 {
+                strikeout_position as f32 * scale
+            } else {
+                fallback.strikeout_offset
+            },
+            strikeout_thickness: thickness(strikeout_size, fallback.strikeout_thickness),
+            ascent: ascender as f32 * scale,
+        }
+    }
+
+    /// Returns conventional metrics for fonts that do not provide them.
+    pub fn fallback(ppem: f32) -> Self {
+        let thickness = (ppem / 14.0).max(1.0);
+        Self {
+            underline_offset: -ppem * 0.1,
+            underline_thickness: thickness,
+            strikeout_offset: ppem * 0.3,
+            strikeout_thickness: thickness,
+            ascent: ppem * 0.8,
+        }
+    }
+
+    /// Returns the rectangle covered by a decoration between `x0` and `x1`
+    /// for text with the baseline at `y`, in y-down coordinates.
+    pub fn rect(&self, kind: DecorationKind, x0: f64, x1: f64, y: f64) -> Rect {
+        let (offset, thickness) = match kind {
+            DecorationKind::Underline => (self.underline_offset, self.underline_thickness),
+            DecorationKind::Strikethrough => (self.strikeout_offset, self.strikeout_thickness),
+            DecorationKind::Overline => (self.ascent, self.underline_thickness),
+        };
+        let top = y - offset as f64;
+        Rect::new(x0, top, x1, top + thickness as f64)
+    }
+}
+
+/// Draws a text decoration between `x0` and `x1` for text with the baseline
+/// at `y`.
+///
+/// If `skip_ink` contains the glyph outlines, underlines and overlines are
+/// interrupted where they would cross the glyphs, such as around
+/// descenders, leaving a gap of the thickness of the line on either side.
+/// Strikethroughs never skip ink.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_decoration<'b>(
+    builder: &mut SceneBuilder,
+    transform: Affine,
+    brush: impl Into<BrushRef<'b>>,
+    metrics: &DecorationMetrics,
+    kind: DecorationKind,
+    x0: f64,
+    x1: f64,
+    y: f64,
+    skip_ink: Option<&BezPath>,
+) {
+    let rect = metrics.rect(kind, x0, x1, y);
+    let skip_ink = skip_ink.filter(|_| kind != DecorationKind::Strikethrough);
+    let outline = match skip_ink {
+        Some(outline) => outline,
+        None => {
+            builder.fill(Fill::NonZero, transform, brush, None, &rect);
+            return;
+        }
+    };
+    let gap = rect.height();
+    let mut path = BezPath::new();
+    let mut push_rect = |x0: f64, x1: f64| {
+        path.move_to((x0, rect.y0));
+        path.line_to((x1, rect.y0));
+        path.line_to((x1, rect.y1));
+        path.line_to((x0, rect.y1));
+        path.close_path();
+    };
+    let mut x = rect.x0;
+    for (ink_x0, ink_x1) in ink_intervals(outline, rect.y0 - gap * 0.5, rect.y1 + gap * 0.5) {
+        let (ink_x0, ink_x1) = (ink_x0 - gap, ink_x1 + gap);
+        if ink_x0 > x {
+            push_rect(x, ink_x0.min(rect.x1));
+        }
+        x = x.max(ink_x1);
+    }
+    if x < rect.x1 {
+        push_rect(x, rect.x1);
+    }
+    builder.fill(Fill::NonZero, transform, brush, None, &path);
+}
+
+impl TextBlob {
+    /// Draws a decoration under, through or over the glyphs of the blob,
+    /// spanning from `x0` to `x1` with the baseline at `y` in the
+    /// coordinates of the blob. See [`draw_decoration`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_decoration<'b>(
+        &self,
+        builder: &mut SceneBuilder,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+        metrics: &DecorationMetrics,
+        kind: DecorationKind,
+        x0: f64,
+        x1: f64,
+        y: f64,
+        skip_ink: bool,
+    ) {
+        let outline = skip_ink.then(|| self.outline());
+        draw_decoration(builder, transform, brush, metrics, kind, x0, x1, y, outline);
+    }
+}
+
+/// Returns the sorted and merged horizontal extents of the parts of the
+/// outline that lie in the band between `y0` and `y1`.
+fn ink_intervals(outline: &BezPath, y0: f64, y1: f64) -> Vec<(f64, f64)> {
+    let mut lines = vec![];
+    let mut start = Point::ZERO;
+    let mut last = Point::ZERO;
+    peniko::kurbo::flatten(outline.iter(), SKIP_INK_TOLERANCE, |el| match el {
+        PathEl::MoveTo(p) => {
+            start = p;
+            last = p;
+        }
+        PathEl::LineTo(p) => {
+            lines.push((last, p));
+            last = p;
+        }
+        PathEl::ClosePath => {
+            lines.push((last, start));
+            last = start;
+        }
+        _ => {}
+    });
+    let mut intervals = vec![];
+    // Edges that pass through the band.
+    for (p0, p1) in &lines {
+        let (lo, hi) = if p0.y <= p1.y { (p0, p1) } else { (p1, p0) };
+        if hi.y < y0 || lo.y > y1 {
+            continue;
+        }
+        if hi.y == lo.y {
+            intervals.push((lo.x.min(hi.x), lo.x.max(hi.x)));
+            continue;
+        }
+        let x_at = |y: f64| lo.x + (hi.x - lo.x) * ((y - lo.y) / (hi.y - lo.y));
+        let xa = x_at(lo.y.max(y0));
+        let xb = x_at(hi.y.min(y1));
+        intervals.push((xa.min(xb), xa.max(xb)));
+    }
+    // Interiors of the glyphs, sampled at the edges and the center of the
+    // band with the nonzero winding rule.
+    for y in [y0, (y0 + y1) * 0.5, y1] {
+        let mut crossings: Vec<(f64, i32)> = lines
+            .iter()
+            .filter_map(|(p0, p1)| {
+                if (p0.y <= y) == (p1.y <= y) {
+                    return None;
+                }
+                let x = p0.x + (p1.x - p0.x) * ((y - p0.y) / (p1.y - p0.y));
+                Some((x, if p1.y > p0.y { 1 } else { -1 }))
+            })
+            .collect();
+        crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut winding = 0;
+        let mut span_start = 0.0;
+        for (x, dir) in crossings {
+            if winding == 0 {
+                span_start = x;
+            }
+            winding += dir;
+            if winding == 0 {
+                intervals.push((span_start, x));
+            }
+        }
+    }
+    intervals.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut merged: Vec<(f64, f64)> = vec![];
+    for (x0, x1) in intervals {
+        match merged.last_mut() {
+            Some(last) if x0 <= last.1 => last.1 = last.1.max(x1),
+            _ => merged.push((x0, x1)),
+        }
+    }
+    merged
+}
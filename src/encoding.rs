@@ -16,21 +16,43 @@
 
 //! Raw scene encoding.
 
+mod dash;
 mod draw;
 mod encoding;
+mod instance;
 mod math;
+mod merge;
 mod monoid;
 mod packed;
 mod path;
+mod sanitize;
+mod serialize;
+mod shape;
+mod version;
 
 pub mod resource;
 
+pub(crate) use dash::dash_path;
 pub use draw::{
-    DrawBeginClip, DrawColor, DrawImage, DrawLinearGradient, DrawMonoid, DrawRadialGradient,
-    DrawTag,
+    DrawBeginClip, DrawColor, DrawEllipse, DrawErase, DrawImage, DrawLinearGradient,
+    DrawMeshGradient, DrawMonoid, DrawPaletteColor, DrawProcedural, DrawRadialGradient,
+    DrawRoundedRect, DrawShadow, DrawSweepGradient, DrawTag, ImageSampling, MeshPatchData,
+    YuvMatrix, YuvRange, DRAW_FLAG_ADDITIVE,
 };
-pub use encoding::Encoding;
+pub(crate) use encoding::{Backdrop, EncodingMark};
+pub use encoding::{Encoding, PathRange, MAX_PALETTE_SIZE};
+pub use instance::Instance;
 pub use math::Transform;
+pub use merge::MergeOffsets;
 pub use monoid::Monoid;
-pub use packed::{Config, Layout, PackedEncoding};
+pub use packed::{
+    Config, Layout, PackedEncoding, CONFIG_DEBUG_COVERAGE, CONFIG_DEBUG_WINDING, CONFIG_DITHER,
+    CONFIG_SIMPLIFY_BLURS,
+};
 pub use path::{PathBbox, PathEncoder, PathMonoid, PathSegment, PathSegmentType, PathTag};
+pub use sanitize::{SanitizeLimits, SanitizeReport};
+pub use serialize::{Compression, MAX_READ_SIZE};
+pub use version::{
+    EncodingFeatures, EncodingHeader, IncompatibleEncoding, ENCODING_MAJOR_VERSION,
+    ENCODING_MINOR_VERSION,
+};
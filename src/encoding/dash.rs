@@ -0,0 +1,190 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Dashing of paths for strokes with a dash pattern.
+
+use peniko::kurbo::{
+    BezPath, CubicBez, Line, ParamCurve, ParamCurveArclen, PathEl, PathSeg, Point, QuadBez,
+};
+
+/// Accuracy of the arc length computations, in user space units.
+const ARCLEN_ACCURACY: f64 = 1e-3;
+
+/// Maximum number of periods of the dash pattern along a path. Paths that
+/// would be split into more dashes are stroked without dashes, since the
+/// pattern is too fine to be visible.
+const MAX_DASH_PERIODS: f64 = 100_000.0;
+
+/// Splits the path into the dashes of a stroke with the specified pattern.
+///
+/// The pattern alternates between the lengths of dashes and gaps, starting
+/// `offset` units into the pattern at the beginning of every subpath. A
+/// pattern with an odd number of lengths is repeated to make it even, as in
+/// SVG. Returns `None` if the pattern is empty, has a negative or
+/// non-finite length or sums to zero, or if its period is negligible
+/// compared to the length of the path, in which case the path is stroked
+/// without dashes.
+pub(crate) fn dash_path(
+    elements: impl IntoIterator<Item = PathEl>,
+    offset: f32,
+    pattern: &[f32],
+) -> Option<BezPath> {
+    let mut lengths: Vec<f64> = pattern.iter().map(|len| *len as f64).collect();
+    if lengths.len() % 2 == 1 {
+        lengths.extend_from_within(..);
+    }
+    let total: f64 = lengths.iter().sum();
+    if lengths.is_empty()
+        || lengths.iter().any(|len| !len.is_finite() || *len < 0.0)
+        || total <= 0.0
+    {
+        return None;
+    }
+    let elements: Vec<PathEl> = elements.into_iter().collect();
+    if control_polygon_length(&elements) / total > MAX_DASH_PERIODS {
+        return None;
+    }
+    // Find the position in the pattern at the start of each subpath.
+    let mut phase = (offset as f64).rem_euclid(total);
+    let mut index = 0;
+    while phase >= lengths[index] {
+        phase -= lengths[index];
+        index = (index + 1) % lengths.len();
+    }
+    let mut dasher = Dasher {
+        out: BezPath::new(),
+        lengths: &lengths,
+        start: (index, lengths[index] - phase),
+        index,
+        remaining: lengths[index] - phase,
+        drawing: false,
+    };
+    let mut start = Point::ZERO;
+    let mut last = Point::ZERO;
+    for el in elements {
+        match el {
+            PathEl::MoveTo(p) => {
+                dasher.restart();
+                start = p;
+                last = p;
+            }
+            PathEl::LineTo(p) => {
+                dasher.segment(PathSeg::Line(Line::new(last, p)));
+                last = p;
+            }
+            PathEl::QuadTo(p1, p2) => {
+                dasher.segment(PathSeg::Quad(QuadBez::new(last, p1, p2)));
+                last = p2;
+            }
+            PathEl::CurveTo(p1, p2, p3) => {
+                dasher.segment(PathSeg::Cubic(CubicBez::new(last, p1, p2, p3)));
+                last = p3;
+            }
+            PathEl::ClosePath => {
+                if last != start {
+                    dasher.segment(PathSeg::Line(Line::new(last, start)));
+                }
+                last = start;
+            }
+        }
+    }
+    Some(dasher.out)
+}
+
+/// Returns the length of the control polygons of the segments of a path,
+/// which bounds its arc length.
+fn control_polygon_length(elements: &[PathEl]) -> f64 {
+    let mut length = 0.0;
+    let mut start = Point::ZERO;
+    let mut last = Point::ZERO;
+    for el in elements {
+        let (points, n) = match *el {
+            PathEl::MoveTo(p) => {
+                start = p;
+                last = p;
+                continue;
+            }
+            PathEl::LineTo(p) => ([p; 3], 1),
+            PathEl::QuadTo(p1, p2) => ([p1, p2, p2], 2),
+            PathEl::CurveTo(p1, p2, p3) => ([p1, p2, p3], 3),
+            PathEl::ClosePath => ([start; 3], 1),
+        };
+        for p in &points[..n] {
+            length += last.distance(*p);
+            last = *p;
+        }
+    }
+    length
+}
+
+struct Dasher<'a> {
+    out: BezPath,
+    lengths: &'a [f64],
+    /// Index into the pattern and remaining length at the start of a
+    /// subpath.
+    start: (usize, f64),
+    index: usize,
+    remaining: f64,
+    /// True if the last emitted dash continues into the next segment.
+    drawing: bool,
+}
+
+impl<'a> Dasher<'a> {
+    fn restart(&mut self) {
+        self.index = self.start.0;
+        self.remaining = self.start.1;
+        self.drawing = false;
+    }
+
+    fn segment(&mut self, seg: PathSeg) {
+        let len = seg.arclen(ARCLEN_ACCURACY);
+        if len <= 0.0 {
+            return;
+        }
+        let mut pos = 0.0;
+        let mut t0 = 0.0;
+        while pos < len {
+            let step = self.remaining.min(len - pos);
+            // The end of the segment is reached exactly, even if adding the
+            // step would round to a smaller value.
+            pos = if step >= len - pos { len } else { pos + step };
+            self.remaining -= step;
+            let t1 = if pos >= len {
+                1.0
+            } else {
+                seg.inv_arclen(pos, ARCLEN_ACCURACY)
+            };
+            if self.index % 2 == 0 && t1 > t0 {
+                let sub = seg.subsegment(t0..t1);
+                if !self.drawing {
+                    self.out.move_to(sub.eval(0.0));
+                    self.drawing = true;
+                }
+                self.out.push(match sub {
+                    PathSeg::Line(line) => PathEl::LineTo(line.p1),
+                    PathSeg::Quad(quad) => PathEl::QuadTo(quad.p1, quad.p2),
+                    PathSeg::Cubic(cubic) => PathEl::CurveTo(cubic.p1, cubic.p2, cubic.p3),
+                });
+            }
+            t0 = t1;
+            if self.remaining <= 0.0 {
+                self.index = (self.index + 1) % self.lengths.len();
+                self.remaining = self.lengths[self.index];
+                self.drawing = false;
+            }
+        }
+    }
+}
@@ -15,10 +15,15 @@
 // Also licensed under MIT license, at your choice.
 
 use bytemuck::{Pod, Zeroable};
-use peniko::{BlendMode, Color};
+use peniko::{BlendMode, Color, Extend, Fill, Image};
 
 use super::Monoid;
 
+/// Draw flag for draw objects that are added to the content beneath them,
+/// clamped as for the plus-lighter compose mode, instead of being composited
+/// with source-over.
+pub const DRAW_FLAG_ADDITIVE: u32 = 1;
+
 /// Draw tag representation.
 #[derive(Copy, Clone, PartialEq, Eq, Pod, Zeroable)]
 #[repr(C)]
@@ -37,8 +42,32 @@ impl DrawTag {
     /// Radial gradient fill.
     pub const RADIAL_GRADIENT: Self = Self(0x2dc);
 
+    /// Sweep gradient fill.
+    pub const SWEEP_GRADIENT: Self = Self(0x298);
+
     /// Image fill.
-    pub const IMAGE: Self = Self(0x48);
+    pub const IMAGE: Self = Self(0x2d0);
+
+    /// Color fill from a palette slot.
+    pub const PALETTE_COLOR: Self = Self(0x444);
+
+    /// Eraser that removes content from the current layer.
+    pub const ERASE: Self = Self(0x46);
+
+    /// Mesh gradient fill.
+    pub const MESH_GRADIENT: Self = Self(0x204);
+
+    /// Procedural pattern fill.
+    pub const PROCEDURAL: Self = Self(0x314);
+
+    /// Gaussian blurred rounded rectangle or path, for shadows.
+    pub const SHADOW: Self = Self(0x714);
+
+    /// Antialiased rounded rectangle with a solid color.
+    pub const ROUNDED_RECT: Self = Self(0x39c);
+
+    /// Antialiased ellipse with a solid color.
+    pub const ELLIPSE: Self = Self(0x316);
 
     /// Begin layer/clip.
     pub const BEGIN_CLIP: Self = Self(0x9);
@@ -52,6 +81,11 @@ impl DrawTag {
     pub const fn info_size(self) -> u32 {
         (self.0 >> 6) & 0xf
     }
+
+    /// Returns the size of the draw data (in u32s) used by this tag.
+    pub const fn data_size(self) -> u32 {
+        (self.0 >> 2) & 0x7
+    }
 }
 
 /// Draw data for a solid color.
@@ -72,6 +106,22 @@ impl DrawColor {
     }
 }
 
+/// Draw data for a color from the palette.
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct DrawPaletteColor {
+    /// Index of the palette slot.
+    pub slot: u32,
+}
+
+/// Draw data for an eraser.
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct DrawErase {
+    /// Strength of the eraser.
+    pub alpha: f32,
+}
+
 /// Draw data for a linear gradient.
 #[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
 #[repr(C)]
@@ -100,14 +150,321 @@ pub struct DrawRadialGradient {
     pub r1: f32,
 }
 
+/// Draw data for a sweep gradient.
+///
+/// The stops span the angles from `t0` to `t1` in radians, measured
+/// clockwise from the positive x axis in a y-down coordinate system. Outside
+/// that range the ramp is extended according to `extend`, so a range of a
+/// fraction of a turn with [`Extend::Repeat`]
+/// repeats the stops around the circle.
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct DrawSweepGradient {
+    /// Ramp index.
+    pub index: u32,
+    /// Center point.
+    pub p0: [f32; 2],
+    /// Start angle.
+    pub t0: f32,
+    /// End angle.
+    pub t1: f32,
+    /// Extend mode: 0 for pad, 1 for repeat and 2 for reflect.
+    pub extend: u32,
+}
+
+impl DrawSweepGradient {
+    /// Returns the encoded value of an extend mode.
+    pub fn encode_extend(extend: Extend) -> u32 {
+        match extend {
+            Extend::Pad => 0,
+            Extend::Repeat => 1,
+            Extend::Reflect => 2,
+        }
+    }
+}
+
 /// Draw data for an image.
 #[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
 #[repr(C)]
 pub struct DrawImage {
-    /// Image index.
-    pub index: u32,
-    /// Packed image offset.
-    pub offset: u32,
+    /// Packed coordinates with x in the high 16 bits and y in the low 16
+    /// bits. These are encoded as the origin of the sampled region within
+    /// the image, and the atlas position of the image is added when the
+    /// encoding is packed.
+    pub xy: u32,
+    /// Packed dimensions of the sampled region with width in the high 16
+    /// bits and height in the low 16 bits.
+    pub width_height: u32,
+    /// Opacity of the image.
+    pub alpha: f32,
+    /// Packed sampling flags with the extend mode in the low two bits,
+    /// encoded as for [`DrawSweepGradient::encode_extend`],
+    /// [`NEAREST`](Self::NEAREST) for nearest neighbor sampling and the
+    /// `YUV` flags for images holding packed YUV samples.
+    pub flags: u32,
+}
+
+/// Filter used when sampling an image brush.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum ImageSampling {
+    /// Bilinear filtering, suitable for photographic content.
+    #[default]
+    Linear,
+    /// Nearest neighbor sampling, which keeps the edges of pixel art sharp
+    /// when it is magnified.
+    Nearest,
+}
+
+/// Matrix used to convert YUV samples of video frames to RGB.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum YuvMatrix {
+    /// ITU-R BT.601, used by standard definition video.
+    Bt601,
+    /// ITU-R BT.709, used by high definition video.
+    #[default]
+    Bt709,
+}
+
+/// Range of the YUV samples of video frames.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum YuvRange {
+    /// Luma in 16..=235 and chroma in 16..=240, as produced by most video
+    /// decoders.
+    #[default]
+    Limited,
+    /// Luma and chroma use the full range of 0..=255, as in JPEG.
+    Full,
+}
+
+impl DrawImage {
+    /// Flag for nearest neighbor sampling.
+    pub const NEAREST: u32 = 4;
+
+    /// Flag for images whose red, green and blue channels hold the Y, U and
+    /// V samples of a video frame, converted with the BT.601 matrix.
+    pub const YUV_BT601: u32 = 8;
+
+    /// Flag for images holding YUV samples converted with the BT.709
+    /// matrix.
+    pub const YUV_BT709: u32 = 16;
+
+    /// Flag for YUV samples in the full range rather than the limited range.
+    pub const YUV_FULL_RANGE: u32 = 32;
+
+    /// Creates new image draw data for an image with the specified opacity.
+    /// The atlas coordinates are resolved when the encoding is packed.
+    pub fn new(image: &Image, alpha: f32) -> Self {
+        Self::with_sampling(image, alpha, ImageSampling::Linear)
+    }
+
+    /// Creates new image draw data for an image with the specified opacity
+    /// and sampling filter.
+    pub fn with_sampling(image: &Image, alpha: f32, sampling: ImageSampling) -> Self {
+        let mut flags = DrawSweepGradient::encode_extend(image.extend);
+        if sampling == ImageSampling::Nearest {
+            flags |= Self::NEAREST;
+        }
+        Self {
+            xy: 0,
+            width_height: (image.width << 16) | (image.height & 0xffff),
+            alpha,
+            flags,
+        }
+    }
+
+    /// Creates new image draw data for an image holding packed YUV samples,
+    /// which are converted to RGB when the image is sampled.
+    pub fn with_yuv(image: &Image, alpha: f32, matrix: YuvMatrix, range: YuvRange) -> Self {
+        let mut draw = Self::new(image, alpha);
+        draw.flags |= match matrix {
+            YuvMatrix::Bt601 => Self::YUV_BT601,
+            YuvMatrix::Bt709 => Self::YUV_BT709,
+        };
+        if range == YuvRange::Full {
+            draw.flags |= Self::YUV_FULL_RANGE;
+        }
+        draw
+    }
+
+    /// Creates new image draw data that samples a region of an image as if
+    /// it were an image of its own, using the region's origin as the origin
+    /// of the brush.
+    ///
+    /// The region is clamped to the bounds of the image. It is always
+    /// extended by repeating its edge texels, whatever the extend mode of
+    /// the image, so that filtering never reads texels outside of it.
+    pub fn with_region(
+        image: &Image,
+        alpha: f32,
+        sampling: ImageSampling,
+        origin: [u32; 2],
+        size: [u32; 2],
+    ) -> Self {
+        let mut draw = Self::with_sampling(image, alpha, sampling);
+        let x = origin[0].min(image.width.saturating_sub(1));
+        let y = origin[1].min(image.height.saturating_sub(1));
+        let width = size[0].clamp(1, (image.width - x).max(1));
+        let height = size[1].clamp(1, (image.height - y).max(1));
+        draw.xy = (x << 16) | (y & 0xffff);
+        draw.width_height = (width << 16) | (height & 0xffff);
+        draw.flags = (draw.flags & !3) | DrawSweepGradient::encode_extend(Extend::Pad);
+        draw
+    }
+}
+
+/// Draw data for a mesh gradient patch.
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct DrawMeshGradient {
+    /// Index of the patch in the mesh patch stream.
+    pub patch: u32,
+}
+
+/// Encoded tensor product patch referenced by a mesh gradient draw object.
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct MeshPatchData {
+    /// Control points in row major order.
+    pub points: [[f32; 2]; 16],
+    /// Packed premultiplied corner colors.
+    pub colors: [u32; 4],
+}
+
+impl MeshPatchData {
+    /// Creates new mesh patch data.
+    pub fn new(patch: &crate::mesh::MeshPatch) -> Self {
+        Self {
+            points: patch.points.map(|p| [p.x as f32, p.y as f32]),
+            colors: patch.colors.map(|c| c.to_premul_u32()),
+        }
+    }
+}
+
+/// Draw data for a procedural brush.
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct DrawProcedural {
+    /// Pattern kind.
+    pub kind: u32,
+    /// First packed premultiplied color.
+    pub color0: u32,
+    /// Second packed premultiplied color.
+    pub color1: u32,
+    /// Size of a pattern cell.
+    pub scale: f32,
+    /// Kind specific parameter: the stripe duty cycle as f32 bits or the
+    /// noise seed.
+    pub param: u32,
+}
+
+impl DrawProcedural {
+    /// Creates new procedural brush draw data.
+    pub fn new(brush: &crate::procedural::ProceduralBrush) -> Self {
+        use crate::procedural::ProceduralKind;
+        let (kind, param) = match brush.kind {
+            ProceduralKind::Checkerboard => (0, 0),
+            ProceduralKind::Stripes { duty } => (1, duty.clamp(0.0, 1.0).to_bits()),
+            ProceduralKind::ValueNoise { seed } => (2, seed),
+            ProceduralKind::SimplexNoise { seed } => (3, seed),
+        };
+        Self {
+            kind,
+            color0: brush.colors[0].to_premul_u32(),
+            color1: brush.colors[1].to_premul_u32(),
+            scale: brush.scale,
+            param,
+        }
+    }
+}
+
+/// Draw data for a shadow.
+///
+/// When the encoded path is a fill, the draw is the analytically blurred
+/// rounded rectangle centered at the origin of brush space. When it is a
+/// stroke, the outline of the stroked path is blurred with a standard
+/// deviation of a sixth of the line width and only the color is used.
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct DrawShadow {
+    /// Packed premultiplied color.
+    pub color: u32,
+    /// Width of the rectangle.
+    pub width: f32,
+    /// Height of the rectangle.
+    pub height: f32,
+    /// Corner radius of the rectangle.
+    pub radius: f32,
+    /// Standard deviation of the blur.
+    pub std_dev: f32,
+}
+
+impl DrawShadow {
+    /// Creates new shadow draw data.
+    pub fn new(color: Color, width: f32, height: f32, radius: f32, std_dev: f32) -> Self {
+        Self {
+            color: color.to_premul_u32(),
+            width,
+            height,
+            radius,
+            std_dev,
+        }
+    }
+}
+
+/// Draw data for a rounded rectangle.
+///
+/// The rectangle is centered at the origin of brush space and its coverage
+/// is computed analytically, so the encoded path only bounds it.
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct DrawRoundedRect {
+    /// Packed premultiplied color.
+    pub color: u32,
+    /// Width of the rectangle.
+    pub width: f32,
+    /// Height of the rectangle.
+    pub height: f32,
+    /// Corner radii in the order top left, top right, bottom right and
+    /// bottom left.
+    pub radii: [f32; 4],
+}
+
+impl DrawRoundedRect {
+    /// Creates new rounded rectangle draw data.
+    pub fn new(color: Color, width: f32, height: f32, radii: [f32; 4]) -> Self {
+        Self {
+            color: color.to_premul_u32(),
+            width,
+            height,
+            radii,
+        }
+    }
+}
+
+/// Draw data for an ellipse.
+///
+/// The ellipse is axis aligned in the coordinate space of the path and its
+/// coverage is computed analytically, so the encoded path only bounds it.
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct DrawEllipse {
+    /// Packed premultiplied color.
+    pub color: u32,
+    /// Center of the ellipse.
+    pub center: [f32; 2],
+    /// Horizontal and vertical radii of the ellipse.
+    pub radii: [f32; 2],
+}
+
+impl DrawEllipse {
+    /// Creates new ellipse draw data.
+    pub fn new(color: Color, center: [f32; 2], radii: [f32; 2]) -> Self {
+        Self {
+            color: color.to_premul_u32(),
+            center,
+            radii,
+        }
+    }
 }
 
 /// Draw data for a clip or layer.
@@ -121,6 +478,28 @@ pub struct DrawBeginClip {
 }
 
 impl DrawBeginClip {
+    /// Flag in the blend mode indicating that the clip path uses the even-odd
+    /// fill rule.
+    pub const EVEN_ODD: u32 = 0x10000;
+
+    /// Flag in the blend mode indicating that the content of the layer is
+    /// replaced by its luminance as alpha before it is composited, as for
+    /// luminance masks.
+    pub const LUMINANCE: u32 = 0x20000;
+
+    /// Flag in the blend mode indicating that the colors of the layer are
+    /// transformed by a color matrix before it is composited. The index of
+    /// the matrix in the color matrix stream is stored in the bits above
+    /// [`COLOR_MATRIX_SHIFT`](Self::COLOR_MATRIX_SHIFT).
+    pub const COLOR_MATRIX: u32 = 0x40000;
+
+    /// Shift of the color matrix index in the blend mode.
+    pub const COLOR_MATRIX_SHIFT: u32 = 20;
+
+    /// Maximum number of color matrices that can be referenced by the
+    /// layers of an encoding.
+    pub const MAX_COLOR_MATRICES: u32 = 1 << (32 - Self::COLOR_MATRIX_SHIFT);
+
     /// Creates new clip draw data.
     pub fn new(blend_mode: BlendMode, alpha: f32) -> Self {
         Self {
@@ -128,6 +507,16 @@ impl DrawBeginClip {
             alpha,
         }
     }
+
+    /// Creates new clip draw data for a clip path with the specified fill
+    /// rule.
+    pub fn with_fill(blend_mode: BlendMode, alpha: f32, fill: Fill) -> Self {
+        let mut clip = Self::new(blend_mode, alpha);
+        if fill == Fill::EvenOdd {
+            clip.blend_mode |= Self::EVEN_ODD;
+        }
+        clip
+    }
 }
 
 /// Monoid for the draw tag stream.
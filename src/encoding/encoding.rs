@@ -14,15 +14,116 @@
 //
 // Also licensed under MIT license, at your choice.
 
-use super::resource::Patch;
+use std::ops::Range;
+use std::sync::Arc;
+
+use super::instance::InstanceBatch;
+use super::resource::{AsyncImage, BrushHandle, CustomResource, Patch, ResourceUsage};
+use super::shape::ShapeCache;
 use super::{
-    DrawColor, DrawLinearGradient, DrawRadialGradient, DrawTag, PathEncoder, PathTag, Transform,
+    DrawBeginClip, DrawColor, DrawEllipse, DrawErase, DrawImage, DrawLinearGradient,
+    DrawMeshGradient, DrawPaletteColor, DrawProcedural, DrawRadialGradient, DrawRoundedRect,
+    DrawShadow, DrawSweepGradient, DrawTag, ImageSampling, MeshPatchData, PathEncoder, PathTag,
+    Transform, YuvMatrix, YuvRange,
+};
+
+use peniko::{
+    kurbo::{Rect, Shape},
+    BlendMode, Brush, BrushRef, Color, ColorStop, ColorStops, Extend, Fill, GradientKind, Image,
 };
 
-use peniko::{kurbo::Shape, BlendMode, BrushRef, Color, ColorStop, Extend, GradientKind};
+/// Maximum number of replaced gradient stops remembered for reuse of their
+/// ramps.
+const MAX_RETIRED_STOPS: usize = 64;
+
+/// Maximum number of entries in the palette of a scene.
+pub const MAX_PALETTE_SIZE: u32 = 1 << 16;
+
+/// Visibility group id for draw objects that are not in any group.
+pub(crate) const NO_VISIBILITY_GROUP: u32 = !0;
+
+/// Scissor index for draw objects without a scissor rectangle.
+pub(crate) const NO_SCISSOR: u32 = !0;
+
+/// Ranges of the path tag and path data streams occupied by an encoded path.
+#[derive(Clone, Debug)]
+pub struct PathRange {
+    /// Range of the path tags, including the path marker.
+    pub tags: Range<usize>,
+    /// Range of the path data.
+    pub data: Range<usize>,
+    /// True if all subpaths were closed explicitly, so the path can also
+    /// be stroked.
+    pub closed: bool,
+}
+
+/// Lengths of the streams of an encoding at some point while it is built.
+#[derive(Copy, Clone, Default)]
+pub(crate) struct EncodingMark {
+    pub path_tags: usize,
+    pub path_data: usize,
+    pub draw_tags: usize,
+    pub draw_data: usize,
+    pub patches: usize,
+    pub color_stops: usize,
+    pub transforms: usize,
+    pub linewidths: usize,
+    pub mesh_patches: usize,
+    pub color_matrices: usize,
+    pub visibility_groups: usize,
+    pub scissors: usize,
+    pub draw_flags: usize,
+    pub instances: usize,
+    pub n_paths: u32,
+    pub n_path_segments: u32,
+    pub n_clips: u32,
+}
+
+impl EncodingMark {
+    /// Returns the mark at the same point of streams that were appended to
+    /// an encoding at `base`.
+    pub fn rebased(&self, base: &EncodingMark) -> Self {
+        Self {
+            path_tags: base.path_tags + self.path_tags,
+            path_data: base.path_data + self.path_data,
+            draw_tags: base.draw_tags + self.draw_tags,
+            draw_data: base.draw_data + self.draw_data,
+            patches: base.patches + self.patches,
+            color_stops: base.color_stops + self.color_stops,
+            transforms: base.transforms + self.transforms,
+            linewidths: base.linewidths + self.linewidths,
+            mesh_patches: base.mesh_patches + self.mesh_patches,
+            color_matrices: base.color_matrices + self.color_matrices,
+            visibility_groups: base.visibility_groups + self.visibility_groups,
+            scissors: base.scissors + self.scissors,
+            draw_flags: base.draw_flags + self.draw_flags,
+            instances: base.instances,
+            n_paths: base.n_paths + self.n_paths,
+            n_path_segments: base.n_path_segments + self.n_path_segments,
+            n_clips: base.n_clips + self.n_clips,
+        }
+    }
+}
+
+/// Layer whose backdrop is rendered and blurred before the encoding is
+/// rendered.
+#[derive(Clone)]
+pub(crate) struct Backdrop {
+    /// Streams that precede the layer.
+    pub mark: EncodingMark,
+    /// Number of layers that are open at the mark.
+    pub open_layers: u32,
+    /// Region of the backdrop in scene coordinates, including the extent of
+    /// the blur.
+    pub bounds: Rect,
+    /// Standard deviation of the blur in scene coordinates.
+    pub std_dev: f32,
+    /// Image that is resolved to the blurred region.
+    pub image: AsyncImage,
+}
 
 /// Encoded data streams for a scene.
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct Encoding {
     /// The path tag stream.
     pub path_tags: Vec<PathTag>,
@@ -40,6 +141,46 @@ pub struct Encoding {
     pub transforms: Vec<Transform>,
     /// The line width stream.
     pub linewidths: Vec<f32>,
+    /// The mesh gradient patch stream.
+    pub mesh_patches: Vec<MeshPatchData>,
+    /// Color matrices of filtered layers, in row major order with the
+    /// offsets in the last column.
+    pub color_matrices: Vec<[f32; 20]>,
+    /// Brushes referenced by handle. This is retained when the encoding is
+    /// reset so that handles remain valid while the scene is rebuilt.
+    pub brushes: Vec<Brush>,
+    /// Colors referenced by palette slot. Like the brush table, this is
+    /// retained when the encoding is reset.
+    pub palette: Vec<Color>,
+    /// Runs of draw objects that belong to a visibility group, as pairs of
+    /// the index of the first draw object in the run and the group id.
+    pub visibility_groups: Vec<(u32, u32)>,
+    /// Bitset of hidden visibility groups. Like the brush table, this is
+    /// retained when the encoding is reset.
+    pub hidden_groups: Vec<u32>,
+    /// Runs of draw objects that are limited to a scissor rectangle, as
+    /// pairs of the index of the first draw object in the run and the index
+    /// of the rectangle.
+    pub scissors: Vec<(u32, u32)>,
+    /// Scissor rectangles in device space as `[x0, y0, x1, y1]`.
+    pub scissor_rects: Vec<[f32; 4]>,
+    /// Runs of draw objects with draw flags, as pairs of the index of the
+    /// first draw object in the run and the flags.
+    pub draw_flags: Vec<(u32, u32)>,
+    /// Cache of the path elements of common shapes. This is retained when
+    /// the encoding is reset.
+    pub(crate) shape_cache: ShapeCache,
+    /// Instanced fragments, each preceded by the streams that were encoded
+    /// before it.
+    pub(crate) instances: Vec<InstanceBatch>,
+    /// Stops of gradients in the brush table that were replaced by
+    /// [`set_gradient_stops`](Self::set_gradient_stops), whose ramps can be
+    /// reused. This is retained when the encoding is reset.
+    pub(crate) retired_stops: Vec<ColorStops>,
+    /// Layers whose backdrop is blurred, in the order they were pushed.
+    /// These are only recorded for scenes and are not appended with
+    /// fragments.
+    pub(crate) backdrops: Vec<Backdrop>,
     /// Number of encoded paths.
     pub n_paths: u32,
     /// Number of encoded path segments.
@@ -56,7 +197,65 @@ impl Encoding {
 
     /// Returns true if the encoding is empty.
     pub fn is_empty(&self) -> bool {
-        self.path_tags.is_empty()
+        self.path_tags.is_empty() && self.instances.is_empty()
+    }
+
+    /// Returns an estimate of the memory used by the streams of the
+    /// encoding in bytes.
+    pub fn heap_size(&self) -> usize {
+        use std::mem::size_of_val;
+        self.uploaded_size() + size_of_val(&self.patches[..]) + size_of_val(&self.color_stops[..])
+    }
+
+    /// Returns the size in bytes of the streams and tables of the encoding
+    /// that are uploaded when it is rendered, including those of its
+    /// instanced fragments.
+    pub(crate) fn uploaded_size(&self) -> usize {
+        use std::mem::size_of_val;
+        let instances: usize = self
+            .instances
+            .iter()
+            .map(|batch| batch.uploaded_size())
+            .sum();
+        size_of_val(&self.path_tags[..])
+            + self.path_data.len()
+            + size_of_val(&self.draw_tags[..])
+            + self.draw_data.len()
+            + size_of_val(&self.transforms[..])
+            + size_of_val(&self.linewidths[..])
+            + size_of_val(&self.mesh_patches[..])
+            + size_of_val(&self.color_matrices[..])
+            + size_of_val(&self.palette[..])
+            + size_of_val(&self.visibility_groups[..])
+            + size_of_val(&self.hidden_groups[..])
+            + size_of_val(&self.scissors[..])
+            + size_of_val(&self.scissor_rects[..])
+            + size_of_val(&self.draw_flags[..])
+            + instances
+    }
+
+    /// Returns the lengths of the streams, which identify the current point
+    /// of the encoding.
+    pub(crate) fn mark(&self) -> EncodingMark {
+        EncodingMark {
+            path_tags: self.path_tags.len(),
+            path_data: self.path_data.len(),
+            draw_tags: self.draw_tags.len(),
+            draw_data: self.draw_data.len(),
+            patches: self.patches.len(),
+            color_stops: self.color_stops.len(),
+            transforms: self.transforms.len(),
+            linewidths: self.linewidths.len(),
+            mesh_patches: self.mesh_patches.len(),
+            color_matrices: self.color_matrices.len(),
+            visibility_groups: self.visibility_groups.len(),
+            scissors: self.scissors.len(),
+            draw_flags: self.draw_flags.len(),
+            instances: self.instances.len(),
+            n_paths: self.n_paths,
+            n_path_segments: self.n_path_segments,
+            n_clips: self.n_clips,
+        }
     }
 
     /// Clears the encoding.
@@ -72,20 +271,201 @@ impl Encoding {
         self.n_clips = 0;
         self.patches.clear();
         self.color_stops.clear();
+        self.mesh_patches.clear();
+        self.color_matrices.clear();
+        self.visibility_groups.clear();
+        self.scissors.clear();
+        self.scissor_rects.clear();
+        self.draw_flags.clear();
+        self.instances.clear();
+        self.backdrops.clear();
         if !is_fragment {
             self.transforms.push(Transform::IDENTITY);
             self.linewidths.push(-1.0);
         }
     }
 
+    /// Returns a copy of the encoding where every draw object is filled with
+    /// opaque white, so that rendering it produces the coverage of the
+    /// geometry in the alpha channel. Layers, clips and erasers are
+    /// preserved.
+    pub fn coverage_mask(&self) -> Self {
+        self.recolored(Color::rgba8(255, 255, 255, 255))
+    }
+
+    /// Returns a copy of the encoding where every draw object is filled with
+    /// a solid color. Layers, clips and erasers are preserved.
+    pub fn recolored(&self, color: Color) -> Self {
+        if let Some(expanded) = self.expand_instances() {
+            return expanded.recolored(color);
+        }
+        let color = DrawColor {
+            rgba: color.to_premul_u32(),
+        };
+        let mut mask = Self {
+            draw_data: Vec::with_capacity(self.draw_data.len()),
+            ..self.clone()
+        };
+        mask.patches.clear();
+        mask.color_stops.clear();
+        mask.mesh_patches.clear();
+        mask.brushes.clear();
+        let mut pos = 0;
+        for tag in &mut mask.draw_tags {
+            let size = tag.data_size() as usize * 4;
+            let data = &self.draw_data[pos..pos + size];
+            pos += size;
+            match *tag {
+                DrawTag::BEGIN_CLIP | DrawTag::END_CLIP | DrawTag::ERASE => {
+                    mask.draw_data.extend_from_slice(data);
+                }
+                // The paths of rounded rectangles, ellipses and shadows only
+                // bound them, so the draw keeps its shape and only the color
+                // is replaced.
+                DrawTag::ROUNDED_RECT | DrawTag::ELLIPSE | DrawTag::SHADOW => {
+                    mask.draw_data.extend_from_slice(bytemuck::bytes_of(&color));
+                    mask.draw_data.extend_from_slice(&data[4..]);
+                }
+                _ => {
+                    *tag = DrawTag::COLOR;
+                    mask.draw_data.extend_from_slice(bytemuck::bytes_of(&color));
+                }
+            }
+        }
+        mask
+    }
+
+    /// Returns the late bound resources referenced by the encoding, including
+    /// those of brushes referenced by handle.
+    pub fn resources(&self) -> ResourceUsage {
+        if let Some(expanded) = self.expand_instances() {
+            return expanded.resources();
+        }
+        let mut usage = ResourceUsage::default();
+        for patch in &self.patches {
+            match patch {
+                Patch::Ramp { stops, .. } => usage.add_gradient(&self.color_stops[stops.clone()]),
+                Patch::Image { image, .. } => usage.add_image(image),
+                Patch::Brush { handle, .. } => match self.brushes.get(handle.0 as usize) {
+                    Some(Brush::Gradient(gradient)) => usage.add_gradient(&gradient.stops),
+                    Some(Brush::Image(image)) => usage.add_image(image),
+                    _ => {}
+                },
+                Patch::Custom { resource, .. } => {
+                    resource.usage(&mut usage);
+                }
+            }
+        }
+        usage
+    }
+
     /// Appends another encoding to this one with an optional transform.
+    ///
+    /// Brush handles in the other encoding refer to the brush table of the
+    /// encoding they are appended to. Instanced fragments of the other
+    /// encoding remain instanced.
     pub fn append(&mut self, other: &Self, transform: &Option<Transform>) {
+        self.append_instances(other, transform);
+        self.append_streams(other, transform);
+    }
+
+    /// Appends the data streams of another encoding, ignoring its instanced
+    /// fragments.
+    pub(crate) fn append_streams(&mut self, other: &Self, transform: &Option<Transform>) {
         let stops_base = self.color_stops.len();
+        let draw_tags_base = self.draw_tags.len();
         let draw_data_base = self.draw_data.len();
+        let mesh_patches_base = self.mesh_patches.len() as u32;
+        let color_matrices_base = self.color_matrices.len() as u32;
         self.path_tags.extend_from_slice(&other.path_tags);
         self.path_data.extend_from_slice(&other.path_data);
         self.draw_tags.extend_from_slice(&other.draw_tags);
         self.draw_data.extend_from_slice(&other.draw_data);
+        if !other.visibility_groups.is_empty() {
+            // Draw objects following the appended encoding return to the
+            // group that was active before it.
+            let current = self.visibility_group();
+            self.visibility_groups.extend(
+                other
+                    .visibility_groups
+                    .iter()
+                    .map(|(ix, group)| (ix + draw_tags_base as u32, *group)),
+            );
+            self.encode_visibility_group(current);
+        }
+        if !other.scissors.is_empty() {
+            let current = self.scissor();
+            let rects_base = self.scissor_rects.len() as u32;
+            self.scissors
+                .extend(other.scissors.iter().map(|(ix, scissor)| {
+                    let scissor = if *scissor == NO_SCISSOR {
+                        NO_SCISSOR
+                    } else {
+                        scissor + rects_base
+                    };
+                    (ix + draw_tags_base as u32, scissor)
+                }));
+            match transform {
+                Some(transform) => {
+                    let transform = transform.to_kurbo();
+                    self.scissor_rects
+                        .extend(other.scissor_rects.iter().map(|[x0, y0, x1, y1]| {
+                            let rect = Rect::new(*x0 as f64, *y0 as f64, *x1 as f64, *y1 as f64);
+                            let rect = transform.transform_rect_bbox(rect);
+                            [rect.x0, rect.y0, rect.x1, rect.y1].map(|x| x as f32)
+                        }));
+                }
+                None => self.scissor_rects.extend_from_slice(&other.scissor_rects),
+            }
+            self.encode_scissor(current);
+        }
+        if !other.draw_flags.is_empty() {
+            let current = self.draw_flags();
+            self.draw_flags.extend(
+                other
+                    .draw_flags
+                    .iter()
+                    .map(|(ix, flags)| (ix + draw_tags_base as u32, *flags)),
+            );
+            self.encode_draw_flags(current);
+        }
+        if mesh_patches_base != 0 && !other.mesh_patches.is_empty() {
+            // Mesh gradients reference their patches by index so those
+            // need to be rebased.
+            let mut offset = draw_data_base;
+            for tag in &other.draw_tags {
+                if *tag == DrawTag::MESH_GRADIENT {
+                    let data = &mut self.draw_data[offset..offset + 4];
+                    let patch = u32::from_le_bytes(data.try_into().unwrap()) + mesh_patches_base;
+                    data.copy_from_slice(&patch.to_le_bytes());
+                }
+                offset += tag.data_size() as usize * 4;
+            }
+        }
+        self.mesh_patches.extend_from_slice(&other.mesh_patches);
+        if color_matrices_base != 0 && !other.color_matrices.is_empty() {
+            // Filtered layers also reference their matrices by index.
+            let mut offset = draw_data_base;
+            for tag in &other.draw_tags {
+                if *tag == DrawTag::BEGIN_CLIP {
+                    let data = &mut self.draw_data[offset..offset + 4];
+                    let mut blend = u32::from_le_bytes(data.try_into().unwrap());
+                    if blend & DrawBeginClip::COLOR_MATRIX != 0 {
+                        let shift = DrawBeginClip::COLOR_MATRIX_SHIFT;
+                        let index = (blend >> shift) + color_matrices_base;
+                        blend &= (1 << shift) - 1;
+                        if index < DrawBeginClip::MAX_COLOR_MATRICES {
+                            blend |= index << shift;
+                        } else {
+                            blend &= !DrawBeginClip::COLOR_MATRIX;
+                        }
+                        data.copy_from_slice(&blend.to_le_bytes());
+                    }
+                }
+                offset += tag.data_size() as usize * 4;
+            }
+        }
+        self.color_matrices.extend_from_slice(&other.color_matrices);
         self.n_paths += other.n_paths;
         self.n_path_segments += other.n_path_segments;
         self.n_clips += other.n_clips;
@@ -98,6 +478,32 @@ impl Encoding {
                         stops,
                     }
                 }
+                Patch::Image { offset, image } => Patch::Image {
+                    offset: draw_data_base + offset,
+                    image: image.clone(),
+                },
+                Patch::Brush {
+                    offset,
+                    draw_tag_ix,
+                    handle,
+                    alpha,
+                } => Patch::Brush {
+                    offset: draw_data_base + offset,
+                    draw_tag_ix: draw_tags_base + draw_tag_ix,
+                    handle: *handle,
+                    alpha: *alpha,
+                },
+                Patch::Custom {
+                    offset,
+                    draw_tag_ix,
+                    resource,
+                    alpha,
+                } => Patch::Custom {
+                    offset: draw_data_base + offset,
+                    draw_tag_ix: draw_tags_base + draw_tag_ix,
+                    resource: resource.clone(),
+                    alpha: *alpha,
+                },
             }));
         self.color_stops.extend_from_slice(&other.color_stops);
         if let Some(transform) = *transform {
@@ -142,9 +548,86 @@ impl Encoding {
     /// Encodes a shape. If `is_fill` is true, all subpaths will be automatically closed.
     /// Returns true if a non-zero number of segments were encoded.
     pub fn encode_shape(&mut self, shape: &impl Shape, is_fill: bool) -> bool {
+        self.encode_cached_shape(shape, is_fill).0 != 0
+    }
+
+    /// Encodes a shape as a fill and returns the ranges of the path tag and
+    /// path data streams that it occupies, or `None` if no segments were
+    /// encoded.
+    pub fn encode_fill_shape(&mut self, shape: &impl Shape) -> Option<PathRange> {
+        let tags_start = self.path_tags.len();
+        let data_start = self.path_data.len();
+        let (n_segments, closed) = self.encode_cached_shape(shape, true);
+        if n_segments == 0 {
+            return None;
+        }
+        Some(PathRange {
+            tags: tags_start..self.path_tags.len(),
+            data: data_start..self.path_data.len(),
+            closed,
+        })
+    }
+
+    /// Encodes a shape through the shape cache and returns the number of
+    /// encoded segments and whether all subpaths were closed explicitly.
+    fn encode_cached_shape(&mut self, shape: &impl Shape, is_fill: bool) -> (u32, bool) {
+        let mut encoder = PathEncoder::new(
+            &mut self.path_tags,
+            &mut self.path_data,
+            &mut self.n_path_segments,
+            &mut self.n_paths,
+            is_fill,
+        );
+        self.shape_cache.encode(&mut encoder, shape);
+        let closed = encoder.is_closed();
+        (encoder.finish(true), closed)
+    }
+
+    /// Encodes a copy of a previously encoded path, which is cheaper than
+    /// encoding the shape again.
+    pub fn encode_path_copy(&mut self, range: &PathRange) {
+        let tags = range.tags.clone();
+        let n_segments = self.path_tags[tags.clone()]
+            .iter()
+            .filter(|tag| tag.is_path_segment())
+            .count();
+        self.path_tags.extend_from_within(tags);
+        self.path_data.extend_from_within(range.data.clone());
+        self.n_path_segments += n_segments as u32;
+        self.n_paths += 1;
+    }
+
+    /// Appends the segments of a shape to the last encoded path without
+    /// modifying the associated draw object.
+    ///
+    /// Returns the number of appended segments or `None` if the encoding does
+    /// not end with a path that can be extended.
+    pub fn extend_last_path(&mut self, shape: &impl Shape) -> Option<u32> {
+        let len = self.path_tags.len();
+        if self.path_tags.last() != Some(&PathTag::PATH)
+            || self.draw_tags.last() == Some(&DrawTag::END_CLIP)
+        {
+            return None;
+        }
+        if len >= 2 && self.path_tags[len - 2] == PathTag::TRANSFORM {
+            // The path has a brush transform which would apply to any
+            // appended segments.
+            return None;
+        }
+        let is_fill = self
+            .linewidths
+            .last()
+            .map_or(true, |linewidth| *linewidth < 0.0);
+        self.path_tags.pop();
+        self.n_paths -= 1;
         let mut encoder = self.encode_path(is_fill);
         encoder.shape(shape);
-        encoder.finish(true) != 0
+        let n_segments = encoder.finish(true);
+        if n_segments == 0 {
+            self.path_tags.push(PathTag::PATH);
+            self.n_paths += 1;
+        }
+        Some(n_segments)
     }
 
     /// Encodes a brush with an optional alpha modifier.
@@ -191,22 +674,205 @@ impl Encoding {
                         gradient.extend,
                     );
                 }
-                GradientKind::Sweep { .. } => {
-                    todo!("sweep gradients aren't supported yet!")
+                GradientKind::Sweep {
+                    center,
+                    start_angle,
+                    end_angle,
+                } => {
+                    self.encode_sweep_gradient(
+                        DrawSweepGradient {
+                            index: 0,
+                            p0: point_to_f32(center),
+                            t0: start_angle,
+                            t1: end_angle,
+                            extend: 0,
+                        },
+                        gradient.stops.iter().copied(),
+                        alpha,
+                        gradient.extend,
+                    );
                 }
             },
-            BrushRef::Image(_) => {
-                todo!("images aren't supported yet!")
+            BrushRef::Image(image) => {
+                self.encode_image(image, alpha);
             }
         }
     }
 
+    /// Encodes a reference to a brush in the brush table. The brush is
+    /// resolved when the encoding is packed.
+    pub fn encode_brush_handle(&mut self, handle: BrushHandle, alpha: f32) {
+        self.patches.push(Patch::Brush {
+            offset: self.draw_data.len(),
+            draw_tag_ix: self.draw_tags.len(),
+            handle,
+            alpha,
+        });
+        self.draw_tags.push(DrawTag::NOP);
+    }
+
+    /// Encodes an asynchronously loaded image, which is resolved to the image
+    /// or its placeholder when the encoding is packed.
+    pub fn encode_async_image(&mut self, image: &AsyncImage, alpha: f32) {
+        self.encode_custom(Arc::new(image.clone()), alpha);
+    }
+
+    /// Encodes a custom resource, which is resolved to a brush when the
+    /// encoding is packed.
+    pub fn encode_custom(&mut self, resource: Arc<dyn CustomResource>, alpha: f32) {
+        self.patches.push(Patch::Custom {
+            offset: self.draw_data.len(),
+            draw_tag_ix: self.draw_tags.len(),
+            resource,
+            alpha,
+        });
+        self.draw_tags.push(DrawTag::NOP);
+    }
+
+    /// Adds a brush to the brush table and returns a handle to it.
+    pub fn add_brush(&mut self, brush: Brush) -> BrushHandle {
+        self.brushes.push(brush);
+        BrushHandle(self.brushes.len() as u32 - 1)
+    }
+
+    /// Replaces the color stops of the gradient in the brush table for the
+    /// specified handle, keeping its geometry. The ramp of the previous stops
+    /// is reused for the new stops once no draw refers to it, so animating
+    /// the stops does not grow the ramp cache.
+    ///
+    /// Returns false if the handle does not refer to a gradient.
+    pub fn set_gradient_stops(&mut self, handle: BrushHandle, stops: &[ColorStop]) -> bool {
+        let gradient = match self.brushes.get_mut(handle.0 as usize) {
+            Some(Brush::Gradient(gradient)) => gradient,
+            _ => return false,
+        };
+        if gradient.stops.as_slice() == stops {
+            return true;
+        }
+        let old = std::mem::replace(&mut gradient.stops, stops.into());
+        if !self.retired_stops.contains(&old) {
+            if self.retired_stops.len() == MAX_RETIRED_STOPS {
+                self.retired_stops.remove(0);
+            }
+            self.retired_stops.push(old);
+        }
+        true
+    }
+
     /// Encodes a solid color brush.
     pub fn encode_color(&mut self, color: DrawColor) {
         self.draw_tags.push(DrawTag::COLOR);
         self.draw_data.extend_from_slice(bytemuck::bytes_of(&color));
     }
 
+    /// Returns the visibility group that is assigned to subsequently encoded
+    /// draw objects.
+    pub fn visibility_group(&self) -> Option<u32> {
+        self.visibility_groups
+            .last()
+            .map(|(_, group)| *group)
+            .filter(|group| *group != NO_VISIBILITY_GROUP)
+    }
+
+    /// Assigns subsequently encoded draw objects to the given visibility
+    /// group.
+    pub fn encode_visibility_group(&mut self, group: Option<u32>) {
+        let group = group.unwrap_or(NO_VISIBILITY_GROUP);
+        let ix = self.draw_tags.len() as u32;
+        match self.visibility_groups.last_mut() {
+            Some(last) if last.0 == ix => last.1 = group,
+            Some(last) if last.1 == group => {}
+            None if group == NO_VISIBILITY_GROUP => {}
+            _ => self.visibility_groups.push((ix, group)),
+        }
+    }
+
+    /// Returns the draw flags that are applied to subsequently encoded draw
+    /// objects.
+    pub fn draw_flags(&self) -> u32 {
+        self.draw_flags.last().map(|(_, flags)| *flags).unwrap_or(0)
+    }
+
+    /// Applies the given draw flags, such as
+    /// [`DRAW_FLAG_ADDITIVE`](super::DRAW_FLAG_ADDITIVE), to subsequently
+    /// encoded draw objects. Clips and layers are not affected.
+    pub fn encode_draw_flags(&mut self, flags: u32) {
+        let ix = self.draw_tags.len() as u32;
+        match self.draw_flags.last_mut() {
+            Some(last) if last.0 == ix => last.1 = flags,
+            Some(last) if last.1 == flags => {}
+            None if flags == 0 => {}
+            _ => self.draw_flags.push((ix, flags)),
+        }
+    }
+
+    /// Returns the scissor rectangle that is applied to subsequently encoded
+    /// draw objects.
+    pub fn scissor(&self) -> Option<[f32; 4]> {
+        self.scissors
+            .last()
+            .filter(|(_, scissor)| *scissor != NO_SCISSOR)
+            .map(|(_, scissor)| self.scissor_rects[*scissor as usize])
+    }
+
+    /// Limits subsequently encoded draw objects to a rectangle in device
+    /// space, given as `[x0, y0, x1, y1]`, or removes the limit if `rect` is
+    /// `None`. Layers are not affected by the scissor.
+    pub fn encode_scissor(&mut self, rect: Option<[f32; 4]>) {
+        if rect == self.scissor() {
+            return;
+        }
+        let scissor = match rect {
+            Some(rect) => {
+                self.scissor_rects.push(rect);
+                self.scissor_rects.len() as u32 - 1
+            }
+            None => NO_SCISSOR,
+        };
+        let ix = self.draw_tags.len() as u32;
+        match self.scissors.last_mut() {
+            Some(last) if last.0 == ix => last.1 = scissor,
+            None if scissor == NO_SCISSOR => {}
+            _ => self.scissors.push((ix, scissor)),
+        }
+    }
+
+    /// Sets whether the draw objects in a visibility group are hidden.
+    pub fn set_group_hidden(&mut self, group: u32, hidden: bool) {
+        let word = (group / 32) as usize;
+        let bit = 1 << (group % 32);
+        if hidden {
+            if word >= self.hidden_groups.len() {
+                self.hidden_groups.resize(word + 1, 0);
+            }
+            self.hidden_groups[word] |= bit;
+        } else if let Some(bits) = self.hidden_groups.get_mut(word) {
+            *bits &= !bit;
+        }
+    }
+
+    /// Returns true if the draw objects in a visibility group are hidden.
+    pub fn is_group_hidden(&self, group: u32) -> bool {
+        self.hidden_groups
+            .get((group / 32) as usize)
+            .map(|bits| bits & (1 << (group % 32)) != 0)
+            .unwrap_or(false)
+    }
+
+    /// Encodes a color from the palette.
+    pub fn encode_palette_color(&mut self, slot: u32) {
+        self.draw_tags.push(DrawTag::PALETTE_COLOR);
+        self.draw_data
+            .extend_from_slice(bytemuck::bytes_of(&DrawPaletteColor { slot }));
+    }
+
+    /// Encodes an eraser with the given strength.
+    pub fn encode_erase(&mut self, alpha: f32) {
+        self.draw_tags.push(DrawTag::ERASE);
+        self.draw_data
+            .extend_from_slice(bytemuck::bytes_of(&DrawErase { alpha }));
+    }
+
     /// Encodes a linear gradient brush.
     pub fn encode_linear_gradient(
         &mut self,
@@ -235,12 +901,164 @@ impl Encoding {
             .extend_from_slice(bytemuck::bytes_of(&gradient));
     }
 
+    /// Encodes a sweep gradient brush.
+    pub fn encode_sweep_gradient(
+        &mut self,
+        gradient: DrawSweepGradient,
+        color_stops: impl Iterator<Item = ColorStop>,
+        alpha: f32,
+        extend: Extend,
+    ) {
+        let gradient = DrawSweepGradient {
+            extend: DrawSweepGradient::encode_extend(extend),
+            ..gradient
+        };
+        self.add_ramp(color_stops, alpha);
+        self.draw_tags.push(DrawTag::SWEEP_GRADIENT);
+        self.draw_data
+            .extend_from_slice(bytemuck::bytes_of(&gradient));
+    }
+
+    /// Encodes an image brush.
+    pub fn encode_image(&mut self, image: &Image, alpha: f32) {
+        self.encode_image_with_sampling(image, alpha, ImageSampling::Linear);
+    }
+
+    /// Encodes an image brush that is sampled with the specified filter.
+    pub fn encode_image_with_sampling(
+        &mut self,
+        image: &Image,
+        alpha: f32,
+        sampling: ImageSampling,
+    ) {
+        self.encode_draw_image(image, DrawImage::with_sampling(image, alpha, sampling));
+    }
+
+    /// Encodes an image brush holding packed YUV samples, which are
+    /// converted to RGB with the specified matrix and range when sampled.
+    pub fn encode_yuv_image(
+        &mut self,
+        image: &Image,
+        alpha: f32,
+        matrix: YuvMatrix,
+        range: YuvRange,
+    ) {
+        self.encode_draw_image(image, DrawImage::with_yuv(image, alpha, matrix, range));
+    }
+
+    /// Encodes an image brush that samples the region of the image with the
+    /// given origin and size in texels, as described by
+    /// [`DrawImage::with_region`].
+    pub fn encode_image_region(
+        &mut self,
+        image: &Image,
+        alpha: f32,
+        sampling: ImageSampling,
+        origin: [u32; 2],
+        size: [u32; 2],
+    ) {
+        self.encode_draw_image(
+            image,
+            DrawImage::with_region(image, alpha, sampling, origin, size),
+        );
+    }
+
+    fn encode_draw_image(&mut self, image: &Image, draw: DrawImage) {
+        self.patches.push(Patch::Image {
+            offset: self.draw_data.len(),
+            image: image.clone(),
+        });
+        self.draw_tags.push(DrawTag::IMAGE);
+        self.draw_data.extend_from_slice(bytemuck::bytes_of(&draw));
+    }
+
+    /// Encodes a mesh gradient patch.
+    pub fn encode_mesh_patch(&mut self, patch: MeshPatchData) {
+        let gradient = DrawMeshGradient {
+            patch: self.mesh_patches.len() as u32,
+        };
+        self.mesh_patches.push(patch);
+        self.draw_tags.push(DrawTag::MESH_GRADIENT);
+        self.draw_data
+            .extend_from_slice(bytemuck::bytes_of(&gradient));
+    }
+
+    /// Encodes a procedural brush.
+    pub fn encode_procedural(&mut self, procedural: DrawProcedural) {
+        self.draw_tags.push(DrawTag::PROCEDURAL);
+        self.draw_data
+            .extend_from_slice(bytemuck::bytes_of(&procedural));
+    }
+
+    /// Encodes a shadow.
+    pub fn encode_shadow(&mut self, shadow: DrawShadow) {
+        self.draw_tags.push(DrawTag::SHADOW);
+        self.draw_data
+            .extend_from_slice(bytemuck::bytes_of(&shadow));
+    }
+
+    /// Encodes a rounded rectangle.
+    pub fn encode_rounded_rect(&mut self, rect: DrawRoundedRect) {
+        self.draw_tags.push(DrawTag::ROUNDED_RECT);
+        self.draw_data.extend_from_slice(bytemuck::bytes_of(&rect));
+    }
+
+    /// Encodes an ellipse.
+    pub fn encode_ellipse(&mut self, ellipse: DrawEllipse) {
+        self.draw_tags.push(DrawTag::ELLIPSE);
+        self.draw_data
+            .extend_from_slice(bytemuck::bytes_of(&ellipse));
+    }
+
     /// Encodes a begin clip command.
     pub fn encode_begin_clip(&mut self, blend_mode: BlendMode, alpha: f32) {
-        use super::DrawBeginClip;
+        self.encode_begin_clip_with_fill(blend_mode, alpha, Fill::NonZero);
+    }
+
+    /// Encodes a begin clip command for a clip path with the specified fill
+    /// rule.
+    pub fn encode_begin_clip_with_fill(&mut self, blend_mode: BlendMode, alpha: f32, fill: Fill) {
         self.draw_tags.push(DrawTag::BEGIN_CLIP);
         self.draw_data
-            .extend_from_slice(bytemuck::bytes_of(&DrawBeginClip::new(blend_mode, alpha)));
+            .extend_from_slice(bytemuck::bytes_of(&DrawBeginClip::with_fill(
+                blend_mode, alpha, fill,
+            )));
+        self.n_clips += 1;
+    }
+
+    /// Encodes a begin clip command for a layer whose content is replaced by
+    /// its luminance as alpha when the layer is popped.
+    pub fn encode_begin_luminance_clip(&mut self, fill: Fill) {
+        let mut clip = DrawBeginClip::with_fill(BlendMode::default(), 1.0, fill);
+        clip.blend_mode |= DrawBeginClip::LUMINANCE;
+        self.draw_tags.push(DrawTag::BEGIN_CLIP);
+        self.draw_data.extend_from_slice(bytemuck::bytes_of(&clip));
+        self.n_clips += 1;
+    }
+
+    /// Encodes a begin clip command for a layer whose colors are
+    /// transformed by a color matrix when the layer is popped.
+    ///
+    /// The matrix is applied to the unpremultiplied colors of the layer, as
+    /// for the SVG `feColorMatrix` filter primitive, before the group alpha
+    /// and the blend mode. Layers beyond the maximum number of matrices are
+    /// encoded without one.
+    pub fn encode_begin_color_matrix_clip(
+        &mut self,
+        blend_mode: BlendMode,
+        alpha: f32,
+        fill: Fill,
+        matrix: &[f32; 20],
+    ) {
+        let mut clip = DrawBeginClip::with_fill(blend_mode, alpha, fill);
+        let index = self.color_matrices.len() as u32;
+        if index < DrawBeginClip::MAX_COLOR_MATRICES {
+            clip.blend_mode |=
+                DrawBeginClip::COLOR_MATRIX | index << DrawBeginClip::COLOR_MATRIX_SHIFT;
+            self.color_matrices.push(*matrix);
+        }
+        self.draw_tags.push(DrawTag::BEGIN_CLIP);
+        self.draw_data.extend_from_slice(bytemuck::bytes_of(&clip));
         self.n_clips += 1;
     }
 
@@ -253,6 +1071,135 @@ impl Encoding {
         self.n_clips += 1;
     }
 
+    /// Removes the draw objects that were encoded between two marks, along
+    /// with their paths and late bound resources. This removes a layer
+    /// whose path and begin clip command were encoded between the marks,
+    /// leaving its content in place as if it had been encoded without the
+    /// layer, or a complete layer with its content.
+    ///
+    /// The transforms and line widths of the removed paths are retained
+    /// since later draw objects may rely on them.
+    ///
+    /// Nothing is removed if the marks do not describe a range of the
+    /// streams.
+    pub(crate) fn remove_range(&mut self, start: &EncodingMark, end: &EncodingMark) {
+        let in_bounds = |start: usize, end: usize, len: usize| start <= end && end <= len;
+        let counts = (|| {
+            Some((
+                self.n_paths
+                    .checked_sub(end.n_paths.checked_sub(start.n_paths)?)?,
+                self.n_path_segments
+                    .checked_sub(end.n_path_segments.checked_sub(start.n_path_segments)?)?,
+                self.n_clips
+                    .checked_sub(end.n_clips.checked_sub(start.n_clips)?)?,
+            ))
+        })();
+        let (n_paths, n_path_segments, n_clips) = match counts {
+            Some(counts)
+                if in_bounds(start.path_tags, end.path_tags, self.path_tags.len())
+                    && in_bounds(start.path_data, end.path_data, self.path_data.len())
+                    && in_bounds(start.draw_tags, end.draw_tags, self.draw_tags.len())
+                    && in_bounds(start.draw_data, end.draw_data, self.draw_data.len())
+                    && in_bounds(start.patches, end.patches, self.patches.len()) =>
+            {
+                counts
+            }
+            _ => return,
+        };
+        let path_tags = self.path_tags[start.path_tags..end.path_tags]
+            .iter()
+            .copied()
+            .filter(|tag| *tag == PathTag::TRANSFORM || *tag == PathTag::LINEWIDTH)
+            .collect::<Vec<_>>();
+        let n_path_tags = end.path_tags - start.path_tags - path_tags.len();
+        self.path_tags
+            .splice(start.path_tags..end.path_tags, path_tags);
+        self.path_data.drain(start.path_data..end.path_data);
+        self.draw_tags.drain(start.draw_tags..end.draw_tags);
+        self.draw_data.drain(start.draw_data..end.draw_data);
+        self.patches.drain(start.patches..end.patches);
+        self.n_paths = n_paths;
+        self.n_path_segments = n_path_segments;
+        self.n_clips = n_clips;
+        let n_data = end.draw_data - start.draw_data;
+        let n_tags = end.draw_tags - start.draw_tags;
+        // Backdrops of removed layers are no longer drawn.
+        self.backdrops.retain(|backdrop| {
+            backdrop.mark.draw_tags < start.draw_tags || backdrop.mark.draw_tags >= end.draw_tags
+        });
+        for backdrop in &mut self.backdrops {
+            let mark = &mut backdrop.mark;
+            if mark.draw_tags >= end.draw_tags {
+                mark.path_tags = mark.path_tags.saturating_sub(n_path_tags);
+                mark.path_data = mark
+                    .path_data
+                    .saturating_sub(end.path_data - start.path_data);
+                mark.draw_tags = mark.draw_tags.saturating_sub(n_tags);
+                mark.draw_data = mark.draw_data.saturating_sub(n_data);
+                mark.patches = mark.patches.saturating_sub(end.patches - start.patches);
+                mark.n_paths = mark.n_paths.saturating_sub(end.n_paths - start.n_paths);
+                mark.n_path_segments = mark
+                    .n_path_segments
+                    .saturating_sub(end.n_path_segments - start.n_path_segments);
+                mark.n_clips = mark.n_clips.saturating_sub(end.n_clips - start.n_clips);
+            }
+        }
+        for patch in &mut self.patches[start.patches..] {
+            match patch {
+                Patch::Ramp { offset, .. } | Patch::Image { offset, .. } => {
+                    *offset = offset.saturating_sub(n_data)
+                }
+                Patch::Brush {
+                    offset,
+                    draw_tag_ix,
+                    ..
+                }
+                | Patch::Custom {
+                    offset,
+                    draw_tag_ix,
+                    ..
+                } => {
+                    *offset = offset.saturating_sub(n_data);
+                    *draw_tag_ix = draw_tag_ix.saturating_sub(n_tags);
+                }
+            }
+        }
+        // Runs that started at the removed draw objects now start at the
+        // content, and a later run at the same index replaces them.
+        for runs in [
+            &mut self.visibility_groups,
+            &mut self.scissors,
+            &mut self.draw_flags,
+        ] {
+            for run in runs.iter_mut() {
+                if run.0 as usize >= end.draw_tags {
+                    run.0 -= n_tags as u32;
+                } else if run.0 as usize > start.draw_tags {
+                    run.0 = start.draw_tags as u32;
+                }
+            }
+            runs.dedup_by(|later, earlier| {
+                if later.0 == earlier.0 {
+                    earlier.1 = later.1;
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+        // The runs of the prefixes of the backdrops end where their draw
+        // objects end.
+        for backdrop in &mut self.backdrops {
+            let mark = &mut backdrop.mark;
+            if mark.draw_tags >= start.draw_tags {
+                let ix = mark.draw_tags as u32;
+                mark.visibility_groups = self.visibility_groups.partition_point(|run| run.0 < ix);
+                mark.scissors = self.scissors.partition_point(|run| run.0 < ix);
+                mark.draw_flags = self.draw_flags.partition_point(|run| run.0 < ix);
+            }
+        }
+    }
+
     // Swap the last two tags in the path tag stream; used for transformed
     // gradients.
     pub fn swap_last_path_tags(&mut self) {
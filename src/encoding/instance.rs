@@ -0,0 +1,325 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! CPU-side instancing of encoded fragments.
+//!
+//! Instanced fragments are stored once along with the parameters of their
+//! instances while a scene is built and appended, and are expanded into
+//! plain draws on the CPU when the scene is rendered. The expansion is
+//! cached by the scene, but the uploaded encoding still grows with the
+//! number of instances.
+
+use std::hash::Hasher;
+use std::sync::Arc;
+
+use peniko::kurbo::Rect;
+use peniko::Color;
+
+use super::encoding::{Backdrop, EncodingMark};
+use super::{DrawColor, DrawTag, Encoding, PathTag, Transform};
+
+/// Parameters for a single instance of an instanced fragment.
+#[derive(Copy, Clone, Debug)]
+pub struct Instance {
+    /// Transform applied to the fragment.
+    pub transform: Transform,
+    /// Color that replaces all brushes of the fragment, if any.
+    pub color: Option<Color>,
+}
+
+/// Instances of a fragment drawn at some point of an encoding.
+#[derive(Clone)]
+pub(crate) struct InstanceBatch {
+    /// Streams of the encoding that precede the instances.
+    before: Encoding,
+    fragment: Arc<Encoding>,
+    instances: Vec<Instance>,
+}
+
+impl InstanceBatch {
+    /// Returns the uploaded size in bytes of the preceding streams, the
+    /// fragment and the parameters of the instances.
+    pub(crate) fn uploaded_size(&self) -> usize {
+        self.before.uploaded_size()
+            + self.fragment.uploaded_size()
+            + std::mem::size_of_val(&self.instances[..])
+    }
+}
+
+impl Encoding {
+    /// Encodes many instances of a fragment.
+    ///
+    /// Only the fragment and the parameters of the instances are stored, so
+    /// the size of the encoding does not grow with the size of the fragment.
+    /// The instances are expanded on the CPU the first time a scene is
+    /// rendered after it was built, and the expansion is reused by later
+    /// renders of the scene.
+    pub fn encode_instances(&mut self, fragment: Arc<Encoding>, instances: Vec<Instance>) {
+        if instances.is_empty() || fragment.is_empty() {
+            return;
+        }
+        let before = self.take_streams();
+        self.instances.push(InstanceBatch {
+            before,
+            fragment,
+            instances,
+        });
+    }
+
+    /// Appends the instanced fragments of another encoding along with the
+    /// streams that precede them, so that they are expanded with this
+    /// encoding rather than when they are appended. The streams that follow
+    /// the last batch are not appended.
+    pub(crate) fn append_instances(&mut self, other: &Self, transform: &Option<Transform>) {
+        for batch in &other.instances {
+            let visibility_group = self.visibility_group();
+            let scissor = self.scissor();
+            let draw_flags = self.draw_flags();
+            self.append_streams(&batch.before, transform);
+            // The instances are drawn with the state at the end of the
+            // preceding streams of the other encoding.
+            self.encode_visibility_group(batch.before.visibility_group());
+            self.encode_scissor(batch.before.scissor().map(|rect| match transform {
+                Some(transform) => {
+                    let rect = Rect::new(
+                        rect[0] as f64,
+                        rect[1] as f64,
+                        rect[2] as f64,
+                        rect[3] as f64,
+                    );
+                    let rect = transform.to_kurbo().transform_rect_bbox(rect);
+                    [rect.x0, rect.y0, rect.x1, rect.y1].map(|x| x as f32)
+                }
+                None => rect,
+            }));
+            self.encode_draw_flags(batch.before.draw_flags());
+            let instances = batch
+                .instances
+                .iter()
+                .map(|instance| Instance {
+                    transform: transform.map_or(instance.transform, |transform| {
+                        transform * instance.transform
+                    }),
+                    ..*instance
+                })
+                .collect();
+            self.encode_instances(batch.fragment.clone(), instances);
+            self.encode_visibility_group(visibility_group);
+            self.encode_scissor(scissor);
+            self.encode_draw_flags(draw_flags);
+        }
+    }
+
+    /// Returns true if the encoding contains instanced fragments.
+    pub fn has_instances(&self) -> bool {
+        !self.instances.is_empty()
+    }
+
+    /// Returns an encoding where all instanced fragments are expanded, or
+    /// `None` if there are none.
+    pub fn expand_instances(&self) -> Option<Self> {
+        if self.instances.is_empty() {
+            return None;
+        }
+        let mut expanded = Self {
+            brushes: self.brushes.clone(),
+            palette: self.palette.clone(),
+            hidden_groups: self.hidden_groups.clone(),
+            retired_stops: self.retired_stops.clone(),
+            ..Default::default()
+        };
+        // Marks of the backdrops refer to the streams of the batch they were
+        // recorded in, or to the trailing streams after all batches.
+        let rebase_backdrops = |expanded: &mut Self, ix: usize| {
+            let base = expanded.mark();
+            for backdrop in self.backdrops.iter().filter(|b| b.mark.instances == ix) {
+                expanded.backdrops.push(Backdrop {
+                    mark: backdrop.mark.rebased(&base),
+                    ..backdrop.clone()
+                });
+            }
+        };
+        for (ix, batch) in self.instances.iter().enumerate() {
+            rebase_backdrops(&mut expanded, ix);
+            expanded.append_streams(&batch.before, &None);
+            // The instances are drawn with the state at the end of the
+            // preceding streams.
+            expanded.encode_visibility_group(batch.before.visibility_group());
+            expanded.encode_scissor(batch.before.scissor());
+            expanded.encode_draw_flags(batch.before.draw_flags());
+            // Recolored instances share a copy of the fragment whose brushes
+            // are replaced by solid colors, which are then overwritten in the
+            // appended draw data.
+            let mut recolored: Option<(Encoding, Vec<usize>)> = None;
+            // Instances nested in the fragment are expanded once for all of
+            // its instances.
+            let nested = batch.fragment.expand_instances();
+            let fragment = nested.as_ref().unwrap_or(&*batch.fragment);
+            for instance in &batch.instances {
+                let transform = Some(instance.transform);
+                match instance.color {
+                    Some(color) => {
+                        let (fragment, offsets) = recolored.get_or_insert_with(|| {
+                            let fragment = batch.fragment.recolored(Color::rgba8(0, 0, 0, 0));
+                            let offsets = fragment.color_offsets();
+                            (fragment, offsets)
+                        });
+                        let base = expanded.draw_data.len();
+                        expanded.append_streams(fragment, &transform);
+                        let color = DrawColor {
+                            rgba: color.to_premul_u32(),
+                        };
+                        for offset in offsets.iter() {
+                            expanded.draw_data[base + offset..base + offset + 4]
+                                .copy_from_slice(bytemuck::bytes_of(&color));
+                        }
+                    }
+                    None => expanded.append_streams(fragment, &transform),
+                }
+            }
+        }
+        rebase_backdrops(&mut expanded, self.instances.len());
+        expanded.append_streams(self, &None);
+        Some(expanded)
+    }
+
+    /// Feeds the geometry and draw streams of the encoding, including its
+    /// instances, to a hasher without serializing the encoding.
+    pub(crate) fn hash_streams(&self, state: &mut impl Hasher) {
+        let mut write = |bytes: &[u8]| {
+            state.write_usize(bytes.len());
+            state.write(bytes);
+        };
+        write(bytemuck::cast_slice(&self.path_tags));
+        write(&self.path_data);
+        write(bytemuck::cast_slice(&self.draw_tags));
+        write(&self.draw_data);
+        write(bytemuck::cast_slice(&self.transforms));
+        write(bytemuck::cast_slice(&self.linewidths));
+        for batch in &self.instances {
+            batch.before.hash_streams(state);
+            batch.fragment.hash_streams(state);
+            for instance in &batch.instances {
+                state.write(bytemuck::bytes_of(&instance.transform));
+            }
+        }
+    }
+
+    /// Returns the streams preceding the batches of instances along with
+    /// the instanced fragments.
+    pub(crate) fn nested_encodings(&self) -> impl Iterator<Item = &Encoding> {
+        self.instances
+            .iter()
+            .flat_map(|batch| [&batch.before, &*batch.fragment])
+    }
+
+    /// Returns the offsets of the colors in the draw data of an encoding
+    /// that was [recolored](Self::recolored).
+    fn color_offsets(&self) -> Vec<usize> {
+        let mut offsets = vec![];
+        let mut offset = 0;
+        for tag in &self.draw_tags {
+            if *tag == DrawTag::COLOR || *tag == DrawTag::ROUNDED_RECT || *tag == DrawTag::ELLIPSE {
+                offsets.push(offset);
+            }
+            offset += tag.data_size() as usize * 4;
+        }
+        offsets
+    }
+
+    /// Returns the part of the encoding that precedes a mark, with
+    /// `open_layers` layers closed at the end.
+    pub(crate) fn prefix(&self, mark: &EncodingMark, open_layers: u32) -> Self {
+        // The streams at the mark were moved into the first batch of
+        // instances that was encoded after it, if any.
+        let (streams, batches) = match self.instances.get(mark.instances) {
+            Some(batch) => (&batch.before, &self.instances[..mark.instances]),
+            None => (self, &self.instances[..]),
+        };
+        let mut prefix = Self {
+            path_tags: streams.path_tags[..mark.path_tags].to_vec(),
+            path_data: streams.path_data[..mark.path_data].to_vec(),
+            draw_tags: streams.draw_tags[..mark.draw_tags].to_vec(),
+            draw_data: streams.draw_data[..mark.draw_data].to_vec(),
+            patches: streams.patches[..mark.patches].to_vec(),
+            color_stops: streams.color_stops[..mark.color_stops].to_vec(),
+            transforms: streams.transforms[..mark.transforms].to_vec(),
+            linewidths: streams.linewidths[..mark.linewidths].to_vec(),
+            mesh_patches: streams.mesh_patches[..mark.mesh_patches].to_vec(),
+            color_matrices: streams.color_matrices[..mark.color_matrices].to_vec(),
+            visibility_groups: streams.visibility_groups[..mark.visibility_groups].to_vec(),
+            scissors: streams.scissors[..mark.scissors].to_vec(),
+            scissor_rects: streams.scissor_rects.clone(),
+            draw_flags: streams.draw_flags[..mark.draw_flags].to_vec(),
+            n_paths: mark.n_paths,
+            n_path_segments: mark.n_path_segments,
+            n_clips: mark.n_clips,
+            brushes: self.brushes.clone(),
+            palette: self.palette.clone(),
+            hidden_groups: self.hidden_groups.clone(),
+            instances: batches.to_vec(),
+            ..Default::default()
+        };
+        for _ in 0..open_layers {
+            prefix.encode_end_clip();
+        }
+        prefix
+    }
+
+    /// Moves the data streams into a new encoding, leaving the retained
+    /// tables and the current state in place.
+    fn take_streams(&mut self) -> Self {
+        let transform = self.transforms.last().copied();
+        let linewidth = self.linewidths.last().copied();
+        let visibility_group = self.visibility_group();
+        let scissor = self.scissor();
+        let draw_flags = self.draw_flags();
+        let taken = Self {
+            path_tags: std::mem::take(&mut self.path_tags),
+            path_data: std::mem::take(&mut self.path_data),
+            draw_tags: std::mem::take(&mut self.draw_tags),
+            draw_data: std::mem::take(&mut self.draw_data),
+            patches: std::mem::take(&mut self.patches),
+            color_stops: std::mem::take(&mut self.color_stops),
+            transforms: std::mem::take(&mut self.transforms),
+            linewidths: std::mem::take(&mut self.linewidths),
+            mesh_patches: std::mem::take(&mut self.mesh_patches),
+            color_matrices: std::mem::take(&mut self.color_matrices),
+            visibility_groups: std::mem::take(&mut self.visibility_groups),
+            scissors: std::mem::take(&mut self.scissors),
+            scissor_rects: std::mem::take(&mut self.scissor_rects),
+            draw_flags: std::mem::take(&mut self.draw_flags),
+            n_paths: std::mem::take(&mut self.n_paths),
+            n_path_segments: std::mem::take(&mut self.n_path_segments),
+            n_clips: std::mem::take(&mut self.n_clips),
+            ..Default::default()
+        };
+        // The streams that follow continue with explicit state since they
+        // are appended after the instances.
+        if let Some(transform) = transform {
+            self.path_tags.push(PathTag::TRANSFORM);
+            self.transforms.push(transform);
+        }
+        if let Some(linewidth) = linewidth {
+            self.path_tags.push(PathTag::LINEWIDTH);
+            self.linewidths.push(linewidth);
+        }
+        self.encode_visibility_group(visibility_group);
+        self.encode_scissor(scissor);
+        self.encode_draw_flags(draw_flags);
+        taken
+    }
+}
@@ -0,0 +1,365 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Merging of scenes that were built independently.
+//!
+//! Documents are often composed of components whose scenes are built and
+//! cached separately. Components tend to share resources, such as an icon
+//! decoded by each of them or a gradient of the theme. [`Encoding::merge`]
+//! appends the encoding of a scene to another along with its brushes,
+//! palette and visibility groups, and shares the ramps of identical
+//! gradients. Images with identical contents are shared by the
+//! [resource cache](super::resource) when they are packed into the atlas.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use peniko::{Color, ColorStop, ColorStops};
+
+use super::encoding::NO_VISIBILITY_GROUP;
+use super::resource::{BrushHandle, Patch};
+use super::{Backdrop, DrawPaletteColor, DrawTag, Encoding, PathTag};
+
+/// Offsets by which the brush handles, palette slots and visibility groups
+/// of a merged encoding were moved.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct MergeOffsets {
+    /// Offset of the brushes of the merged encoding in the brush table.
+    pub brushes: u32,
+    /// Offset of the palette of the merged encoding in the palette.
+    pub palette: u32,
+    /// Offset of the visibility group ids of the merged encoding.
+    pub visibility_groups: u32,
+}
+
+impl MergeOffsets {
+    /// Returns the handle of a brush of the merged encoding.
+    pub fn brush(&self, handle: BrushHandle) -> BrushHandle {
+        BrushHandle(handle.0.saturating_add(self.brushes))
+    }
+
+    /// Returns the slot of a palette entry of the merged encoding.
+    pub fn palette_slot(&self, slot: u32) -> u32 {
+        slot.saturating_add(self.palette)
+    }
+
+    /// Returns the id of a visibility group of the merged encoding.
+    pub fn visibility_group(&self, group: u32) -> u32 {
+        group
+            .saturating_add(self.visibility_groups)
+            .min(NO_VISIBILITY_GROUP - 1)
+    }
+}
+
+impl Encoding {
+    /// Appends the encoding of a scene to this scene encoding, merging the
+    /// resources of the two.
+    ///
+    /// The brushes and the palette of `other` are appended to those of this
+    /// encoding and its visibility groups are given ids that follow the
+    /// groups of this encoding, so either scene can update its brushes,
+    /// palette entries and groups independently. The returned offsets map
+    /// the handles, slots and ids of `other` to those of the merged draws.
+    /// Gradients with identical color stops share their stops.
+    pub fn merge(&mut self, other: &Self) -> MergeOffsets {
+        let offsets = MergeOffsets {
+            brushes: self.brushes.len() as u32,
+            palette: (self.palette.len() as u32).max(self.palette_slots_end()),
+            visibility_groups: ((self.hidden_groups.len() * 32) as u32)
+                .max(self.visibility_groups_end()),
+        };
+        let expanded = other.expand_instances();
+        let other = expanded.as_ref().unwrap_or(other);
+        if other.path_tags.is_empty() || other.transforms.is_empty() {
+            return offsets;
+        }
+        if self.is_empty() && self.transforms.is_empty() {
+            self.reset(false);
+        }
+        let mut ramps = HashMap::<ColorStops, Range<usize>>::new();
+        for patch in &self.patches {
+            if let Patch::Ramp { stops, .. } = patch {
+                ramps
+                    .entry(self.color_stops[stops.clone()].into())
+                    .or_insert_with(|| stops.clone());
+            }
+        }
+        let base = self.mark();
+        let group = self.visibility_group();
+        self.append_streams(other, &None);
+        // The first transform and line width of a scene are implied, so
+        // they are made explicit for the draws that follow this encoding.
+        self.path_tags.splice(
+            base.path_tags..base.path_tags,
+            [PathTag::TRANSFORM, PathTag::LINEWIDTH],
+        );
+        let appended_stops = self.color_stops.split_off(base.color_stops);
+        // Length of the color stops before each of the appended patches.
+        let mut stops_before = Vec::with_capacity(other.patches.len() + 1);
+        for patch in &mut self.patches[base.patches..] {
+            stops_before.push(self.color_stops.len());
+            match patch {
+                Patch::Ramp { stops, .. } => {
+                    let range = stops.start - base.color_stops..stops.end - base.color_stops;
+                    *stops =
+                        shared_stops(&mut ramps, &mut self.color_stops, &appended_stops[range]);
+                }
+                Patch::Brush { handle, .. } => *handle = offsets.brush(*handle),
+                Patch::Image { .. } | Patch::Custom { .. } => {}
+            }
+        }
+        stops_before.push(self.color_stops.len());
+        for backdrop in &other.backdrops {
+            let mut mark = backdrop.mark.rebased(&base);
+            mark.path_tags += 2;
+            mark.color_stops = stops_before[backdrop.mark.patches];
+            self.backdrops.push(Backdrop {
+                mark,
+                ..backdrop.clone()
+            });
+        }
+        self.brushes.extend_from_slice(&other.brushes);
+        self.palette
+            .resize(offsets.palette as usize, Color::rgba8(0, 0, 0, 0));
+        self.palette.extend_from_slice(&other.palette);
+        let mut offset = base.draw_data;
+        for tag in &self.draw_tags[base.draw_tags..] {
+            if *tag == DrawTag::PALETTE_COLOR {
+                let data = &mut self.draw_data[offset..offset + 4];
+                let color: DrawPaletteColor = bytemuck::pod_read_unaligned(data);
+                let color = DrawPaletteColor {
+                    slot: offsets.palette_slot(color.slot),
+                };
+                data.copy_from_slice(bytemuck::bytes_of(&color));
+            }
+            offset += tag.data_size() as usize * 4;
+        }
+        if !other.visibility_groups.is_empty() {
+            // The runs are appended again with rebased ids, followed by the
+            // run that restores the group of this encoding.
+            self.visibility_groups.truncate(base.visibility_groups);
+            self.visibility_groups
+                .extend(other.visibility_groups.iter().map(|(ix, group)| {
+                    let group = if *group == NO_VISIBILITY_GROUP {
+                        NO_VISIBILITY_GROUP
+                    } else {
+                        offsets.visibility_group(*group)
+                    };
+                    (ix + base.draw_tags as u32, group)
+                }));
+            self.encode_visibility_group(group);
+        }
+        for (word, bits) in other.hidden_groups.iter().enumerate() {
+            for bit in (0..32).filter(|bit| bits & (1 << bit) != 0) {
+                let group = (word * 32 + bit) as u32;
+                self.set_group_hidden(offsets.visibility_group(group), true);
+            }
+        }
+        offsets
+    }
+
+    /// Returns one past the largest palette slot referenced by the draws.
+    fn palette_slots_end(&self) -> u32 {
+        let mut end = 0;
+        let mut offset = 0;
+        for tag in &self.draw_tags {
+            if *tag == DrawTag::PALETTE_COLOR {
+                if let Some(data) = self.draw_data.get(offset..offset + 4) {
+                    let color: DrawPaletteColor = bytemuck::pod_read_unaligned(data);
+                    end = end.max(color.slot.saturating_add(1));
+                }
+            }
+            offset += tag.data_size() as usize * 4;
+        }
+        self.nested_encodings()
+            .map(|nested| nested.palette_slots_end())
+            .fold(end, u32::max)
+    }
+
+    /// Returns one past the largest visibility group id assigned to draws.
+    fn visibility_groups_end(&self) -> u32 {
+        let end = self
+            .visibility_groups
+            .iter()
+            .filter(|(_, group)| *group != NO_VISIBILITY_GROUP)
+            .map(|(_, group)| group + 1)
+            .max()
+            .unwrap_or(0);
+        self.nested_encodings()
+            .map(|nested| nested.visibility_groups_end())
+            .fold(end, u32::max)
+    }
+}
+
+/// Returns the range of identical color stops that were seen before, or
+/// appends the stops and returns their range.
+fn shared_stops(
+    ramps: &mut HashMap<ColorStops, Range<usize>>,
+    color_stops: &mut Vec<ColorStop>,
+    stops: &[ColorStop],
+) -> Range<usize> {
+    if let Some(range) = ramps.get(stops) {
+        return range.clone();
+    }
+    let start = color_stops.len();
+    color_stops.extend_from_slice(stops);
+    ramps.insert(stops.into(), start..color_stops.len());
+    start..color_stops.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::kurbo::Rect;
+    use peniko::{Brush, Color, Gradient};
+
+    use super::MergeOffsets;
+    use crate::encoding::resource::{BrushHandle, Patch};
+    use crate::encoding::{DrawPaletteColor, DrawTag, Encoding};
+
+    fn rect(encoding: &mut Encoding) {
+        encoding.encode_shape(&Rect::new(0.0, 0.0, 10.0, 10.0), true);
+    }
+
+    fn gradient() -> Gradient {
+        Gradient::new_linear((0.0, 0.0), (10.0, 0.0))
+            .with_stops([Color::rgb8(255, 0, 0), Color::rgb8(0, 0, 255)])
+    }
+
+    /// Returns a scene encoding with a rectangle drawn with a brush from its
+    /// brush table, a palette color in group 0 and a gradient.
+    fn scene(color: Color) -> Encoding {
+        let mut encoding = Encoding::new();
+        encoding.reset(false);
+        let handle = encoding.add_brush(Brush::Solid(color));
+        encoding.palette.push(color);
+        rect(&mut encoding);
+        encoding.encode_brush_handle(handle, 1.0);
+        encoding.encode_visibility_group(Some(0));
+        rect(&mut encoding);
+        encoding.encode_palette_color(0);
+        encoding.encode_visibility_group(None);
+        rect(&mut encoding);
+        encoding.encode_brush(&gradient(), 1.0);
+        encoding
+    }
+
+    fn palette_slots(encoding: &Encoding) -> Vec<u32> {
+        let mut slots = vec![];
+        let mut offset = 0;
+        for tag in &encoding.draw_tags {
+            if *tag == DrawTag::PALETTE_COLOR {
+                let data = &encoding.draw_data[offset..offset + 4];
+                slots.push(bytemuck::pod_read_unaligned::<DrawPaletteColor>(data).slot);
+            }
+            offset += tag.data_size() as usize * 4;
+        }
+        slots
+    }
+
+    #[test]
+    fn appends_brushes() {
+        let red = Color::rgb8(255, 0, 0);
+        let mut encoding = scene(red);
+        let offsets = encoding.merge(&scene(red));
+        assert_eq!(offsets.brushes, 1);
+        // Identical brushes are not shared, so each scene can replace its
+        // own brush.
+        assert_eq!(encoding.brushes.len(), 2);
+        let handles = encoding
+            .patches
+            .iter()
+            .filter_map(|patch| match patch {
+                Patch::Brush { handle, .. } => Some(*handle),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(handles, [BrushHandle(0), offsets.brush(BrushHandle(0))]);
+        assert!(encoding.is_consistent());
+    }
+
+    #[test]
+    fn rebases_palette_slots() {
+        let mut encoding = scene(Color::rgb8(255, 0, 0));
+        // A slot beyond the palette that this scene fills in later.
+        rect(&mut encoding);
+        encoding.encode_palette_color(3);
+        let offsets = encoding.merge(&scene(Color::rgb8(0, 255, 0)));
+        assert_eq!(offsets.palette, 4);
+        assert_eq!(palette_slots(&encoding), [0, 3, 4]);
+        assert_eq!(encoding.palette.len(), 5);
+        assert_eq!(encoding.palette[4], Color::rgb8(0, 255, 0));
+    }
+
+    #[test]
+    fn rebases_visibility_groups() {
+        let mut encoding = scene(Color::rgb8(255, 0, 0));
+        let mut other = scene(Color::rgb8(0, 255, 0));
+        other.set_group_hidden(0, true);
+        encoding.encode_visibility_group(Some(2));
+        let offsets = encoding.merge(&other);
+        assert_eq!(
+            offsets,
+            MergeOffsets {
+                brushes: 1,
+                palette: 1,
+                visibility_groups: 3,
+            }
+        );
+        assert!(!encoding.is_group_hidden(0));
+        assert!(encoding.is_group_hidden(3));
+        let groups = encoding
+            .visibility_groups
+            .iter()
+            .map(|(_, group)| *group)
+            .collect::<Vec<_>>();
+        // The group of this encoding is restored after the merged draws.
+        assert_eq!(groups, [0, !0, 2, 3, !0, 2]);
+        assert_eq!(encoding.visibility_group(), Some(2));
+    }
+
+    #[test]
+    fn shares_gradient_stops() {
+        let mut encoding = scene(Color::rgb8(255, 0, 0));
+        let stops = encoding.color_stops.len();
+        encoding.merge(&scene(Color::rgb8(0, 255, 0)));
+        assert_eq!(encoding.color_stops.len(), stops);
+        let ramps = encoding
+            .patches
+            .iter()
+            .filter_map(|patch| match patch {
+                Patch::Ramp { stops, .. } => Some(stops.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ramps, [0..2, 0..2]);
+        assert!(encoding.is_consistent());
+    }
+
+    #[test]
+    fn merges_into_empty_encoding() {
+        let mut encoding = Encoding::new();
+        let other = scene(Color::rgb8(255, 0, 0));
+        encoding.merge(&other);
+        assert!(encoding.is_consistent());
+        assert_eq!(encoding.draw_tags.len(), other.draw_tags.len());
+        // The first transform of the merged scene is made explicit.
+        assert_eq!(encoding.transforms.len(), 2);
+        let empty = Encoding::new();
+        let before = encoding.path_tags.len();
+        encoding.merge(&empty);
+        assert_eq!(encoding.path_tags.len(), before);
+    }
+}
@@ -15,10 +15,12 @@
 // Also licensed under MIT license, at your choice.
 
 use bytemuck::{Pod, Zeroable};
+use peniko::{kurbo::Rect, Brush, Color, ColorStop};
 
 use super::{
+    encoding::{NO_SCISSOR, NO_VISIBILITY_GROUP},
     resource::{Patch, ResourceCache, Token},
-    DrawTag, Encoding, PathTag, Transform,
+    DrawTag, Encoding, MeshPatchData, PathTag, Transform,
 };
 use crate::shaders;
 
@@ -46,6 +48,35 @@ pub struct Layout {
     pub transform_base: u32,
     /// Start of linewidth stream.
     pub linewidth_base: u32,
+    /// Start of mesh patch stream.
+    pub mesh_base: u32,
+    /// Start of palette stream.
+    pub palette_base: u32,
+    /// Number of palette entries.
+    pub n_palette: u32,
+    /// Start of the per draw object visibility group stream.
+    pub draw_group_base: u32,
+    /// Start of the hidden visibility group bitset.
+    pub visibility_base: u32,
+    /// Number of words in the hidden visibility group bitset. If this is
+    /// zero, the visibility group stream is empty.
+    pub n_visibility: u32,
+    /// Start of the per draw object scissor index stream.
+    pub draw_scissor_base: u32,
+    /// Start of the scissor rectangle stream.
+    pub scissor_base: u32,
+    /// Number of scissor rectangles. If this is zero, the scissor index
+    /// stream is empty.
+    pub n_scissor: u32,
+    /// Start of the color matrix stream.
+    pub color_matrix_base: u32,
+    /// Number of color matrices.
+    pub n_color_matrix: u32,
+    /// Start of the per draw object flag stream.
+    pub draw_flags_base: u32,
+    /// Number of entries in the draw flag stream, which is either zero or
+    /// the number of draw objects.
+    pub n_draw_flags: u32,
 }
 
 /// Scene configuration.
@@ -70,8 +101,21 @@ pub struct Config {
     pub segments_size: u32,
     /// Size of per-tile command list buffer allocation (in u32s).
     pub ptcl_size: u32,
+    /// Quality and debug flags for fine rasterization.
+    pub flags: u32,
+    /// Flattening tolerance in pixels.
+    pub tolerance: f32,
 }
 
+/// Config flag that enables dithering of the output.
+pub const CONFIG_DITHER: u32 = 1;
+/// Config flag that reduces the number of samples taken by blurs.
+pub const CONFIG_SIMPLIFY_BLURS: u32 = 2;
+/// Config flag that renders the winding numbers of fills in false color.
+pub const CONFIG_DEBUG_WINDING: u32 = 4;
+/// Config flag that renders the coverage of draws in false color.
+pub const CONFIG_DEBUG_COVERAGE: u32 = 8;
+
 /// Packed encoding of scene data.
 #[derive(Default)]
 pub struct PackedEncoding {
@@ -134,6 +178,85 @@ impl PackedEncoding {
     /// Returns the linewidth stream.
     pub fn linewidths(&self) -> &[f32] {
         let start = self.layout.linewidth_base as usize * 4;
+        let end = self.layout.mesh_base as usize * 4;
+        bytemuck::cast_slice(&self.data[start..end])
+    }
+
+    /// Applies a transform to all draws by premultiplying the transform
+    /// stream.
+    pub fn transform(&mut self, transform: Transform) {
+        let start = self.layout.transform_base as usize * 4;
+        let end = self.layout.linewidth_base as usize * 4;
+        for chunk in self.data[start..end].chunks_exact_mut(std::mem::size_of::<Transform>()) {
+            let x: Transform = bytemuck::pod_read_unaligned(chunk);
+            chunk.copy_from_slice(bytemuck::bytes_of(&(transform * x)));
+        }
+        // Scissor rectangles are in device space and so are transformed
+        // as well.
+        let transform = transform.to_kurbo();
+        let start = self.layout.scissor_base as usize * 4;
+        let end = self.layout.color_matrix_base as usize * 4;
+        for chunk in self.data[start..end].chunks_exact_mut(16) {
+            let [x0, y0, x1, y1]: [f32; 4] = bytemuck::pod_read_unaligned(chunk);
+            let rect = Rect::new(x0 as f64, y0 as f64, x1 as f64, y1 as f64);
+            let rect = transform.transform_rect_bbox(rect);
+            let rect = [rect.x0, rect.y0, rect.x1, rect.y1].map(|x| x as f32);
+            chunk.copy_from_slice(bytemuck::bytes_of(&rect));
+        }
+    }
+
+    /// Returns the mesh patch stream.
+    pub fn mesh_patches(&self) -> &[MeshPatchData] {
+        let start = self.layout.mesh_base as usize * 4;
+        let end = self.layout.palette_base as usize * 4;
+        bytemuck::cast_slice(&self.data[start..end])
+    }
+
+    /// Returns the palette stream of packed premultiplied colors.
+    pub fn palette(&self) -> &[u32] {
+        let start = self.layout.palette_base as usize * 4;
+        let end = self.layout.draw_group_base as usize * 4;
+        bytemuck::cast_slice(&self.data[start..end])
+    }
+
+    /// Returns the visibility group stream.
+    pub fn draw_groups(&self) -> &[u32] {
+        let start = self.layout.draw_group_base as usize * 4;
+        let end = self.layout.visibility_base as usize * 4;
+        bytemuck::cast_slice(&self.data[start..end])
+    }
+
+    /// Returns the hidden visibility group bitset.
+    pub fn hidden_groups(&self) -> &[u32] {
+        let start = self.layout.visibility_base as usize * 4;
+        let end = self.layout.draw_scissor_base as usize * 4;
+        bytemuck::cast_slice(&self.data[start..end])
+    }
+
+    /// Returns the scissor index stream.
+    pub fn draw_scissors(&self) -> &[u32] {
+        let start = self.layout.draw_scissor_base as usize * 4;
+        let end = self.layout.scissor_base as usize * 4;
+        bytemuck::cast_slice(&self.data[start..end])
+    }
+
+    /// Returns the scissor rectangle stream.
+    pub fn scissor_rects(&self) -> &[[f32; 4]] {
+        let start = self.layout.scissor_base as usize * 4;
+        let end = self.layout.color_matrix_base as usize * 4;
+        bytemuck::cast_slice(&self.data[start..end])
+    }
+
+    /// Returns the color matrix stream.
+    pub fn color_matrices(&self) -> &[[f32; 20]] {
+        let start = self.layout.color_matrix_base as usize * 4;
+        let end = self.layout.draw_flags_base as usize * 4;
+        bytemuck::cast_slice(&self.data[start..end])
+    }
+
+    /// Returns the draw flag stream.
+    pub fn draw_flags(&self) -> &[u32] {
+        let start = self.layout.draw_flags_base as usize * 4;
         bytemuck::cast_slice(&self.data[start..])
     }
 }
@@ -144,6 +267,7 @@ impl PackedEncoding {
     pub fn pack(&mut self, encoding: &Encoding, resource_cache: &mut ResourceCache) {
         // Advance the resource cache epoch.
         self.resources = resource_cache.advance();
+        resource_cache.retire_ramps(&encoding.retired_stops);
         // Pack encoded data.
         let layout = &mut self.layout;
         *layout = Layout::default();
@@ -160,7 +284,9 @@ impl PackedEncoding {
             + slice_size_in_bytes(&encoding.draw_tags)
             + slice_size_in_bytes(&encoding.draw_data)
             + slice_size_in_bytes(&encoding.transforms)
-            + slice_size_in_bytes(&encoding.linewidths);
+            + slice_size_in_bytes(&encoding.linewidths)
+            + slice_size_in_bytes(&encoding.mesh_patches)
+            + slice_size_in_bytes(&encoding.palette);
         data.reserve(capacity);
         layout.path_tag_base = size_to_words(data.len());
         data.extend_from_slice(bytemuck::cast_slice(&encoding.path_tags));
@@ -171,39 +297,178 @@ impl PackedEncoding {
         // Draw tag stream
         layout.draw_tag_base = size_to_words(data.len());
         data.extend_from_slice(bytemuck::cast_slice(&encoding.draw_tags));
-        // Bin data follows draw info
-        layout.bin_data_start = encoding.draw_tags.iter().map(|tag| tag.info_size()).sum();
         // Draw data stream
         layout.draw_data_base = size_to_words(data.len());
         // Handle patches, if any
+        let mut resolved_tags = vec![];
         if !encoding.patches.is_empty() {
-            let stop_data = &encoding.color_stops;
-            let mut pos = 0;
-            for patch in &encoding.patches {
-                let (offset, value) = match patch {
-                    Patch::Ramp { offset, stops } => {
-                        let ramp_id = resource_cache.add_ramp(&stop_data[stops.clone()]);
-                        (*offset, ramp_id)
-                    }
-                };
-                if pos < offset {
-                    data.extend_from_slice(&encoding.draw_data[pos..offset]);
-                }
-                data.extend_from_slice(bytemuck::bytes_of(&value));
-                pos = offset + 4;
-            }
-            if pos < encoding.draw_data.len() {
-                data.extend_from_slice(&encoding.draw_data[pos..])
-            }
+            pack_draw_data(
+                data,
+                &encoding.draw_data,
+                &encoding.patches,
+                &encoding.color_stops,
+                &encoding.brushes,
+                resource_cache,
+                &mut resolved_tags,
+            );
         } else {
             data.extend_from_slice(&encoding.draw_data);
         }
+        // Bin data follows draw info
+        layout.bin_data_start = encoding.draw_tags.iter().map(|tag| tag.info_size()).sum();
+        // Replace placeholder tags for brushes that were resolved from the
+        // brush table.
+        let draw_tag_base = layout.draw_tag_base as usize * 4;
+        for (ix, tag) in &resolved_tags {
+            let start = draw_tag_base + ix * 4;
+            data[start..start + 4].copy_from_slice(bytemuck::bytes_of(tag));
+            layout.bin_data_start += tag.info_size() - encoding.draw_tags[*ix].info_size();
+        }
         // Transform stream
         layout.transform_base = size_to_words(data.len());
         data.extend_from_slice(bytemuck::cast_slice(&encoding.transforms));
         // Linewidth stream
         layout.linewidth_base = size_to_words(data.len());
         data.extend_from_slice(bytemuck::cast_slice(&encoding.linewidths));
+        // Mesh patch stream
+        layout.mesh_base = size_to_words(data.len());
+        data.extend_from_slice(bytemuck::cast_slice(&encoding.mesh_patches));
+        // Palette stream
+        layout.palette_base = size_to_words(data.len());
+        layout.n_palette = encoding.palette.len() as u32;
+        for color in &encoding.palette {
+            data.extend_from_slice(bytemuck::bytes_of(&color.to_premul_u32()));
+        }
+        // Visibility group stream, only present when a group is hidden
+        layout.draw_group_base = size_to_words(data.len());
+        if !encoding.visibility_groups.is_empty() && encoding.hidden_groups.iter().any(|w| *w != 0)
+        {
+            let n_draw_objects = encoding.draw_tags.len();
+            let mut group = NO_VISIBILITY_GROUP;
+            let mut runs = encoding.visibility_groups.iter().peekable();
+            for ix in 0..n_draw_objects {
+                while let Some((_, next)) = runs.next_if(|(start, _)| *start as usize <= ix) {
+                    group = *next;
+                }
+                data.extend_from_slice(bytemuck::bytes_of(&group));
+            }
+            layout.visibility_base = size_to_words(data.len());
+            layout.n_visibility = encoding.hidden_groups.len() as u32;
+            data.extend_from_slice(bytemuck::cast_slice(&encoding.hidden_groups));
+        } else {
+            layout.visibility_base = layout.draw_group_base;
+        }
+        // Scissor stream, only present when a draw object has a scissor
+        layout.draw_scissor_base = size_to_words(data.len());
+        if !encoding.scissors.is_empty() {
+            let n_draw_objects = encoding.draw_tags.len();
+            let mut scissor = NO_SCISSOR;
+            let mut runs = encoding.scissors.iter().peekable();
+            for ix in 0..n_draw_objects {
+                while let Some((_, next)) = runs.next_if(|(start, _)| *start as usize <= ix) {
+                    scissor = *next;
+                }
+                data.extend_from_slice(bytemuck::bytes_of(&scissor));
+            }
+            layout.scissor_base = size_to_words(data.len());
+            layout.n_scissor = encoding.scissor_rects.len() as u32;
+            data.extend_from_slice(bytemuck::cast_slice(&encoding.scissor_rects));
+        } else {
+            layout.scissor_base = layout.draw_scissor_base;
+        }
+        // Color matrix stream
+        layout.color_matrix_base = size_to_words(data.len());
+        layout.n_color_matrix = encoding.color_matrices.len() as u32;
+        data.extend_from_slice(bytemuck::cast_slice(&encoding.color_matrices));
+        // Draw flag stream, only present when a draw object has flags
+        layout.draw_flags_base = size_to_words(data.len());
+        if encoding.draw_flags.iter().any(|(_, flags)| *flags != 0) {
+            let n_draw_objects = encoding.draw_tags.len();
+            let mut flags = 0;
+            let mut runs = encoding.draw_flags.iter().peekable();
+            for ix in 0..n_draw_objects {
+                while let Some((_, next)) = runs.next_if(|(start, _)| *start as usize <= ix) {
+                    flags = *next;
+                }
+                data.extend_from_slice(bytemuck::bytes_of(&flags));
+            }
+            layout.n_draw_flags = n_draw_objects as u32;
+        }
+    }
+}
+
+/// Writes the draw data stream, resolving late bound resources.
+///
+/// Brushes referenced by handle are encoded in place and the tags that
+/// should replace their placeholders are pushed to `resolved_tags`.
+fn pack_draw_data(
+    data: &mut Vec<u8>,
+    draw_data: &[u8],
+    patches: &[Patch],
+    color_stops: &[ColorStop],
+    brushes: &[Brush],
+    resource_cache: &mut ResourceCache,
+    resolved_tags: &mut Vec<(usize, DrawTag)>,
+) {
+    let mut pos = 0;
+    for patch in patches {
+        let offset = patch.offset();
+        if pos < offset {
+            data.extend_from_slice(&draw_data[pos..offset]);
+        }
+        pos = offset;
+        match patch {
+            Patch::Ramp { stops, .. } => {
+                let ramp_id = resource_cache.add_ramp(&color_stops[stops.clone()]);
+                data.extend_from_slice(bytemuck::bytes_of(&ramp_id));
+                pos += 4;
+            }
+            Patch::Image { image, .. } => {
+                let (x, y) = resource_cache.add_image(image);
+                // The encoded coordinates are the origin of the sampled
+                // region within the image.
+                let region: u32 = bytemuck::pod_read_unaligned(&draw_data[offset..offset + 4]);
+                let xy = ((x + (region >> 16)) << 16) | ((y + (region & 0xffff)) & 0xffff);
+                data.extend_from_slice(bytemuck::bytes_of(&xy));
+                pos += 4;
+            }
+            Patch::Brush {
+                draw_tag_ix,
+                handle,
+                alpha,
+                ..
+            } => {
+                let transparent = Brush::Solid(Color::rgba8(0, 0, 0, 0));
+                let brush = brushes.get(handle.0 as usize).unwrap_or(&transparent);
+                pack_brush(
+                    data,
+                    brush,
+                    *alpha,
+                    *draw_tag_ix,
+                    resource_cache,
+                    resolved_tags,
+                );
+            }
+            Patch::Custom {
+                draw_tag_ix,
+                resource,
+                alpha,
+                ..
+            } => {
+                let brush = resource_cache.resolve_custom(resource);
+                pack_brush(
+                    data,
+                    &brush,
+                    *alpha,
+                    *draw_tag_ix,
+                    resource_cache,
+                    resolved_tags,
+                );
+            }
+        }
+    }
+    if pos < draw_data.len() {
+        data.extend_from_slice(&draw_data[pos..])
     }
 }
 
@@ -218,3 +483,27 @@ fn size_to_words(byte_size: usize) -> u32 {
 fn align_up(len: usize, alignment: u32) -> usize {
     len + (len.wrapping_neg() & (alignment as usize - 1))
 }
+
+/// Packs the draw data of a brush that is resolved at pack time in place of
+/// the placeholder tag at `draw_tag_ix`.
+fn pack_brush(
+    data: &mut Vec<u8>,
+    brush: &Brush,
+    alpha: f32,
+    draw_tag_ix: usize,
+    resource_cache: &mut ResourceCache,
+    resolved_tags: &mut Vec<(usize, DrawTag)>,
+) {
+    let mut scratch = Encoding::new();
+    scratch.encode_brush(brush, alpha);
+    resolved_tags.push((draw_tag_ix, scratch.draw_tags[0]));
+    pack_draw_data(
+        data,
+        &scratch.draw_data,
+        &scratch.patches,
+        &scratch.color_stops,
+        &[],
+        resource_cache,
+        resolved_tags,
+    );
+}
@@ -15,7 +15,7 @@
 // Also licensed under MIT license, at your choice.
 
 use bytemuck::{Pod, Zeroable};
-use peniko::kurbo::Shape;
+use peniko::kurbo::{PathEl, Shape};
 
 use super::Monoid;
 
@@ -198,6 +198,7 @@ pub struct PathEncoder<'a> {
     state: PathState,
     n_encoded_segments: u32,
     is_fill: bool,
+    closed_implicitly: bool,
 }
 
 #[derive(PartialEq)]
@@ -226,13 +227,14 @@ impl<'a> PathEncoder<'a> {
             state: PathState::Start,
             n_encoded_segments: 0,
             is_fill,
+            closed_implicitly: false,
         }
     }
 
     /// Encodes a move, starting a new subpath.
     pub fn move_to(&mut self, x: f32, y: f32) {
         if self.is_fill {
-            self.close();
+            self.close_subpath(false);
         }
         let buf = [x, y];
         let bytes = bytemuck::bytes_of(&buf);
@@ -304,6 +306,17 @@ impl<'a> PathEncoder<'a> {
 
     /// Closes the current subpath.
     pub fn close(&mut self) {
+        self.close_subpath(true);
+    }
+
+    /// Returns true if every subpath encoded so far has been closed
+    /// explicitly, in which case the encoding is the same for fills and
+    /// strokes.
+    pub fn is_closed(&self) -> bool {
+        !self.closed_implicitly && self.state != PathState::NonemptySubpath
+    }
+
+    fn close_subpath(&mut self, explicit: bool) {
         match self.state {
             PathState::Start => return,
             PathState::MoveTo => {
@@ -326,16 +339,43 @@ impl<'a> PathEncoder<'a> {
             tag.set_subpath_end();
             self.tags.push(tag);
             self.n_encoded_segments += 1;
+            self.closed_implicitly |= !explicit;
         } else if let Some(tag) = self.tags.last_mut() {
             tag.set_subpath_end();
         }
         self.state = PathState::Start;
     }
 
+    /// Encodes line segments through a sequence of points.
+    ///
+    /// Points with non-finite coordinates split the polyline into separate
+    /// subpaths, which is the usual representation of gaps in plotted data.
+    /// This copies runs of points directly into the path data and is much
+    /// cheaper than encoding the lines individually.
+    pub fn polyline(&mut self, points: &[[f32; 2]]) {
+        let is_finite = |p: &[f32; 2]| p[0].is_finite() && p[1].is_finite();
+        for run in points.split(|p| !is_finite(p)) {
+            if run.len() < 2 {
+                continue;
+            }
+            self.move_to(run[0][0], run[0][1]);
+            let lines = &run[1..];
+            self.data.extend_from_slice(bytemuck::cast_slice(lines));
+            self.tags
+                .extend(std::iter::repeat(PathTag::LINE_TO_F32).take(lines.len()));
+            self.state = PathState::NonemptySubpath;
+            self.n_encoded_segments += lines.len() as u32;
+        }
+    }
+
     /// Encodes a shape.
     pub fn shape(&mut self, shape: &impl Shape) {
-        use peniko::kurbo::PathEl;
-        for el in shape.path_elements(0.1) {
+        self.path_elements(shape.path_elements(0.1));
+    }
+
+    /// Encodes a sequence of path elements.
+    pub fn path_elements(&mut self, elements: impl IntoIterator<Item = PathEl>) {
+        for el in elements {
             match el {
                 PathEl::MoveTo(p0) => self.move_to(p0.x as f32, p0.y as f32),
                 PathEl::LineTo(p0) => self.line_to(p0.x as f32, p0.y as f32),
@@ -362,7 +402,7 @@ impl<'a> PathEncoder<'a> {
     /// multiple paths with differing transforms for a single draw object.
     pub fn finish(mut self, insert_path_marker: bool) -> u32 {
         if self.is_fill {
-            self.close();
+            self.close_subpath(false);
         }
         if self.state == PathState::MoveTo {
             let new_len = self.data.len() - 8;
@@ -16,13 +16,233 @@
 
 //! Late bound resource management.
 
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::ops::Range;
+use std::sync::{Arc, Mutex};
 
-use peniko::{Color, ColorStop, ColorStops};
+use peniko::{Brush, Color, ColorStop, ColorStops, Image};
 
 const N_SAMPLES: usize = 512;
+/// Number of ramp rows that are retained across frames.
 const RETAINED_COUNT: usize = 64;
+/// Minimum number of samples per color stop. Ramps with more stops than fit
+/// in a row at this density span several consecutive rows.
+const SAMPLES_PER_STOP: usize = 8;
+/// Maximum number of rows spanned by a single ramp.
+const MAX_RAMP_ROWS: usize = 256;
+/// Shift of the number of additional rows in a ramp id. The low bits hold
+/// the first row of the ramp.
+const RAMP_ROWS_SHIFT: u32 = 24;
+/// Width at which the image atlas starts a new row of images.
+const IMAGE_ATLAS_WIDTH: u32 = 2048;
+/// Height beyond which the image atlas is cleared and repacked.
+const IMAGE_ATLAS_MAX_HEIGHT: u32 = 8192;
+/// Number of epochs after which the updated contents of an image that is no
+/// longer drawn are discarded.
+const IMAGE_CONTENTS_MAX_AGE: u64 = 16;
+
+/// Handle to a brush in the brush table of a scene.
+///
+/// Draws that reference a handle pick up the current value of the brush when
+/// the scene is rendered, so the brush can be changed without encoding the
+/// geometry again.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct BrushHandle(pub(crate) u32);
+
+/// Resource kind defined outside of the crate, such as a video frame or a
+/// texture produced by another library, that is resolved to a brush each time
+/// a scene that references it is rendered.
+pub trait CustomResource: Send + Sync {
+    /// Returns the brush that draws the current contents of the resource.
+    fn resolve(&self, cx: &mut ResolveContext) -> Brush;
+
+    /// Called after the images of a brush for which
+    /// [`ResolveContext::notify_when_resident`] was requested have been
+    /// uploaded to the GPU.
+    fn resident(&self) {}
+
+    /// Adds the resources that the current brush depends on to `usage`.
+    fn usage(&self, usage: &mut ResourceUsage) {
+        let _ = usage;
+    }
+}
+
+/// Context for resolving a [`CustomResource`].
+#[derive(Default)]
+pub struct ResolveContext {
+    notify: bool,
+}
+
+impl ResolveContext {
+    /// Requests a call to [`CustomResource::resident`] once the images of
+    /// the resolved brush have been uploaded.
+    pub fn notify_when_resident(&mut self) {
+        self.notify = true;
+    }
+}
+
+/// Policy for drawing an asynchronously loaded image that is not yet
+/// available.
+#[derive(Copy, Clone, Debug)]
+pub enum Placeholder {
+    /// Draws nothing.
+    Skip,
+    /// Fills with a solid color.
+    Color(Color),
+    /// Draws the previously loaded image, if any, and otherwise nothing.
+    Previous,
+}
+
+/// Image whose data is loaded asynchronously, for example from the network.
+///
+/// Scenes can reference the image before it has been loaded, in which case
+/// the placeholder is drawn instead. The image can be set from any thread and
+/// is picked up the next time a scene that references it is rendered.
+#[derive(Clone)]
+pub struct AsyncImage(Arc<Mutex<AsyncImageState>>);
+
+struct AsyncImageState {
+    image: Option<Image>,
+    previous: Option<Image>,
+    placeholder: Placeholder,
+    resident: bool,
+    on_resident: Option<Box<dyn FnMut() + Send>>,
+}
+
+impl AsyncImage {
+    /// Creates a new image that has not been loaded.
+    pub fn new(placeholder: Placeholder) -> Self {
+        Self(Arc::new(Mutex::new(AsyncImageState {
+            image: None,
+            previous: None,
+            placeholder,
+            resident: false,
+            on_resident: None,
+        })))
+    }
+
+    /// Sets the loaded image.
+    pub fn set(&self, image: Image) {
+        let mut state = self.0.lock().unwrap();
+        state.image = Some(image);
+        state.resident = false;
+    }
+
+    /// Marks the image as loading again, for example to fetch a new version.
+    /// The current image is drawn in the meantime with the
+    /// [`Placeholder::Previous`] policy.
+    pub fn reset(&self) {
+        let mut state = self.0.lock().unwrap();
+        if let Some(image) = state.image.take() {
+            state.previous = Some(image);
+        }
+        state.resident = false;
+    }
+
+    /// Returns true if the image has been loaded and uploaded to the GPU.
+    pub fn is_resident(&self) -> bool {
+        self.0.lock().unwrap().resident
+    }
+
+    /// Sets a callback that is invoked when the image becomes resident on the
+    /// GPU. The callback is invoked on the thread that renders the scene.
+    pub fn on_resident(&self, callback: impl FnMut() + Send + 'static) {
+        self.0.lock().unwrap().on_resident = Some(Box::new(callback));
+    }
+}
+
+impl CustomResource for AsyncImage {
+    fn resolve(&self, cx: &mut ResolveContext) -> Brush {
+        let state = self.0.lock().unwrap();
+        let transparent = Brush::Solid(Color::rgba8(0, 0, 0, 0));
+        match (&state.image, state.placeholder) {
+            (Some(image), _) => {
+                if !state.resident {
+                    cx.notify_when_resident();
+                }
+                Brush::Image(image.clone())
+            }
+            (None, Placeholder::Color(color)) => Brush::Solid(color),
+            (None, Placeholder::Previous) => match &state.previous {
+                Some(image) => Brush::Image(image.clone()),
+                None => transparent,
+            },
+            (None, Placeholder::Skip) => transparent,
+        }
+    }
+
+    fn resident(&self) {
+        let callback = {
+            let mut state = self.0.lock().unwrap();
+            if state.resident || state.image.is_none() {
+                return;
+            }
+            state.resident = true;
+            state.on_resident.take()
+        };
+        // Invoke the callback without holding the lock so that it can query
+        // the image.
+        if let Some(mut callback) = callback {
+            callback();
+            let mut state = self.0.lock().unwrap();
+            if state.on_resident.is_none() {
+                state.on_resident = Some(callback);
+            }
+        }
+    }
+
+    fn usage(&self, usage: &mut ResourceUsage) {
+        if let Some(image) = &self.0.lock().unwrap().image {
+            usage.add_image(image);
+        }
+    }
+}
+
+/// Late bound resources referenced by an encoding.
+///
+/// Glyphs are encoded as outlines, so fonts are not tracked here.
+#[derive(Clone, Default)]
+pub struct ResourceUsage {
+    /// Images referenced by the encoding, deduplicated by data.
+    pub images: Vec<Image>,
+    /// Distinct gradient color stop collections referenced by the encoding.
+    pub gradients: Vec<ColorStops>,
+}
+
+impl ResourceUsage {
+    /// Returns the size in bytes of the images when uploaded to the GPU.
+    pub fn image_bytes(&self) -> usize {
+        self.images
+            .iter()
+            .map(|image| image.width as usize * image.height as usize * 4)
+            .sum()
+    }
+
+    /// Returns the size in bytes of the gradient ramps when uploaded to the
+    /// GPU.
+    pub fn gradient_bytes(&self) -> usize {
+        self.gradients
+            .iter()
+            .map(|stops| ramp_rows(stops.len()) * N_SAMPLES * 4)
+            .sum()
+    }
+
+    /// Adds an image, unless an image with the same data is already present.
+    pub fn add_image(&mut self, image: &Image) {
+        if !self.images.iter().any(|x| x.data.id() == image.data.id()) {
+            self.images.push(image.clone());
+        }
+    }
+
+    /// Adds a gradient, unless identical color stops are already present.
+    pub fn add_gradient(&mut self, stops: &[ColorStop]) {
+        if !self.gradients.iter().any(|x| x.as_slice() == stops) {
+            self.gradients.push(stops.into());
+        }
+    }
+}
 
 /// Token for ensuring that an encoded scene matches the current state
 /// of a resource cache.
@@ -33,6 +253,11 @@ pub struct Token(u64);
 #[derive(Default)]
 pub struct ResourceCache {
     ramps: RampCache,
+    images: ImageCache,
+    loading: Vec<Arc<dyn CustomResource>>,
+    /// Maximum texture dimension supported by the device, if lower than the
+    /// default size limits of the image atlas.
+    max_texture_size: Option<u32>,
 }
 
 impl ResourceCache {
@@ -41,6 +266,17 @@ impl ResourceCache {
         Self::default()
     }
 
+    /// Limits the dimensions of the image atlas to the maximum texture size
+    /// of the device. Images are packed into narrower rows and the atlas is
+    /// repacked sooner, so devices with small textures can render scenes
+    /// with many images at the cost of more uploads.
+    pub fn set_max_texture_size(&mut self, size: u32) {
+        self.max_texture_size = (size < IMAGE_ATLAS_MAX_HEIGHT).then_some(size);
+        self.images.max_width = self
+            .max_texture_size
+            .map_or(IMAGE_ATLAS_WIDTH, |size| size.min(IMAGE_ATLAS_WIDTH));
+    }
+
     /// Returns the ramp data, width and height. Returns `None` if the
     /// given token does not match the current state of the cache.
     pub fn ramps(&self, token: Token) -> Option<(&[u32], u32, u32)> {
@@ -51,14 +287,137 @@ impl ResourceCache {
         }
     }
 
+    /// Returns the image atlas width and height along with the images that
+    /// have been added to the atlas but not yet uploaded and their positions.
+    /// Returns `None` if the given token does not match the current state of
+    /// the cache.
+    pub fn images(&self, token: Token) -> Option<(u32, u32, &[(Image, u32, u32)])> {
+        if token.0 == self.ramps.epoch {
+            Some((self.images.width, self.images.height, &self.images.pending))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the position in the atlas of the image with the given data id.
+    pub(crate) fn image_position(&self, id: u64) -> Option<(u32, u32)> {
+        self.images.map.get(&id).copied()
+    }
+
+    pub(crate) fn pending_images(&self) -> (u32, u32, &[(Image, u32, u32)]) {
+        (self.images.width, self.images.height, &self.images.pending)
+    }
+
+    /// Returns a counter that changes whenever the image atlas is repacked,
+    /// invalidating all previously uploaded images.
+    pub fn image_generation(&self) -> u64 {
+        self.images.generation
+    }
+
+    /// Clears the image atlas so that images are packed and uploaded again,
+    /// for example after moving to a new device.
+    pub fn invalidate_images(&mut self) {
+        self.images.clear();
+    }
+
+    /// Returns the updates to rectangles of images that were already added
+    /// to the atlas and have not yet been uploaded.
+    pub fn image_updates(&self) -> &[ImageUpdate] {
+        &self.images.updates
+    }
+
+    /// Replaces the pixels in a rectangle of an image with tightly packed
+    /// RGBA8 data.
+    ///
+    /// The image keeps its place in the atlas and only the rectangle is
+    /// uploaded with the pending images. The updated contents are also used
+    /// whenever the whole image is uploaded again, so the data of `image`
+    /// itself is left unchanged. The updated contents are discarded once the
+    /// image has not been drawn for a number of renders, after which it is
+    /// drawn with its own data again. Returns false without making changes if
+    /// the rectangle is not inside the image or the data does not match its
+    /// size.
+    pub fn update_image(
+        &mut self,
+        image: &Image,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        data: &[u8],
+    ) -> bool {
+        self.images.update(image, [x, y, width, height], data)
+    }
+
+    /// Forgets the position of the image with the given data id, so that
+    /// it is packed and uploaded again if it is used afterwards.
+    pub(crate) fn remove_image(&mut self, id: u64) {
+        self.images.remove(id);
+    }
+
+    /// Marks the pending images and image updates as uploaded.
+    pub fn clear_pending_images(&mut self) {
+        self.images.pending.clear();
+        self.images.updates.clear();
+    }
+
     pub(crate) fn advance(&mut self) -> Token {
         self.ramps.advance();
+        self.images.advance();
+        let max_height = self.max_texture_size.unwrap_or(IMAGE_ATLAS_MAX_HEIGHT);
+        if self.images.height > max_height {
+            self.images.clear();
+        }
         Token(self.ramps.epoch)
     }
 
     pub(crate) fn add_ramp(&mut self, stops: &[ColorStop]) -> u32 {
         self.ramps.add(stops)
     }
+
+    /// Marks the ramps of gradient stops that were replaced so that their
+    /// slots are reused before new ones are allocated.
+    pub(crate) fn retire_ramps(&mut self, stops: &[ColorStops]) {
+        for stops in stops {
+            self.ramps.retire(stops);
+        }
+    }
+
+    /// Returns the color stops of the cached ramps ordered by ramp id.
+    pub(crate) fn ramp_stops(&self) -> Vec<&ColorStops> {
+        let mut ramps: Vec<_> = self
+            .ramps
+            .map
+            .iter()
+            .map(|(k, v)| (ramp_id_row(v.0), k))
+            .collect();
+        ramps.sort_by_key(|(id, _)| *id);
+        ramps.into_iter().map(|(_, stops)| stops).collect()
+    }
+
+    pub(crate) fn add_image(&mut self, image: &Image) -> (u32, u32) {
+        self.images.add(image)
+    }
+
+    /// Resolves a custom resource to a brush, recording it for notification
+    /// if requested.
+    pub(crate) fn resolve_custom(&mut self, resource: &Arc<dyn CustomResource>) -> Brush {
+        let mut cx = ResolveContext::default();
+        let brush = resource.resolve(&mut cx);
+        if cx.notify {
+            self.loading.push(resource.clone());
+        }
+        brush
+    }
+
+    /// Notifies custom resources recorded by
+    /// [`resolve_custom`](Self::resolve_custom) that their images are
+    /// resident. This should be called after the pending images are uploaded.
+    pub(crate) fn notify_resident(&mut self) {
+        for resource in self.loading.drain(..) {
+            resource.resident();
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -71,6 +430,258 @@ pub enum Patch {
         /// Range of the gradient stops in the resource set.
         stops: Range<usize>,
     },
+    /// Image resource.
+    Image {
+        /// Byte offset to the packed atlas position in the draw data stream.
+        offset: usize,
+        /// The image.
+        image: Image,
+    },
+    /// Brush from the brush table.
+    Brush {
+        /// Byte offset in the draw data stream where the brush data is
+        /// inserted.
+        offset: usize,
+        /// Index of the placeholder tag in the draw tag stream.
+        draw_tag_ix: usize,
+        /// Handle of the brush.
+        handle: BrushHandle,
+        /// Alpha modifier for the brush.
+        alpha: f32,
+    },
+    /// Resource resolved to a brush at render time, including
+    /// asynchronously loaded images.
+    Custom {
+        /// Byte offset in the draw data stream where the brush data is
+        /// inserted.
+        offset: usize,
+        /// Index of the placeholder tag in the draw tag stream.
+        draw_tag_ix: usize,
+        /// The resource.
+        resource: Arc<dyn CustomResource>,
+        /// Alpha modifier for the brush.
+        alpha: f32,
+    },
+}
+
+impl Patch {
+    /// Returns the byte offset of the patch in the draw data stream.
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::Ramp { offset, .. }
+            | Self::Image { offset, .. }
+            | Self::Brush { offset, .. }
+            | Self::Custom { offset, .. } => *offset,
+        }
+    }
+}
+
+/// Pixels to be written to a rectangle of the image atlas.
+#[derive(Clone, Debug)]
+pub struct ImageUpdate {
+    /// Horizontal position of the rectangle in the atlas.
+    pub x: u32,
+    /// Vertical position of the rectangle in the atlas.
+    pub y: u32,
+    /// Width of the rectangle.
+    pub width: u32,
+    /// Height of the rectangle.
+    pub height: u32,
+    /// Tightly packed RGBA8 pixels.
+    pub data: Vec<u8>,
+}
+
+/// Images packed into rows of an atlas. Images remain in the atlas until it
+/// grows too large, at which point it is cleared and images are packed again
+/// as they are used.
+///
+/// Images whose data was allocated separately but has identical contents
+/// share a position in the atlas until one of them is updated.
+#[derive(Default)]
+struct ImageCache {
+    map: HashMap<u64, (u32, u32)>,
+    /// Data ids of images that share the position of another image, mapped
+    /// to the id of the image that owns the position.
+    aliases: HashMap<u64, u64>,
+    /// Ids of the images that own a position, keyed by a hash of their
+    /// dimensions and contents.
+    by_contents: HashMap<u64, u64>,
+    /// Randomly keyed so that colliding contents cannot be crafted.
+    hasher: RandomState,
+    pending: Vec<(Image, u32, u32)>,
+    updates: Vec<ImageUpdate>,
+    /// Current contents of images that were partially updated, keyed by the
+    /// id of their original data.
+    contents: HashMap<u64, Vec<u8>>,
+    /// Epochs in which the images with updated contents were last used.
+    contents_used: HashMap<u64, u64>,
+    epoch: u64,
+    generation: u64,
+    width: u32,
+    height: u32,
+    /// Width at which a new row of images is started, or zero for the
+    /// default.
+    max_width: u32,
+    row_x: u32,
+    row_y: u32,
+}
+
+impl ImageCache {
+    fn clear(&mut self) {
+        self.map.clear();
+        self.aliases.clear();
+        self.by_contents.clear();
+        self.pending.clear();
+        self.updates.clear();
+        self.generation += 1;
+        self.width = 0;
+        self.height = 0;
+        self.row_x = 0;
+        self.row_y = 0;
+    }
+
+    fn add(&mut self, image: &Image) -> (u32, u32) {
+        let id = image.data.id();
+        if let Some(used) = self.contents_used.get_mut(&id) {
+            *used = self.epoch;
+        }
+        if let Some(pos) = self.map.get(&id) {
+            return *pos;
+        }
+        // Partially updated images have contents of their own, and images
+        // without data, such as registered textures, are filled on the GPU.
+        let has_data = !image.data.data().is_empty();
+        let key = (has_data && !self.contents.contains_key(&id)).then(|| {
+            let mut hasher = self.hasher.build_hasher();
+            (image.width, image.height, image.data.data()).hash(&mut hasher);
+            hasher.finish()
+        });
+        if let Some(owner) = key.and_then(|key| self.by_contents.get(&key)) {
+            if let Some(pos) = self.map.get(owner).copied() {
+                self.aliases.insert(id, *owner);
+                self.map.insert(id, pos);
+                return pos;
+            }
+        }
+        let max_width = if self.max_width == 0 {
+            IMAGE_ATLAS_WIDTH
+        } else {
+            self.max_width
+        };
+        if self.row_x != 0 && self.row_x + image.width > max_width {
+            self.row_x = 0;
+            self.row_y = self.height;
+        }
+        let pos = (self.row_x, self.row_y);
+        self.row_x += image.width;
+        self.width = self.width.max(self.row_x);
+        self.height = self.height.max(self.row_y + image.height);
+        self.map.insert(id, pos);
+        if let Some(key) = key {
+            self.by_contents.insert(key, id);
+        }
+        self.pending.push((self.current(image), pos.0, pos.1));
+        pos
+    }
+
+    /// Returns the image with its partial updates applied.
+    fn current(&self, image: &Image) -> Image {
+        let mut current = image.clone();
+        if let Some(data) = self.contents.get(&image.data.id()) {
+            current.data = data.clone().into();
+        }
+        current
+    }
+
+    fn update(&mut self, image: &Image, rect: [u32; 4], data: &[u8]) -> bool {
+        let [x, y, width, height] = rect;
+        let fits = x.checked_add(width).map_or(false, |x1| x1 <= image.width)
+            && y.checked_add(height).map_or(false, |y1| y1 <= image.height);
+        if !fits || data.len() != width as usize * height as usize * 4 {
+            return false;
+        }
+        let id = image.data.id();
+        if self.aliases.remove(&id).is_some() {
+            // The image no longer matches the one it shares a position with,
+            // so it is packed separately when it is next used.
+            self.map.remove(&id);
+        } else {
+            self.unalias(id);
+        }
+        self.contents_used.insert(id, self.epoch);
+        let contents = self
+            .contents
+            .entry(id)
+            .or_insert_with(|| image.data.data().to_vec());
+        let stride = image.width as usize * 4;
+        let row_size = width as usize * 4;
+        for (row, src) in data.chunks_exact(row_size.max(1)).enumerate() {
+            let start = (y as usize + row) * stride + x as usize * 4;
+            contents[start..start + row_size].copy_from_slice(src);
+        }
+        let pos = match self.map.get(&id) {
+            Some(pos) => *pos,
+            None => return true,
+        };
+        if let Some(index) = self
+            .pending
+            .iter()
+            .position(|(_, px, py)| (*px, *py) == pos)
+        {
+            // The whole image has not been uploaded yet.
+            self.pending[index].0 = self.current(image);
+        } else {
+            self.updates.push(ImageUpdate {
+                x: pos.0 + x,
+                y: pos.1 + y,
+                width,
+                height,
+                data: data.to_vec(),
+            });
+        }
+        true
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.map.remove(&id);
+        self.contents.remove(&id);
+        self.contents_used.remove(&id);
+        if self.aliases.remove(&id).is_none() {
+            self.unalias(id);
+        }
+    }
+
+    /// Starts a new epoch and discards the updated contents of images that
+    /// were not used for [`IMAGE_CONTENTS_MAX_AGE`] epochs, along with their
+    /// positions, so that they are uploaded from their data if they are used
+    /// again.
+    fn advance(&mut self) {
+        self.epoch += 1;
+        let epoch = self.epoch;
+        let mut stale = vec![];
+        for (id, used) in &self.contents_used {
+            if *used + IMAGE_CONTENTS_MAX_AGE < epoch {
+                stale.push(*id);
+            }
+        }
+        for id in stale {
+            self.remove(id);
+        }
+    }
+
+    /// Removes the images that share the position of the given image and
+    /// keeps others from sharing it.
+    fn unalias(&mut self, owner: u64) {
+        let map = &mut self.map;
+        self.aliases.retain(|id, x| {
+            let keep = *x != owner;
+            if !keep {
+                map.remove(id);
+            }
+            keep
+        });
+        self.by_contents.retain(|_, x| *x != owner);
+    }
 }
 
 #[derive(Default)]
@@ -78,52 +689,90 @@ struct RampCache {
     epoch: u64,
     map: HashMap<ColorStops, (u32, u64)>,
     data: Vec<u32>,
+    retired: Vec<ColorStops>,
 }
 
 impl RampCache {
     pub fn advance(&mut self) {
         self.epoch += 1;
-        if self.map.len() > RETAINED_COUNT {
+        if self.data.len() > RETAINED_COUNT * N_SAMPLES {
             self.map
-                .retain(|_key, value| value.0 < RETAINED_COUNT as u32);
+                .retain(|_key, value| ramp_end(value.0) <= RETAINED_COUNT);
             self.data.truncate(RETAINED_COUNT * N_SAMPLES);
         }
     }
 
+    pub fn retire(&mut self, stops: &ColorStops) {
+        if self.map.contains_key(stops) && !self.retired.contains(stops) {
+            self.retired.push(stops.clone());
+        }
+    }
+
+    /// Removes a retired ramp with the given number of rows that is not used
+    /// in the current epoch and returns its id.
+    fn take_retired(&mut self, rows: usize) -> Option<u32> {
+        let Self {
+            epoch,
+            map,
+            retired,
+            ..
+        } = self;
+        retired.retain(|stops| map.contains_key(stops));
+        let ix = retired.iter().position(|stops| match map.get(stops) {
+            Some((id, used)) => *used < *epoch && ramp_id_rows(*id) == rows,
+            None => false,
+        })?;
+        let stops = retired.swap_remove(ix);
+        map.remove(&stops).map(|(id, _)| id)
+    }
+
     pub fn add(&mut self, stops: &[ColorStop]) -> u32 {
         if let Some(entry) = self.map.get_mut(stops) {
             entry.1 = self.epoch;
-            entry.0
-        } else if self.map.len() < RETAINED_COUNT {
-            let id = (self.data.len() / N_SAMPLES) as u32;
-            self.data.extend(make_ramp(stops));
-            self.map.insert(stops.into(), (id, self.epoch));
+            return entry.0;
+        }
+        let rows = ramp_rows(stops.len());
+        let id = if let Some(id) = self.take_retired(rows) {
+            self.write(id, stops);
             id
+        } else if self.height() as usize + rows <= RETAINED_COUNT {
+            self.push(rows, stops)
         } else {
             let mut reuse = None;
             for (stops, (id, epoch)) in &self.map {
-                if *epoch + 2 < self.epoch {
+                if *epoch + 2 < self.epoch && ramp_id_rows(*id) == rows {
                     reuse = Some((stops.to_owned(), *id));
                     break;
                 }
             }
             if let Some((old_stops, id)) = reuse {
                 self.map.remove(&old_stops);
-                let start = id as usize * N_SAMPLES;
-                for (dst, src) in self.data[start..start + N_SAMPLES]
-                    .iter_mut()
-                    .zip(make_ramp(stops))
-                {
-                    *dst = src;
-                }
-                self.map.insert(stops.into(), (id, self.epoch));
+                self.write(id, stops);
                 id
             } else {
-                let id = (self.data.len() / N_SAMPLES) as u32;
-                self.data.extend(make_ramp(stops));
-                self.map.insert(stops.into(), (id, self.epoch));
-                id
+                self.push(rows, stops)
             }
+        };
+        self.map.insert(stops.into(), (id, self.epoch));
+        id
+    }
+
+    /// Appends a ramp spanning `rows` rows and returns its id.
+    fn push(&mut self, rows: usize, stops: &[ColorStop]) -> u32 {
+        let id = self.height() | ((rows as u32 - 1) << RAMP_ROWS_SHIFT);
+        self.data.extend(make_ramp(stops, rows * N_SAMPLES));
+        id
+    }
+
+    /// Overwrites the samples of an existing ramp.
+    fn write(&mut self, id: u32, stops: &[ColorStop]) {
+        let n_samples = ramp_id_rows(id) * N_SAMPLES;
+        let start = ramp_id_row(id) * N_SAMPLES;
+        for (dst, src) in self.data[start..start + n_samples]
+            .iter_mut()
+            .zip(make_ramp(stops, n_samples))
+        {
+            *dst = src;
         }
     }
 
@@ -140,14 +789,35 @@ impl RampCache {
     }
 }
 
-fn make_ramp(stops: &[ColorStop]) -> impl Iterator<Item = u32> + '_ {
+/// Returns the number of rows of the ramp for a gradient with `n_stops`
+/// color stops.
+fn ramp_rows(n_stops: usize) -> usize {
+    ((n_stops * SAMPLES_PER_STOP + N_SAMPLES - 1) / N_SAMPLES).clamp(1, MAX_RAMP_ROWS)
+}
+
+/// Returns the first row of the ramp with the given id.
+fn ramp_id_row(id: u32) -> usize {
+    (id & ((1 << RAMP_ROWS_SHIFT) - 1)) as usize
+}
+
+/// Returns the number of rows of the ramp with the given id.
+fn ramp_id_rows(id: u32) -> usize {
+    (id >> RAMP_ROWS_SHIFT) as usize + 1
+}
+
+/// Returns the row after the last row of the ramp with the given id.
+fn ramp_end(id: u32) -> usize {
+    ramp_id_row(id) + ramp_id_rows(id)
+}
+
+fn make_ramp(stops: &[ColorStop], n_samples: usize) -> impl Iterator<Item = u32> + '_ {
     let mut last_u = 0.0;
     let mut last_c = ColorF64::from_color(stops[0].color);
     let mut this_u = last_u;
     let mut this_c = last_c;
     let mut j = 0;
-    (0..N_SAMPLES).map(move |i| {
-        let u = (i as f64) / (N_SAMPLES - 1) as f64;
+    (0..n_samples).map(move |i| {
+        let u = (i as f64) / (n_samples - 1) as f64;
         while u > this_u {
             last_u = this_u;
             last_c = this_c;
@@ -203,3 +873,70 @@ impl ColorF64 {
         r | (g << 8) | (b << 16) | (a << 24)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use peniko::{Format, Image};
+
+    use super::ResourceCache;
+
+    fn image(value: u8) -> Image {
+        Image::new(vec![value; 16].into(), Format::Rgba8, 2, 2)
+    }
+
+    #[test]
+    fn shares_identical_images() {
+        let mut cache = ResourceCache::new();
+        let (a, b, c) = (image(1), image(1), image(2));
+        let pos = cache.add_image(&a);
+        assert_eq!(cache.add_image(&b), pos);
+        assert_ne!(cache.add_image(&c), pos);
+        assert_eq!(cache.pending_images().2.len(), 2);
+        // Images without data are not shared.
+        let empty = || Image::new(vec![].into(), Format::Rgba8, 2, 2);
+        assert_ne!(cache.add_image(&empty()), cache.add_image(&empty()));
+    }
+
+    #[test]
+    fn separates_updated_images() {
+        let mut cache = ResourceCache::new();
+        let (a, b) = (image(1), image(1));
+        let pos = cache.add_image(&a);
+        assert_eq!(cache.add_image(&b), pos);
+        assert!(cache.update_image(&b, 0, 0, 1, 1, &[9; 4]));
+        let b_pos = cache.add_image(&b);
+        assert_ne!(b_pos, pos);
+        assert_eq!(cache.pending_images().2[1].0.data.data()[..4], [9; 4]);
+        // Updating the image that owns a position moves the images that
+        // shared it.
+        let c = image(1);
+        assert_eq!(cache.add_image(&c), pos);
+        assert!(cache.update_image(&a, 0, 0, 1, 1, &[7; 4]));
+        assert_eq!(cache.add_image(&a), pos);
+        let c_pos = cache.add_image(&c);
+        assert_ne!(c_pos, pos);
+        assert_ne!(c_pos, b_pos);
+    }
+
+    #[test]
+    fn discards_contents_of_unused_images() {
+        let mut cache = ResourceCache::new();
+        let (a, b) = (image(1), image(2));
+        cache.add_image(&a);
+        cache.add_image(&b);
+        assert!(cache.update_image(&a, 0, 0, 1, 1, &[9; 4]));
+        assert!(cache.update_image(&b, 0, 0, 1, 1, &[9; 4]));
+        cache.clear_pending_images();
+        for _ in 0..=super::IMAGE_CONTENTS_MAX_AGE {
+            cache.advance();
+            cache.add_image(&a);
+        }
+        cache.advance();
+        cache.add_image(&a);
+        cache.add_image(&b);
+        // Only the unused image is uploaded again, without its update.
+        let pending = cache.pending_images().2;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].0.data.data(), b.data.data());
+    }
+}
@@ -0,0 +1,485 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Cleaning of encodings built from untrusted input.
+//!
+//! Scenes converted from user supplied documents can contain values that
+//! are expensive or undefined to render: non-finite coordinates, points and
+//! transforms far outside of any target, enormous stroke widths and layers
+//! nested thousands deep. [`Encoding::sanitize`] replaces these with values
+//! that render in bounded time, so that a server rasterizing such documents
+//! cannot be stalled by a malicious one.
+
+use super::encoding::NO_SCISSOR;
+use super::resource::Patch;
+use super::{DrawBeginClip, DrawTag, Encoding, EncodingMark, PathTag, Transform};
+
+/// Limits that are enforced by [`Encoding::sanitize`].
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SanitizeLimits {
+    /// Maximum magnitude of path coordinates and of the translation of
+    /// transforms.
+    pub max_coordinate: f32,
+    /// Maximum magnitude of the coefficients of the matrices of transforms.
+    pub max_scale: f32,
+    /// Maximum width of strokes, before they are transformed.
+    pub max_stroke_width: f32,
+    /// Maximum nesting depth of clips and layers. Layers that are nested
+    /// deeper are removed along with their content.
+    pub max_layer_depth: u32,
+}
+
+impl Default for SanitizeLimits {
+    fn default() -> Self {
+        Self {
+            max_coordinate: 1e6,
+            max_scale: 1e4,
+            max_stroke_width: 1e4,
+            max_layer_depth: 64,
+        }
+    }
+}
+
+/// Counts of the values that were changed by [`Encoding::sanitize`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct SanitizeReport {
+    /// Number of path coordinates that were not finite.
+    pub non_finite_coordinates: usize,
+    /// Number of path coordinates that were clamped to the limit.
+    pub clamped_coordinates: usize,
+    /// Number of transforms that were not finite and now collapse their
+    /// paths to a point, or that were clamped to the limits.
+    pub transforms: usize,
+    /// Number of stroke widths that were not finite or were clamped to the
+    /// limit.
+    pub stroke_widths: usize,
+    /// Number of layers that were removed because they were nested too
+    /// deeply, not counting the layers inside of them.
+    pub removed_layers: usize,
+    /// True if the lengths of the streams or the counts of the encoding were
+    /// inconsistent, in which case all of its draws were removed.
+    pub malformed: bool,
+}
+
+impl SanitizeReport {
+    /// Returns true if the encoding was not changed.
+    pub fn is_clean(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Encoding {
+    /// Replaces values of the encoding that exceed the limits, and returns
+    /// what was changed.
+    ///
+    /// Non-finite coordinates are replaced with zero, or with the limit for
+    /// infinities, and other coordinates are clamped to the limit.
+    /// Transforms with non-finite coefficients are replaced with one that
+    /// collapses their paths, so that nothing is drawn with them. Instanced
+    /// fragments are not sanitized, so they should be expanded with
+    /// [`expand_instances`](Self::expand_instances) first. An encoding whose
+    /// streams do not agree with each other is emptied.
+    pub fn sanitize(&mut self, limits: &SanitizeLimits) -> SanitizeReport {
+        let mut report = SanitizeReport::default();
+        if !self.is_consistent() {
+            self.reset(false);
+            report.malformed = true;
+            return report;
+        }
+        self.sanitize_path_data(limits, &mut report);
+        for transform in &mut self.transforms {
+            if let Some(clean) = sanitize_transform(transform, limits) {
+                *transform = clean;
+                report.transforms += 1;
+            }
+        }
+        for linewidth in &mut self.linewidths {
+            // Negative widths mark fills.
+            let clean = if linewidth.is_nan() {
+                -1.0
+            } else {
+                linewidth.min(limits.max_stroke_width)
+            };
+            if clean != *linewidth {
+                *linewidth = clean;
+                report.stroke_widths += 1;
+            }
+        }
+        self.limit_layer_depth(limits.max_layer_depth, &mut report);
+        report
+    }
+
+    /// Returns true if the lengths of the streams and the counts of paths,
+    /// segments and clips agree with the tags and the indices into the mesh
+    /// patches, color matrices and scissor rectangles are in range, so that
+    /// the streams can be walked and edited, and the tables read by the
+    /// shaders, without going out of bounds.
+    pub(crate) fn is_consistent(&self) -> bool {
+        let (mut path_data, mut n_transforms, mut n_linewidths) = (0, 0, 0);
+        let (mut n_paths, mut n_segments, mut n_clips) = (0u32, 0u32, 0u32);
+        for tag in &self.path_tags {
+            if tag.is_path_segment() {
+                let n_points = tag.path_segment_type().0 as usize + tag.is_subpath_end() as usize;
+                path_data += n_points * if tag.is_f32() { 8 } else { 4 };
+                n_segments += 1;
+            }
+            match *tag {
+                PathTag::PATH => n_paths += 1,
+                PathTag::TRANSFORM => n_transforms += 1,
+                PathTag::LINEWIDTH => n_linewidths += 1,
+                _ => {}
+            }
+        }
+        let mut draw_data = 0;
+        let mut indices_valid = true;
+        for tag in &self.draw_tags {
+            let index = self
+                .draw_data
+                .get(draw_data..draw_data + 4)
+                .map(|data| u32::from_le_bytes(data.try_into().unwrap()));
+            match *tag {
+                DrawTag::MESH_GRADIENT => {
+                    indices_valid &=
+                        matches!(index, Some(patch) if (patch as usize) < self.mesh_patches.len());
+                }
+                DrawTag::BEGIN_CLIP => {
+                    indices_valid &= match index {
+                        Some(blend) if blend & DrawBeginClip::COLOR_MATRIX != 0 => {
+                            let matrix = blend >> DrawBeginClip::COLOR_MATRIX_SHIFT;
+                            (matrix as usize) < self.color_matrices.len()
+                        }
+                        _ => true,
+                    };
+                }
+                _ => {}
+            }
+            draw_data += tag.data_size() as usize * 4;
+            if *tag == DrawTag::BEGIN_CLIP || *tag == DrawTag::END_CLIP {
+                n_clips += 1;
+            }
+        }
+        let scissors_valid = self.scissors.iter().all(|(_, scissor)| {
+            *scissor == NO_SCISSOR || (*scissor as usize) < self.scissor_rects.len()
+        });
+        let n_draws = self.draw_tags.len() as u32;
+        let runs_valid = [&self.visibility_groups, &self.scissors, &self.draw_flags]
+            .iter()
+            .all(|runs| {
+                runs.windows(2).all(|pair| pair[0].0 <= pair[1].0)
+                    && runs.iter().all(|(ix, _)| *ix <= n_draws)
+            });
+        let mut last_offset = 0;
+        let patches_valid = self.patches.iter().all(|patch| {
+            let offset = patch.offset();
+            let valid = offset >= last_offset
+                && offset <= self.draw_data.len()
+                && match patch {
+                    Patch::Ramp { stops, .. } => {
+                        offset + 4 <= self.draw_data.len()
+                            && stops.start <= stops.end
+                            && stops.end <= self.color_stops.len()
+                    }
+                    Patch::Image { .. } => offset + 4 <= self.draw_data.len(),
+                    Patch::Brush { draw_tag_ix, .. } | Patch::Custom { draw_tag_ix, .. } => {
+                        *draw_tag_ix < self.draw_tags.len()
+                    }
+                };
+            last_offset = offset;
+            valid
+        });
+        path_data == self.path_data.len()
+            && n_paths == self.n_paths
+            && n_segments == self.n_path_segments
+            && n_clips == self.n_clips
+            && draw_data == self.draw_data.len()
+            && self.draw_tags.len() <= n_paths as usize
+            && n_transforms <= self.transforms.len()
+            && n_linewidths <= self.linewidths.len()
+            && runs_valid
+            && patches_valid
+            && indices_valid
+            && scissors_valid
+    }
+
+    fn sanitize_path_data(&mut self, limits: &SanitizeLimits, report: &mut SanitizeReport) {
+        let max = limits.max_coordinate;
+        let mut offset = 0;
+        for tag in &self.path_tags {
+            if !tag.is_path_segment() {
+                continue;
+            }
+            // The data of a segment holds its points and, at the end of a
+            // subpath, the start of the next.
+            let n_points = tag.path_segment_type().0 as usize + tag.is_subpath_end() as usize;
+            let size = n_points * if tag.is_f32() { 8 } else { 4 };
+            // 16-bit coordinates are always finite and small.
+            if tag.is_f32() {
+                let end = (offset + size).min(self.path_data.len());
+                for bytes in self.path_data[offset.min(end)..end].chunks_exact_mut(4) {
+                    let value = f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    let clean = if value.is_nan() {
+                        report.non_finite_coordinates += 1;
+                        0.0
+                    } else if value.is_infinite() {
+                        report.non_finite_coordinates += 1;
+                        value.clamp(-max, max)
+                    } else if value.abs() > max {
+                        report.clamped_coordinates += 1;
+                        value.clamp(-max, max)
+                    } else {
+                        continue;
+                    };
+                    bytes.copy_from_slice(&clean.to_ne_bytes());
+                }
+            }
+            offset += size;
+        }
+    }
+
+    /// Removes the layers that are nested deeper than `max_depth`, along
+    /// with their content.
+    fn limit_layer_depth(&mut self, max_depth: u32, report: &mut SanitizeReport) {
+        // Ranges of draw objects from a layer that is too deep to the end of
+        // the layer.
+        let mut ranges = vec![];
+        let mut depth = 0;
+        let mut removed_start = None;
+        for (ix, tag) in self.draw_tags.iter().enumerate() {
+            if *tag == DrawTag::BEGIN_CLIP {
+                if depth == max_depth && removed_start.is_none() {
+                    removed_start = Some(ix);
+                }
+                depth += 1;
+            } else if *tag == DrawTag::END_CLIP {
+                depth = depth.saturating_sub(1);
+                if depth == max_depth {
+                    if let Some(start) = removed_start.take() {
+                        ranges.push(start..ix + 1);
+                    }
+                }
+            }
+        }
+        if let Some(start) = removed_start {
+            ranges.push(start..self.draw_tags.len());
+        }
+        if ranges.is_empty() {
+            return;
+        }
+        report.removed_layers += ranges.len();
+        let marks = self.draw_marks(ranges.iter().flat_map(|range| [range.start, range.end]));
+        // Later ranges are removed first so that the marks of the earlier
+        // ones remain valid.
+        for pair in marks.chunks_exact(2).rev() {
+            self.remove_range(&pair[0], &pair[1]);
+        }
+    }
+
+    /// Returns the marks of the streams that precede each of the draw
+    /// objects at the given ascending indices.
+    fn draw_marks(&self, indices: impl Iterator<Item = usize>) -> Vec<EncodingMark> {
+        let mut marks = vec![];
+        let mut mark = EncodingMark::default();
+        let mut path_tags = self.path_tags.iter();
+        let mut draw_ix = 0;
+        for ix in indices {
+            // Advance over the paths of the draw objects before the index,
+            // each of which ends with a path marker.
+            while draw_ix < ix {
+                for tag in path_tags.by_ref() {
+                    mark.path_tags += 1;
+                    if tag.is_path_segment() {
+                        let n_points =
+                            tag.path_segment_type().0 as usize + tag.is_subpath_end() as usize;
+                        mark.path_data += n_points * if tag.is_f32() { 8 } else { 4 };
+                        mark.n_path_segments += 1;
+                    }
+                    if *tag == PathTag::PATH {
+                        break;
+                    }
+                }
+                let draw_tag = self.draw_tags[draw_ix];
+                mark.draw_data += draw_tag.data_size() as usize * 4;
+                if draw_tag == DrawTag::BEGIN_CLIP || draw_tag == DrawTag::END_CLIP {
+                    mark.n_clips += 1;
+                }
+                mark.n_paths += 1;
+                draw_ix += 1;
+            }
+            mark.draw_tags = ix;
+            mark.patches = self
+                .patches
+                .iter()
+                .take_while(|patch| match patch {
+                    Patch::Ramp { offset, .. } | Patch::Image { offset, .. } => {
+                        *offset < mark.draw_data
+                    }
+                    Patch::Brush { draw_tag_ix, .. } | Patch::Custom { draw_tag_ix, .. } => {
+                        *draw_tag_ix < ix
+                    }
+                })
+                .count();
+            marks.push(mark);
+        }
+        marks
+    }
+}
+
+/// Returns a clean replacement for a transform that exceeds the limits.
+fn sanitize_transform(transform: &Transform, limits: &SanitizeLimits) -> Option<Transform> {
+    let finite = transform
+        .matrix
+        .iter()
+        .chain(&transform.translation)
+        .all(|v| v.is_finite());
+    if !finite {
+        return Some(Transform {
+            matrix: [0.0; 4],
+            translation: [0.0; 2],
+        });
+    }
+    let (max_scale, max_coordinate) = (limits.max_scale, limits.max_coordinate);
+    let clean = Transform {
+        matrix: transform.matrix.map(|v| v.clamp(-max_scale, max_scale)),
+        translation: transform
+            .translation
+            .map(|v| v.clamp(-max_coordinate, max_coordinate)),
+    };
+    (clean != *transform).then_some(clean)
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::kurbo::Rect;
+    use peniko::{BlendMode, Color, Compose, Mix};
+
+    use super::SanitizeLimits;
+    use crate::encoding::{
+        DrawBeginClip, DrawColor, DrawTag, Encoding, EncodingHeader, IncompatibleEncoding,
+    };
+
+    /// Returns an encoding with a rectangle nested in `depth` layers.
+    fn nested(depth: usize) -> Encoding {
+        let mut encoding = Encoding::new();
+        encoding.reset(false);
+        let rect = Rect::new(0.0, 0.0, 10.0, 10.0);
+        for _ in 0..depth {
+            encoding.encode_shape(&rect, true);
+            encoding.encode_begin_clip(BlendMode::new(Mix::Normal, Compose::SrcOver), 1.0);
+        }
+        encoding.encode_shape(&rect, true);
+        encoding.encode_color(DrawColor::new(Color::rgb8(255, 0, 0)));
+        for _ in 0..depth {
+            encoding.encode_end_clip();
+        }
+        encoding
+    }
+
+    #[test]
+    fn limits_layer_depth() {
+        let mut encoding = nested(4);
+        assert!(encoding.is_consistent());
+        let limits = SanitizeLimits {
+            max_layer_depth: 2,
+            ..Default::default()
+        };
+        let report = encoding.sanitize(&limits);
+        assert_eq!(report.removed_layers, 1);
+        assert!(!report.malformed);
+        assert!(encoding.is_consistent());
+        assert_eq!(encoding.n_clips, 4);
+    }
+
+    #[test]
+    fn empties_truncated_path_data() {
+        let mut encoding = nested(3);
+        let len = encoding.path_data.len();
+        encoding.path_data.truncate(len - 4);
+        let report = encoding.sanitize(&SanitizeLimits::default());
+        assert!(report.malformed);
+        assert!(encoding.draw_tags.is_empty());
+        assert!(encoding.is_consistent());
+    }
+
+    #[test]
+    fn empties_inconsistent_counts() {
+        let corruptions: [fn(&mut Encoding); 3] = [
+            |e| e.n_paths += 5,
+            |e| e.n_path_segments = 0,
+            |e| e.n_clips = u32::MAX,
+        ];
+        for corrupt in corruptions {
+            let mut encoding = nested(80);
+            corrupt(&mut encoding);
+            let report = encoding.sanitize(&SanitizeLimits::default());
+            assert!(report.malformed);
+            assert!(encoding.draw_tags.is_empty());
+        }
+    }
+
+    #[test]
+    fn fills_with_nan_linewidths() {
+        let mut encoding = nested(0);
+        encoding.encode_linewidth(f32::NAN);
+        encoding.encode_shape(&Rect::new(0.0, 0.0, 10.0, 10.0), true);
+        encoding.encode_color(DrawColor::new(Color::rgb8(0, 255, 0)));
+        let report = encoding.sanitize(&SanitizeLimits::default());
+        assert_eq!(report.stroke_widths, 1);
+        assert_eq!(encoding.linewidths, [-1.0, -1.0]);
+    }
+
+    #[test]
+    fn empties_out_of_range_indices() {
+        let corruptions: [fn(&mut Encoding); 3] = [
+            |e| {
+                e.encode_shape(&Rect::new(0.0, 0.0, 10.0, 10.0), true);
+                e.draw_tags.push(DrawTag::MESH_GRADIENT);
+                e.draw_data.extend_from_slice(&0u32.to_le_bytes());
+            },
+            |e| {
+                e.encode_shape(&Rect::new(0.0, 0.0, 10.0, 10.0), true);
+                e.encode_begin_clip(BlendMode::default(), 1.0);
+                let offset = e.draw_data.len() - DrawTag::BEGIN_CLIP.data_size() as usize * 4;
+                let blend = DrawBeginClip::COLOR_MATRIX | (3 << DrawBeginClip::COLOR_MATRIX_SHIFT);
+                e.draw_data[offset..offset + 4].copy_from_slice(&blend.to_le_bytes());
+                e.encode_end_clip();
+            },
+            |e| e.scissors.push((0, 7)),
+        ];
+        for corrupt in corruptions {
+            let mut encoding = nested(1);
+            corrupt(&mut encoding);
+            let report = encoding.sanitize(&SanitizeLimits::default());
+            assert!(report.malformed);
+            assert!(encoding.draw_tags.is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_inconsistent_bytes() {
+        let encoding = nested(2);
+        let bytes = encoding.to_bytes().unwrap();
+        assert!(Encoding::from_bytes(&bytes).is_ok());
+        // The counts of paths, segments and clips follow the header.
+        let counts = std::mem::size_of::<EncodingHeader>();
+        let mut corrupt = bytes.clone();
+        corrupt[counts..counts + 4].copy_from_slice(&1000u32.to_ne_bytes());
+        assert_eq!(
+            Encoding::from_bytes(&corrupt).err(),
+            Some(IncompatibleEncoding::Malformed)
+        );
+        assert!(Encoding::from_bytes(&bytes[..bytes.len() - 3]).is_err());
+    }
+}
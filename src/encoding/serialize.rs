@@ -0,0 +1,385 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Serialization of self-contained encodings.
+
+use std::io::Read;
+
+use bytemuck::Pod;
+use peniko::{Color, ColorStop};
+
+use super::encoding::NO_SCISSOR;
+use super::resource::{Patch, ResourceCache};
+use super::{DrawBeginClip, DrawTag, Encoding, EncodingHeader, IncompatibleEncoding};
+
+impl Encoding {
+    /// Serializes the encoding, prefixed by an [`EncodingHeader`].
+    ///
+    /// The output is a pure function of the encoded streams. Returns `None`
+    /// if the encoding references images, brushes by handle or custom
+    /// resources, which cannot be serialized.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        if let Some(expanded) = self.expand_instances() {
+            return expanded.to_bytes();
+        }
+        let mut ramps = vec![];
+        for patch in &self.patches {
+            match patch {
+                Patch::Ramp { offset, stops } => {
+                    ramps.extend([*offset as u32, stops.start as u32, stops.end as u32])
+                }
+                _ => return None,
+            }
+        }
+        let mut out = vec![];
+        out.extend_from_slice(bytemuck::bytes_of(&EncodingHeader::for_encoding(self)));
+        out.extend_from_slice(bytemuck::bytes_of(&[
+            self.n_paths,
+            self.n_path_segments,
+            self.n_clips,
+        ]));
+        write_slice(&mut out, &self.path_tags);
+        write_slice(&mut out, &self.path_data);
+        write_slice(&mut out, &self.draw_tags);
+        write_slice(&mut out, &self.draw_data);
+        write_slice(&mut out, &ramps);
+        let stops: Vec<[u32; 2]> = self
+            .color_stops
+            .iter()
+            .map(|stop| [stop.offset.to_bits(), color_to_u32(stop.color)])
+            .collect();
+        write_slice(&mut out, &stops);
+        write_slice(&mut out, &self.transforms);
+        write_slice(&mut out, &self.linewidths);
+        write_slice(&mut out, &self.mesh_patches);
+        let palette: Vec<u32> = self.palette.iter().map(|c| color_to_u32(*c)).collect();
+        write_slice(&mut out, &palette);
+        let groups: Vec<[u32; 2]> = self
+            .visibility_groups
+            .iter()
+            .map(|(ix, group)| [*ix, *group])
+            .collect();
+        write_slice(&mut out, &groups);
+        write_slice(&mut out, &self.hidden_groups);
+        // Added in version 0.2.
+        let scissors: Vec<[u32; 2]> = self.scissors.iter().map(|(ix, s)| [*ix, *s]).collect();
+        write_slice(&mut out, &scissors);
+        write_slice(&mut out, &self.scissor_rects);
+        // Added in version 0.7.
+        write_slice(&mut out, &self.color_matrices);
+        // Added in version 0.8.
+        let draw_flags: Vec<[u32; 2]> = self.draw_flags.iter().map(|(ix, f)| [*ix, *f]).collect();
+        write_slice(&mut out, &draw_flags);
+        Some(out)
+    }
+
+    /// Deserializes an encoding produced by [`to_bytes`](Self::to_bytes),
+    /// checking that its header is compatible with this version of the crate.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, IncompatibleEncoding> {
+        let mut reader = Reader(bytes);
+        let header: EncodingHeader = reader.read()?;
+        header.check_compatible()?;
+        let [n_paths, n_path_segments, n_clips]: [u32; 3] = reader.read()?;
+        let mut encoding = Encoding {
+            n_paths,
+            n_path_segments,
+            n_clips,
+            ..Default::default()
+        };
+        encoding.path_tags = reader.read_slice()?;
+        encoding.path_data = reader.read_slice()?;
+        encoding.draw_tags = reader.read_slice()?;
+        encoding.draw_data = reader.read_slice()?;
+        let ramps: Vec<[u32; 3]> = reader.read_slice()?;
+        let stops: Vec<[u32; 2]> = reader.read_slice()?;
+        encoding.transforms = reader.read_slice()?;
+        encoding.linewidths = reader.read_slice()?;
+        encoding.mesh_patches = reader.read_slice()?;
+        let palette: Vec<u32> = reader.read_slice()?;
+        let groups: Vec<[u32; 2]> = reader.read_slice()?;
+        encoding.hidden_groups = reader.read_slice()?;
+        if header.minor >= 2 {
+            let scissors: Vec<[u32; 2]> = reader.read_slice()?;
+            encoding.scissors = scissors.into_iter().map(|[ix, s]| (ix, s)).collect();
+            encoding.scissor_rects = reader.read_slice()?;
+            let n_rects = encoding.scissor_rects.len() as u32;
+            if encoding
+                .scissors
+                .iter()
+                .any(|(_, s)| *s != NO_SCISSOR && *s >= n_rects)
+            {
+                return Err(IncompatibleEncoding::Malformed);
+            }
+        }
+        if header.minor >= 7 {
+            encoding.color_matrices = reader.read_slice()?;
+        }
+        if header.minor >= 8 {
+            let draw_flags: Vec<[u32; 2]> = reader.read_slice()?;
+            encoding.draw_flags = draw_flags.into_iter().map(|[ix, f]| (ix, f)).collect();
+        }
+        // Layers may only reference matrices that are present.
+        let n_matrices = encoding.color_matrices.len() as u32;
+        let mut offset = 0;
+        for tag in &encoding.draw_tags {
+            let size = tag.data_size() as usize * 4;
+            if offset + size > encoding.draw_data.len() {
+                return Err(IncompatibleEncoding::Malformed);
+            }
+            if *tag == DrawTag::BEGIN_CLIP {
+                let clip: DrawBeginClip =
+                    bytemuck::pod_read_unaligned(&encoding.draw_data[offset..offset + 8]);
+                if clip.blend_mode & DrawBeginClip::COLOR_MATRIX != 0
+                    && clip.blend_mode >> DrawBeginClip::COLOR_MATRIX_SHIFT >= n_matrices
+                {
+                    return Err(IncompatibleEncoding::Malformed);
+                }
+            }
+            offset += size;
+        }
+        encoding.color_stops = stops
+            .iter()
+            .map(|[offset, color]| ColorStop {
+                offset: f32::from_bits(*offset),
+                color: color_from_u32(*color),
+            })
+            .collect();
+        let mut last_offset = 0;
+        for [offset, start, end] in ramps {
+            let (offset, start, end) = (offset as usize, start as usize, end as usize);
+            if offset < last_offset
+                || offset + 4 > encoding.draw_data.len()
+                || start >= end
+                || end > encoding.color_stops.len()
+            {
+                return Err(IncompatibleEncoding::Malformed);
+            }
+            last_offset = offset + 4;
+            encoding.patches.push(Patch::Ramp {
+                offset,
+                stops: start..end,
+            });
+        }
+        encoding.palette = palette.into_iter().map(color_from_u32).collect();
+        encoding.visibility_groups = groups.into_iter().map(|[ix, group]| (ix, group)).collect();
+        if !encoding.is_consistent() {
+            return Err(IncompatibleEncoding::Malformed);
+        }
+        Ok(encoding)
+    }
+}
+
+/// Method used to compress a serialized encoding.
+///
+/// Each method other than [`None`](Self::None) is enabled by the cargo
+/// feature of the same name, so matches on this enum need a wildcard arm.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[non_exhaustive]
+pub enum Compression {
+    /// The encoding is stored as produced by [`Encoding::to_bytes`].
+    #[default]
+    None,
+    /// LZ4 frames, which decode quickly.
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Zstandard with the specified level, which produces smaller output.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Magic number at the start of compressed encodings, which is followed
+    /// by a byte identifying the method.
+    const MAGIC: u32 = u32::from_le_bytes(*b"VELZ");
+}
+
+/// Maximum size of a serialized encoding read by [`Encoding::from_reader`],
+/// after decompression.
+pub const MAX_READ_SIZE: u64 = 1 << 30;
+
+impl Encoding {
+    /// Serializes the encoding as with [`to_bytes`](Self::to_bytes) and
+    /// compresses it with the specified method, for storing large scenes on
+    /// disk.
+    pub fn to_compressed_bytes(&self, compression: Compression) -> Option<Vec<u8>> {
+        let bytes = self.to_bytes()?;
+        let out = match compression {
+            Compression::None => bytes,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                use std::io::Write;
+                let mut out = Compression::MAGIC.to_le_bytes().to_vec();
+                out.push(1);
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(out);
+                encoder.write_all(&bytes).ok()?;
+                encoder.finish().ok()?
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => {
+                let mut out = Compression::MAGIC.to_le_bytes().to_vec();
+                out.push(2);
+                zstd::stream::copy_encode(&bytes[..], &mut out, level).ok()?;
+                out
+            }
+        };
+        Some(out)
+    }
+
+    /// Deserializes an encoding produced by [`to_bytes`](Self::to_bytes) or
+    /// [`to_compressed_bytes`](Self::to_compressed_bytes), decompressing it
+    /// while it is read so that the compressed data does not have to be
+    /// loaded first. Fails with [`IncompatibleEncoding::TooLarge`] instead of
+    /// reading [`MAX_READ_SIZE`] bytes of serialized data or more.
+    pub fn from_reader(mut reader: impl Read) -> Result<Self, IncompatibleEncoding> {
+        let mut magic = [0u8; 4];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|_| IncompatibleEncoding::Malformed)?;
+        let mut bytes = vec![];
+        if u32::from_le_bytes(magic) == Compression::MAGIC {
+            let mut id = [0u8];
+            reader
+                .read_exact(&mut id)
+                .map_err(|_| IncompatibleEncoding::Malformed)?;
+            decompress(id[0], reader, &mut bytes)?;
+        } else {
+            bytes.extend_from_slice(&magic);
+            reader
+                .take(MAX_READ_SIZE)
+                .read_to_end(&mut bytes)
+                .map_err(|_| IncompatibleEncoding::Malformed)?;
+        }
+        if bytes.len() as u64 >= MAX_READ_SIZE {
+            return Err(IncompatibleEncoding::TooLarge);
+        }
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// Decompresses the rest of a compressed encoding with the method `id`,
+/// stopping at [`MAX_READ_SIZE`] bytes of output.
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn decompress(id: u8, reader: impl Read, out: &mut Vec<u8>) -> Result<(), IncompatibleEncoding> {
+    match id {
+        #[cfg(feature = "lz4")]
+        1 => lz4_flex::frame::FrameDecoder::new(reader)
+            .take(MAX_READ_SIZE)
+            .read_to_end(out)
+            .map(|_| ())
+            .map_err(|_| IncompatibleEncoding::Malformed),
+        #[cfg(feature = "zstd")]
+        2 => zstd::stream::read::Decoder::new(reader)
+            .and_then(|decoder| decoder.take(MAX_READ_SIZE).read_to_end(out))
+            .map(|_| ())
+            .map_err(|_| IncompatibleEncoding::Malformed),
+        _ => Err(IncompatibleEncoding::UnsupportedCompression(id)),
+    }
+}
+
+impl ResourceCache {
+    /// Serializes the color stops of the cached gradient ramps so that the
+    /// cache can be warmed with [`preload_ramps`](Self::preload_ramps) in a
+    /// later session. The output is ordered by ramp id and so is
+    /// deterministic for a given sequence of renders.
+    pub fn save_ramps(&self) -> Vec<u8> {
+        let mut out = vec![];
+        let header = EncodingHeader::new(Default::default());
+        out.extend_from_slice(bytemuck::bytes_of(&header));
+        let ramps = self.ramp_stops();
+        out.extend_from_slice(bytemuck::bytes_of(&(ramps.len() as u32)));
+        for stops in ramps {
+            let stops: Vec<[u32; 2]> = stops
+                .iter()
+                .map(|stop| [stop.offset.to_bits(), color_to_u32(stop.color)])
+                .collect();
+            write_slice(&mut out, &stops);
+        }
+        out
+    }
+
+    /// Adds the gradient ramps serialized by [`save_ramps`](Self::save_ramps)
+    /// to the cache and returns the number of ramps.
+    pub fn preload_ramps(&mut self, bytes: &[u8]) -> Result<usize, IncompatibleEncoding> {
+        let mut reader = Reader(bytes);
+        let header: EncodingHeader = reader.read()?;
+        header.check_compatible()?;
+        let count = reader.read::<u32>()? as usize;
+        let mut ramps = Vec::new();
+        for _ in 0..count {
+            let stops: Vec<[u32; 2]> = reader.read_slice()?;
+            if stops.is_empty() {
+                return Err(IncompatibleEncoding::Malformed);
+            }
+            let stops: Vec<ColorStop> = stops
+                .iter()
+                .map(|[offset, color]| ColorStop {
+                    offset: f32::from_bits(*offset),
+                    color: color_from_u32(*color),
+                })
+                .collect();
+            ramps.push(stops);
+        }
+        for stops in &ramps {
+            self.add_ramp(stops);
+        }
+        Ok(ramps.len())
+    }
+}
+
+fn write_slice<T: Pod>(out: &mut Vec<u8>, slice: &[T]) {
+    out.extend_from_slice(bytemuck::bytes_of(&(slice.len() as u32)));
+    out.extend_from_slice(bytemuck::cast_slice(slice));
+}
+
+fn color_to_u32(color: Color) -> u32 {
+    u32::from_le_bytes([color.r, color.g, color.b, color.a])
+}
+
+fn color_from_u32(color: u32) -> Color {
+    let [r, g, b, a] = color.to_le_bytes();
+    Color::rgba8(r, g, b, a)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], IncompatibleEncoding> {
+        if len > self.0.len() {
+            return Err(IncompatibleEncoding::Malformed);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn read<T: Pod>(&mut self) -> Result<T, IncompatibleEncoding> {
+        let bytes = self.bytes(std::mem::size_of::<T>())?;
+        Ok(bytemuck::pod_read_unaligned(bytes))
+    }
+
+    fn read_slice<T: Pod>(&mut self) -> Result<Vec<T>, IncompatibleEncoding> {
+        let len = self.read::<u32>()? as usize;
+        let size = std::mem::size_of::<T>();
+        let bytes = self.bytes(
+            len.checked_mul(size)
+                .ok_or(IncompatibleEncoding::Malformed)?,
+        )?;
+        Ok(bytes
+            .chunks_exact(size)
+            .map(bytemuck::pod_read_unaligned)
+            .collect())
+    }
+}
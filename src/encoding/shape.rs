@@ -0,0 +1,93 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Fast paths for encoding common shapes.
+
+use std::collections::HashMap;
+
+use peniko::kurbo::{Affine, PathEl, Shape};
+
+use super::PathEncoder;
+
+/// Maximum number of shapes retained by the cache before it is cleared.
+const MAX_CACHED_SHAPES: usize = 256;
+
+/// Cache of the path elements of rounded rectangles and circles.
+///
+/// Computing the arcs of these shapes dominates the cost of encoding them,
+/// and user interfaces tend to draw many shapes of the same size at
+/// different positions. The elements are stored relative to the origin of
+/// the shape and translated when they are encoded.
+#[derive(Clone, Default)]
+pub(crate) struct ShapeCache {
+    entries: HashMap<ShapeKey, Vec<PathEl>>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum ShapeKey {
+    /// Width, height and the four corner radii.
+    RoundedRect([u64; 6]),
+    /// Radius.
+    Circle(u64),
+}
+
+impl ShapeCache {
+    /// Encodes a shape, using a fast path for rectangles and the cache for
+    /// rounded rectangles and circles.
+    pub fn encode(&mut self, encoder: &mut PathEncoder, shape: &impl Shape) {
+        if let Some(rect) = shape.as_rect() {
+            let [x0, y0, x1, y1] = [rect.x0, rect.y0, rect.x1, rect.y1].map(|v| v as f32);
+            encoder.move_to(x0, y0);
+            encoder.line_to(x1, y0);
+            encoder.line_to(x1, y1);
+            encoder.line_to(x0, y1);
+            encoder.close();
+            return;
+        }
+        let (key, origin) = if let Some(rect) = shape.as_rounded_rect() {
+            let radii = rect.radii();
+            let key = ShapeKey::RoundedRect(
+                [
+                    rect.width(),
+                    rect.height(),
+                    radii.top_left,
+                    radii.top_right,
+                    radii.bottom_right,
+                    radii.bottom_left,
+                ]
+                .map(f64::to_bits),
+            );
+            (key, rect.origin().to_vec2())
+        } else if let Some(circle) = shape.as_circle() {
+            (
+                ShapeKey::Circle(circle.radius.to_bits()),
+                circle.center.to_vec2(),
+            )
+        } else {
+            encoder.shape(shape);
+            return;
+        };
+        if !self.entries.contains_key(&key) && self.entries.len() >= MAX_CACHED_SHAPES {
+            self.entries.clear();
+        }
+        let elements = self.entries.entry(key).or_insert_with(|| {
+            let to_local = Affine::translate(-origin);
+            shape.path_elements(0.1).map(|el| to_local * el).collect()
+        });
+        let to_shape = Affine::translate(origin);
+        encoder.path_elements(elements.iter().map(|el| to_shape * *el));
+    }
+}
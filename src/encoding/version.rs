@@ -0,0 +1,277 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Versioning of the encoded scene format.
+//!
+//! The compatibility policy is as follows:
+//!
+//! * The major version changes whenever the layout of an existing stream or
+//!   the meaning of an existing tag changes. Encodings with a different major
+//!   version are rejected.
+//! * The minor version changes when new tags or streams are added. A
+//!   consumer accepts encodings with an older or equal minor version, and
+//!   encodings with a newer minor version as long as they only use features
+//!   that the consumer supports.
+//! * Each optional capability of the format is identified by a bit in
+//!   [`EncodingFeatures`]. Bits are never reused.
+
+use bytemuck::{Pod, Zeroable};
+
+use super::resource::Patch;
+use super::{DrawBeginClip, DrawImage, DrawTag, Encoding, DRAW_FLAG_ADDITIVE};
+
+/// Major version of the encoded scene format.
+pub const ENCODING_MAJOR_VERSION: u16 = 0;
+
+/// Minor version of the encoded scene format.
+pub const ENCODING_MINOR_VERSION: u16 = 11;
+
+/// Set of optional capabilities used by an encoding.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default, Zeroable, Pod)]
+#[repr(transparent)]
+pub struct EncodingFeatures(pub u32);
+
+impl EncodingFeatures {
+    /// No optional capabilities.
+    pub const NONE: Self = Self(0);
+
+    /// Image brushes.
+    pub const IMAGES: Self = Self(1 << 0);
+
+    /// Mesh gradient brushes.
+    pub const MESH_GRADIENTS: Self = Self(1 << 1);
+
+    /// Procedural brushes.
+    pub const PROCEDURAL: Self = Self(1 << 2);
+
+    /// Erasers.
+    pub const ERASE: Self = Self(1 << 3);
+
+    /// Palette colors.
+    pub const PALETTE: Self = Self(1 << 4);
+
+    /// Visibility groups.
+    pub const VISIBILITY_GROUPS: Self = Self(1 << 5);
+
+    /// Brushes and images resolved when the encoding is packed.
+    pub const LATE_BOUND_BRUSHES: Self = Self(1 << 6);
+
+    /// Per draw scissor rectangles.
+    pub const SCISSORS: Self = Self(1 << 7);
+
+    /// Sweep gradient brushes.
+    pub const SWEEP_GRADIENTS: Self = Self(1 << 8);
+
+    /// Clip layers with the even-odd fill rule.
+    pub const CLIP_FILL_RULES: Self = Self(1 << 9);
+
+    /// Layers that are replaced by their luminance, used for luminance
+    /// masks.
+    pub const LUMINANCE_MASKS: Self = Self(1 << 10);
+
+    /// Blurred shadows.
+    pub const SHADOWS: Self = Self(1 << 11);
+
+    /// Layers whose colors are transformed by a color matrix.
+    pub const COLOR_MATRICES: Self = Self(1 << 12);
+
+    /// Draw objects that are composited additively.
+    pub const ADDITIVE: Self = Self(1 << 13);
+
+    /// Analytically antialiased rounded rectangles.
+    pub const ROUNDED_RECTS: Self = Self(1 << 14);
+
+    /// Analytically antialiased circles and ellipses.
+    pub const ELLIPSES: Self = Self(1 << 15);
+
+    /// Image brushes that sample a region of their image.
+    pub const IMAGE_REGIONS: Self = Self(1 << 16);
+
+    /// All capabilities supported by this version of the crate.
+    pub const SUPPORTED: Self = Self((1 << 17) - 1);
+
+    /// Returns true if all capabilities in `other` are also in `self`.
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the capabilities in `self` that are not in `other`.
+    pub fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Returns the union of the two sets.
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Header that identifies the version and capabilities of an encoding.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Zeroable, Pod)]
+#[repr(C)]
+pub struct EncodingHeader {
+    /// Magic number identifying an encoded scene.
+    pub magic: u32,
+    /// Major version of the format.
+    pub major: u16,
+    /// Minor version of the format.
+    pub minor: u16,
+    /// Capabilities used by the encoding.
+    pub features: EncodingFeatures,
+}
+
+impl EncodingHeader {
+    /// Magic number at the start of every header.
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"VELO");
+
+    /// Creates a header for the current version with the given features.
+    pub fn new(features: EncodingFeatures) -> Self {
+        Self {
+            magic: Self::MAGIC,
+            major: ENCODING_MAJOR_VERSION,
+            minor: ENCODING_MINOR_VERSION,
+            features,
+        }
+    }
+
+    /// Creates a header for the encoding.
+    pub fn for_encoding(encoding: &Encoding) -> Self {
+        Self::new(encoding.features())
+    }
+
+    /// Checks whether an encoding with this header can be consumed by this
+    /// version of the crate according to the compatibility policy.
+    pub fn check_compatible(&self) -> Result<(), IncompatibleEncoding> {
+        if self.magic != Self::MAGIC {
+            return Err(IncompatibleEncoding::BadMagic);
+        }
+        if self.major != ENCODING_MAJOR_VERSION {
+            return Err(IncompatibleEncoding::Version {
+                major: self.major,
+                minor: self.minor,
+            });
+        }
+        let unsupported = self.features.difference(EncodingFeatures::SUPPORTED);
+        if unsupported != EncodingFeatures::NONE {
+            return Err(IncompatibleEncoding::Features(unsupported));
+        }
+        Ok(())
+    }
+}
+
+/// Reason an encoding cannot be consumed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum IncompatibleEncoding {
+    /// The data does not start with an encoding header.
+    BadMagic,
+    /// The major version of the encoding is not supported.
+    Version { major: u16, minor: u16 },
+    /// The encoding uses capabilities that are not supported.
+    Features(EncodingFeatures),
+    /// The data is truncated or otherwise malformed.
+    Malformed,
+    /// The data is compressed with a method that is not enabled.
+    UnsupportedCompression(u8),
+    /// The serialized encoding is larger than the limit of the reader.
+    TooLarge,
+}
+
+impl std::fmt::Display for IncompatibleEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not an encoded scene"),
+            Self::Version { major, minor } => write!(
+                f,
+                "encoding version {major}.{minor} is not compatible with {ENCODING_MAJOR_VERSION}.{ENCODING_MINOR_VERSION}"
+            ),
+            Self::Features(features) => {
+                write!(f, "encoding uses unsupported features {:#x}", features.0)
+            }
+            Self::Malformed => write!(f, "encoded scene is malformed"),
+            Self::UnsupportedCompression(method) => {
+                write!(f, "encoded scene uses unsupported compression method {method}")
+            }
+            Self::TooLarge => write!(f, "encoded scene is too large"),
+        }
+    }
+}
+
+impl std::error::Error for IncompatibleEncoding {}
+
+impl Encoding {
+    /// Returns the optional capabilities used by the encoding.
+    pub fn features(&self) -> EncodingFeatures {
+        let mut features = EncodingFeatures::NONE;
+        let mut offset = 0;
+        for tag in &self.draw_tags {
+            let data = &self.draw_data[offset..];
+            offset += tag.data_size() as usize * 4;
+            let feature = match *tag {
+                DrawTag::BEGIN_CLIP => {
+                    let clip: DrawBeginClip = bytemuck::pod_read_unaligned(&data[..8]);
+                    if clip.blend_mode & DrawBeginClip::LUMINANCE != 0 {
+                        features = features.union(EncodingFeatures::LUMINANCE_MASKS);
+                    }
+                    if clip.blend_mode & DrawBeginClip::COLOR_MATRIX != 0 {
+                        features = features.union(EncodingFeatures::COLOR_MATRICES);
+                    }
+                    if clip.blend_mode & DrawBeginClip::EVEN_ODD == 0 {
+                        continue;
+                    }
+                    EncodingFeatures::CLIP_FILL_RULES
+                }
+                DrawTag::IMAGE => {
+                    let image: DrawImage = bytemuck::pod_read_unaligned(&data[..16]);
+                    if image.xy != 0 {
+                        features = features.union(EncodingFeatures::IMAGE_REGIONS);
+                    }
+                    EncodingFeatures::IMAGES
+                }
+                DrawTag::MESH_GRADIENT => EncodingFeatures::MESH_GRADIENTS,
+                DrawTag::PROCEDURAL => EncodingFeatures::PROCEDURAL,
+                DrawTag::ERASE => EncodingFeatures::ERASE,
+                DrawTag::PALETTE_COLOR => EncodingFeatures::PALETTE,
+                DrawTag::SWEEP_GRADIENT => EncodingFeatures::SWEEP_GRADIENTS,
+                DrawTag::SHADOW => EncodingFeatures::SHADOWS,
+                DrawTag::ROUNDED_RECT => EncodingFeatures::ROUNDED_RECTS,
+                DrawTag::ELLIPSE => EncodingFeatures::ELLIPSES,
+                _ => continue,
+            };
+            features = features.union(feature);
+        }
+        if !self.visibility_groups.is_empty() {
+            features = features.union(EncodingFeatures::VISIBILITY_GROUPS);
+        }
+        if !self.scissors.is_empty() {
+            features = features.union(EncodingFeatures::SCISSORS);
+        }
+        if self
+            .draw_flags
+            .iter()
+            .any(|(_, flags)| flags & DRAW_FLAG_ADDITIVE != 0)
+        {
+            features = features.union(EncodingFeatures::ADDITIVE);
+        }
+        let late_bound = self
+            .patches
+            .iter()
+            .any(|patch| matches!(patch, Patch::Brush { .. } | Patch::Custom { .. }));
+        if late_bound {
+            features = features.union(EncodingFeatures::LATE_BOUND_BRUSHES);
+        }
+        features
+    }
+}
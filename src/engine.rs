@@ -18,14 +18,24 @@ use std::{
     borrow::Cow,
     collections::{hash_map::Entry, HashMap, HashSet},
     num::{NonZeroU32, NonZeroU64},
-    sync::atomic::{AtomicU64, Ordering},
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    sync::Arc,
+    time::Duration,
 };
 
 use wgpu::{
     util::DeviceExt, BindGroup, BindGroupLayout, Buffer, BufferUsages, ComputePipeline, Device,
-    Queue, Texture, TextureAspect, TextureFormat, TextureUsages, TextureView, TextureViewDimension,
+    Queue, SubmissionIndex, Texture, TextureAspect, TextureFormat, TextureUsages, TextureView,
+    TextureViewDimension,
 };
 
+use crate::profiling::{profile_scope, GpuTimings, StageTiming};
+use crate::util::block_on_wgpu;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::util::block_on_wgpu_timeout;
+use crate::validation::{ValidationCapture, ValidationError};
+use crate::watchdog::GpuHang;
+
 pub type Error = Box<dyn std::error::Error>;
 
 #[derive(Clone, Copy)]
@@ -41,13 +51,169 @@ pub struct Engine {
     pool: ResourcePool,
     bind_map: BindMap,
     downloads: HashMap<Id, Buffer>,
+    /// Whether dispatches are timed with timestamp queries.
+    timestamps: bool,
+    pending_timings: Vec<PendingTimings>,
+    validation: ValidationCapture,
+    /// Timeout for resolving error scopes.
+    watchdog: Option<Duration>,
+}
+
+/// Validation error scope that is popped when dropped, so that the scopes of
+/// the device stay balanced when a recording fails.
+struct ErrorScope<'a> {
+    device: &'a Device,
+    open: bool,
+}
+
+impl<'a> ErrorScope<'a> {
+    fn push(device: &'a Device) -> Self {
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        Self { device, open: true }
+    }
+
+    /// Pops the scope. Resolving it waits for the submitted work, which
+    /// gives up after the timeout if there is one.
+    fn pop(mut self, timeout: Option<Duration>) -> Result<Option<wgpu::Error>, GpuHang> {
+        self.open = false;
+        let future = self.device.pop_error_scope();
+        match timeout {
+            // The watchdog is never enabled on the web.
+            #[cfg(not(target_arch = "wasm32"))]
+            Some(timeout) => block_on_wgpu_timeout(self.device, future, timeout).ok_or(GpuHang {
+                operation: "validation errors",
+                timeout,
+            }),
+            _ => Ok(block_on_wgpu(self.device, future)),
+        }
+    }
+}
+
+impl Drop for ErrorScope<'_> {
+    fn drop(&mut self) {
+        if self.open {
+            // The scope is popped when the future is created.
+            drop(self.device.pop_error_scope());
+        }
+    }
+}
+
+/// Timestamps of a submission that are being read back.
+struct PendingTimings {
+    labels: Vec<&'static str>,
+    buffer: Buffer,
+    /// One of the `MAP_` states of the readback buffer.
+    map_state: Arc<AtomicU8>,
+    period: f32,
+    #[cfg(feature = "puffin")]
+    submitted_ns: puffin::NanoSecond,
+}
+
+/// Maximum number of submissions whose timestamps are read back at the same
+/// time. Older timings are dropped if they are not collected.
+const MAX_PENDING_TIMINGS: usize = 8;
+
+/// States of the buffer that timestamps are read back into.
+const MAP_PENDING: u8 = 0;
+const MAP_DONE: u8 = 1;
+const MAP_FAILED: u8 = 2;
+
+/// Timestamp queries written around the dispatches of a recording.
+struct TimestampQueries {
+    query_set: wgpu::QuerySet,
+    labels: Vec<&'static str>,
+}
+
+impl TimestampQueries {
+    fn new(device: &Device, recording: &Recording) -> Option<Self> {
+        let count = recording
+            .commands
+            .iter()
+            .filter(|command| matches!(command, Command::Dispatch(..)))
+            .count() as u32
+            * 2;
+        if count == 0 || count > wgpu::QUERY_SET_MAX_QUERIES {
+            return None;
+        }
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("timestamps"),
+            ty: wgpu::QueryType::Timestamp,
+            count,
+        });
+        Some(Self {
+            query_set,
+            labels: vec![],
+        })
+    }
+
+    fn begin(&mut self, encoder: &mut wgpu::CommandEncoder, label: &'static str) {
+        encoder.write_timestamp(&self.query_set, self.labels.len() as u32 * 2);
+        self.labels.push(label);
+    }
+
+    fn end(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.write_timestamp(&self.query_set, self.labels.len() as u32 * 2 - 1);
+    }
+
+    /// Resolves the queries into a buffer that can be mapped once the
+    /// submission has completed.
+    fn resolve(&self, device: &Device, encoder: &mut wgpu::CommandEncoder) -> Buffer {
+        let count = self.labels.len() as u32 * 2;
+        let size = count as u64 * 8;
+        let resolve = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("timestamp resolve"),
+            size,
+            usage: BufferUsages::QUERY_RESOLVE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("timestamp readback"),
+            size,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        encoder.resolve_query_set(&self.query_set, 0..count, &resolve, 0);
+        encoder.copy_buffer_to_buffer(&resolve, 0, &readback, 0, size);
+        readback
+    }
 }
 
 struct Shader {
-    pipeline: ComputePipeline,
+    label: &'static str,
+    /// Source of the shader until the pipeline has been created.
+    wgsl: Option<Cow<'static, str>>,
+    pipeline: Option<ComputePipeline>,
     bind_group_layout: BindGroupLayout,
 }
 
+impl Shader {
+    /// Creates the pipeline if it has not been created yet.
+    fn pipeline(&mut self, device: &Device) -> &ComputePipeline {
+        if self.pipeline.is_none() {
+            let wgsl = self.wgsl.take().unwrap_or_default();
+            let shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(self.label),
+                source: wgpu::ShaderSource::Wgsl(wgsl),
+            });
+            let compute_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: None,
+                    bind_group_layouts: &[&self.bind_group_layout],
+                    push_constant_ranges: &[],
+                });
+            self.pipeline = Some(device.create_compute_pipeline(
+                &wgpu::ComputePipelineDescriptor {
+                    label: Some(self.label),
+                    layout: Some(&compute_pipeline_layout),
+                    module: &shader_module,
+                    entry_point: "main",
+                },
+            ));
+        }
+        self.pipeline.as_ref().unwrap()
+    }
+}
+
 #[derive(Default)]
 pub struct Recording {
     commands: Vec<Command>,
@@ -89,6 +255,7 @@ pub enum Command {
     Upload(BufProxy, Vec<u8>),
     UploadUniform(BufProxy, Vec<u8>),
     UploadImage(ImageProxy, Vec<u8>),
+    WriteImage(ImageProxy, [u32; 4], Vec<u8>),
     // Discussion question: third argument is vec of resources?
     // Maybe use tricks to make more ergonomic?
     // Alternative: provide bufs & images as separate sequences
@@ -115,6 +282,19 @@ pub enum BindType {
     // TODO: Uniform, Sampler, maybe others
 }
 
+/// Returns the number of storage buffers in a shader layout.
+pub const fn storage_buffers(layout: &[BindType]) -> u32 {
+    let mut count = 0;
+    let mut i = 0;
+    while i < layout.len() {
+        if matches!(layout[i], BindType::Buffer | BindType::BufReadOnly) {
+            count += 1;
+        }
+        i += 1;
+    }
+    count
+}
+
 struct BindMapBuffer {
     buffer: Buffer,
     #[cfg_attr(not(feature = "buffer_labels"), allow(unused))]
@@ -147,9 +327,76 @@ impl Engine {
             pool: Default::default(),
             bind_map: Default::default(),
             downloads: Default::default(),
+            timestamps: false,
+            pending_timings: vec![],
+            validation: ValidationCapture::Off,
+            watchdog: None,
         }
     }
 
+    /// Sets the granularity at which validation errors of subsequent
+    /// recordings are captured. Capture is not supported on wasm, where
+    /// error scopes can only be resolved asynchronously.
+    pub fn set_validation_capture(&mut self, capture: ValidationCapture) {
+        self.validation = capture;
+    }
+
+    /// Sets the timeout for resolving the error scopes of captured
+    /// validation errors, after which recordings fail with a [`GpuHang`].
+    pub fn set_watchdog(&mut self, timeout: Option<Duration>) {
+        self.watchdog = timeout;
+    }
+
+    /// Sets whether the dispatches of subsequent recordings are timed. This
+    /// has no effect on devices without
+    /// [`TIMESTAMP_QUERY`](wgpu::Features::TIMESTAMP_QUERY).
+    pub fn set_timestamps(&mut self, enabled: bool) {
+        self.timestamps = enabled;
+        if !enabled {
+            self.pending_timings.clear();
+        }
+    }
+
+    /// Returns the timings of the submissions whose timestamps have been
+    /// read back, oldest first.
+    ///
+    /// Readback completes while the device is polled, so this does not
+    /// block. Timings whose readback failed are dropped.
+    pub fn take_timings(&mut self) -> Vec<GpuTimings> {
+        let n_ready = self
+            .pending_timings
+            .iter()
+            .take_while(|pending| pending.map_state.load(Ordering::Acquire) != MAP_PENDING)
+            .count();
+        self.pending_timings
+            .drain(..n_ready)
+            .filter(|pending| pending.map_state.load(Ordering::Acquire) == MAP_DONE)
+            .map(|pending| {
+                let slice = pending.buffer.slice(..);
+                let timestamps: Vec<u64> =
+                    bytemuck::cast_slice(&slice.get_mapped_range()[..]).to_vec();
+                pending.buffer.unmap();
+                let base = timestamps.first().copied().unwrap_or_default();
+                let to_ms = |ts: u64| ts.saturating_sub(base) as f64 * pending.period as f64 * 1e-6;
+                let stages = pending
+                    .labels
+                    .iter()
+                    .zip(timestamps.chunks_exact(2))
+                    .map(|(label, ts)| StageTiming {
+                        label: *label,
+                        start: to_ms(ts[0]),
+                        end: to_ms(ts[1]),
+                    })
+                    .collect();
+                GpuTimings {
+                    stages,
+                    #[cfg(feature = "puffin")]
+                    submitted_ns: pending.submitted_ns,
+                }
+            })
+            .collect()
+    }
+
     /// Add a shader.
     ///
     /// This function is somewhat limited, it doesn't apply a label, only allows one bind group,
@@ -157,6 +404,10 @@ impl Engine {
     ///
     /// Maybe should do template instantiation here? But shader compilation pipeline feels maybe
     /// a bit separate.
+    ///
+    /// The shader module and pipeline are created when the shader is first dispatched, so that
+    /// shaders that are never used, such as the variants for very large scenes, cost nothing.
+    /// Use [`create_pipelines`](Self::create_pipelines) to create them ahead of time.
     pub fn add_shader(
         &mut self,
         device: &Device,
@@ -164,10 +415,14 @@ impl Engine {
         wgsl: Cow<'static, str>,
         layout: &[BindType],
     ) -> Result<ShaderId, Error> {
-        let shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some(label),
-            source: wgpu::ShaderSource::Wgsl(wgsl),
-        });
+        let max_buffers = device.limits().max_storage_buffers_per_shader_stage;
+        if storage_buffers(layout) > max_buffers {
+            return Err(format!(
+                "shader {label} binds {} storage buffers but the device supports {max_buffers}",
+                storage_buffers(layout)
+            )
+            .into());
+        }
         let entries = layout
             .iter()
             .enumerate()
@@ -220,20 +475,10 @@ impl Engine {
             label: None,
             entries: &entries,
         });
-        let compute_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: None,
-                bind_group_layouts: &[&bind_group_layout],
-                push_constant_ranges: &[],
-            });
-        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some(label),
-            layout: Some(&compute_pipeline_layout),
-            module: &shader_module,
-            entry_point: "main",
-        });
         let shader = Shader {
-            pipeline,
+            label,
+            wgsl: Some(wgsl),
+            pipeline: None,
             bind_group_layout,
         };
         let id = self.shaders.len();
@@ -241,15 +486,68 @@ impl Engine {
         Ok(ShaderId(id))
     }
 
+    /// Creates the pipelines of all shaders that have not been dispatched yet,
+    /// compiling up to `parallelism` of them concurrently.
+    pub fn create_pipelines(&mut self, device: &Device, parallelism: usize) {
+        #[cfg_attr(target_arch = "wasm32", allow(unused_mut))]
+        let mut pending: Vec<&mut Shader> = self
+            .shaders
+            .iter_mut()
+            .filter(|shader| shader.pipeline.is_none())
+            .collect();
+        #[cfg(not(target_arch = "wasm32"))]
+        if parallelism > 1 && pending.len() > 1 {
+            let chunk_size = (pending.len() + parallelism - 1) / parallelism;
+            std::thread::scope(|scope| {
+                for chunk in pending.chunks_mut(chunk_size) {
+                    scope.spawn(move || {
+                        for shader in chunk {
+                            shader.pipeline(device);
+                        }
+                    });
+                }
+            });
+            return;
+        }
+        #[cfg(target_arch = "wasm32")]
+        let _ = parallelism;
+        for shader in pending {
+            shader.pipeline(device);
+        }
+    }
+
+    /// Returns the number of shaders whose pipelines have been created.
+    pub fn n_pipelines(&self) -> usize {
+        self.shaders
+            .iter()
+            .filter(|shader| shader.pipeline.is_some())
+            .count()
+    }
+
     pub fn run_recording(
         &mut self,
         device: &Device,
         queue: &Queue,
         recording: &Recording,
         external_resources: &[ExternalResource],
-    ) -> Result<(), Error> {
+    ) -> Result<SubmissionIndex, Error> {
+        profile_scope!("run_recording");
         let mut free_bufs: HashSet<Id> = Default::default();
         let mut free_images: HashSet<Id> = Default::default();
+        let mut queries =
+            if self.timestamps && device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+                TimestampQueries::new(device, recording)
+            } else {
+                None
+            };
+        let capture = if cfg!(target_arch = "wasm32") {
+            ValidationCapture::Off
+        } else {
+            self.validation
+        };
+        let submission_scope =
+            (capture != ValidationCapture::Off).then(|| ErrorScope::push(device));
+        let mut stage_error = None;
 
         let mut encoder = device.create_command_encoder(&Default::default());
         for command in &recording.commands {
@@ -329,8 +627,32 @@ impl Engine {
                     self.bind_map
                         .insert_image(image_proxy.id, texture, texture_view)
                 }
+                Command::WriteImage(proxy, [x, y, width, height], data) => {
+                    let (texture, _) = self.bind_map.get_or_create_image(*proxy, device);
+                    queue.write_texture(
+                        wgpu::ImageCopyTexture {
+                            texture,
+                            mip_level: 0,
+                            origin: wgpu::Origin3d { x: *x, y: *y, z: 0 },
+                            aspect: TextureAspect::All,
+                        },
+                        &data[..],
+                        wgpu::ImageDataLayout {
+                            offset: 0,
+                            bytes_per_row: NonZeroU32::new(*width * 4),
+                            rows_per_image: None,
+                        },
+                        wgpu::Extent3d {
+                            width: *width,
+                            height: *height,
+                            depth_or_array_layers: 1,
+                        },
+                    );
+                }
                 Command::Dispatch(shader_id, wg_size, bindings) => {
                     // println!("dispatching {:?} with {} bindings", wg_size, bindings.len());
+                    let shader = &mut self.shaders[shader_id.0];
+                    shader.pipeline(device);
                     let shader = &self.shaders[shader_id.0];
                     let bind_group = self.bind_map.create_bind_group(
                         device,
@@ -339,10 +661,27 @@ impl Engine {
                         external_resources,
                         &mut self.pool,
                     )?;
-                    let mut cpass = encoder.begin_compute_pass(&Default::default());
-                    cpass.set_pipeline(&shader.pipeline);
-                    cpass.set_bind_group(0, &bind_group, &[]);
-                    cpass.dispatch_workgroups(wg_size.0, wg_size.1, wg_size.2);
+                    let dispatch_scope =
+                        (capture == ValidationCapture::Dispatch).then(|| ErrorScope::push(device));
+                    if let Some(queries) = &mut queries {
+                        queries.begin(&mut encoder, shader.label);
+                    }
+                    {
+                        let mut cpass = encoder.begin_compute_pass(&Default::default());
+                        cpass.set_pipeline(shader.pipeline.as_ref().unwrap());
+                        cpass.set_bind_group(0, &bind_group, &[]);
+                        cpass.dispatch_workgroups(wg_size.0, wg_size.1, wg_size.2);
+                    }
+                    if let Some(queries) = &queries {
+                        queries.end(&mut encoder);
+                    }
+                    let dispatch_error = dispatch_scope
+                        .map(|scope| scope.pop(self.watchdog))
+                        .transpose()?
+                        .flatten();
+                    if let Some(error) = dispatch_error {
+                        stage_error.get_or_insert((shader.label, error));
+                    }
                 }
                 Command::Download(proxy) => {
                     let src_buf = self
@@ -381,7 +720,35 @@ impl Engine {
                 }
             }
         }
-        queue.submit(Some(encoder.finish()));
+        let readback = queries
+            .as_ref()
+            .map(|queries| queries.resolve(device, &mut encoder));
+        let index = queue.submit(Some(encoder.finish()));
+        let submission_error = submission_scope
+            .map(|scope| scope.pop(self.watchdog))
+            .transpose()?
+            .flatten();
+        if let (Some(queries), Some(buffer)) = (queries, readback) {
+            let map_state = Arc::new(AtomicU8::new(MAP_PENDING));
+            let signal = map_state.clone();
+            buffer
+                .slice(..)
+                .map_async(wgpu::MapMode::Read, move |result| {
+                    let state = if result.is_ok() { MAP_DONE } else { MAP_FAILED };
+                    signal.store(state, Ordering::Release)
+                });
+            if self.pending_timings.len() >= MAX_PENDING_TIMINGS {
+                self.pending_timings.remove(0);
+            }
+            self.pending_timings.push(PendingTimings {
+                labels: queries.labels,
+                buffer,
+                map_state,
+                period: queue.get_timestamp_period(),
+                #[cfg(feature = "puffin")]
+                submitted_ns: crate::profiling::submission_time(),
+            });
+        }
         for id in free_bufs {
             if let Some(buf) = self.bind_map.buf_map.remove(&id) {
                 let props = BufferProperties {
@@ -400,7 +767,18 @@ impl Engine {
                 drop(view);
             }
         }
-        Ok(())
+        let error = match (stage_error, submission_error) {
+            (Some((stage, error)), _) => Some((Some(stage), error)),
+            (None, error) => error.map(|error| (None, error)),
+        };
+        if let Some((stage, error)) = error {
+            return Err(Box::new(ValidationError {
+                stage,
+                stats: Default::default(),
+                error,
+            }));
+        }
+        Ok(index)
     }
 
     pub fn get_download(&self, buf: BufProxy) -> Option<&Buffer> {
@@ -417,6 +795,14 @@ impl Recording {
         self.commands.push(cmd);
     }
 
+    /// Returns the number of dispatches in the recording.
+    pub fn n_dispatches(&self) -> u32 {
+        self.commands
+            .iter()
+            .filter(|cmd| matches!(cmd, Command::Dispatch(..)))
+            .count() as u32
+    }
+
     pub fn upload(&mut self, name: &'static str, data: impl Into<Vec<u8>>) -> BufProxy {
         let data = data.into();
         let buf_proxy = BufProxy::new(data.len() as u64, name);
@@ -444,6 +830,25 @@ impl Recording {
         image_proxy
     }
 
+    /// Writes a region of an image.
+    ///
+    /// The data is tightly packed RGBA with 4 bytes per pixel.
+    pub fn write_image(
+        &mut self,
+        image: ImageProxy,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        data: impl Into<Vec<u8>>,
+    ) {
+        self.push(Command::WriteImage(
+            image,
+            [x, y, width, height],
+            data.into(),
+        ));
+    }
+
     pub fn dispatch<R>(&mut self, shader: ShaderId, wg_size: (u32, u32, u32), resources: R)
     where
         R: IntoIterator,
@@ -633,34 +1038,7 @@ impl BindMap {
                     if find_image(external_resources, proxy).is_some() {
                         continue;
                     }
-                    if let Entry::Vacant(v) = self.image_map.entry(proxy.id) {
-                        let format = proxy.format.to_wgpu();
-                        let texture = device.create_texture(&wgpu::TextureDescriptor {
-                            label: None,
-                            size: wgpu::Extent3d {
-                                width: proxy.width,
-                                height: proxy.height,
-                                depth_or_array_layers: 1,
-                            },
-                            mip_level_count: 1,
-                            sample_count: 1,
-                            dimension: wgpu::TextureDimension::D2,
-                            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                            format,
-                            view_formats: &[],
-                        });
-                        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
-                            label: None,
-                            dimension: Some(TextureViewDimension::D2),
-                            aspect: TextureAspect::All,
-                            mip_level_count: None,
-                            base_mip_level: 0,
-                            base_array_layer: 0,
-                            array_layer_count: None,
-                            format: Some(proxy.format.to_wgpu()),
-                        });
-                        v.insert((texture, texture_view));
-                    }
+                    self.get_or_create_image(*proxy, device);
                 }
             }
         }
@@ -696,6 +1074,41 @@ impl BindMap {
         Ok(bind_group)
     }
 
+    fn get_or_create_image(
+        &mut self,
+        proxy: ImageProxy,
+        device: &Device,
+    ) -> &(Texture, TextureView) {
+        self.image_map.entry(proxy.id).or_insert_with(|| {
+            let format = proxy.format.to_wgpu();
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d {
+                    width: proxy.width,
+                    height: proxy.height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                format,
+                view_formats: &[],
+            });
+            let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
+                label: None,
+                dimension: Some(TextureViewDimension::D2),
+                aspect: TextureAspect::All,
+                mip_level_count: None,
+                base_mip_level: 0,
+                base_array_layer: 0,
+                array_layer_count: None,
+                format: Some(format),
+            });
+            (texture, texture_view)
+        })
+    }
+
     fn get_or_create(
         &mut self,
         proxy: BufProxy,
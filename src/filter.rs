@@ -0,0 +1,206 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Color filters that are applied to layers when they are composited.
+//!
+//! A [`ColorMatrix`] is attached to a layer with
+//! [`SceneBuilder::push_color_matrix_layer`](crate::SceneBuilder::push_color_matrix_layer),
+//! for example to gray out disabled controls or to implement the SVG
+//! `feColorMatrix` filter primitive. The presets follow the definitions of
+//! the CSS filter functions of the same names.
+
+/// Matrix that transforms the unpremultiplied colors of a layer.
+///
+/// The matrix has four rows of five coefficients, in row major order with
+/// the offsets in the last column, so that
+/// `r' = m[0] * r + m[1] * g + m[2] * b + m[3] * a + m[4]` and so on for the
+/// green, blue and alpha channels. Channels are in the range 0 to 1 and the
+/// results are clamped to that range. This is the layout of the `values`
+/// attribute of `feColorMatrix`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ColorMatrix(pub [f32; 20]);
+
+impl Default for ColorMatrix {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl ColorMatrix {
+    /// The matrix that leaves colors unchanged.
+    pub const IDENTITY: Self = Self([
+        1.0, 0.0, 0.0, 0.0, 0.0, //
+        0.0, 1.0, 0.0, 0.0, 0.0, //
+        0.0, 0.0, 1.0, 0.0, 0.0, //
+        0.0, 0.0, 0.0, 1.0, 0.0,
+    ]);
+
+    /// Creates a matrix from its coefficients.
+    pub fn new(matrix: [f32; 20]) -> Self {
+        Self(matrix)
+    }
+
+    /// Creates a matrix that converts colors to grayscale, by the specified
+    /// amount between 0 and 1, as for the CSS `grayscale()` function.
+    pub fn grayscale(amount: f32) -> Self {
+        let s = 1.0 - amount.clamp(0.0, 1.0);
+        Self::rgb([
+            [
+                0.2126 + 0.7874 * s,
+                0.7152 - 0.7152 * s,
+                0.0722 - 0.0722 * s,
+            ],
+            [
+                0.2126 - 0.2126 * s,
+                0.7152 + 0.2848 * s,
+                0.0722 - 0.0722 * s,
+            ],
+            [
+                0.2126 - 0.2126 * s,
+                0.7152 - 0.7152 * s,
+                0.0722 + 0.9278 * s,
+            ],
+        ])
+    }
+
+    /// Creates a matrix that converts colors to sepia, by the specified
+    /// amount between 0 and 1, as for the CSS `sepia()` function.
+    pub fn sepia(amount: f32) -> Self {
+        let s = 1.0 - amount.clamp(0.0, 1.0);
+        Self::rgb([
+            [0.393 + 0.607 * s, 0.769 - 0.769 * s, 0.189 - 0.189 * s],
+            [0.349 - 0.349 * s, 0.686 + 0.314 * s, 0.168 - 0.168 * s],
+            [0.272 - 0.272 * s, 0.534 - 0.534 * s, 0.131 + 0.869 * s],
+        ])
+    }
+
+    /// Creates a matrix that scales the saturation of colors, as for the
+    /// CSS `saturate()` function and the `saturate` type of
+    /// `feColorMatrix`. A value of 0 produces grayscale and 1 leaves colors
+    /// unchanged.
+    pub fn saturate(amount: f32) -> Self {
+        let s = amount.max(0.0);
+        Self::rgb([
+            [0.213 + 0.787 * s, 0.715 - 0.715 * s, 0.072 - 0.072 * s],
+            [0.213 - 0.213 * s, 0.715 + 0.285 * s, 0.072 - 0.072 * s],
+            [0.213 - 0.213 * s, 0.715 - 0.715 * s, 0.072 + 0.928 * s],
+        ])
+    }
+
+    /// Creates a matrix that rotates the hue of colors by an angle in
+    /// radians, as for the CSS `hue-rotate()` function and the `hueRotate`
+    /// type of `feColorMatrix`.
+    pub fn hue_rotate(angle: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        Self::rgb([
+            [
+                0.213 + cos * 0.787 - sin * 0.213,
+                0.715 - cos * 0.715 - sin * 0.715,
+                0.072 - cos * 0.072 + sin * 0.928,
+            ],
+            [
+                0.213 - cos * 0.213 + sin * 0.143,
+                0.715 + cos * 0.285 + sin * 0.140,
+                0.072 - cos * 0.072 - sin * 0.283,
+            ],
+            [
+                0.213 - cos * 0.213 - sin * 0.787,
+                0.715 - cos * 0.715 + sin * 0.715,
+                0.072 + cos * 0.928 + sin * 0.072,
+            ],
+        ])
+    }
+
+    /// Creates a matrix that inverts colors, by the specified amount between
+    /// 0 and 1, as for the CSS `invert()` function.
+    pub fn invert(amount: f32) -> Self {
+        let a = amount.clamp(0.0, 1.0);
+        let scale = 1.0 - 2.0 * a;
+        let mut matrix = Self::IDENTITY;
+        for row in 0..3 {
+            matrix.0[row * 6] = scale;
+            matrix.0[row * 5 + 4] = a;
+        }
+        matrix
+    }
+
+    /// Creates a matrix that scales the color channels, as for the CSS
+    /// `brightness()` function.
+    pub fn brightness(amount: f32) -> Self {
+        let a = amount.max(0.0);
+        Self::rgb([[a, 0.0, 0.0], [0.0, a, 0.0], [0.0, 0.0, a]])
+    }
+
+    /// Creates a matrix that scales the contrast of colors around the middle
+    /// gray, as for the CSS `contrast()` function.
+    pub fn contrast(amount: f32) -> Self {
+        let a = amount.max(0.0);
+        let offset = 0.5 - 0.5 * a;
+        let mut matrix = Self::brightness(a);
+        for row in 0..3 {
+            matrix.0[row * 5 + 4] = offset;
+        }
+        matrix
+    }
+
+    /// Creates a matrix that scales the alpha channel, as for the CSS
+    /// `opacity()` function.
+    pub fn opacity(amount: f32) -> Self {
+        let mut matrix = Self::IDENTITY;
+        matrix.0[18] = amount.clamp(0.0, 1.0);
+        matrix
+    }
+
+    /// Creates a matrix that replaces the alpha channel with the luminance
+    /// of the color and the color with black, as for the `luminanceToAlpha`
+    /// type of `feColorMatrix`.
+    pub fn luminance_to_alpha() -> Self {
+        let mut matrix = Self([0.0; 20]);
+        matrix.0[15..18].copy_from_slice(&[0.2125, 0.7154, 0.0721]);
+        matrix
+    }
+
+    /// Returns the matrix that applies `self` followed by `other`.
+    pub fn then(&self, other: &Self) -> Self {
+        let (a, b) = (&self.0, &other.0);
+        let mut out = [0.0; 20];
+        for row in 0..4 {
+            for col in 0..5 {
+                let mut sum: f32 = (0..4).map(|k| b[row * 5 + k] * a[k * 5 + col]).sum();
+                if col == 4 {
+                    sum += b[row * 5 + 4];
+                }
+                out[row * 5 + col] = sum;
+            }
+        }
+        Self(out)
+    }
+
+    /// Returns true if the matrix leaves colors unchanged.
+    pub fn is_identity(&self) -> bool {
+        *self == Self::IDENTITY
+    }
+
+    /// Creates a matrix that transforms the color channels by a 3x3 matrix
+    /// and leaves the alpha channel unchanged.
+    fn rgb(rows: [[f32; 3]; 3]) -> Self {
+        let mut matrix = Self::IDENTITY;
+        for (row, coeffs) in rows.iter().enumerate() {
+            matrix.0[row * 5..row * 5 + 3].copy_from_slice(coeffs);
+        }
+        matrix
+    }
+}
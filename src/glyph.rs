@@ -18,9 +18,13 @@
 
 pub use moscato::pinot;
 
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+
+use crate::encoding::IncompatibleEncoding;
 use crate::scene::{SceneBuilder, SceneFragment};
-use peniko::kurbo::{Affine, Rect};
-use peniko::{Brush, Color, Fill, Mix};
+use peniko::kurbo::{Affine, BezPath, Rect, Shape};
+use peniko::{BlendMode, Brush, BrushRef, Color, Compose, Fill, Mix, Stroke};
 
 use moscato::{Context, Scaler};
 use pinot::{types::Tag, FontRef};
@@ -30,18 +34,172 @@ use smallvec::SmallVec;
 /// General context for creating scene fragments for glyph outlines.
 pub struct GlyphContext {
     ctx: Context,
+    cache: GlyphCache,
+    fonts: HashMap<u64, Weak<FontData>>,
+    next_font_id: u64,
+}
+
+/// Data of a font registered with [`GlyphContext::register_font`].
+struct FontData {
+    id: u64,
+    data: Arc<[u8]>,
+    offset: u32,
+}
+
+/// Reference counted handle to a font registered with a [`GlyphContext`].
+///
+/// The font and the glyphs cached for it are released by
+/// [`GlyphContext::evict_unused_fonts`] once all handles are dropped, so
+/// applications that load many fonts, such as the embedded fonts of user
+/// documents, do not accumulate them.
+#[derive(Clone)]
+pub struct FontHandle(Arc<FontData>);
+
+impl FontHandle {
+    /// Returns the id of the font, which keys its glyphs in the
+    /// [`GlyphCache`].
+    pub fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    /// Returns a reference to the font for reading its tables.
+    pub fn font_ref(&self) -> FontRef {
+        FontRef {
+            data: &self.0.data,
+            offset: self.0.offset,
+        }
+    }
+
+    /// Returns a weak handle that does not keep the font registered.
+    pub fn downgrade(&self) -> WeakFontHandle {
+        WeakFontHandle(Arc::downgrade(&self.0))
+    }
+}
+
+impl std::fmt::Debug for FontHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("FontHandle").field(&self.0.id).finish()
+    }
 }
 
+/// Weak handle to a registered font, for caches that should not keep fonts
+/// alive.
+#[derive(Clone, Debug, Default)]
+pub struct WeakFontHandle(Weak<FontData>);
+
+impl WeakFontHandle {
+    /// Returns a handle to the font if it is still registered.
+    pub fn upgrade(&self) -> Option<FontHandle> {
+        self.0.upgrade().map(FontHandle)
+    }
+}
+
+/// Ids of registered fonts have the high bit set so that they do not collide
+/// with the font ids chosen by applications.
+const REGISTERED_FONT_ID: u64 = 1 << 63;
+
 impl GlyphContext {
     /// Creates a new context.
     pub fn new() -> Self {
         Self {
             ctx: Context::new(),
+            cache: GlyphCache::default(),
+            fonts: HashMap::new(),
+            next_font_id: REGISTERED_FONT_ID,
+        }
+    }
+
+    /// Registers the font at `offset` in the font data, which is the offset
+    /// of its table directory in a collection and zero otherwise, and
+    /// returns a handle to it.
+    pub fn register_font(&mut self, data: Arc<[u8]>, offset: u32) -> FontHandle {
+        let id = self.next_font_id;
+        self.next_font_id += 1;
+        let font = Arc::new(FontData { id, data, offset });
+        self.fonts.insert(id, Arc::downgrade(&font));
+        FontHandle(font)
+    }
+
+    /// Returns the number of registered fonts, including those whose
+    /// handles were dropped but that have not been evicted yet.
+    pub fn n_registered_fonts(&self) -> usize {
+        self.fonts.len()
+    }
+
+    /// Unregisters the fonts whose handles have all been dropped and
+    /// removes their glyphs from the cache. Returns the number of fonts that
+    /// were evicted.
+    pub fn evict_unused_fonts(&mut self) -> usize {
+        let unused: Vec<u64> = self
+            .fonts
+            .iter()
+            .filter(|(_, font)| font.strong_count() == 0)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &unused {
+            self.fonts.remove(id);
+            self.cache.remove_font(*id);
         }
+        unused.len()
+    }
+
+    /// Creates a new provider for a registered font, whose glyphs are cached
+    /// under the id of the handle.
+    pub fn provider_for<'a, V>(
+        &'a mut self,
+        font: &'a FontHandle,
+        ppem: f32,
+        hint: bool,
+        variations: V,
+    ) -> GlyphProvider<'a>
+    where
+        V: IntoIterator,
+        V::Item: Into<(Tag, f32)>,
+    {
+        let font_ref = font.font_ref();
+        self.new_provider(&font_ref, Some(font.id()), ppem, hint, variations)
+    }
+
+    /// Creates a new provider for a registered font that generates glyphs for
+    /// text of size `size` drawn with `scale` device pixels per unit.
+    ///
+    /// See [`new_provider_with_scale`](Self::new_provider_with_scale).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `scale` is not positive and finite.
+    pub fn provider_for_scale<'a, V>(
+        &'a mut self,
+        font: &'a FontHandle,
+        size: f32,
+        scale: f32,
+        hint: bool,
+        variations: V,
+    ) -> GlyphProvider<'a>
+    where
+        V: IntoIterator,
+        V::Item: Into<(Tag, f32)>,
+    {
+        let font_ref = font.font_ref();
+        self.new_provider_with_scale(&font_ref, Some(font.id()), size, scale, hint, variations)
+    }
+
+    /// Returns the cache of glyph fragments.
+    pub fn cache(&self) -> &GlyphCache {
+        &self.cache
+    }
+
+    /// Returns a mutable reference to the cache of glyph fragments, for
+    /// example to preload it from a previous session.
+    pub fn cache_mut(&mut self) -> &mut GlyphCache {
+        &mut self.cache
     }
 
     /// Creates a new provider for generating scene fragments for glyphs from
     /// the specified font and settings.
+    ///
+    /// Fragments are cached when a font id is given, so the id must uniquely
+    /// identify the font data.
     pub fn new_provider<'a, V>(
         &'a mut self,
         font: &FontRef<'a>,
@@ -54,6 +212,21 @@ impl GlyphContext {
         V: IntoIterator,
         V::Item: Into<(Tag, f32)>,
     {
+        let key = font_id.map(|font_id| GlyphKey {
+            font_id,
+            gid: 0,
+            ppem: ppem.to_bits(),
+            hint,
+            palette_index: None,
+            synthesis: 0,
+        });
+        // The cache is keyed by font id only, so fragments are not cached
+        // for variable font instances.
+        let mut variations = variations.into_iter().peekable();
+        let cache = match key {
+            Some(key) if variations.peek().is_none() => Some((&mut self.cache, key)),
+            _ => None,
+        };
         let scaler = if let Some(font_id) = font_id {
             self.ctx
                 .new_scaler_with_id(font, font_id)
@@ -69,7 +242,414 @@ impl GlyphContext {
                 .variations(variations)
                 .build()
         };
-        GlyphProvider { scaler }
+        GlyphProvider {
+            scaler,
+            cache,
+            synthesis: Synthesis::NONE,
+            scale: None,
+        }
+    }
+
+    /// Creates a new provider for text of size `size` that is drawn with
+    /// `scale` device pixels per unit, such as the scale factor of a window.
+    ///
+    /// Glyphs are generated and hinted at the [`effective_ppem`] and
+    /// [`TextBlob::new`] snaps their origins to [`SUBPIXEL_POSITIONS`] in
+    /// device space. Every raster decision is therefore a function of the
+    /// effective size alone, so text of size 12 at a scale of 2 renders
+    /// identically to text of size 24 at a scale of 1, and both share
+    /// cached glyphs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `scale` is not positive and finite, since glyphs could not
+    /// be placed with [`GlyphProvider::glyph_transform`].
+    pub fn new_provider_with_scale<'a, V>(
+        &'a mut self,
+        font: &FontRef<'a>,
+        font_id: Option<u64>,
+        size: f32,
+        scale: f32,
+        hint: bool,
+        variations: V,
+    ) -> GlyphProvider<'a>
+    where
+        V: IntoIterator,
+        V::Item: Into<(Tag, f32)>,
+    {
+        assert!(
+            scale.is_finite() && scale > 0.0,
+            "glyph scale must be positive and finite"
+        );
+        let ppem = effective_ppem(size, scale);
+        let mut provider = self.new_provider(font, font_id, ppem, hint, variations);
+        provider.scale = Some(scale);
+        provider
+    }
+}
+
+/// Number of horizontal positions within a device pixel to which the origins
+/// of glyphs are snapped by providers created with a scale.
+pub const SUBPIXEL_POSITIONS: u32 = 4;
+
+/// Returns the size in device pixels per em at which glyphs of text of size
+/// `size` are generated when drawn with `scale` device pixels per unit.
+///
+/// The product is rounded to 1/64 of a pixel, so that sizes and scales whose
+/// products only differ by rounding error, such as 12 at 1.25 and 10 at 1.5,
+/// produce identical glyphs.
+pub fn effective_ppem(size: f32, scale: f32) -> f32 {
+    ((size as f64 * scale as f64 * 64.0).round() / 64.0) as f32
+}
+
+/// Key identifying a glyph fragment in a [`GlyphCache`].
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct GlyphKey {
+    /// Identifier of the font.
+    pub font_id: u64,
+    /// Glyph identifier.
+    pub gid: u16,
+    /// Bit representation of the size in pixels per em.
+    pub ppem: u32,
+    /// True if the outline is hinted.
+    pub hint: bool,
+    /// Palette index for color glyphs, or `None` for outlines.
+    pub palette_index: Option<u16>,
+    /// Bit representation of the [`Synthesis`] applied to the glyph, zero
+    /// if none.
+    pub synthesis: u64,
+}
+
+/// Synthetic bold and oblique styles for fonts that lack the corresponding
+/// faces.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Synthesis {
+    /// Distance in pixels by which outlines are dilated.
+    pub embolden: f32,
+    /// Horizontal shift per unit of height, where positive values slant the
+    /// glyphs to the right.
+    pub skew: f32,
+}
+
+impl Synthesis {
+    /// No synthetic styles.
+    pub const NONE: Self = Self {
+        embolden: 0.0,
+        skew: 0.0,
+    };
+
+    /// Skew of a synthetic oblique style, which is slanted by about 14
+    /// degrees like in browsers.
+    pub const OBLIQUE_SKEW: f32 = 0.25;
+
+    /// Returns the emboldening that matches the stroke weight of a
+    /// synthetic bold style at the given size.
+    pub fn bold(ppem: f32) -> Self {
+        Self {
+            embolden: ppem / 48.0,
+            skew: 0.0,
+        }
+    }
+
+    /// Returns a synthetic oblique style.
+    pub fn oblique() -> Self {
+        Self {
+            embolden: 0.0,
+            skew: Self::OBLIQUE_SKEW,
+        }
+    }
+
+    /// Builder method for combining with a synthetic oblique style.
+    pub fn with_skew(mut self, skew: f32) -> Self {
+        self.skew = skew;
+        self
+    }
+
+    /// Returns true if no synthetic styles are applied.
+    pub fn is_none(&self) -> bool {
+        self.embolden == 0.0 && self.skew == 0.0
+    }
+
+    /// Returns the transform that slants glyph outlines, which are y-up.
+    pub fn transform(&self) -> Affine {
+        Affine::new([1.0, 0.0, self.skew as f64, 1.0, 0.0, 0.0])
+    }
+
+    fn to_bits(self) -> u64 {
+        ((self.embolden.to_bits() as u64) << 32) | self.skew.to_bits() as u64
+    }
+}
+
+/// Limits on the glyphs kept by a [`GlyphCache`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct GlyphCacheBudget {
+    /// Maximum number of cached glyphs, or `None` for no limit.
+    pub max_glyphs: Option<usize>,
+    /// Maximum estimated size of the cached fragments in bytes, or `None`
+    /// for no limit.
+    pub max_bytes: Option<usize>,
+}
+
+/// Occupancy and effectiveness of a [`GlyphCache`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct GlyphCacheStats {
+    /// Number of cached glyphs.
+    pub glyphs: usize,
+    /// Estimated size of the cached fragments in bytes.
+    pub bytes: usize,
+    /// Number of glyphs that were found in the cache.
+    pub hits: u64,
+    /// Number of glyphs that were generated because they were not cached.
+    pub misses: u64,
+    /// Number of glyphs that were evicted to stay within the budget.
+    pub evictions: u64,
+}
+
+/// Returns the limit that the glyph cache is trimmed down to when it exceeds
+/// `limit`, which leaves room for an eighth of the budget.
+fn low_water_mark(limit: usize) -> usize {
+    if limit == usize::MAX {
+        limit
+    } else {
+        limit - limit / 8
+    }
+}
+
+/// Cached fragment along with its size and the time it was last used.
+#[derive(Clone)]
+struct CachedGlyph {
+    fragment: SceneFragment,
+    size: usize,
+    last_used: u64,
+}
+
+/// Cache of scene fragments for glyphs.
+///
+/// The cache can be serialized with [`save`](Self::save) and restored with
+/// [`load`](Self::load) to avoid generating the glyphs of a previous session
+/// again at startup.
+///
+/// The cache is unbounded by default. Applications that display many fonts
+/// or sizes should set a [`GlyphCacheBudget`], in which case the least
+/// recently used glyphs are evicted when it is exceeded, until the cache is
+/// somewhat below the budget.
+#[derive(Clone, Default)]
+pub struct GlyphCache {
+    map: HashMap<GlyphKey, CachedGlyph>,
+    budget: GlyphCacheBudget,
+    bytes: usize,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl GlyphCache {
+    /// Sets the limits of the cache and evicts glyphs until they are met.
+    pub fn set_budget(&mut self, budget: GlyphCacheBudget) {
+        self.budget = budget;
+        self.trim();
+    }
+
+    /// Returns the limits of the cache.
+    pub fn budget(&self) -> GlyphCacheBudget {
+        self.budget
+    }
+
+    /// Returns the occupancy of the cache and the number of hits, misses and
+    /// evictions since it was created or the counters were reset.
+    pub fn stats(&self) -> GlyphCacheStats {
+        GlyphCacheStats {
+            glyphs: self.map.len(),
+            bytes: self.bytes,
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+        }
+    }
+
+    /// Resets the hit, miss and eviction counters, for example at the start
+    /// of each frame.
+    pub fn reset_stats(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+        self.evictions = 0;
+    }
+
+    /// Returns the number of cached glyphs.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns true if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Removes all glyphs from the cache.
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.bytes = 0;
+    }
+
+    /// Removes the glyphs of a font from the cache and returns the number of
+    /// glyphs that were removed.
+    pub fn remove_font(&mut self, font_id: u64) -> usize {
+        let len = self.map.len();
+        let mut removed_bytes = 0;
+        self.map.retain(|key, glyph| {
+            let keep = key.font_id != font_id;
+            if !keep {
+                removed_bytes += glyph.size;
+            }
+            keep
+        });
+        self.bytes -= removed_bytes;
+        len - self.map.len()
+    }
+
+    /// Returns the fragment for the glyph, if cached.
+    ///
+    /// This does not count as a use of the glyph for eviction.
+    pub fn get(&self, key: &GlyphKey) -> Option<&SceneFragment> {
+        self.map.get(key).map(|glyph| &glyph.fragment)
+    }
+
+    /// Returns the fragment for the glyph and marks it as recently used, or
+    /// counts a miss if it is not cached.
+    fn lookup(&mut self, key: &GlyphKey) -> Option<&SceneFragment> {
+        self.clock += 1;
+        match self.map.get_mut(key) {
+            Some(glyph) => {
+                self.hits += 1;
+                glyph.last_used = self.clock;
+                Some(&glyph.fragment)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Adds a glyph to the cache without enforcing the budget.
+    fn insert(&mut self, key: GlyphKey, fragment: SceneFragment) {
+        self.clock += 1;
+        let glyph = CachedGlyph {
+            size: fragment.heap_size(),
+            fragment,
+            last_used: self.clock,
+        };
+        self.bytes += glyph.size;
+        if let Some(old) = self.map.insert(key, glyph) {
+            self.bytes -= old.size;
+        }
+    }
+
+    /// Evicts the least recently used glyphs when the cache exceeds its
+    /// budget.
+    ///
+    /// Glyphs are evicted in a batch down to a low water mark below the
+    /// budget, so that the cost of finding the least recently used glyphs is
+    /// shared by the insertions until the budget is exceeded again.
+    fn trim(&mut self) {
+        let max_glyphs = self.budget.max_glyphs.unwrap_or(usize::MAX);
+        let max_bytes = self.budget.max_bytes.unwrap_or(usize::MAX);
+        if self.map.len() <= max_glyphs && self.bytes <= max_bytes {
+            return;
+        }
+        let max_glyphs = low_water_mark(max_glyphs);
+        let max_bytes = low_water_mark(max_bytes);
+        let mut entries: Vec<_> = self
+            .map
+            .iter()
+            .map(|(key, glyph)| (glyph.last_used, *key))
+            .collect();
+        entries.sort_unstable();
+        for (_, key) in entries {
+            if self.map.len() <= max_glyphs && self.bytes <= max_bytes {
+                break;
+            }
+            if let Some(glyph) = self.map.remove(&key) {
+                self.bytes -= glyph.size;
+                self.evictions += 1;
+            }
+        }
+    }
+
+    /// Serializes the cached glyphs. Entries are ordered by key, so the
+    /// output is deterministic for a given set of glyphs. Glyphs that
+    /// reference images are skipped.
+    pub fn save(&self) -> Vec<u8> {
+        let mut keys: Vec<_> = self.map.keys().collect();
+        keys.sort();
+        let mut out = vec![];
+        for key in keys {
+            let data = match self.map[key].fragment.to_bytes() {
+                Some(data) => data,
+                None => continue,
+            };
+            out.extend_from_slice(&key.font_id.to_le_bytes());
+            out.extend_from_slice(&key.ppem.to_le_bytes());
+            out.extend_from_slice(&key.gid.to_le_bytes());
+            out.extend_from_slice(&key.palette_index.unwrap_or(0).to_le_bytes());
+            out.push(key.hint as u8);
+            // Flags for the palette index and, in a trailing field, the
+            // synthesis.
+            let flags = key.palette_index.is_some() as u8 | ((key.synthesis != 0) as u8) << 1;
+            out.push(flags);
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            if key.synthesis != 0 {
+                out.extend_from_slice(&key.synthesis.to_le_bytes());
+            }
+            out.extend_from_slice(&data);
+        }
+        out
+    }
+
+    /// Adds the glyphs serialized by [`save`](Self::save) to the cache and
+    /// returns the number of glyphs. Glyphs beyond the budget are evicted
+    /// again, so the count may exceed the growth of the cache.
+    pub fn load(&mut self, mut data: &[u8]) -> Result<usize, IncompatibleEncoding> {
+        const ENTRY_HEADER_SIZE: usize = 22;
+        let mut entries = vec![];
+        while !data.is_empty() {
+            if data.len() < ENTRY_HEADER_SIZE {
+                return Err(IncompatibleEncoding::Malformed);
+            }
+            let (header, mut rest) = data.split_at(ENTRY_HEADER_SIZE);
+            let palette_index = u16::from_le_bytes([header[14], header[15]]);
+            let flags = header[17];
+            let mut synthesis = 0;
+            if flags & 2 != 0 {
+                if rest.len() < 8 {
+                    return Err(IncompatibleEncoding::Malformed);
+                }
+                synthesis = u64::from_le_bytes(rest[..8].try_into().unwrap());
+                rest = &rest[8..];
+            }
+            let key = GlyphKey {
+                font_id: u64::from_le_bytes(header[0..8].try_into().unwrap()),
+                ppem: u32::from_le_bytes(header[8..12].try_into().unwrap()),
+                gid: u16::from_le_bytes([header[12], header[13]]),
+                hint: header[16] != 0,
+                palette_index: (flags & 1 != 0).then_some(palette_index),
+                synthesis,
+            };
+            let len = u32::from_le_bytes(header[18..22].try_into().unwrap()) as usize;
+            if rest.len() < len {
+                return Err(IncompatibleEncoding::Malformed);
+            }
+            let (fragment, rest) = rest.split_at(len);
+            entries.push((key, SceneFragment::from_bytes(fragment)?));
+            data = rest;
+        }
+        let count = entries.len();
+        for (key, fragment) in entries {
+            self.insert(key, fragment);
+        }
+        self.trim();
+        Ok(count)
     }
 }
 
@@ -77,30 +657,152 @@ impl GlyphContext {
 /// font.
 pub struct GlyphProvider<'a> {
     scaler: Scaler<'a>,
+    cache: Option<(&'a mut GlyphCache, GlyphKey)>,
+    synthesis: Synthesis,
+    scale: Option<f32>,
 }
 
 impl<'a> GlyphProvider<'a> {
+    /// Sets the synthetic styles applied to subsequently generated glyphs,
+    /// for example for a run of text that requests a bold or italic style
+    /// that the font does not provide.
+    pub fn set_synthesis(&mut self, synthesis: Synthesis) {
+        self.synthesis = synthesis;
+    }
+
+    /// Returns the synthetic styles applied to generated glyphs.
+    pub fn synthesis(&self) -> Synthesis {
+        self.synthesis
+    }
+
+    /// Returns the number of device pixels per unit if the provider was
+    /// created with [`GlyphContext::new_provider_with_scale`], in which case
+    /// generated glyphs are in device pixels.
+    pub fn scale(&self) -> Option<f32> {
+        self.scale
+    }
+
+    /// Returns the transform that places a glyph generated by the provider
+    /// with its origin at a position in units.
+    ///
+    /// For providers with a scale, the origin is snapped to the subpixel grid
+    /// in device space and the glyph is scaled from device pixels to units.
+    pub fn glyph_transform(&self, x: f32, y: f32) -> Affine {
+        // Glyph outlines are y-up.
+        let flip = Affine::scale_non_uniform(1.0, -1.0);
+        match self.scale {
+            Some(scale) => {
+                let scale = scale as f64;
+                let n = SUBPIXEL_POSITIONS as f64;
+                let x = (x as f64 * scale * n).round() / n;
+                let y = (y as f64 * scale).round();
+                Affine::scale(1.0 / scale) * Affine::translate((x, y)) * flip
+            }
+            None => Affine::translate((x as f64, y as f64)) * flip,
+        }
+    }
+
     /// Returns a scene fragment containing the commands to render the
     /// specified glyph.
     pub fn get(&mut self, gid: u16, brush: Option<&Brush>) -> Option<SceneFragment> {
+        // Only fragments with the default brush are cached.
+        if brush.is_some() {
+            return self.make_glyph(gid, brush);
+        }
+        self.cached(gid, None, |provider| provider.make_glyph(gid, None))
+    }
+
+    /// Returns a scene fragment containing the commands and resources to
+    /// render the specified color glyph.
+    pub fn get_color(&mut self, palette_index: u16, gid: u16) -> Option<SceneFragment> {
+        self.cached(gid, Some(palette_index), |provider| {
+            provider.make_color_glyph(palette_index, gid)
+        })
+    }
+
+    fn cached(
+        &mut self,
+        gid: u16,
+        palette_index: Option<u16>,
+        f: impl FnOnce(&mut Self) -> Option<SceneFragment>,
+    ) -> Option<SceneFragment> {
+        let key = match &self.cache {
+            Some((_, key)) => GlyphKey {
+                gid,
+                palette_index,
+                synthesis: self.synthesis.to_bits(),
+                ..*key
+            },
+            None => return f(self),
+        };
+        if let Some(fragment) = self
+            .cache
+            .as_mut()
+            .and_then(|(cache, _)| cache.lookup(&key))
+        {
+            return Some(fragment.clone());
+        }
+        let fragment = f(self)?;
+        if let Some((cache, _)) = &mut self.cache {
+            cache.insert(key, fragment.clone());
+            cache.trim();
+        }
+        Some(fragment)
+    }
+
+    /// Returns the outline of the specified glyph, slanted by the synthetic
+    /// oblique style if any. Synthetic emboldening is applied when the
+    /// outline is drawn and is not included.
+    pub fn outline(&mut self, gid: u16) -> Option<BezPath> {
+        let glyph = self.scaler.glyph(gid)?;
+        let path = glyph.path(0)?;
+        if self.synthesis.skew != 0.0 {
+            Some(convert_transformed_path(
+                path.elements(),
+                &self.synthesis.transform(),
+            ))
+        } else {
+            Some(convert_path(path.elements()))
+        }
+    }
+
+    fn make_glyph(&mut self, gid: u16, brush: Option<&Brush>) -> Option<SceneFragment> {
         let glyph = self.scaler.glyph(gid)?;
         let path = glyph.path(0)?;
         let mut fragment = SceneFragment::default();
         let mut builder = SceneBuilder::for_fragment(&mut fragment);
-        builder.fill(
-            Fill::NonZero,
-            Affine::IDENTITY,
-            brush.unwrap_or(&Brush::Solid(Color::rgb8(255, 255, 255))),
-            None,
-            &convert_path(path.elements()),
-        );
+        let brush = brush.unwrap_or(&Brush::Solid(Color::rgb8(255, 255, 255)));
+        let path = convert_path(path.elements());
+        let transform = self.synthesis.transform();
+        if self.synthesis.embolden > 0.0 {
+            // Emboldening strokes the outline in addition to filling it,
+            // which dilates it by half the stroke width.
+            let stroke = Stroke::new(self.synthesis.embolden * 2.0);
+            if matches!(brush, Brush::Solid(color) if color.a == 255) {
+                builder.draw(Fill::NonZero, transform, brush, &stroke, brush, &path);
+            } else {
+                // The fill and the stroke overlap, so their union is drawn as
+                // a mask for the brush to keep translucent brushes from
+                // being applied twice.
+                let extent = self.synthesis.embolden as f64 * 2.0;
+                let bounds = path.bounding_box().inflate(extent, extent);
+                let white = Brush::Solid(Color::rgb8(255, 255, 255));
+                builder.push_layer(Mix::Normal, 1.0, transform, &bounds);
+                builder.draw(Fill::NonZero, transform, &white, &stroke, &white, &path);
+                let src_in = BlendMode::new(Mix::Normal, Compose::SrcIn);
+                builder.push_layer(src_in, 1.0, transform, &bounds);
+                builder.fill(Fill::NonZero, transform, brush, None, &bounds);
+                builder.pop_layer();
+                builder.pop_layer();
+            }
+        } else {
+            builder.fill(Fill::NonZero, transform, brush, None, &path);
+        }
         builder.finish();
         Some(fragment)
     }
 
-    /// Returns a scene fragment containing the commands and resources to
-    /// render the specified color glyph.
-    pub fn get_color(&mut self, palette_index: u16, gid: u16) -> Option<SceneFragment> {
+    fn make_color_glyph(&mut self, palette_index: u16, gid: u16) -> Option<SceneFragment> {
         use moscato::Command;
         let glyph = self.scaler.color_glyph(palette_index, gid)?;
         let mut fragment = SceneFragment::default();
@@ -189,10 +891,139 @@ impl<'a> GlyphProvider<'a> {
             }
         }
         builder.finish();
+        if self.synthesis.skew != 0.0 {
+            let mut slanted = SceneFragment::default();
+            let mut builder = SceneBuilder::for_fragment(&mut slanted);
+            builder.append(&fragment, Some(self.synthesis.transform()));
+            builder.finish();
+            return Some(slanted);
+        }
         Some(fragment)
     }
 }
 
+/// Glyph with a position in a [`TextBlob`].
+#[derive(Copy, Clone, Debug)]
+pub struct PositionedGlyph {
+    /// Glyph identifier.
+    pub gid: u16,
+    /// Horizontal position of the origin of the glyph.
+    pub x: f32,
+    /// Vertical position of the baseline, increasing downwards.
+    pub y: f32,
+    /// Presentation requested for the glyph.
+    pub presentation: Presentation,
+}
+
+/// Presentation of a glyph that has both a color and an outline form.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Presentation {
+    /// Color when the blob is drawn with a palette.
+    #[default]
+    Default,
+    /// Always the outline, as requested by U+FE0E VARIATION SELECTOR-15.
+    Text,
+    /// Color when available, even if the blob is drawn without a palette,
+    /// as requested by U+FE0F VARIATION SELECTOR-16 and emoji sequences.
+    Emoji,
+}
+
+/// Shaped and positioned run of glyphs that is encoded once and drawn as a
+/// unit.
+///
+/// Drawing a blob appends its fragment, so a paragraph that is scrolled or
+/// animated only pays for glyph generation and encoding when it is created.
+#[derive(Clone, Default)]
+pub struct TextBlob {
+    fragment: SceneFragment,
+    outline: BezPath,
+    bounds: Rect,
+}
+
+impl TextBlob {
+    /// Creates a blob from glyphs of the font of the provider.
+    ///
+    /// Glyphs are placed with [`GlyphProvider::glyph_transform`], so the
+    /// origins are snapped to device pixels for providers with a scale.
+    ///
+    /// Color glyphs are drawn with their palette when the blob is drawn
+    /// with [`draw`](Self::draw) and with their outline otherwise. Without a
+    /// palette index, glyphs with [`Presentation::Emoji`] use the first
+    /// palette.
+    pub fn new(
+        provider: &mut GlyphProvider,
+        palette_index: Option<u16>,
+        glyphs: impl IntoIterator<Item = PositionedGlyph>,
+    ) -> Self {
+        let mut fragment = SceneFragment::default();
+        let mut builder = SceneBuilder::for_fragment(&mut fragment);
+        let mut outline = BezPath::new();
+        for glyph in glyphs {
+            let transform = provider.glyph_transform(glyph.x, glyph.y);
+            let palette_index = match glyph.presentation {
+                Presentation::Default => palette_index,
+                Presentation::Text => None,
+                Presentation::Emoji => Some(palette_index.unwrap_or(0)),
+            };
+            let glyph_fragment = match palette_index {
+                Some(palette_index) => provider
+                    .get_color(palette_index, glyph.gid)
+                    .or_else(|| provider.get(glyph.gid, None)),
+                None => provider.get(glyph.gid, None),
+            };
+            if let Some(glyph_fragment) = glyph_fragment {
+                builder.append(&glyph_fragment, Some(transform));
+            }
+            if let Some(glyph_outline) = provider.outline(glyph.gid) {
+                for el in glyph_outline.iter() {
+                    outline.push(transform * el);
+                }
+            }
+        }
+        builder.finish();
+        let bounds = if outline.elements().is_empty() {
+            Rect::ZERO
+        } else {
+            outline.bounding_box()
+        };
+        Self {
+            fragment,
+            outline,
+            bounds,
+        }
+    }
+
+    /// Returns the fragment containing the glyphs.
+    pub fn fragment(&self) -> &SceneFragment {
+        &self.fragment
+    }
+
+    /// Returns the union of the glyph outlines.
+    pub fn outline(&self) -> &BezPath {
+        &self.outline
+    }
+
+    /// Returns the bounding box of the glyph outlines.
+    pub fn bounds(&self) -> Rect {
+        self.bounds
+    }
+
+    /// Draws the blob with the brushes that it was created with.
+    pub fn draw(&self, builder: &mut SceneBuilder, transform: Affine) {
+        builder.append(&self.fragment, Some(transform));
+    }
+
+    /// Draws the outlines of all glyphs of the blob with a single brush.
+    pub fn draw_with_brush<'b>(
+        &self,
+        builder: &mut SceneBuilder,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+    ) {
+        builder.fill(Fill::NonZero, transform, brush, None, &self.outline);
+    }
+}
+
 fn convert_path(path: impl Iterator<Item = moscato::Element> + Clone) -> peniko::kurbo::BezPath {
     let mut result = peniko::kurbo::BezPath::new();
     for el in path {
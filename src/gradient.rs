@@ -0,0 +1,267 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Helpers for constructing gradient brushes.
+
+use peniko::kurbo::Point;
+use peniko::{Color, ColorStop, Extend, Gradient, GradientKind};
+
+/// Creates a sweep gradient around `center` whose stops span the angles
+/// from `start_angle` to `end_angle` in radians, measured clockwise from the
+/// positive x axis. Angles outside the range take the color of the nearest
+/// end.
+pub fn sweep(
+    center: impl Into<Point>,
+    start_angle: f32,
+    end_angle: f32,
+    stops: &[ColorStop],
+) -> Gradient {
+    Gradient {
+        kind: GradientKind::Sweep {
+            center: center.into(),
+            start_angle,
+            end_angle,
+        },
+        extend: Extend::Pad,
+        stops: stops.into(),
+    }
+}
+
+/// Creates a sweep gradient around `center` that repeats its stops
+/// `repeats` times around the circle, starting at `start_angle` in radians.
+///
+/// Each repetition spans an equal fraction of a turn and the stops are only
+/// encoded once, which suits spinners and radar sweeps.
+pub fn repeated_sweep(
+    center: impl Into<Point>,
+    start_angle: f32,
+    repeats: u32,
+    stops: &[ColorStop],
+) -> Gradient {
+    let span = std::f32::consts::TAU / repeats.max(1) as f32;
+    Gradient {
+        extend: Extend::Repeat,
+        ..sweep(center, start_angle, start_angle + span, stops)
+    }
+}
+
+/// Creates a sweep gradient around `center` through the fully saturated
+/// hues, starting with red on the positive x axis and passing through
+/// yellow, green, cyan, blue and magenta clockwise.
+pub fn color_wheel(center: impl Into<Point>) -> Gradient {
+    let hues = [
+        Color::rgb8(255, 0, 0),
+        Color::rgb8(255, 255, 0),
+        Color::rgb8(0, 255, 0),
+        Color::rgb8(0, 255, 255),
+        Color::rgb8(0, 0, 255),
+        Color::rgb8(255, 0, 255),
+        Color::rgb8(255, 0, 0),
+    ];
+    let stops: Vec<ColorStop> = hues
+        .iter()
+        .enumerate()
+        .map(|(i, color)| ColorStop {
+            offset: i as f32 / (hues.len() - 1) as f32,
+            color: *color,
+        })
+        .collect();
+    sweep(center, 0.0, std::f32::consts::TAU, &stops)
+}
+
+/// Color space in which the colors of a gradient are interpolated.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum InterpolationSpace {
+    /// Gamma encoded sRGB, which is what gradient ramps use by default.
+    #[default]
+    Srgb,
+    /// Linear light sRGB.
+    LinearSrgb,
+    /// The perceptually uniform OKLab space.
+    Oklab,
+    /// Hue, saturation and lightness, interpolating the hue along the
+    /// shorter arc.
+    Hsl,
+}
+
+/// Number of stops that each interval between two stops is divided into
+/// when interpolating in a space other than sRGB.
+const INTERPOLATION_STEPS: usize = 16;
+
+/// Returns the gradient with its stops interpolated in the given color
+/// space, following the rules of CSS Color 4: components are interpolated
+/// premultiplied by alpha, except for hue.
+///
+/// Gradient ramps are generated in sRGB, so this replaces each interval
+/// between stops with a sequence of shorter intervals whose endpoints are
+/// computed in `space`.
+pub fn with_interpolation(mut gradient: Gradient, space: InterpolationSpace) -> Gradient {
+    if space == InterpolationSpace::Srgb {
+        return gradient;
+    }
+    let stops = std::mem::take(&mut gradient.stops);
+    for (i, stop) in stops.iter().enumerate() {
+        let next = match stops.get(i + 1) {
+            Some(next) if next.offset > stop.offset && next.color != stop.color => next,
+            _ => {
+                gradient.stops.push(*stop);
+                continue;
+            }
+        };
+        let c0 = to_space(stop.color, space);
+        let c1 = to_space(next.color, space);
+        for step in 0..INTERPOLATION_STEPS {
+            let t = step as f32 / INTERPOLATION_STEPS as f32;
+            gradient.stops.push(ColorStop {
+                offset: stop.offset + (next.offset - stop.offset) * t,
+                color: from_space(interpolate(c0, c1, t as f64, space), space),
+            });
+        }
+    }
+    gradient
+}
+
+/// Converts a color to the components of the space with the color
+/// components premultiplied by alpha. Hue is in degrees and is NaN for
+/// achromatic colors.
+fn to_space(color: Color, space: InterpolationSpace) -> [f64; 4] {
+    let a = color.a as f64 / 255.0;
+    let srgb = [color.r, color.g, color.b].map(|c| c as f64 / 255.0);
+    let [x, y, z] = match space {
+        InterpolationSpace::Srgb => srgb,
+        InterpolationSpace::LinearSrgb => srgb.map(srgb_to_linear),
+        InterpolationSpace::Oklab => linear_to_oklab(srgb.map(srgb_to_linear)),
+        InterpolationSpace::Hsl => {
+            let [h, s, l] = srgb_to_hsl(srgb);
+            return [h, s * a, l * a, a];
+        }
+    };
+    [x * a, y * a, z * a, a]
+}
+
+fn from_space(c: [f64; 4], space: InterpolationSpace) -> Color {
+    let a = c[3];
+    let unpremul = |x: f64| if a > 0.0 { x / a } else { 0.0 };
+    let srgb = match space {
+        InterpolationSpace::Srgb => [unpremul(c[0]), unpremul(c[1]), unpremul(c[2])],
+        InterpolationSpace::LinearSrgb => {
+            [unpremul(c[0]), unpremul(c[1]), unpremul(c[2])].map(linear_to_srgb)
+        }
+        InterpolationSpace::Oklab => {
+            oklab_to_linear([unpremul(c[0]), unpremul(c[1]), unpremul(c[2])]).map(linear_to_srgb)
+        }
+        InterpolationSpace::Hsl => hsl_to_srgb([c[0], unpremul(c[1]), unpremul(c[2])]),
+    };
+    let to_u8 = |x: f64| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+    Color::rgba8(to_u8(srgb[0]), to_u8(srgb[1]), to_u8(srgb[2]), to_u8(a))
+}
+
+fn interpolate(c0: [f64; 4], c1: [f64; 4], t: f64, space: InterpolationSpace) -> [f64; 4] {
+    let mut out = [0.0; 4];
+    for (out, (x, y)) in out.iter_mut().zip(c0.iter().zip(c1)) {
+        *out = x + (y - x) * t;
+    }
+    if space == InterpolationSpace::Hsl {
+        // A missing hue takes the hue of the other color.
+        let (h0, h1) = match (c0[0].is_nan(), c1[0].is_nan()) {
+            (true, true) => (0.0, 0.0),
+            (true, false) => (c1[0], c1[0]),
+            (false, true) => (c0[0], c0[0]),
+            (false, false) => (c0[0], c1[0]),
+        };
+        let mut delta = h1 - h0;
+        if delta > 180.0 {
+            delta -= 360.0;
+        } else if delta < -180.0 {
+            delta += 360.0;
+        }
+        out[0] = (h0 + delta * t).rem_euclid(360.0);
+    }
+    out
+}
+
+fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn linear_to_oklab([r, g, b]: [f64; 3]) -> [f64; 3] {
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+fn oklab_to_linear([l, a, b]: [f64; 3]) -> [f64; 3] {
+    let l_ = (l + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m_ = (l - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s_ = (l - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+    [
+        4.0767416621 * l_ - 3.3077115913 * m_ + 0.2309699292 * s_,
+        -1.2684380046 * l_ + 2.6097574011 * m_ - 0.3413193965 * s_,
+        -0.0041960863 * l_ - 0.7034186147 * m_ + 1.7076147010 * s_,
+    ]
+}
+
+fn srgb_to_hsl([r, g, b]: [f64; 3]) -> [f64; 3] {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let l = (max + min) / 2.0;
+    let d = max - min;
+    if d <= 0.0 {
+        return [f64::NAN, 0.0, l];
+    }
+    let s = d / (1.0 - (2.0 * l - 1.0).abs());
+    let h = if max == r {
+        ((g - b) / d).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / d + 2.0
+    } else {
+        (r - g) / d + 4.0
+    };
+    [h * 60.0, s, l]
+}
+
+fn hsl_to_srgb([h, s, l]: [f64; 3]) -> [f64; 3] {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let h = h / 60.0;
+    let x = c * (1.0 - (h.rem_euclid(2.0) - 1.0).abs());
+    let m = l - c / 2.0;
+    let [r, g, b] = match h as u32 {
+        0 => [c, x, 0.0],
+        1 => [x, c, 0.0],
+        2 => [0.0, c, x],
+        3 => [0.0, x, c],
+        4 => [x, 0.0, c],
+        _ => [c, 0.0, x],
+    };
+    [r + m, g + m, b + m]
+}
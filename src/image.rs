@@ -0,0 +1,198 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Orientation and alpha conventions of images.
+//!
+//! Image brushes and the images produced by
+//! [`Renderer::render_to_image`](crate::Renderer::render_to_image) hold RGBA8
+//! pixels with straight (not premultiplied) alpha. Texels are premultiplied
+//! when sampled, before filtering, so that soft edges blend without fringes.
+//! Premultiplied data, such as the output of most decoders for platform
+//! image APIs, must be converted with [`image_from_rgba8`] first; otherwise
+//! it is premultiplied twice and its translucent pixels are too dark.
+
+use peniko::kurbo::Affine;
+use peniko::{Format, Image};
+
+/// Interpretation of the color channels of image data.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub enum AlphaMode {
+    /// The color channels are independent of the alpha channel.
+    #[default]
+    Straight,
+    /// The color channels have been multiplied by the alpha channel.
+    Premultiplied,
+}
+
+/// Creates an image from tightly packed RGBA8 pixels with the specified
+/// alpha mode, converting premultiplied data to straight alpha.
+///
+/// Premultiplied color channels that exceed their alpha are invalid and are
+/// clamped to it.
+///
+/// # Panics
+///
+/// Panics if the length of the data is not `width * height * 4`.
+pub fn image_from_rgba8(mut data: Vec<u8>, width: u32, height: u32, mode: AlphaMode) -> Image {
+    assert_eq!(
+        data.len(),
+        width as usize * height as usize * 4,
+        "image data does not match its size"
+    );
+    if mode == AlphaMode::Premultiplied {
+        unpremultiply(&mut data);
+    }
+    Image::new(data.into(), Format::Rgba8, width, height)
+}
+
+/// Converts RGBA8 pixels from premultiplied to straight alpha in place.
+pub fn unpremultiply(data: &mut [u8]) {
+    for pixel in data.chunks_exact_mut(4) {
+        let a = pixel[3] as u32;
+        if a == 0 {
+            pixel[..3].fill(0);
+            continue;
+        }
+        for c in &mut pixel[..3] {
+            *c = (((*c as u32).min(a) * 255 + a / 2) / a) as u8;
+        }
+    }
+}
+
+/// Converts RGBA8 pixels from straight to premultiplied alpha in place.
+pub fn premultiply(data: &mut [u8]) {
+    for pixel in data.chunks_exact_mut(4) {
+        let a = pixel[3] as u32;
+        for c in &mut pixel[..3] {
+            *c = ((*c as u32 * a + 127) / 255) as u8;
+        }
+    }
+}
+
+/// Orientation in which an image is displayed, with the same meaning as the
+/// values of the EXIF orientation tag.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub enum Orientation {
+    /// The image is displayed as stored.
+    #[default]
+    Normal,
+    /// The image is mirrored horizontally.
+    FlipHorizontal,
+    /// The image is rotated by 180 degrees.
+    Rotate180,
+    /// The image is mirrored vertically.
+    FlipVertical,
+    /// The image is mirrored along its main diagonal.
+    Transpose,
+    /// The image is rotated by 90 degrees clockwise.
+    Rotate90,
+    /// The image is mirrored along its anti-diagonal.
+    Transverse,
+    /// The image is rotated by 270 degrees clockwise.
+    Rotate270,
+}
+
+impl Orientation {
+    /// Returns the orientation for a value of the EXIF orientation tag, or
+    /// `None` if the value is not between 1 and 8.
+    pub fn from_exif(value: u16) -> Option<Self> {
+        Some(match value {
+            1 => Self::Normal,
+            2 => Self::FlipHorizontal,
+            3 => Self::Rotate180,
+            4 => Self::FlipVertical,
+            5 => Self::Transpose,
+            6 => Self::Rotate90,
+            7 => Self::Transverse,
+            8 => Self::Rotate270,
+            _ => return None,
+        })
+    }
+
+    /// Returns the orientation obtained by rotating the image by a further
+    /// 90 degrees clockwise.
+    pub fn then_rotate_90(self) -> Self {
+        let (flip, turns) = self.parts();
+        Self::from_parts(flip, turns + 1)
+    }
+
+    /// Returns the orientation obtained by further mirroring the image
+    /// horizontally.
+    pub fn then_flip_horizontal(self) -> Self {
+        let (flip, turns) = self.parts();
+        Self::from_parts(!flip, 4 - turns)
+    }
+
+    /// Returns true if the width and height of the image are swapped when
+    /// displayed.
+    pub fn swaps_axes(self) -> bool {
+        self.parts().1 % 2 == 1
+    }
+
+    /// Returns the displayed size of an image with the stored size.
+    pub fn oriented_size(self, width: u32, height: u32) -> (u32, u32) {
+        if self.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// Returns the transform from the pixels of a stored image with the
+    /// specified size to the displayed image, whose origin is at the top
+    /// left corner.
+    pub fn transform(self, width: f64, height: f64) -> Affine {
+        let (w, h) = (width, height);
+        Affine::new(match self {
+            Self::Normal => [1.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+            Self::FlipHorizontal => [-1.0, 0.0, 0.0, 1.0, w, 0.0],
+            Self::Rotate180 => [-1.0, 0.0, 0.0, -1.0, w, h],
+            Self::FlipVertical => [1.0, 0.0, 0.0, -1.0, 0.0, h],
+            Self::Transpose => [0.0, 1.0, 1.0, 0.0, 0.0, 0.0],
+            Self::Rotate90 => [0.0, 1.0, -1.0, 0.0, h, 0.0],
+            Self::Transverse => [0.0, -1.0, -1.0, 0.0, h, w],
+            Self::Rotate270 => [0.0, -1.0, 1.0, 0.0, 0.0, w],
+        })
+    }
+
+    /// Decomposes the orientation into a horizontal mirror applied first and
+    /// a number of clockwise quarter turns.
+    fn parts(self) -> (bool, u8) {
+        match self {
+            Self::Normal => (false, 0),
+            Self::Rotate90 => (false, 1),
+            Self::Rotate180 => (false, 2),
+            Self::Rotate270 => (false, 3),
+            Self::FlipHorizontal => (true, 0),
+            Self::Transverse => (true, 1),
+            Self::FlipVertical => (true, 2),
+            Self::Transpose => (true, 3),
+        }
+    }
+
+    fn from_parts(flip: bool, turns: u8) -> Self {
+        match (flip, turns % 4) {
+            (false, 0) => Self::Normal,
+            (false, 1) => Self::Rotate90,
+            (false, 2) => Self::Rotate180,
+            (false, _) => Self::Rotate270,
+            (true, 0) => Self::FlipHorizontal,
+            (true, 1) => Self::Transverse,
+            (true, 2) => Self::FlipVertical,
+            (true, _) => Self::Transpose,
+        }
+    }
+}
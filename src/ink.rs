@@ -0,0 +1,286 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Variable width ink strokes for stylus input.
+
+use peniko::kurbo::{self, Affine, BezPath, Circle, PathEl, Point, Rect, Shape, Vec2};
+use peniko::{Brush, Stroke};
+
+use crate::encoding::dash_path;
+use crate::scene::{SceneBuilder, SceneFragment};
+
+/// Single sample of stylus input.
+#[derive(Copy, Clone, Debug)]
+pub struct InkPoint {
+    /// Position of the sample.
+    pub point: Point,
+    /// Normalized pressure in the range 0.0 to 1.0.
+    pub pressure: f32,
+    /// Normalized tilt in the range 0.0 (perpendicular to the surface) to
+    /// 1.0 (parallel to the surface). Tilting the stylus widens the nib.
+    pub tilt: f32,
+}
+
+impl InkPoint {
+    /// Creates a new sample with the specified position and pressure and
+    /// no tilt.
+    pub fn new(point: impl Into<Point>, pressure: f32) -> Self {
+        Self {
+            point: point.into(),
+            pressure,
+            tilt: 0.0,
+        }
+    }
+
+    /// Builder method for setting the tilt of the sample.
+    pub fn with_tilt(mut self, tilt: f32) -> Self {
+        self.tilt = tilt;
+        self
+    }
+}
+
+/// Polyline with per point pressure and tilt that is rendered as a smooth
+/// variable width stroke with round caps.
+///
+/// The stroke is built by stamping a disk at each sample and connecting
+/// adjacent disks with their tangent hull. The outline is extended as
+/// samples are pushed so adding a sample only computes the geometry for
+/// the new segment.
+#[derive(Clone, Debug)]
+pub struct InkStroke {
+    width: f64,
+    points: Vec<InkPoint>,
+    outline: BezPath,
+}
+
+impl InkStroke {
+    /// Creates a new empty stroke with the specified width at full pressure.
+    pub fn new(width: f64) -> Self {
+        Self {
+            width,
+            points: Vec::new(),
+            outline: BezPath::new(),
+        }
+    }
+
+    /// Returns the width of the stroke at full pressure.
+    pub fn width(&self) -> f64 {
+        self.width
+    }
+
+    /// Returns the samples of the stroke.
+    pub fn points(&self) -> &[InkPoint] {
+        &self.points
+    }
+
+    /// Returns true if the stroke does not contain any samples.
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Returns the outline of the stroke. This should be filled with the
+    /// non-zero fill rule.
+    pub fn outline(&self) -> &BezPath {
+        &self.outline
+    }
+
+    /// Appends a sample to the stroke.
+    pub fn push(&mut self, point: InkPoint) {
+        let r1 = self.radius(&point);
+        if let Some(prev) = self.points.last() {
+            let r0 = self.radius(prev);
+            push_hull(&mut self.outline, prev.point, r0, point.point, r1);
+        }
+        push_disk(&mut self.outline, point.point, r1);
+        self.points.push(point);
+    }
+
+    /// Appends a sequence of samples to the stroke.
+    pub fn extend(&mut self, points: impl IntoIterator<Item = InkPoint>) {
+        for point in points {
+            self.push(point);
+        }
+    }
+
+    /// Removes all samples from the stroke.
+    pub fn clear(&mut self) {
+        self.points.clear();
+        self.outline = BezPath::new();
+    }
+
+    fn radius(&self, point: &InkPoint) -> f64 {
+        let pressure = point.pressure.clamp(0.0, 1.0) as f64;
+        let tilt = point.tilt.clamp(0.0, 1.0) as f64;
+        0.5 * self.width * pressure * (1.0 + tilt)
+    }
+}
+
+/// Scene fragment for an ink stroke that is updated incrementally as the
+/// stroke grows.
+///
+/// Each update only encodes the geometry for samples that were added since
+/// the previous update, which keeps the cost per frame independent of the
+/// length of the stroke.
+pub struct InkFragment {
+    brush: Brush,
+    fragment: SceneFragment,
+    n_encoded: usize,
+}
+
+impl InkFragment {
+    /// Creates a new fragment that draws strokes with the specified brush.
+    pub fn new(brush: impl Into<Brush>) -> Self {
+        Self {
+            brush: brush.into(),
+            fragment: SceneFragment::new(),
+            n_encoded: 0,
+        }
+    }
+
+    /// Returns the fragment containing the encoded stroke.
+    pub fn fragment(&self) -> &SceneFragment {
+        &self.fragment
+    }
+
+    /// Updates the fragment to match the current state of the stroke.
+    ///
+    /// Returns the bounding box of the region that changed, in the coordinate
+    /// space of the stroke, or `None` if nothing changed.
+    pub fn update(&mut self, stroke: &InkStroke) -> Option<Rect> {
+        let elements = stroke.outline().elements();
+        if elements.len() == self.n_encoded {
+            return None;
+        }
+        if self.fragment.is_empty() || elements.len() < self.n_encoded {
+            // Nothing to extend or the stroke was cleared, so start over.
+            let mut builder = SceneBuilder::for_fragment(&mut self.fragment);
+            builder.draw_ink(Affine::IDENTITY, &self.brush, None, stroke);
+            builder.finish();
+            self.n_encoded = elements.len();
+            return Some(stroke.outline().bounding_box());
+        }
+        let pending = BezPath::from_vec(elements[self.n_encoded..].to_vec());
+        self.n_encoded = elements.len();
+        self.fragment.extend_last_path(&pending)
+    }
+}
+
+/// Returns the outline of a round capped stroke through the points whose
+/// width varies along the stroke, given as pairs of a point and the width of
+/// the stroke at that point. The outline should be filled with the non-zero
+/// fill rule.
+pub fn varying_outline(points: impl IntoIterator<Item = (Point, f64)>) -> BezPath {
+    let mut outline = BezPath::new();
+    let mut prev: Option<(Point, f64)> = None;
+    for (point, width) in points {
+        let radius = 0.5 * width.max(0.0);
+        if let Some((p0, r0)) = prev {
+            push_hull(&mut outline, p0, r0, point, radius);
+        }
+        push_disk(&mut outline, point, radius);
+        prev = Some((point, radius));
+    }
+    outline
+}
+
+/// Returns the outline of a stroke of the shape with round joins and caps,
+/// applying the dash pattern of the style. The outline should be filled with
+/// the non-zero fill rule.
+///
+/// This matches the geometry that [`SceneBuilder::stroke`] renders and can be
+/// used where a fill is required, such as the shape of a clip layer.
+pub fn stroke_outline(style: &Stroke, shape: &impl Shape) -> BezPath {
+    let dashed = dash_path(
+        shape.path_elements(0.1),
+        style.dash_offset,
+        &style.dash_pattern,
+    );
+    let elements: Vec<PathEl> = match dashed {
+        Some(dashes) => dashes.elements().to_vec(),
+        None => shape.path_elements(0.1).collect(),
+    };
+    let width = style.width as f64;
+    let mut outline = BezPath::new();
+    let mut subpath: Vec<(Point, f64)> = vec![];
+    let mut flush = |subpath: &mut Vec<(Point, f64)>| {
+        for el in varying_outline(subpath.drain(..)) {
+            outline.push(el);
+        }
+    };
+    kurbo::flatten(elements, 0.1, |el| match el {
+        PathEl::MoveTo(p) => {
+            flush(&mut subpath);
+            subpath.push((p, width));
+        }
+        PathEl::LineTo(p) => subpath.push((p, width)),
+        PathEl::ClosePath => {
+            if let Some(start) = subpath.first().copied() {
+                subpath.push(start);
+            }
+        }
+        _ => {}
+    });
+    flush(&mut subpath);
+    outline
+}
+
+/// Appends a disk as a new subpath with positive orientation.
+fn push_disk(path: &mut BezPath, center: Point, radius: f64) {
+    if radius <= 0.0 {
+        return;
+    }
+    for el in Circle::new(center, radius).path_elements(0.1) {
+        path.push(el);
+    }
+}
+
+/// Appends the region between the outer tangents of two disks as a new
+/// subpath with the same orientation as the disks so that they union under
+/// the non-zero fill rule.
+fn push_hull(path: &mut BezPath, c0: Point, r0: f64, c1: Point, r1: f64) {
+    let delta = c1 - c0;
+    let d = delta.hypot();
+    if d <= (r1 - r0).abs() {
+        // One disk contains the other.
+        return;
+    }
+    let u = delta / d;
+    let n = Vec2::new(-u.y, u.x);
+    let a = (r0 - r1) / d;
+    let b = (1.0 - a * a).sqrt();
+    let left = a * u + b * n;
+    let right = a * u - b * n;
+    let mut quad = [
+        c0 + left * r0,
+        c1 + left * r1,
+        c1 + right * r1,
+        c0 + right * r0,
+    ];
+    let area = (0..4)
+        .map(|i| {
+            let (p, q) = (quad[i], quad[(i + 1) % 4]);
+            p.x * q.y - q.x * p.y
+        })
+        .sum::<f64>();
+    if area < 0.0 {
+        quad.reverse();
+    }
+    path.move_to(quad[0]);
+    path.line_to(quad[1]);
+    path.line_to(quad[2]);
+    path.line_to(quad[3]);
+    path.close_path();
+}
@@ -0,0 +1,176 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Getting rendered output into other graphics APIs.
+//!
+//! The supported path for applications with an existing OpenGL context is
+//! [`FrameReadback`], which renders on any wgpu backend and copies each frame
+//! into host memory using buffers that are reused between frames. The rows
+//! can then be uploaded with `glTexSubImage2D` using `GL_RGBA` and
+//! `GL_UNSIGNED_BYTE`, passing [`FrameReadback::stride`] divided by four as
+//! `GL_UNPACK_ROW_LENGTH`. The pixels have separate (not premultiplied)
+//! alpha, so they should be composited with
+//! `glBlendFunc(GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA)`.
+//!
+//! Sharing textures without a copy requires creating the wgpu device on top
+//! of the application's own context through wgpu's hal interop, which is
+//! backend specific and not covered here.
+//!
+//! The same applies to exporting the output as a shared NT handle on
+//! Windows: wgpu does not create textures with the shared heap flags that
+//! `ID3D12Device::CreateSharedHandle` requires. Until it does, applications
+//! compositing with DirectComposition or sharing output with another process
+//! should upload the frames from [`FrameReadback`] into a texture that they
+//! create with `D3D11_RESOURCE_MISC_SHARED_NTHANDLE` themselves.
+//!
+//! On systems without 3D acceleration, such as virtual machines and remote
+//! desktops, a device can be created on the software adapter with
+//! [`RenderContext::fallback_device`](crate::util::RenderContext::fallback_device)
+//! and frames presented through shared memory (`wl_shm` or the X11 SHM
+//! extension) from the pixels produced by [`FrameReadback::render_argb`].
+
+use wgpu::{Buffer, Device, Queue, Texture, TextureView};
+
+use crate::{block_on_wgpu, Renderer, Result, Scene};
+
+/// Renders frames and reads them back into host memory.
+pub struct FrameReadback {
+    texture: Texture,
+    view: TextureView,
+    buffer: Buffer,
+    width: u32,
+    height: u32,
+    stride: u32,
+}
+
+impl FrameReadback {
+    /// Creates a new readback target with the specified dimensions.
+    pub fn new(device: &Device, width: u32, height: u32) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("readback_target"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let stride = crate::render::next_multiple_of(width * 4, wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback_buffer"),
+            size: stride as u64 * height as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self {
+            texture,
+            view,
+            buffer,
+            width,
+            height,
+            stride,
+        }
+    }
+
+    /// Returns the width and height of the frames.
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Returns the number of bytes between the starts of consecutive rows.
+    pub fn stride(&self) -> u32 {
+        self.stride
+    }
+
+    /// Renders the scene and reads the frame back, passing the rows of RGBA
+    /// pixels with [`stride`](Self::stride) bytes per row to `f`.
+    ///
+    /// This blocks until the GPU has finished rendering.
+    pub fn render(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        f: impl FnOnce(&[u8]),
+    ) -> Result<()> {
+        renderer.render_to_texture(device, queue, scene, &self.view, self.width, self.height)?;
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_texture_to_buffer(
+            self.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &self.buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(self.stride),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1,
+            },
+        );
+        queue.submit(Some(encoder.finish()));
+        let buf_slice = self.buffer.slice(..);
+        let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+        buf_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
+        if let Some(recv_result) = block_on_wgpu(device, receiver.receive()) {
+            recv_result?;
+        } else {
+            return Err("channel was closed".into());
+        }
+        {
+            let mapped = buf_slice.get_mapped_range();
+            f(&mapped);
+        }
+        self.buffer.unmap();
+        Ok(())
+    }
+
+    /// Renders the scene and reads the frame back as tightly packed,
+    /// premultiplied `0xAARRGGBB` pixels, the layout of the `ARGB8888` format
+    /// used by shared memory buffers for presentation.
+    pub fn render_argb(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        pixels: &mut Vec<u32>,
+    ) -> Result<()> {
+        let row_size = self.width as usize * 4;
+        let stride = self.stride as usize;
+        pixels.clear();
+        self.render(renderer, device, queue, scene, |data| {
+            for row in data.chunks(stride) {
+                pixels.extend(row[..row_size].chunks_exact(4).map(|rgba| {
+                    let a = rgba[3] as u32;
+                    let premul = |c: u8| (c as u32 * a + 127) / 255;
+                    (a << 24) | (premul(rgba[0]) << 16) | (premul(rgba[1]) << 8) | premul(rgba[2])
+                }));
+            }
+        })
+    }
+}
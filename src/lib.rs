@@ -26,15 +26,54 @@ pub use peniko::kurbo;
 
 pub mod encoding;
 
+pub mod blur;
+pub mod budget;
+pub mod canvas;
+pub mod clip_cache;
+pub mod compressed;
+pub mod decoration;
+pub mod filter;
 pub mod glyph;
+pub mod gradient;
+pub mod image;
+pub mod ink;
+pub mod interop;
+pub mod mesh;
+pub mod nine_slice;
+pub mod pattern;
+pub mod procedural;
+pub mod profiling;
+pub mod scroll;
+pub mod snap;
+pub mod text;
 pub mod util;
+pub mod validation;
+pub mod watchdog;
+pub mod yuv;
 
+use budget::RenderBudget;
+use encoding::resource::ResourceCache;
+pub use encoding::resource::{
+    AsyncImage, BrushHandle, CustomResource, Placeholder, ResolveContext, ResourceUsage,
+};
+use encoding::{IncompatibleEncoding, SanitizeLimits, SanitizeReport};
 use render::Render;
-pub use scene::{Scene, SceneBuilder, SceneFragment};
+pub use scene::{MaskMode, Scene, SceneBuilder, SceneFragment};
 pub use util::block_on_wgpu;
+#[cfg(not(target_arch = "wasm32"))]
+pub use util::block_on_wgpu_timeout;
 
 use engine::{Engine, ExternalResource, Recording};
+use profiling::{profile_scope, GpuTimings};
 use shaders::FullShaders;
+pub use shaders::ShaderSources;
+use validation::{SceneStats, ValidationCapture, ValidationError};
+use watchdog::GpuHang;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use wgpu::{Device, Queue, SurfaceTexture, TextureFormat, TextureView};
 
@@ -48,22 +87,874 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub struct Renderer {
     engine: Engine,
     shaders: FullShaders,
+    sources: ShaderSources,
     blit: BlitPipeline,
     target: Option<TargetTexture>,
+    resources: ResourceCache,
+    atlas: ImageAtlas,
+    pacing: FramePacing,
+    quality: RenderQuality,
+    last_submission: Option<wgpu::SubmissionIndex>,
+    in_flight: VecDeque<(wgpu::SubmissionIndex, Arc<AtomicBool>)>,
+    degradations: Vec<Degradation>,
+    capacity_callback: Option<Box<dyn Fn(&CapacityEvent) + Send + Sync>>,
+    compressed_decoder: Option<compressed::CompressedDecoder>,
+    blur: Option<blur::LayerBlur>,
+    /// Blurred backdrops registered for the previous render.
+    backdrop_images: Vec<peniko::Image>,
+    gpu_profiling: bool,
+    gpu_timings: Vec<GpuTimings>,
+    validation: ValidationCapture,
+    validation_callback: Option<Box<dyn Fn(&ValidationError) + Send + Sync>>,
+    layers: HashMap<u64, LayerTexture>,
+    sanitize_limits: Option<SanitizeLimits>,
+    sanitize_report: SanitizeReport,
+    render_budget: Option<RenderBudget>,
+    /// Dispatches submitted for the current render, counted against the
+    /// render budget.
+    budget_dispatches: u32,
+    watchdog: Option<Duration>,
+    /// Hang that was detected by the watchdog, with a flag that is set once
+    /// the work submitted before it has completed.
+    hang: Option<(GpuHang, Arc<AtomicBool>)>,
+}
+
+/// Event reported when a render reaches an internal capacity limit.
+///
+/// These are not errors, but frequent events point at scenes that are more
+/// expensive to render than they appear, so applications can aggregate them
+/// with [`Renderer::set_capacity_callback`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CapacityEvent {
+    /// The image atlas was reallocated with a larger size to fit new images,
+    /// copying its previous contents.
+    AtlasGrown {
+        /// New width of the atlas.
+        width: u32,
+        /// New height of the atlas.
+        height: u32,
+    },
+    /// The image atlas exceeded its maximum size and was repacked, so every
+    /// image in the scene was uploaded again.
+    AtlasRepacked,
+    /// The images of a frame did not fit in the largest texture supported by
+    /// the device, so the images outside of it were not drawn and the atlas
+    /// is repacked for the next frame.
+    AtlasOverflow {
+        /// Width of the atlas that the images required.
+        width: u32,
+        /// Height of the atlas that the images required.
+        height: u32,
+        /// Maximum width and height of textures on the device.
+        max_size: u32,
+    },
+    /// An internal buffer was too small for the scene, so its contents were
+    /// truncated. Only detected by the async render methods, which read
+    /// back the allocation counters.
+    BufferOverflow {
+        /// Name of the buffer.
+        buffer: &'static str,
+        /// Number of elements that the scene required.
+        required: u32,
+        /// Number of elements in the buffer.
+        capacity: u32,
+    },
+    /// Layers are nested deeper than the blend stack that the fine shader
+    /// keeps in registers.
+    LayerSpill {
+        /// Maximum nesting depth of layers in the scene.
+        depth: u32,
+        /// Depth of the blend stack in registers.
+        limit: u32,
+    },
+}
+
+/// Depth of the blend stack held in registers by the fine shader, which must
+/// match `BLEND_STACK_SPLIT` in the shaders.
+const BLEND_STACK_SPLIT: u32 = 4;
+
+/// Reduced functionality that the renderer selected because the device does
+/// not support the default configuration.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Degradation {
+    /// The image atlas is limited to the maximum texture size of the device,
+    /// which causes images to be uploaded again more often.
+    SmallImageAtlas {
+        /// Maximum width and height of the atlas.
+        max_size: u32,
+    },
+}
+
+/// Texture size below which the image atlas is limited.
+const ATLAS_TEXTURE_SIZE: u32 = 8192;
+
+/// Quality settings that trade fidelity for power consumption.
+///
+/// All settings can be changed between frames without rebuilding pipelines.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct RenderQuality {
+    /// Applies ordered dithering to the output to reduce banding. This is
+    /// off in the presets since it changes the output of every pixel.
+    pub dithering: bool,
+    /// Scale of the internal render target relative to the surface when
+    /// rendering to a surface. Values below 1 reduce the cost of
+    /// rasterization at the expense of resolution.
+    pub scale: f32,
+    /// Reduces the number of samples taken by blurs.
+    pub simplify_blurs: bool,
+    /// Maximum distance in pixels of the render target between a curve and
+    /// the line segments it is flattened into. Smaller values give higher
+    /// fidelity at the cost of more segments. Values below
+    /// [`MIN_TOLERANCE`](Self::MIN_TOLERANCE) are clamped.
+    pub tolerance: f32,
+    /// Replaces the final colors with a false color visualization for
+    /// debugging.
+    pub debug_view: DebugView,
+}
+
+/// False color visualizations of the rasterized geometry.
+///
+/// These render every draw with a color derived from its geometry instead of
+/// its brush, which helps with diagnosing fill rule and flattening problems.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum DebugView {
+    /// Regular rendering.
+    #[default]
+    None,
+    /// Renders the signed winding number of fills before the fill rule is
+    /// applied. Positive winding numbers are red and negative ones are blue,
+    /// with brightness increasing up to a magnitude of four.
+    Winding,
+    /// Renders the antialiased coverage of every draw in white.
+    Coverage,
+}
+
+impl RenderQuality {
+    /// Smallest supported flattening tolerance.
+    pub const MIN_TOLERANCE: f32 = 0.01;
+
+    /// Full quality rendering.
+    pub const HIGH: Self = Self {
+        dithering: false,
+        scale: 1.0,
+        simplify_blurs: false,
+        tolerance: 0.25,
+        debug_view: DebugView::None,
+    };
+
+    /// Reduced quality rendering for low power states.
+    pub const LOW_POWER: Self = Self {
+        dithering: false,
+        scale: 0.5,
+        simplify_blurs: true,
+        tolerance: 0.25,
+        debug_view: DebugView::None,
+    };
+}
+
+impl Default for RenderQuality {
+    fn default() -> Self {
+        Self::HIGH
+    }
+}
+
+/// Policy for limiting the number of frames that are submitted to the GPU
+/// but have not finished rendering.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum FramePacing {
+    /// Frames are never held back.
+    #[default]
+    Unlimited,
+    /// [`Renderer::begin_frame`] blocks until fewer than the given number of
+    /// frames are in flight.
+    Block(u32),
+    /// [`Renderer::begin_frame`] returns false while the given number of
+    /// frames are in flight, so that the frame can be skipped.
+    Skip(u32),
+}
+
+/// A layer that is rendered into a texture managed by the renderer and
+/// drawn as an image.
+///
+/// Created with [`Renderer::cache_layer`]. Complex content that rarely
+/// changes, such as the base layer of a map, is rasterized once and then
+/// composited as an image in subsequent frames until it is invalidated with
+/// [`Renderer::invalidate_layer`].
+#[derive(Clone, Debug)]
+pub struct CachedLayer {
+    image: peniko::Image,
+}
+
+impl CachedLayer {
+    /// Returns the image that draws the contents of the layer, at one pixel
+    /// per unit, for example with
+    /// [`SceneBuilder::draw_image`](crate::SceneBuilder::draw_image).
+    pub fn image(&self) -> &peniko::Image {
+        &self.image
+    }
+
+    /// Returns the width of the layer in pixels.
+    pub fn width(&self) -> u32 {
+        self.image.width
+    }
+
+    /// Returns the height of the layer in pixels.
+    pub fn height(&self) -> u32 {
+        self.image.height
+    }
+}
+
+/// Texture of a cached layer, which is also registered with the atlas.
+struct LayerTexture {
+    view: TextureView,
+    valid: bool,
 }
 
 impl Renderer {
     /// Creates a new renderer for the specified device.
+    #[cfg(feature = "embedded_shaders")]
     pub fn new(device: &Device) -> Result<Self> {
+        Self::with_shaders(device, ShaderSources::embedded())
+    }
+
+    /// Creates a new renderer for the specified device that compiles the
+    /// shaders from the given sources.
+    ///
+    /// Devices that do not support the default configuration are handled by
+    /// selecting reduced functionality where possible, which is reported by
+    /// [`degradations`](Self::degradations). An error is returned only if
+    /// the device cannot run the pipeline at all.
+    pub fn with_shaders(device: &Device, sources: ShaderSources) -> Result<Self> {
+        check_limits(device)?;
         let mut engine = Engine::new();
-        let shaders = shaders::full_shaders(device, &mut engine)?;
+        let shaders = shaders::full_shaders(device, &mut engine, &sources)?;
         let blit = BlitPipeline::new(device, TextureFormat::Bgra8Unorm);
-        Ok(Self {
+        let mut renderer = Self {
             engine,
             shaders,
+            sources,
             blit,
             target: None,
-        })
+            resources: ResourceCache::new(),
+            atlas: ImageAtlas::default(),
+            pacing: FramePacing::default(),
+            quality: RenderQuality::default(),
+            last_submission: None,
+            in_flight: VecDeque::new(),
+            degradations: vec![],
+            capacity_callback: None,
+            compressed_decoder: None,
+            blur: None,
+            backdrop_images: vec![],
+            gpu_profiling: false,
+            gpu_timings: vec![],
+            validation: ValidationCapture::Off,
+            validation_callback: None,
+            layers: HashMap::new(),
+            sanitize_limits: None,
+            sanitize_report: SanitizeReport::default(),
+            render_budget: None,
+            budget_dispatches: 0,
+            watchdog: None,
+            hang: None,
+        };
+        renderer.adapt_to_device(device);
+        Ok(renderer)
+    }
+
+    /// Sets a callback that is invoked with each [`CapacityEvent`] during
+    /// rendering, for example to log how often users reach the limits of the
+    /// renderer.
+    pub fn set_capacity_callback(
+        &mut self,
+        callback: impl Fn(&CapacityEvent) + Send + Sync + 'static,
+    ) {
+        self.capacity_callback = Some(Box::new(callback));
+    }
+
+    /// Removes the callback set with
+    /// [`set_capacity_callback`](Self::set_capacity_callback).
+    pub fn clear_capacity_callback(&mut self) {
+        self.capacity_callback = None;
+    }
+
+    fn report_capacity(&self, events: &[CapacityEvent]) {
+        if let Some(callback) = &self.capacity_callback {
+            for event in events {
+                callback(event);
+            }
+        }
+    }
+
+    /// Sets whether the GPU time of each stage of the pipeline is measured.
+    ///
+    /// Returns false if the device does not support
+    /// [`TIMESTAMP_QUERY`](wgpu::Features::TIMESTAMP_QUERY), in which case
+    /// only the CPU scopes are reported to the profilers. Timings are
+    /// available from [`gpu_timings`](Self::gpu_timings) a frame or two after
+    /// they were measured. See the [`profiling`] module.
+    pub fn set_gpu_profiling(&mut self, device: &Device, enabled: bool) -> bool {
+        self.gpu_profiling = enabled;
+        self.engine.set_timestamps(enabled);
+        if !enabled {
+            self.gpu_timings.clear();
+        }
+        device.features().contains(wgpu::Features::TIMESTAMP_QUERY)
+    }
+
+    /// Returns the GPU timings of the submissions that were read back most
+    /// recently, oldest first.
+    ///
+    /// Timings are read back while the device is polled, and collected by
+    /// [`begin_frame`](Self::begin_frame) and at the start of each render.
+    pub fn gpu_timings(&self) -> &[GpuTimings] {
+        &self.gpu_timings
+    }
+
+    /// Collects the GPU timings that have been read back and reports them to
+    /// the profilers.
+    fn collect_gpu_timings(&mut self) {
+        if !self.gpu_profiling {
+            return;
+        }
+        let timings = self.engine.take_timings();
+        if timings.is_empty() {
+            return;
+        }
+        for timing in &timings {
+            profiling::report_gpu_timings(timing);
+        }
+        self.gpu_timings = timings;
+    }
+
+    /// Sets the granularity at which wgpu validation errors are captured.
+    ///
+    /// Captured errors are returned from the render as a [`ValidationError`]
+    /// with the stage and statistics of the scene, and passed to the
+    /// callback set with
+    /// [`set_validation_callback`](Self::set_validation_callback). See the
+    /// [`validation`] module.
+    pub fn set_validation_capture(&mut self, capture: ValidationCapture) {
+        self.validation = capture;
+        self.engine.set_validation_capture(capture);
+    }
+
+    /// Sets a callback that is invoked with each captured validation error,
+    /// for example to log it before the error is returned.
+    pub fn set_validation_callback(
+        &mut self,
+        callback: impl Fn(&ValidationError) + Send + Sync + 'static,
+    ) {
+        self.validation_callback = Some(Box::new(callback));
+    }
+
+    /// Removes the callback set with
+    /// [`set_validation_callback`](Self::set_validation_callback).
+    pub fn clear_validation_callback(&mut self) {
+        self.validation_callback = None;
+    }
+
+    /// Runs a recording of the given encoding, attaching the statistics of
+    /// the encoding to captured validation errors.
+    #[allow(clippy::too_many_arguments)]
+    fn run_recording(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        recording: &Recording,
+        external_resources: &[ExternalResource],
+        encoding: &encoding::Encoding,
+        width: u32,
+        height: u32,
+    ) -> Result<wgpu::SubmissionIndex> {
+        if let Some(budget) = &self.render_budget {
+            self.budget_dispatches += recording.n_dispatches();
+            budget.check_dispatches(self.budget_dispatches)?;
+        }
+        let result = self
+            .engine
+            .run_recording(device, queue, recording, external_resources)
+            .map_err(|err| match err.downcast::<ValidationError>() {
+                Ok(mut err) => {
+                    err.stats = SceneStats::new(encoding, width, height);
+                    if let Some(callback) = &self.validation_callback {
+                        callback(&err);
+                    }
+                    err as Error
+                }
+                Err(err) => err,
+            });
+        if let Err(err) = &result {
+            if let Some(hang) = err.downcast_ref::<GpuHang>() {
+                self.enter_hang(*hang, work_done_flag(queue));
+            }
+        }
+        result
+    }
+
+    /// Sets the timeout after which the renderer gives up waiting for the
+    /// GPU, or removes it if `timeout` is `None`. See the [`watchdog`]
+    /// module.
+    ///
+    /// Without a timeout, the renderer blocks until the GPU completes the
+    /// work, however long that takes. The timeout is ignored on wasm.
+    pub fn set_watchdog(&mut self, timeout: Option<Duration>) {
+        self.watchdog = timeout.filter(|_| !cfg!(target_arch = "wasm32"));
+        self.engine.set_watchdog(self.watchdog);
+    }
+
+    /// Returns the hang that was detected by the watchdog, if the work that
+    /// hung has not completed since.
+    pub fn gpu_hang(&mut self, device: &Device) -> Option<GpuHang> {
+        let (hang, done) = self.hang.clone()?;
+        device.poll(wgpu::Maintain::Poll);
+        if done.load(Ordering::Acquire) {
+            self.hang = None;
+            return None;
+        }
+        Some(hang)
+    }
+
+    /// Blocks until the most recent render has completed, for at most the
+    /// timeout of the watchdog.
+    ///
+    /// This is like [`wait_for_render`](Self::wait_for_render), but fails
+    /// with a [`GpuHang`] when the timeout elapses.
+    pub fn try_wait_for_render(&mut self, device: &Device, queue: &Queue) -> Result<()> {
+        match self.watchdog {
+            Some(timeout) => {
+                let done = work_done_flag(queue);
+                if !watchdog::wait_for_flag(device, &done, timeout) {
+                    let hang = GpuHang {
+                        operation: "render completion",
+                        timeout,
+                    };
+                    return Err(self.enter_hang(hang, done).into());
+                }
+            }
+            None => self.wait_for_render(device),
+        }
+        Ok(())
+    }
+
+    /// Fails with the hang detected by the watchdog, if there is one, so
+    /// that no more work is queued behind the work that hung.
+    fn check_hang(&mut self, device: &Device) -> Result<()> {
+        match self.gpu_hang(device) {
+            Some(hang) => Err(hang.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Blocks on a future that awaits GPU work, for at most the timeout of
+    /// the watchdog.
+    #[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+    fn block_on<F: std::future::Future>(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        fut: F,
+        operation: &'static str,
+    ) -> Result<F::Output> {
+        match self.watchdog {
+            // The watchdog is never enabled on the web.
+            #[cfg(not(target_arch = "wasm32"))]
+            Some(timeout) => match block_on_wgpu_timeout(device, fut, timeout) {
+                Some(output) => Ok(output),
+                None => {
+                    let hang = GpuHang { operation, timeout };
+                    Err(self.enter_hang(hang, work_done_flag(queue)).into())
+                }
+            },
+            _ => Ok(block_on_wgpu(device, fut)),
+        }
+    }
+
+    /// Records a hang detected by the watchdog. Frames in flight are no
+    /// longer tracked, since waiting for them would block on the hang.
+    fn enter_hang(&mut self, hang: GpuHang, done: Arc<AtomicBool>) -> GpuHang {
+        self.in_flight.clear();
+        self.last_submission = None;
+        self.hang = Some((hang, done));
+        hang
+    }
+
+    /// Sets the limits that scenes are sanitized to before they are
+    /// rendered, or disables sanitization if `limits` is `None`.
+    ///
+    /// This is intended for scenes built from untrusted input, such as user
+    /// supplied SVG documents rendered on a server. Each scene is copied and
+    /// cleaned with [`Encoding::sanitize`](encoding::Encoding::sanitize), so
+    /// this adds the cost of a copy of the encoding to every render.
+    pub fn set_sanitize_limits(&mut self, limits: Option<SanitizeLimits>) {
+        self.sanitize_limits = limits;
+        self.sanitize_report = SanitizeReport::default();
+    }
+
+    /// Returns what was changed when the most recently rendered scene was
+    /// sanitized.
+    pub fn sanitize_report(&self) -> SanitizeReport {
+        self.sanitize_report
+    }
+
+    /// Returns a sanitized copy of a scene whose instances are expanded, if
+    /// sanitization is enabled.
+    fn sanitize_scene(&mut self, scene: &Scene) -> Option<Scene> {
+        let limits = self.sanitize_limits?;
+        let mut encoding = scene.data().clone();
+        self.sanitize_report = encoding.sanitize(&limits);
+        Some(Scene::from_encoding(encoding))
+    }
+
+    /// Sets the limits on the work of each render, or removes them if
+    /// `budget` is `None`.
+    ///
+    /// A render that exceeds the budget fails with a
+    /// [`BudgetExceeded`](budget::BudgetExceeded) error. Segments and tiles
+    /// are checked before anything is submitted. Dispatches are counted as
+    /// the submissions of the render are made, so a render with backdrop
+    /// layers may submit the renders of some backdrops before it fails.
+    pub fn set_render_budget(&mut self, budget: Option<RenderBudget>) {
+        self.render_budget = budget;
+    }
+
+    /// Checks the scene against the render budget and starts counting the
+    /// dispatches of its render.
+    fn begin_budget(
+        &mut self,
+        scene: &Scene,
+        width: u32,
+        height: u32,
+        quality: &RenderQuality,
+    ) -> Result<()> {
+        self.budget_dispatches = 0;
+        if let Some(budget) = &self.render_budget {
+            budget.check_scene(scene.data(), width, height, quality.scale)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the reduced functionality that was selected for the device.
+    pub fn degradations(&self) -> &[Degradation] {
+        &self.degradations
+    }
+
+    /// Selects the configuration for the limits of the device.
+    fn adapt_to_device(&mut self, device: &Device) {
+        self.degradations.clear();
+        let max_size = device.limits().max_texture_dimension_2d;
+        self.resources.set_max_texture_size(max_size);
+        if max_size < ATLAS_TEXTURE_SIZE {
+            self.degradations
+                .push(Degradation::SmallImageAtlas { max_size });
+        }
+    }
+
+    /// Moves the renderer to a new device, for example when a window moves to
+    /// a monitor driven by another GPU.
+    ///
+    /// Pipelines are recreated on the new device when they are next needed
+    /// and all images are uploaded again when they are next used.
+    pub fn migrate(&mut self, device: &Device) -> Result<()> {
+        check_limits(device)?;
+        let mut engine = Engine::new();
+        let shaders = shaders::full_shaders(device, &mut engine, &self.sources)?;
+        self.engine = engine;
+        self.shaders = shaders;
+        self.blit = BlitPipeline::new(device, TextureFormat::Bgra8Unorm);
+        self.target = None;
+        self.atlas = ImageAtlas::default();
+        self.resources.invalidate_images();
+        // Frames submitted to the old device no longer hold back new frames.
+        self.in_flight.clear();
+        self.compressed_decoder = None;
+        self.blur = None;
+        self.backdrop_images.clear();
+        // Cached layers are rendered again on the new device when they are
+        // next updated.
+        self.layers.clear();
+        self.engine.set_timestamps(self.gpu_profiling);
+        self.engine.set_validation_capture(self.validation);
+        self.engine.set_watchdog(self.watchdog);
+        // Work that hung on the old device does not block the new one.
+        self.hang = None;
+        self.gpu_timings.clear();
+        self.adapt_to_device(device);
+        Ok(())
+    }
+
+    /// Creates the pipelines of all shaders used by the renderer.
+    ///
+    /// Pipelines are otherwise created when a shader is first needed, which
+    /// keeps startup fast and skips the shaders that are only used for very
+    /// large scenes, but can make the first frames slow. Calling this at a
+    /// convenient time, for example while a loading screen is shown, moves
+    /// that cost out of the first frames. wgpu does not expose asynchronous
+    /// pipeline creation, so this blocks until the pipelines are created.
+    ///
+    /// The pipelines are compiled concurrently on as many threads as the
+    /// system reports to be available.
+    pub fn prepare_pipelines(&mut self, device: &Device) {
+        self.prepare_pipelines_with_parallelism(device, default_parallelism());
+    }
+
+    /// Creates the pipelines of all shaders used by the renderer, compiling
+    /// up to `parallelism` of them concurrently on scoped threads. A value of
+    /// one or less compiles them on the calling thread, which is always the
+    /// case on wasm.
+    pub fn prepare_pipelines_with_parallelism(&mut self, device: &Device, parallelism: usize) {
+        self.engine.create_pipelines(device, parallelism);
+    }
+
+    /// Returns the number of pipelines that have been created so far.
+    pub fn n_pipelines(&self) -> usize {
+        self.engine.n_pipelines()
+    }
+
+    /// Returns the submission index of the most recent render.
+    ///
+    /// Once the submission has completed, the output texture can be consumed
+    /// by other APIs, for example with
+    /// [`wgpu::Maintain::WaitForSubmissionIndex`] or through the native fence
+    /// of the queue obtained with wgpu's hal interop. Exportable fences are
+    /// not exposed by wgpu and are not provided here.
+    pub fn last_submission(&self) -> Option<&wgpu::SubmissionIndex> {
+        self.last_submission.as_ref()
+    }
+
+    /// Blocks until the most recent render has completed.
+    pub fn wait_for_render(&self, device: &Device) {
+        if let Some(index) = &self.last_submission {
+            device.poll(wgpu::Maintain::WaitForSubmissionIndex(index.clone()));
+        }
+    }
+
+    /// Invokes the callback once all work submitted to the queue so far,
+    /// including the most recent render, has completed.
+    ///
+    /// The callback is invoked while the device is polled, for example by
+    /// [`Device::poll`] or during a later submission.
+    pub fn on_render_complete(&self, queue: &Queue, callback: impl FnOnce() + Send + 'static) {
+        queue.on_submitted_work_done(callback);
+    }
+
+    /// Sets the quality settings used for subsequent frames, for example to
+    /// reduce power consumption when the application enters a low power
+    /// state.
+    pub fn set_quality(&mut self, quality: RenderQuality) {
+        self.quality = quality;
+    }
+
+    /// Returns the current quality settings.
+    pub fn quality(&self) -> RenderQuality {
+        self.quality
+    }
+
+    /// Sets the policy for limiting the number of frames in flight.
+    ///
+    /// Without a limit, heavy scenes can be submitted faster than the GPU
+    /// renders them, so that latency grows without bound.
+    pub fn set_frame_pacing(&mut self, pacing: FramePacing) {
+        self.pacing = pacing;
+    }
+
+    /// Returns the number of frames rendered to a surface that the GPU has
+    /// not yet finished.
+    pub fn frames_in_flight(&mut self, device: &Device) -> u32 {
+        device.poll(wgpu::Maintain::Poll);
+        self.retire_frames();
+        self.collect_gpu_timings();
+        self.in_flight.len() as u32
+    }
+
+    /// Applies the frame pacing policy before starting a new frame.
+    ///
+    /// This should be called before acquiring the surface texture. Returns
+    /// false if the frame should be skipped, which is also the case while
+    /// the watchdog reports a hang.
+    pub fn begin_frame(&mut self, device: &Device) -> bool {
+        if self.gpu_hang(device).is_some() {
+            return false;
+        }
+        let in_flight = self.frames_in_flight(device);
+        match self.pacing {
+            FramePacing::Unlimited => true,
+            FramePacing::Block(max) => {
+                let max = max.max(1) as usize;
+                while self.in_flight.len() >= max {
+                    let (index, done) = self.in_flight.pop_front().unwrap();
+                    match self.watchdog {
+                        Some(timeout) => {
+                            if !watchdog::wait_for_flag(device, &done, timeout) {
+                                let hang = GpuHang {
+                                    operation: "frame pacing",
+                                    timeout,
+                                };
+                                self.enter_hang(hang, done);
+                                return false;
+                            }
+                        }
+                        None => {
+                            device.poll(wgpu::Maintain::WaitForSubmissionIndex(index));
+                        }
+                    }
+                }
+                true
+            }
+            FramePacing::Skip(max) => in_flight < max.max(1),
+        }
+    }
+
+    fn track_frame(&mut self, queue: &Queue, index: wgpu::SubmissionIndex) {
+        self.in_flight.push_back((index, work_done_flag(queue)));
+    }
+
+    fn retire_frames(&mut self) {
+        while let Some((_, done)) = self.in_flight.front() {
+            if !done.load(Ordering::Acquire) {
+                break;
+            }
+            self.in_flight.pop_front();
+        }
+    }
+
+    /// Uploads images ahead of the frame that first uses them.
+    ///
+    /// Images are otherwise uploaded when a scene that references them is
+    /// rendered, which can cause a hitch for large images. Images that were
+    /// already uploaded are skipped.
+    pub fn prepare_images(&mut self, device: &Device, queue: &Queue, images: &[peniko::Image]) {
+        for image in images {
+            self.resources.add_image(image);
+        }
+        let events = self.atlas.update(device, queue, &mut self.resources);
+        self.report_capacity(&events);
+    }
+
+    /// Replaces the pixels in a rectangle of an image with tightly packed
+    /// RGBA8 data, for example to stream video frames into a single image.
+    ///
+    /// Only the rectangle is uploaded, before the next frame is rendered.
+    /// Scenes keep referencing the same image. Returns false if the rectangle
+    /// is not inside the image or the data does not match its size.
+    pub fn update_image(
+        &mut self,
+        image: &peniko::Image,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        data: &[u8],
+    ) -> bool {
+        self.resources
+            .update_image(image, x, y, width, height, data)
+    }
+
+    /// Registers a texture that already holds content on the GPU, such as the
+    /// output of another render pass, and returns an image that draws it when
+    /// used as a brush.
+    ///
+    /// The contents are copied into the image atlas on the GPU, without a
+    /// round trip through host memory, when the image is first drawn and
+    /// again after the atlas is repacked. Call
+    /// [`invalidate_texture`](Self::invalidate_texture) after changing the
+    /// texture so that the next render copies it again. The copy requires the
+    /// texture rather than a view of it. Returns `None` unless the texture
+    /// has the `Rgba8Unorm` format with straight alpha, a single layer, a
+    /// nonzero size and the `COPY_SRC` usage.
+    pub fn register_texture(&mut self, texture: wgpu::Texture) -> Option<peniko::Image> {
+        if texture.format() != TextureFormat::Rgba8Unorm
+            || texture.depth_or_array_layers() != 1
+            || texture.width() == 0
+            || texture.height() == 0
+            || !texture.usage().contains(wgpu::TextureUsages::COPY_SRC)
+        {
+            return None;
+        }
+        let image = peniko::Image::new(
+            Vec::new().into(),
+            peniko::Format::Rgba8,
+            texture.width(),
+            texture.height(),
+        );
+        self.atlas
+            .external
+            .insert(image.data.id(), ExternalTexture::new(texture));
+        Some(image)
+    }
+
+    /// Marks the contents of a texture registered with
+    /// [`register_texture`](Self::register_texture) as changed, so that the
+    /// next render copies them into the image atlas again.
+    pub fn invalidate_texture(&mut self, image: &peniko::Image) {
+        if let Some(external) = self.atlas.external.get_mut(&image.data.id()) {
+            external.copied = None;
+        }
+    }
+
+    /// Removes a texture registered with
+    /// [`register_texture`](Self::register_texture) and frees its place in
+    /// the image atlas. Scenes that still draw its image draw it transparent.
+    pub fn unregister_texture(&mut self, image: &peniko::Image) -> Option<wgpu::Texture> {
+        let id = image.data.id();
+        self.resources.remove_image(id);
+        self.atlas
+            .external
+            .remove(&id)
+            .map(|external| external.texture)
+    }
+
+    /// Loads a block compressed image and returns an image that draws it
+    /// when used as a brush.
+    ///
+    /// If the device has the features required by the format and the image
+    /// fits its texture limits, the blocks are uploaded as they are and
+    /// decoded on the GPU once into a texture that is registered as with
+    /// [`register_texture`](Self::register_texture), and can be released with
+    /// [`unregister_texture`](Self::unregister_texture). The decoded texture
+    /// never changes, so it is only copied into the image atlas when the
+    /// image is first drawn and after the atlas is repacked. Otherwise the
+    /// image is decompressed on the CPU, which returns an error for formats
+    /// without a CPU decoder.
+    pub fn load_compressed_image(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        image: &compressed::CompressedImage,
+    ) -> Result<peniko::Image> {
+        let format = image.format();
+        let (bw, bh) = format.block_dimensions();
+        let max_size = device.limits().max_texture_dimension_2d;
+        // The blocks are uploaded to a texture rounded up to whole blocks.
+        let fits = image.width().saturating_add(bw - 1) <= max_size
+            && image.height().saturating_add(bh - 1) <= max_size;
+        if fits && device.features().contains(format.required_features()) {
+            let decoder = self
+                .compressed_decoder
+                .get_or_insert_with(|| compressed::CompressedDecoder::new(device));
+            let texture = decoder.decode(device, queue, image);
+            return Ok(self
+                .register_texture(texture)
+                .expect("decoded textures can be registered"));
+        }
+        image
+            .decompress()
+            .ok_or_else(|| format!("{format:?} images are not supported by the device").into())
+    }
+
+    /// Serializes the gradient ramp cache so that it can be restored with
+    /// [`preload_ramps`](Self::preload_ramps) at startup.
+    pub fn save_ramps(&self) -> Vec<u8> {
+        self.resources.save_ramps()
+    }
+
+    /// Warms the gradient ramp cache from data produced by
+    /// [`save_ramps`](Self::save_ramps) and returns the number of ramps.
+    pub fn preload_ramps(
+        &mut self,
+        data: &[u8],
+    ) -> std::result::Result<usize, IncompatibleEncoding> {
+        self.resources.preload_ramps(data)
     }
 
     /// Renders a scene to the target texture.
@@ -80,16 +971,161 @@ impl Renderer {
         width: u32,
         height: u32,
     ) -> Result<()> {
-        let (recording, target) = render::render_full(scene, &self.shaders, width, height);
-        let external_resources = [ExternalResource::Image(
-            *target.as_image().unwrap(),
-            texture,
-        )];
-        self.engine
-            .run_recording(device, queue, &recording, &external_resources)?;
+        let quality = RenderQuality {
+            scale: 1.0,
+            ..self.quality
+        };
+        self.render_to_texture_with(device, queue, scene, texture, width, height, &quality)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn render_to_texture_with(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        texture: &TextureView,
+        width: u32,
+        height: u32,
+        quality: &RenderQuality,
+    ) -> Result<()> {
+        self.check_hang(device)?;
+        // Instances are expanded once for each change to the scene, and
+        // everything that follows works with the expanded scene.
+        let expanded = scene.expanded();
+        let scene = expanded.as_deref().unwrap_or(scene);
+        let sanitized = self.sanitize_scene(scene);
+        let scene = sanitized.as_ref().unwrap_or(scene);
+        self.begin_budget(scene, width, height, quality)?;
+        self.render_backdrops(device, queue, scene, width, height, quality)?;
+        self.render_unfiltered(device, queue, scene, texture, width, height, quality)
+    }
+
+    /// Renders a scene without resolving the backdrops of its backdrop
+    /// layers.
+    #[allow(clippy::too_many_arguments)]
+    fn render_unfiltered(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        texture: &TextureView,
+        width: u32,
+        height: u32,
+        quality: &RenderQuality,
+    ) -> Result<()> {
+        self.collect_gpu_timings();
+        let (recording, target, image_atlas) = {
+            profile_scope!("encode");
+            render::render_full(
+                scene,
+                &self.shaders,
+                &mut self.resources,
+                quality,
+                width,
+                height,
+            )
+        };
+        let mut events = {
+            profile_scope!("upload_images");
+            self.atlas.update(device, queue, &mut self.resources)
+        };
+        events.extend(layer_spill(scene.data()));
+        self.report_capacity(&events);
+        let external_resources = [
+            ExternalResource::Image(*target.as_image().unwrap(), texture),
+            ExternalResource::Image(image_atlas, self.atlas.view()),
+        ];
+        let index = self.run_recording(
+            device,
+            queue,
+            &recording,
+            &external_resources,
+            scene.data(),
+            width,
+            height,
+        )?;
+        self.last_submission = Some(index);
         Ok(())
     }
 
+    /// Renders the content beneath each backdrop layer of a scene, blurs it
+    /// and resolves the image that the layer draws to the result.
+    ///
+    /// Backdrops are rendered in scene coordinates, so the size of the target
+    /// is divided by the render scale of the quality.
+    fn render_backdrops(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        width: u32,
+        height: u32,
+        quality: &RenderQuality,
+    ) -> Result<()> {
+        profile_scope!("render_backdrops");
+        // The previous render has been submitted, so the atlas holds copies
+        // of the previous backdrops.
+        for image in std::mem::take(&mut self.backdrop_images) {
+            self.unregister_texture(&image);
+        }
+        let backdrops = &scene.data().backdrops;
+        if backdrops.is_empty() {
+            return Ok(());
+        }
+        let (width, height) = scaled_size(width, height, 1.0 / quality.scale);
+        let unscaled = RenderQuality {
+            scale: 1.0,
+            ..*quality
+        };
+        let blur = self
+            .blur
+            .take()
+            .unwrap_or_else(|| blur::LayerBlur::new(device));
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("backdrop"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+            format: TextureFormat::Rgba8Unorm,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let mut result = Ok(());
+        for backdrop in backdrops {
+            // Earlier backdrops are drawn by the prefix, so they are
+            // resolved in order.
+            let prefix =
+                Scene::from_encoding(scene.data().prefix(&backdrop.mark, backdrop.open_layers));
+            result =
+                self.render_unfiltered(device, queue, &prefix, &view, width, height, &unscaled);
+            if result.is_err() {
+                break;
+            }
+            let blurred = blur.blur_region(
+                device,
+                queue,
+                &texture,
+                backdrop.bounds,
+                backdrop.std_dev,
+                quality.simplify_blurs,
+            );
+            let image = self
+                .register_texture(blurred)
+                .expect("blur textures can be registered");
+            backdrop.image.set(image.clone());
+            self.backdrop_images.push(image);
+        }
+        self.blur = Some(blur);
+        result
+    }
+
     /// Renders a scene to the target surface.
     ///
     /// This renders to an intermediate texture and then runs a render pass to blit to the
@@ -106,6 +1142,9 @@ impl Renderer {
         width: u32,
         height: u32,
     ) -> Result<()> {
+        profile_scope!("render_to_surface");
+        let quality = self.quality;
+        let (width, height) = scaled_size(width, height, quality.scale);
         let mut target = self
             .target
             .take()
@@ -115,7 +1154,94 @@ impl Renderer {
         if target.width != width || target.height != height {
             target = TargetTexture::new(device, width, height);
         }
-        self.render_to_texture(device, queue, scene, &target.view, width, height)?;
+        self.render_to_texture_with(device, queue, scene, &target.view, width, height, &quality)?;
+        self.blit_to_surface(device, queue, &target, surface);
+        self.target = Some(target);
+        Ok(())
+    }
+
+    /// Renders a cross-fade from one scene to another into a texture, where
+    /// `t` ranges from 0, which renders only `from`, to 1, which renders
+    /// only `to`.
+    ///
+    /// Both scenes are rasterized in one pass and mixed on the GPU as
+    /// `from * (1 - t) + to * t`, as with
+    /// [`SceneBuilder::append_crossfade`]. The requirements on the texture
+    /// are those of [`render_to_texture`](Self::render_to_texture).
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_crossfade_to_texture(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        from: &Scene,
+        to: &Scene,
+        t: f32,
+        texture: &TextureView,
+        width: u32,
+        height: u32,
+    ) -> Result<()> {
+        let scene = crossfade_scene(from, to, t, width, height);
+        self.render_to_texture(device, queue, &scene, texture, width, height)
+    }
+
+    /// Renders a cross-fade from one scene to another to the target surface.
+    ///
+    /// See [`render_crossfade_to_texture`](Self::render_crossfade_to_texture)
+    /// and [`render_to_surface`](Self::render_to_surface).
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_crossfade_to_surface(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        from: &Scene,
+        to: &Scene,
+        t: f32,
+        surface: &SurfaceTexture,
+        width: u32,
+        height: u32,
+    ) -> Result<()> {
+        let scene = crossfade_scene(from, to, t, width, height);
+        self.render_to_surface(device, queue, &scene, surface, width, height)
+    }
+
+    /// Presents the previously rendered frame to the surface without running
+    /// the pipeline again.
+    ///
+    /// This is intended for content that has not changed since the last call
+    /// to [`render_to_surface`](Self::render_to_surface), so that responding
+    /// to a compositor frame callback costs only a blit. Returns an error if
+    /// no frame has been rendered at the size of the surface.
+    pub fn present_last_frame(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        surface: &SurfaceTexture,
+    ) -> Result<()> {
+        let target = match self.target.take() {
+            Some(target) => target,
+            None => return Err("no frame has been rendered".into()),
+        };
+        let size = surface.texture.size();
+        let (width, height) = scaled_size(size.width, size.height, self.quality.scale);
+        if target.width != width || target.height != height {
+            self.target = Some(target);
+            return Err("last frame does not match the size of the surface".into());
+        }
+        self.blit_to_surface(device, queue, &target, surface);
+        self.target = Some(target);
+        Ok(())
+    }
+
+    /// Blits the target texture to the surface and tracks the submission as
+    /// a frame in flight.
+    fn blit_to_surface(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        target: &TargetTexture,
+        surface: &SurfaceTexture,
+    ) {
+        profile_scope!("blit_to_surface");
         let mut encoder =
             device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
         {
@@ -146,9 +1272,205 @@ impl Renderer {
             render_pass.set_bind_group(0, &bind_group, &[]);
             render_pass.draw(0..6, 0..1);
         }
+        let index = queue.submit(Some(encoder.finish()));
+        self.last_submission = Some(index.clone());
+        self.track_frame(queue, index);
+    }
+
+    /// Renders a scene and reads the result back into an image.
+    ///
+    /// This blocks until the GPU has finished rendering so it is intended for
+    /// content that is rendered once and reused, rather than every frame.
+    pub fn render_to_image(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        width: u32,
+        height: u32,
+    ) -> Result<peniko::Image> {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        self.render_to_texture(device, queue, scene, &view, width, height)?;
+        let row_size = width * 4;
+        let padded_row_size =
+            render::next_multiple_of(row_size, wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: padded_row_size as u64 * height as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(padded_row_size),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
         queue.submit(Some(encoder.finish()));
-        self.target = Some(target);
-        Ok(())
+        let buf_slice = buffer.slice(..);
+        let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+        buf_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
+        if let Some(recv_result) = self.block_on(device, queue, receiver.receive(), "readback")? {
+            recv_result?;
+        } else {
+            return Err("channel was closed".into());
+        }
+        let mapped = buf_slice.get_mapped_range();
+        let mut data = Vec::with_capacity((row_size * height) as usize);
+        for row in mapped.chunks(padded_row_size as usize) {
+            data.extend_from_slice(&row[..row_size as usize]);
+        }
+        Ok(peniko::Image::new(
+            data.into(),
+            peniko::Format::Rgba8,
+            width,
+            height,
+        ))
+    }
+
+    /// Renders a scene fragment into a texture of the given size that is
+    /// managed by the renderer, and returns a layer that draws it.
+    ///
+    /// The fragment is drawn in pixels of the layer. The texture is copied
+    /// into the image atlas like a texture registered with
+    /// [`register_texture`](Self::register_texture) after each render, so it
+    /// is composited as an image without rendering the fragment again.
+    /// Returns an error if the size is zero or exceeds the texture limits of
+    /// the device.
+    pub fn cache_layer(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        fragment: &SceneFragment,
+        width: u32,
+        height: u32,
+    ) -> Result<CachedLayer> {
+        let max_size = device.limits().max_texture_dimension_2d;
+        if width == 0 || height == 0 || width > max_size || height > max_size {
+            return Err(format!(
+                "layer size {width}x{height} is outside the device limit of {max_size}"
+            )
+            .into());
+        }
+        let layer = CachedLayer {
+            image: peniko::Image::new(Vec::new().into(), peniko::Format::Rgba8, width, height),
+        };
+        self.update_layer(device, queue, &layer, fragment)?;
+        Ok(layer)
+    }
+
+    /// Marks a cached layer as stale, so that the next call to
+    /// [`update_layer`](Self::update_layer) renders it again. Scenes keep
+    /// drawing the previous contents until then.
+    pub fn invalidate_layer(&mut self, layer: &CachedLayer) {
+        if let Some(texture) = self.layers.get_mut(&layer.image.data.id()) {
+            texture.valid = false;
+        }
+    }
+
+    /// Returns true if a cached layer holds the contents of its last render.
+    ///
+    /// Layers become invalid when they are invalidated and when the renderer
+    /// is migrated to another device.
+    pub fn is_layer_valid(&self, layer: &CachedLayer) -> bool {
+        self.layers
+            .get(&layer.image.data.id())
+            .map_or(false, |texture| texture.valid)
+    }
+
+    /// Renders a fragment into a cached layer if the layer is not valid,
+    /// and returns whether it was rendered.
+    pub fn update_layer(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        layer: &CachedLayer,
+        fragment: &SceneFragment,
+    ) -> Result<bool> {
+        profile_scope!("update_layer");
+        if self.is_layer_valid(layer) {
+            return Ok(false);
+        }
+        let id = layer.image.data.id();
+        if !self.layers.contains_key(&id) {
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("cached layer"),
+                size: wgpu::Extent3d {
+                    width: layer.width(),
+                    height: layer.height(),
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+                format: TextureFormat::Rgba8Unorm,
+                view_formats: &[],
+            });
+            let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+            self.atlas
+                .external
+                .insert(id, ExternalTexture::new(texture));
+            self.layers.insert(id, LayerTexture { view, valid: false });
+        }
+        let scene = Scene::from_fragment(fragment);
+        let quality = RenderQuality {
+            scale: 1.0,
+            ..self.quality
+        };
+        // The view is taken out of the map for the duration of the render,
+        // which borrows the renderer.
+        let mut texture = self.layers.remove(&id).unwrap();
+        let result = self.render_to_texture_with(
+            device,
+            queue,
+            &scene,
+            &texture.view,
+            layer.width(),
+            layer.height(),
+            &quality,
+        );
+        texture.valid = result.is_ok();
+        self.layers.insert(id, texture);
+        if let Some(external) = self.atlas.external.get_mut(&id) {
+            external.copied = None;
+        }
+        result.map(|_| true)
+    }
+
+    /// Releases the texture of a cached layer and frees its place in the
+    /// image atlas. Scenes that still draw its image draw it transparent.
+    pub fn release_layer(&mut self, layer: CachedLayer) {
+        let id = layer.image.data.id();
+        self.layers.remove(&id);
+        self.atlas.external.remove(&id);
+        self.resources.remove_image(id);
     }
 
     /// Reload the shaders. This should only be used during `vello` development
@@ -156,13 +1478,17 @@ impl Renderer {
     pub async fn reload_shaders(&mut self, device: &Device) -> Result<()> {
         device.push_error_scope(wgpu::ErrorFilter::Validation);
         let mut engine = Engine::new();
-        let shaders = shaders::full_shaders(device, &mut engine)?;
+        let sources = ShaderSources::embedded();
+        let shaders = shaders::full_shaders(device, &mut engine, &sources)?;
+        // Create the pipelines now so that errors are caught by the scope.
+        engine.create_pipelines(device, default_parallelism());
         let error = device.pop_error_scope().await;
         if let Some(error) = error {
             return Err(error.into());
         }
         self.engine = engine;
         self.shaders = shaders;
+        self.sources = sources;
         Ok(())
     }
 
@@ -180,12 +1506,52 @@ impl Renderer {
         width: u32,
         height: u32,
     ) -> Result<()> {
+        let quality = RenderQuality {
+            scale: 1.0,
+            ..self.quality
+        };
+        self.render_to_texture_async_with(device, queue, scene, texture, width, height, &quality)
+            .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn render_to_texture_async_with(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        texture: &TextureView,
+        width: u32,
+        height: u32,
+        quality: &RenderQuality,
+    ) -> Result<()> {
+        self.check_hang(device)?;
+        // Instances are expanded once for each change to the scene, and
+        // everything that follows works with the expanded scene.
+        let expanded = scene.expanded();
+        let scene = expanded.as_deref().unwrap_or(scene);
+        let sanitized = self.sanitize_scene(scene);
+        let scene = sanitized.as_ref().unwrap_or(scene);
+        self.begin_budget(scene, width, height, quality)?;
+        self.render_backdrops(device, queue, scene, width, height, quality)?;
         let mut render = Render::new();
         let encoding = scene.data();
-        let recording = render.render_encoding_coarse(encoding, &self.shaders, width, height, true);
+        let recording = render.render_encoding_coarse(
+            encoding,
+            &self.shaders,
+            &mut self.resources,
+            quality,
+            width,
+            height,
+            true,
+        );
         let target = render.out_image();
+        let image_atlas = render.image_atlas();
+        let mut events = self.atlas.update(device, queue, &mut self.resources);
+        events.extend(layer_spill(encoding));
+        self.report_capacity(&events);
         let bump_buf = render.bump_buf();
-        self.engine.run_recording(device, queue, &recording, &[])?;
+        self.run_recording(device, queue, &recording, &[], encoding, width, height)?;
         if let Some(bump_buf) = self.engine.get_download(bump_buf) {
             let buf_slice = bump_buf.slice(..);
             let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
@@ -196,7 +1562,8 @@ impl Renderer {
                 return Err("channel was closed".into());
             }
             let mapped = buf_slice.get_mapped_range();
-            println!("{:?}", bytemuck::cast_slice::<_, u32>(&mapped));
+            let events = render.overflows(&mapped);
+            self.report_capacity(&events);
         }
         // TODO: apply logic to determine whether we need to rerun coarse, and also
         // allocate the blend stack as needed.
@@ -204,9 +1571,20 @@ impl Renderer {
         // Maybe clear to reuse allocation?
         let mut recording = Recording::default();
         render.record_fine(&self.shaders, &mut recording);
-        let external_resources = [ExternalResource::Image(target, texture)];
-        self.engine
-            .run_recording(device, queue, &recording, &external_resources)?;
+        let external_resources = [
+            ExternalResource::Image(target, texture),
+            ExternalResource::Image(image_atlas, self.atlas.view()),
+        ];
+        let index = self.run_recording(
+            device,
+            queue,
+            &recording,
+            &external_resources,
+            encoding,
+            width,
+            height,
+        )?;
+        self.last_submission = Some(index);
         Ok(())
     }
 
@@ -219,6 +1597,8 @@ impl Renderer {
         width: u32,
         height: u32,
     ) -> Result<()> {
+        let quality = self.quality;
+        let (width, height) = scaled_size(width, height, quality.scale);
         let mut target = self
             .target
             .take()
@@ -228,44 +1608,45 @@ impl Renderer {
         if target.width != width || target.height != height {
             target = TargetTexture::new(device, width, height);
         }
-        self.render_to_texture_async(device, queue, scene, &target.view, width, height)
-            .await?;
-        let mut encoder =
-            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        {
-            let surface_view = surface
-                .texture
-                .create_view(&wgpu::TextureViewDescriptor::default());
-            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: None,
-                layout: &self.blit.bind_layout,
-                entries: &[wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&target.view),
-                }],
-            });
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &surface_view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::default()),
-                        store: true,
-                    },
-                })],
-                depth_stencil_attachment: None,
-            });
-            render_pass.set_pipeline(&self.blit.pipeline);
-            render_pass.set_bind_group(0, &bind_group, &[]);
-            render_pass.draw(0..6, 0..1);
-        }
-        queue.submit(Some(encoder.finish()));
+        self.render_to_texture_async_with(
+            device,
+            queue,
+            scene,
+            &target.view,
+            width,
+            height,
+            &quality,
+        )
+        .await?;
+        self.blit_to_surface(device, queue, &target, surface);
         self.target = Some(target);
         Ok(())
     }
 }
 
+/// Builds a scene that cross-fades between two scenes over a target of the
+/// given size.
+fn crossfade_scene(from: &Scene, to: &Scene, t: f32, width: u32, height: u32) -> Scene {
+    let mut scene = Scene::new();
+    let mut builder = SceneBuilder::for_scene(&mut scene);
+    let bounds = kurbo::Rect::new(0.0, 0.0, width as f64, height as f64);
+    builder.append_crossfade_scenes(from, to, t, &bounds);
+    builder.finish();
+    scene
+}
+
+/// Returns the size of the internal render target for a surface of the
+/// given size.
+fn scaled_size(width: u32, height: u32, scale: f32) -> (u32, u32) {
+    if scale == 1.0 {
+        return (width, height);
+    }
+    (
+        ((width as f32 * scale).ceil() as u32).max(1),
+        ((height as f32 * scale).ceil() as u32).max(1),
+    )
+}
+
 struct TargetTexture {
     view: TextureView,
     width: u32,
@@ -297,6 +1678,225 @@ impl TargetTexture {
     }
 }
 
+/// Image atlas that persists across frames so that images are only uploaded
+/// once.
+#[derive(Default)]
+struct ImageAtlas {
+    texture: Option<(wgpu::Texture, TextureView)>,
+    width: u32,
+    height: u32,
+    generation: u64,
+    /// Registered textures keyed by the id of the data of their images.
+    external: HashMap<u64, ExternalTexture>,
+}
+
+/// Texture registered with the atlas, which is copied into it on the GPU.
+struct ExternalTexture {
+    texture: wgpu::Texture,
+    /// Atlas generation and position of the last copy, or `None` if the
+    /// contents must be copied again.
+    copied: Option<(u64, u32, u32)>,
+}
+
+impl ExternalTexture {
+    fn new(texture: wgpu::Texture) -> Self {
+        Self {
+            texture,
+            copied: None,
+        }
+    }
+}
+
+impl ImageAtlas {
+    /// Grows the atlas to fit the images in the resource cache and uploads
+    /// the pending images, returning the capacity events that occurred.
+    fn update(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        resources: &mut ResourceCache,
+    ) -> Vec<CapacityEvent> {
+        let mut events = vec![];
+        let (width, height, images) = resources.pending_images();
+        let max_size = device.limits().max_texture_dimension_2d;
+        let overflow = width > max_size || height > max_size;
+        if overflow {
+            events.push(CapacityEvent::AtlasOverflow {
+                width,
+                height,
+                max_size,
+            });
+        }
+        // Creating a texture beyond the limits of the device would fail, so
+        // the atlas is clamped and images outside of it are skipped.
+        let width = width.clamp(1, max_size);
+        let height = height.clamp(1, max_size);
+        let generation = resources.image_generation();
+        if generation != self.generation {
+            // The atlas was repacked so the previous contents are stale.
+            if self.texture.is_some() {
+                events.push(CapacityEvent::AtlasRepacked);
+            }
+            self.texture = None;
+            self.generation = generation;
+        }
+        if self.texture.is_none() || width > self.width || height > self.height {
+            let new_width = width.max(self.width);
+            let new_height = height.max(self.height).next_power_of_two().min(max_size);
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("image_atlas"),
+                size: wgpu::Extent3d {
+                    width: new_width,
+                    height: new_height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING
+                    | wgpu::TextureUsages::COPY_DST
+                    | wgpu::TextureUsages::COPY_SRC,
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                view_formats: &[],
+            });
+            if let Some((old, _)) = &self.texture {
+                events.push(CapacityEvent::AtlasGrown {
+                    width: new_width,
+                    height: new_height,
+                });
+                let mut encoder =
+                    device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+                encoder.copy_texture_to_texture(
+                    old.as_image_copy(),
+                    texture.as_image_copy(),
+                    wgpu::Extent3d {
+                        width: self.width,
+                        height: self.height,
+                        depth_or_array_layers: 1,
+                    },
+                );
+                queue.submit(Some(encoder.finish()));
+            }
+            let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+            self.texture = Some((texture, view));
+            self.width = new_width;
+            self.height = new_height;
+        }
+        let texture = &self.texture.as_ref().unwrap().0;
+        let (atlas_width, atlas_height) = (self.width, self.height);
+        let fits = |x: u32, y: u32, width: u32, height: u32| {
+            x.checked_add(width).map_or(false, |x1| x1 <= atlas_width)
+                && y.checked_add(height).map_or(false, |y1| y1 <= atlas_height)
+        };
+        for (image, x, y) in images {
+            if self.external.contains_key(&image.data.id())
+                || !fits(*x, *y, image.width, image.height)
+            {
+                continue;
+            }
+            let size = image.width as usize * image.height as usize * 4;
+            // Images of unregistered textures have no data and are cleared.
+            let zeros;
+            let data = if image.data.data().len() == size {
+                image.data.data()
+            } else {
+                zeros = vec![0; size];
+                &zeros
+            };
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d { x: *x, y: *y, z: 0 },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(image.width * 4),
+                    rows_per_image: None,
+                },
+                wgpu::Extent3d {
+                    width: image.width,
+                    height: image.height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+        for update in resources.image_updates() {
+            if !fits(update.x, update.y, update.width, update.height) {
+                continue;
+            }
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: update.x,
+                        y: update.y,
+                        z: 0,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                &update.data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(update.width * 4),
+                    rows_per_image: None,
+                },
+                wgpu::Extent3d {
+                    width: update.width,
+                    height: update.height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+        // Registered textures are copied on the GPU when they are first
+        // placed, moved by a repack or marked as changed.
+        let mut encoder = None;
+        for (id, external) in &mut self.external {
+            let (x, y) = match resources.image_position(*id) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            let copied = Some((self.generation, x, y));
+            let size = external.texture.size();
+            if external.copied == copied || !fits(x, y, size.width, size.height) {
+                continue;
+            }
+            external.copied = copied;
+            encoder
+                .get_or_insert_with(|| {
+                    device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None })
+                })
+                .copy_texture_to_texture(
+                    external.texture.as_image_copy(),
+                    wgpu::ImageCopyTexture {
+                        texture,
+                        mip_level: 0,
+                        origin: wgpu::Origin3d { x, y, z: 0 },
+                        aspect: wgpu::TextureAspect::All,
+                    },
+                    size,
+                );
+        }
+        if let Some(encoder) = encoder {
+            queue.submit(Some(encoder.finish()));
+        }
+        resources.clear_pending_images();
+        resources.notify_resident();
+        if overflow {
+            // Only the images that are used by the next frame are packed.
+            resources.invalidate_images();
+        }
+        events
+    }
+
+    fn view(&self) -> &TextureView {
+        &self.texture.as_ref().unwrap().1
+    }
+}
+
 struct BlitPipeline {
     bind_layout: wgpu::BindGroupLayout,
     pipeline: wgpu::RenderPipeline,
@@ -305,8 +1905,13 @@ struct BlitPipeline {
 impl BlitPipeline {
     fn new(device: &Device, format: TextureFormat) -> Self {
         const SHADERS: &str = r#"
+            struct VertexOutput {
+                @builtin(position) position: vec4<f32>,
+                @location(0) uv: vec2<f32>,
+            }
+
             @vertex
-            fn vs_main(@builtin(vertex_index) ix: u32) -> @builtin(position) vec4<f32> {
+            fn vs_main(@builtin(vertex_index) ix: u32) -> VertexOutput {
                 // Generate a full screen quad in NDCs
                 var vertex = vec2(-1.0, 1.0);
                 switch ix {
@@ -321,15 +1926,20 @@ impl BlitPipeline {
                     }
                     default: {}
                 }
-                return vec4(vertex, 0.0, 1.0);
+                let uv = vec2(vertex.x * 0.5 + 0.5, 0.5 - vertex.y * 0.5);
+                return VertexOutput(vec4(vertex, 0.0, 1.0), uv);
             }
             
             @group(0) @binding(0)
             var fine_output: texture_2d<f32>;
             
             @fragment
-            fn fs_main(@builtin(position) pos: vec4<f32>) -> @location(0) vec4<f32> {
-                let rgba_sep = textureLoad(fine_output, vec2<i32>(pos.xy), 0);
+            fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+                // The fine output may be smaller than the surface when
+                // rendering at reduced scale.
+                let size = vec2<f32>(textureDimensions(fine_output));
+                let xy = min(vec2<i32>(input.uv * size), vec2<i32>(size) - 1);
+                let rgba_sep = textureLoad(fine_output, xy, 0);
                 return vec4(rgba_sep.rgb * rgba_sep.a, rgba_sep.a);
             }
         "#;
@@ -396,3 +2006,60 @@ impl BlitPipeline {
         }
     }
 }
+
+/// Returns a flag that is set once the work submitted to the queue so far
+/// has completed.
+fn work_done_flag(queue: &Queue) -> Arc<AtomicBool> {
+    let done = Arc::new(AtomicBool::new(false));
+    let signal = done.clone();
+    queue.on_submitted_work_done(move || signal.store(true, Ordering::Release));
+    done
+}
+
+/// Returns an event if layers in the encoding are nested deeper than the
+/// blend stack in registers.
+fn layer_spill(encoding: &encoding::Encoding) -> Option<CapacityEvent> {
+    let mut depth = 0u32;
+    let mut max_depth = 0;
+    for tag in &encoding.draw_tags {
+        match *tag {
+            encoding::DrawTag::BEGIN_CLIP => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            encoding::DrawTag::END_CLIP => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    (max_depth > BLEND_STACK_SPLIT).then_some(CapacityEvent::LayerSpill {
+        depth: max_depth,
+        limit: BLEND_STACK_SPLIT,
+    })
+}
+
+/// Returns an error if the device cannot run the pipeline in any
+/// configuration.
+fn check_limits(device: &Device) -> Result<()> {
+    let limits = device.limits();
+    let required = shaders::MAX_STORAGE_BUFFERS;
+    if limits.max_storage_buffers_per_shader_stage < required {
+        return Err(format!(
+            "device supports {} storage buffers per shader stage but {required} are required",
+            limits.max_storage_buffers_per_shader_stage
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Returns the number of threads used to compile pipelines by default.
+fn default_parallelism() -> usize {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        1
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Mesh gradients built from Coons and tensor product patches.
+
+use peniko::kurbo::{BezPath, Point};
+use peniko::Color;
+
+/// Bicubic tensor product patch with a color at each corner.
+///
+/// The control points are stored in row major order where the row is the `v`
+/// parameter and the column is the `u` parameter. The corners are therefore
+/// at indices 0 (u=0, v=0), 3 (u=1, v=0), 15 (u=1, v=1) and 12 (u=0, v=1).
+#[derive(Copy, Clone, Debug)]
+pub struct MeshPatch {
+    /// Control points of the patch.
+    pub points: [Point; 16],
+    /// Corner colors in the order (u=0, v=0), (u=1, v=0), (u=1, v=1), (u=0, v=1).
+    pub colors: [Color; 4],
+}
+
+impl MeshPatch {
+    /// Creates a new tensor product patch from the full grid of control points.
+    pub fn tensor(points: [Point; 16], colors: [Color; 4]) -> Self {
+        Self { points, colors }
+    }
+
+    /// Creates a new Coons patch from the 12 control points of its boundary.
+    ///
+    /// The boundary starts at the (u=0, v=0) corner and proceeds in the same
+    /// direction as the corner colors: along v=0, then u=1, then v=1 and
+    /// finally back along u=0. The interior control points are derived as
+    /// specified for type 6 shadings in PDF.
+    pub fn coons(boundary: [Point; 12], colors: [Color; 4]) -> Self {
+        let b = boundary;
+        let mut p = [Point::ZERO; 16];
+        // Top edge (v=0)
+        p[0] = b[0];
+        p[1] = b[1];
+        p[2] = b[2];
+        p[3] = b[3];
+        // Right edge (u=1)
+        p[7] = b[4];
+        p[11] = b[5];
+        p[15] = b[6];
+        // Bottom edge (v=1), traversed right to left
+        p[14] = b[7];
+        p[13] = b[8];
+        p[12] = b[9];
+        // Left edge (u=0), traversed bottom to top
+        p[8] = b[10];
+        p[4] = b[11];
+        let q = p;
+        let interior = |corner: usize,
+                        adj: [usize; 2],
+                        far: [usize; 2],
+                        near_far: [usize; 2],
+                        opposite: usize| {
+            let v = -4.0 * q[corner].to_vec2() + 6.0 * (q[adj[0]].to_vec2() + q[adj[1]].to_vec2())
+                - 2.0 * (q[far[0]].to_vec2() + q[far[1]].to_vec2())
+                + 3.0 * (q[near_far[0]].to_vec2() + q[near_far[1]].to_vec2())
+                - q[opposite].to_vec2();
+            (v / 9.0).to_point()
+        };
+        p[5] = interior(0, [1, 4], [3, 12], [13, 7], 15);
+        p[6] = interior(3, [2, 7], [0, 15], [14, 4], 12);
+        p[9] = interior(12, [13, 8], [15, 0], [1, 11], 3);
+        p[10] = interior(15, [14, 11], [12, 3], [2, 8], 0);
+        Self { points: p, colors }
+    }
+
+    /// Creates a patch covering a triangle whose colors are interpolated
+    /// linearly between the vertices, as for Gouraud shading.
+    ///
+    /// This is a bilinear patch where the (u=1, v=1) and (u=0, v=1) corners
+    /// coincide, which makes the interpolation barycentric.
+    pub fn triangle(points: [Point; 3], colors: [Color; 3]) -> Self {
+        let [p0, p1, p2] = points;
+        let mut p = [Point::ZERO; 16];
+        for (ix, point) in p.iter_mut().enumerate() {
+            let u = (ix % 4) as f64 / 3.0;
+            let v = (ix / 4) as f64 / 3.0;
+            *point = p0.lerp(p1, u).lerp(p2, v);
+        }
+        Self {
+            points: p,
+            colors: [colors[0], colors[1], colors[2], colors[2]],
+        }
+    }
+
+    /// Returns the boundary of the patch as a closed path.
+    pub fn boundary(&self) -> BezPath {
+        let p = &self.points;
+        let mut path = BezPath::new();
+        path.move_to(p[0]);
+        path.curve_to(p[1], p[2], p[3]);
+        path.curve_to(p[7], p[11], p[15]);
+        path.curve_to(p[14], p[13], p[12]);
+        path.curve_to(p[8], p[4], p[0]);
+        path.close_path();
+        path
+    }
+}
+
+/// Mesh gradient consisting of a collection of patches.
+///
+/// Patches are painted in order, so later patches will be drawn over earlier
+/// ones where they overlap.
+#[derive(Clone, Debug, Default)]
+pub struct MeshGradient {
+    /// Patches of the mesh.
+    pub patches: Vec<MeshPatch>,
+}
+
+impl MeshGradient {
+    /// Creates a new empty mesh gradient.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builder method for adding a patch to the mesh.
+    pub fn with_patch(mut self, patch: MeshPatch) -> Self {
+        self.patches.push(patch);
+        self
+    }
+
+    /// Adds a patch to the mesh.
+    pub fn push(&mut self, patch: MeshPatch) {
+        self.patches.push(patch);
+    }
+
+    /// Creates a mesh from a grid of `columns` by `rows` tensor product
+    /// patches that share their edges, such as an SVG 2 `meshgradient`.
+    ///
+    /// `points` holds the `(3 * columns + 1) * (3 * rows + 1)` control points
+    /// of the grid in row major order and `colors` holds the
+    /// `(columns + 1) * (rows + 1)` colors at the patch corners, also in row
+    /// major order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lengths of `points` and `colors` do not match the
+    /// dimensions of the grid.
+    pub fn grid(columns: usize, rows: usize, points: &[Point], colors: &[Color]) -> Self {
+        let stride = 3 * columns + 1;
+        assert_eq!(points.len(), stride * (3 * rows + 1));
+        assert_eq!(colors.len(), (columns + 1) * (rows + 1));
+        let mut mesh = Self::new();
+        for row in 0..rows {
+            for column in 0..columns {
+                let mut p = [Point::ZERO; 16];
+                for (ix, point) in p.iter_mut().enumerate() {
+                    let x = 3 * column + ix % 4;
+                    let y = 3 * row + ix / 4;
+                    *point = points[y * stride + x];
+                }
+                let color = |x: usize, y: usize| colors[y * (columns + 1) + x];
+                mesh.push(MeshPatch::tensor(
+                    p,
+                    [
+                        color(column, row),
+                        color(column + 1, row),
+                        color(column + 1, row + 1),
+                        color(column, row + 1),
+                    ],
+                ));
+            }
+        }
+        mesh
+    }
+}
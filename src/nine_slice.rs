@@ -0,0 +1,145 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Expensive effects baked into reusable nine-slice images.
+
+use peniko::kurbo::{Affine, Insets, Rect, Size};
+use peniko::Image;
+use wgpu::{Device, Queue};
+
+use crate::{Renderer, Result, Scene, SceneBuilder, SceneFragment};
+
+/// Image that is split into nine regions by a set of insets.
+///
+/// When drawn into a rectangle, the corners keep their size, the edges are
+/// stretched along one axis and the center is stretched along both.
+#[derive(Clone, Debug)]
+pub struct NineSlice {
+    image: Image,
+    insets: Insets,
+    scale: f64,
+}
+
+impl NineSlice {
+    /// Creates a new nine-slice from an image rendered at the specified
+    /// scale. The insets are in logical units, i.e. before scaling.
+    pub fn new(image: Image, insets: Insets, scale: f64) -> Self {
+        Self {
+            image,
+            insets,
+            scale,
+        }
+    }
+
+    /// Returns the underlying image.
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+
+    /// Returns the insets in logical units.
+    pub fn insets(&self) -> Insets {
+        self.insets
+    }
+
+    /// Returns the scale at which the image was rendered.
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// Draws the nine-slice stretched to fill the specified rectangle.
+    pub fn draw(&self, builder: &mut SceneBuilder, transform: Affine, rect: Rect) {
+        // The image is drawn in texels, so that its insets are in the same
+        // units as the image.
+        let scale = self.scale;
+        let insets = Insets::new(
+            self.insets.x0 * scale,
+            self.insets.y0 * scale,
+            self.insets.x1 * scale,
+            self.insets.y1 * scale,
+        );
+        builder.draw_image_nine_slice(
+            &self.image,
+            insets,
+            rect.scale_from_origin(scale),
+            transform * Affine::scale(scale.recip()),
+        );
+    }
+}
+
+/// Scales down a pair of insets so that they fit within the given length.
+pub(crate) fn fit(start: f64, end: f64, len: f64) -> (f64, f64) {
+    let total = start + end;
+    if total > len && total > 0.0 {
+        let s = len.max(0.0) / total;
+        (start * s, end * s)
+    } else {
+        (start, end)
+    }
+}
+
+/// Effect that is baked into a nine-slice image on demand.
+///
+/// The effect is described by a scene fragment in logical units which is
+/// rendered at the requested scale the first time it is needed and again
+/// whenever the scale changes, for example when a window moves to a display
+/// with a different DPI.
+pub struct BakedNineSlice {
+    fragment: SceneFragment,
+    size: Size,
+    insets: Insets,
+    baked: Option<NineSlice>,
+}
+
+impl BakedNineSlice {
+    /// Creates a new baked effect from a fragment that covers the rectangle
+    /// from the origin to `size` along with the insets that split it into
+    /// nine regions.
+    pub fn new(fragment: SceneFragment, size: Size, insets: Insets) -> Self {
+        Self {
+            fragment,
+            size,
+            insets,
+            baked: None,
+        }
+    }
+
+    /// Discards the baked image so it will be rendered again on next use.
+    pub fn invalidate(&mut self) {
+        self.baked = None;
+    }
+
+    /// Returns the baked nine-slice for the specified scale, rendering it if
+    /// there is no image for that scale yet.
+    pub fn get(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &Queue,
+        scale: f64,
+    ) -> Result<&NineSlice> {
+        if self.baked.as_ref().map(|baked| baked.scale) != Some(scale) {
+            let width = (self.size.width * scale).ceil().max(1.0) as u32;
+            let height = (self.size.height * scale).ceil().max(1.0) as u32;
+            let mut scene = Scene::new();
+            let mut builder = SceneBuilder::for_scene(&mut scene);
+            builder.append(&self.fragment, Some(Affine::scale(scale)));
+            builder.finish();
+            let image = renderer.render_to_image(device, queue, &scene, width, height)?;
+            self.baked = Some(NineSlice::new(image, self.insets, scale));
+        }
+        Ok(self.baked.as_ref().unwrap())
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Pattern brushes that repeat a vector motif.
+
+use peniko::kurbo::{Affine, Rect, Shape};
+use peniko::{BrushRef, Extend, Fill, Image};
+use wgpu::{Device, Queue};
+
+use crate::{CachedLayer, Renderer, Result, SceneBuilder, SceneFragment};
+
+/// Brush that tiles the plane with the contents of a fragment, as used for
+/// hatching and decorative fills.
+///
+/// The tile rectangle of the fragment is rasterized into a
+/// [cached layer](CachedLayer) the first time the pattern is prepared at a
+/// given scale, and its image is repeated when filling. Content outside of
+/// the tile is clipped. The layer is replaced when the pattern is prepared at
+/// another scale and freed with [`release`](Self::release).
+pub struct Pattern {
+    fragment: SceneFragment,
+    tile: Rect,
+    transform: Affine,
+    image: Option<(CachedLayer, Image, f64)>,
+}
+
+impl Pattern {
+    /// Creates a pattern that repeats the contents of `tile` in the
+    /// coordinate space of the fragment.
+    pub fn new(fragment: SceneFragment, tile: Rect) -> Self {
+        Self {
+            fragment,
+            tile,
+            transform: Affine::IDENTITY,
+            image: None,
+        }
+    }
+
+    /// Builder method for setting the transform from the space of the
+    /// fragment to the space of the filled shape, for example to rotate
+    /// hatching.
+    pub fn with_transform(mut self, transform: Affine) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    /// Returns the tile rectangle.
+    pub fn tile(&self) -> Rect {
+        self.tile
+    }
+
+    /// Returns the transform of the pattern.
+    pub fn transform(&self) -> Affine {
+        self.transform
+    }
+
+    /// Rasterizes the tile with `scale` device pixels per unit of the
+    /// fragment, unless it was already rasterized at that scale.
+    ///
+    /// The scale is usually the scale of the transform of the filled shape
+    /// combined with that of the pattern, so that the pattern is sharp. The
+    /// size of the tile is clamped to the texture limits of the device.
+    pub fn prepare(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &Queue,
+        scale: f64,
+    ) -> Result<()> {
+        if matches!(&self.image, Some((_, _, s)) if *s == scale) {
+            return Ok(());
+        }
+        // The tile is rasterized at a whole number of pixels so that the
+        // image repeats without seams.
+        let max_size = device.limits().max_texture_dimension_2d;
+        let width = ((self.tile.width() * scale).ceil().max(1.0) as u32).min(max_size);
+        let height = ((self.tile.height() * scale).ceil().max(1.0) as u32).min(max_size);
+        let tile = self.tile_fragment(width, height);
+        let layer = match self.image.take() {
+            Some((layer, _, _)) if (layer.width(), layer.height()) == (width, height) => {
+                // The texture of the previous scale is reused.
+                renderer.invalidate_layer(&layer);
+                if let Err(err) = renderer.update_layer(device, queue, &layer, &tile) {
+                    renderer.release_layer(layer);
+                    return Err(err);
+                }
+                layer
+            }
+            previous => {
+                if let Some((layer, _, _)) = previous {
+                    renderer.release_layer(layer);
+                }
+                renderer.cache_layer(device, queue, &tile, width, height)?
+            }
+        };
+        let mut image = layer.image().clone();
+        image.extend = Extend::Repeat;
+        self.image = Some((layer, image, scale));
+        Ok(())
+    }
+
+    /// Releases the rasterized tile, which is rasterized again when the
+    /// pattern is next prepared.
+    pub fn release(&mut self, renderer: &mut Renderer) {
+        if let Some((layer, _, _)) = self.image.take() {
+            renderer.release_layer(layer);
+        }
+    }
+
+    /// Returns the rasterized tile, if the pattern has been prepared.
+    pub fn image(&self) -> Option<&Image> {
+        self.image.as_ref().map(|(_, image, _)| image)
+    }
+
+    /// Fills a shape with the pattern. Draws nothing if the pattern has not
+    /// been prepared.
+    pub fn fill(
+        &self,
+        builder: &mut SceneBuilder,
+        style: Fill,
+        transform: Affine,
+        shape: &impl Shape,
+    ) {
+        if let Some((_, image, _)) = &self.image {
+            let brush_transform =
+                self.transform * self.tile_to_image(image.width, image.height).inverse();
+            builder.fill(
+                style,
+                transform,
+                BrushRef::Image(image),
+                Some(brush_transform),
+                shape,
+            );
+        }
+    }
+
+    /// Returns the fragment placed in a tile image of the given size.
+    fn tile_fragment(&self, width: u32, height: u32) -> SceneFragment {
+        SceneFragment::record(|builder| {
+            builder.append(&self.fragment, Some(self.tile_to_image(width, height)));
+        })
+    }
+
+    /// Returns the transform from the space of the fragment to the pixels of
+    /// the rasterized tile.
+    fn tile_to_image(&self, width: u32, height: u32) -> Affine {
+        let sx = width as f64 / self.tile.width().max(f64::EPSILON);
+        let sy = height as f64 / self.tile.height().max(f64::EPSILON);
+        Affine::scale_non_uniform(sx, sy) * Affine::translate(-self.tile.origin().to_vec2())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::kurbo::{Affine, Rect};
+    use peniko::{Color, Fill};
+
+    use super::Pattern;
+    use crate::encoding::Transform;
+    use crate::{Scene, SceneBuilder, SceneFragment};
+
+    #[test]
+    fn tile_keeps_transformed_fills() {
+        let transform = Affine::translate((2.0, 1.0)) * Affine::rotate(0.25);
+        let rect = Rect::new(0.0, 0.0, 4.0, 4.0);
+        let fragment = SceneFragment::record(|builder| {
+            builder.fill(Fill::NonZero, transform, Color::rgb8(0, 0, 0), None, &rect);
+        });
+        let pattern = Pattern::new(fragment, Rect::new(0.0, 0.0, 8.0, 8.0))
+            .with_transform(Affine::rotate(0.5));
+        let scene = Scene::from_fragment(&pattern.tile_fragment(16, 16));
+        let mut direct = Scene::new();
+        let mut builder = SceneBuilder::for_scene(&mut direct);
+        builder.append(
+            &SceneFragment::record(|builder| {
+                builder.fill(Fill::NonZero, transform, Color::rgb8(0, 0, 0), None, &rect);
+            }),
+            Some(Affine::scale(2.0)),
+        );
+        builder.finish();
+        let (scene, direct) = (scene.data(), direct.data());
+        assert!(scene.transforms == direct.transforms);
+        assert!(scene.transforms[0] == Transform::IDENTITY);
+        assert_eq!(scene.linewidths, direct.linewidths);
+        // The fill is not drawn as a stroke.
+        assert!(scene.linewidths.iter().all(|width| *width < 0.0));
+    }
+}
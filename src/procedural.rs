@@ -0,0 +1,74 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Brushes that are generated procedurally during fine rasterization.
+
+use peniko::Color;
+
+/// Pattern generated by a procedural brush.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ProceduralKind {
+    /// Alternating squares of the two colors.
+    Checkerboard,
+    /// Vertical stripes where `duty` is the fraction of each period covered
+    /// by the first color.
+    Stripes {
+        /// Fraction of the period covered by the first color.
+        duty: f32,
+    },
+    /// Smoothly interpolated lattice noise.
+    ValueNoise {
+        /// Seed for the noise function.
+        seed: u32,
+    },
+    /// Two dimensional simplex noise.
+    SimplexNoise {
+        /// Seed for the noise function.
+        seed: u32,
+    },
+}
+
+/// Brush that computes its color from a procedural pattern.
+///
+/// The pattern is defined in brush space where a single cell, stripe period
+/// or noise lattice step has the size given by `scale`. Rotation and other
+/// adjustments are applied using the brush transform.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ProceduralBrush {
+    /// Pattern to generate.
+    pub kind: ProceduralKind,
+    /// Colors that are selected or interpolated by the pattern.
+    pub colors: [Color; 2],
+    /// Size of a pattern cell in brush space.
+    pub scale: f32,
+}
+
+impl ProceduralBrush {
+    /// Creates a new procedural brush with a scale of 1.
+    pub fn new(kind: ProceduralKind, color0: Color, color1: Color) -> Self {
+        Self {
+            kind,
+            colors: [color0, color1],
+            scale: 1.0,
+        }
+    }
+
+    /// Builder method for setting the scale of the pattern.
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Integration with the puffin and Tracy profilers.
+//!
+//! With the `puffin` or `tracy` feature enabled, the renderer emits CPU
+//! scopes for encoding, resource uploads and submission, which appear
+//! alongside the scopes of the application.
+//!
+//! GPU timings are measured with timestamp queries once they are enabled
+//! with [`Renderer::set_gpu_profiling`](crate::Renderer::set_gpu_profiling),
+//! which requires [`wgpu::Features::TIMESTAMP_QUERY`]. The results of a
+//! frame are read back without blocking, a frame or two later, and are
+//! available from [`Renderer::gpu_timings`](crate::Renderer::gpu_timings).
+//! They are also reported to puffin as a separate "vello GPU" thread, with
+//! a scope for each stage placed relative to the time of the submission,
+//! and to Tracy as a plot for each stage, in milliseconds.
+
+/// Opens a profiler scope that lasts until the end of the enclosing block.
+macro_rules! profile_scope {
+    ($name:literal) => {
+        #[cfg(feature = "puffin")]
+        puffin::profile_scope!($name);
+        #[cfg(feature = "tracy")]
+        let _tracy_span = tracy_client::span!($name);
+    };
+}
+
+pub(crate) use profile_scope;
+
+/// Time spent by the GPU on a stage of the pipeline.
+#[derive(Clone, Debug)]
+pub struct StageTiming {
+    /// Name of the shader.
+    pub label: &'static str,
+    /// Start of the stage in milliseconds, relative to the start of the
+    /// first stage of the submission.
+    pub start: f64,
+    /// End of the stage in milliseconds, relative to the start of the first
+    /// stage of the submission.
+    pub end: f64,
+}
+
+impl StageTiming {
+    /// Returns the duration of the stage in milliseconds.
+    pub fn duration(&self) -> f64 {
+        self.end - self.start
+    }
+}
+
+/// GPU timings of the stages of a submission.
+///
+/// A frame consists of one submission, and one more for each backdrop
+/// layer.
+#[derive(Clone, Debug, Default)]
+pub struct GpuTimings {
+    /// Timings of the stages, in the order they were dispatched.
+    pub stages: Vec<StageTiming>,
+    #[cfg(feature = "puffin")]
+    pub(crate) submitted_ns: puffin::NanoSecond,
+}
+
+impl GpuTimings {
+    /// Returns the time in milliseconds from the start of the first stage to
+    /// the end of the last one.
+    pub fn total(&self) -> f64 {
+        self.stages.last().map_or(0.0, |stage| stage.end)
+    }
+}
+
+/// Reports GPU timings to the enabled profilers.
+pub(crate) fn report_gpu_timings(timings: &GpuTimings) {
+    #[cfg(feature = "puffin")]
+    report_puffin(timings);
+    #[cfg(feature = "tracy")]
+    report_tracy(timings);
+    #[cfg(not(any(feature = "puffin", feature = "tracy")))]
+    let _ = timings;
+}
+
+/// Returns the time at which a submission is made, as used by the profiler.
+#[cfg(feature = "puffin")]
+pub(crate) fn submission_time() -> puffin::NanoSecond {
+    puffin::now_ns()
+}
+
+#[cfg(feature = "puffin")]
+fn report_puffin(timings: &GpuTimings) {
+    if !puffin::are_scopes_on() || timings.stages.is_empty() {
+        return;
+    }
+    let to_ns = |ms: f64| timings.submitted_ns + (ms * 1e6) as puffin::NanoSecond;
+    let mut stream = puffin::Stream::default();
+    for stage in &timings.stages {
+        let offset = stream.begin_scope(to_ns(stage.start), stage.label, "", "");
+        stream.end_scope(offset, to_ns(stage.end));
+    }
+    let info = puffin::StreamInfo {
+        stream,
+        num_scopes: timings.stages.len(),
+        depth: 1,
+        range_ns: (to_ns(0.0), to_ns(timings.total())),
+    };
+    puffin::GlobalProfiler::lock().report(
+        puffin::ThreadInfo {
+            start_time_ns: None,
+            name: "vello GPU".into(),
+        },
+        &info.as_stream_into_ref(),
+    );
+}
+
+#[cfg(feature = "tracy")]
+fn report_tracy(timings: &GpuTimings) {
+    use tracy_client::{plot_name, Client, PlotName};
+    // Plot names must outlive the client, so they are leaked once for each
+    // stage.
+    static PLOT_NAMES: parking_lot::Mutex<Vec<(&'static str, PlotName)>> =
+        parking_lot::const_mutex(Vec::new());
+    let client = match Client::running() {
+        Some(client) => client,
+        None => return,
+    };
+    client.plot(plot_name!("vello GPU"), timings.total());
+    let mut names = PLOT_NAMES.lock();
+    for stage in &timings.stages {
+        let name = match names.iter().find(|(label, _)| *label == stage.label) {
+            Some((_, name)) => *name,
+            None => {
+                let name = PlotName::new_leak(format!("vello GPU {}", stage.label));
+                names.push((stage.label, name));
+                name
+            }
+        };
+        client.plot(name, stage.duration());
+    }
+}
@@ -3,10 +3,10 @@
 use bytemuck::{Pod, Zeroable};
 
 use crate::{
-    encoding::Encoding,
+    encoding::{resource::ResourceCache, Encoding},
     engine::{BufProxy, ImageFormat, ImageProxy, Recording, ResourceProxy},
     shaders::{self, FullShaders, Shaders},
-    Scene,
+    CapacityEvent, DebugView, RenderQuality, Scene,
 };
 
 /// State for a render in progress.
@@ -19,6 +19,9 @@ pub struct Render {
     segments_size: u32,
     /// Size of per-tile command list in u32 units
     ptcl_size: u32,
+    /// Size of the binning part of the binning and info buffer of the last
+    /// render in u32 units
+    binning_size: u32,
     width_in_tiles: u32,
     height_in_tiles: u32,
     fine: Option<FineResources>,
@@ -27,11 +30,13 @@ pub struct Render {
 /// Resources produced by pipeline, needed for fine rasterization.
 struct FineResources {
     config_buf: ResourceProxy,
+    scene_buf: ResourceProxy,
     bump_buf: ResourceProxy,
     tile_buf: ResourceProxy,
     segments_buf: ResourceProxy,
     ptcl_buf: ResourceProxy,
     gradient_image: ResourceProxy,
+    image_atlas: ImageProxy,
     info_bin_data_buf: ResourceProxy,
 
     out_image: ImageProxy,
@@ -179,27 +184,36 @@ fn render(scene: &Scene, shaders: &Shaders) -> (Recording, BufProxy) {
 pub fn render_full(
     scene: &Scene,
     shaders: &FullShaders,
+    resources: &mut ResourceCache,
+    quality: &RenderQuality,
     width: u32,
     height: u32,
-) -> (Recording, ResourceProxy) {
-    render_encoding_full(scene.data(), shaders, width, height)
+) -> (Recording, ResourceProxy, ImageProxy) {
+    render_encoding_full(scene.data(), shaders, resources, quality, width, height)
 }
 
 /// Create a single recording with both coarse and fine render stages.
 ///
 /// This function is not recommended when the scene can be complex, as it does not
 /// implement robust dynamic memory.
+///
+/// Images used by the encoding are added to the resource cache and must be
+/// uploaded to the image atlas, which is returned along with the output image.
 pub fn render_encoding_full(
     encoding: &Encoding,
     shaders: &FullShaders,
+    resources: &mut ResourceCache,
+    quality: &RenderQuality,
     width: u32,
     height: u32,
-) -> (Recording, ResourceProxy) {
+) -> (Recording, ResourceProxy, ImageProxy) {
     let mut render = Render::new();
-    let mut recording = render.render_encoding_coarse(encoding, shaders, width, height, false);
+    let mut recording =
+        render.render_encoding_coarse(encoding, shaders, resources, quality, width, height, false);
     let out_image = render.out_image();
+    let image_atlas = render.image_atlas();
     render.record_fine(shaders, &mut recording);
-    (recording, out_image.into())
+    (recording, out_image.into(), image_atlas)
 }
 
 pub fn align_up(len: usize, alignment: u32) -> usize {
@@ -214,6 +228,7 @@ impl Render {
             tiles_size: (1 << 24) / TILE_SIZE as u32,
             segments_size: (1 << 26) / SEGMENT_SIZE as u32,
             ptcl_size: (1 << 25) / 4 as u32,
+            binning_size: 0,
             width_in_tiles: 0,
             height_in_tiles: 0,
             fine: None,
@@ -228,17 +243,39 @@ impl Render {
         &mut self,
         encoding: &Encoding,
         shaders: &FullShaders,
+        resources: &mut ResourceCache,
+        quality: &RenderQuality,
         width: u32,
         height: u32,
         robust: bool,
     ) -> Recording {
-        use crate::encoding::{resource::ResourceCache, PackedEncoding};
+        use crate::encoding::{
+            PackedEncoding, Transform, CONFIG_DEBUG_COVERAGE, CONFIG_DEBUG_WINDING, CONFIG_DITHER,
+            CONFIG_SIMPLIFY_BLURS,
+        };
         let mut recording = Recording::default();
-        let mut resources = ResourceCache::new();
+        let expanded = encoding.expand_instances();
+        let encoding = expanded.as_ref().unwrap_or(encoding);
         let mut packed = PackedEncoding::default();
-        packed.pack(encoding, &mut resources);
+        packed.pack(encoding, resources);
+        if quality.scale != 1.0 {
+            let scale = peniko::kurbo::Affine::scale(quality.scale as f64);
+            packed.transform(Transform::from_kurbo(&scale));
+        }
+        let mut flags = 0;
+        if quality.dithering {
+            flags |= CONFIG_DITHER;
+        }
+        if quality.simplify_blurs {
+            flags |= CONFIG_SIMPLIFY_BLURS;
+        }
+        flags |= match quality.debug_view {
+            DebugView::None => 0,
+            DebugView::Winding => CONFIG_DEBUG_WINDING,
+            DebugView::Coverage => CONFIG_DEBUG_COVERAGE,
+        };
         let (ramp_data, ramps_width, ramps_height) = resources.ramps(packed.resources).unwrap();
-        let gradient_image = if encoding.patches.is_empty() {
+        let gradient_image = if ramps_height == 0 {
             ResourceProxy::new_image(1, 1, ImageFormat::Rgba8)
         } else {
             let data: &[u8] = bytemuck::cast_slice(ramp_data);
@@ -249,6 +286,11 @@ impl Render {
                 data,
             ))
         };
+        // The atlas is bound as an external resource that is kept up to date
+        // by the caller.
+        let (atlas_width, atlas_height, _) = resources.images(packed.resources).unwrap();
+        let image_atlas =
+            ImageProxy::new(atlas_width.max(1), atlas_height.max(1), ImageFormat::Rgba8);
         // TODO: calculate for real when we do rectangles
         let n_pathtag = encoding.path_tags.len();
         let pathtag_padded = align_up(encoding.path_tags.len(), 4 * shaders::PATHTAG_REDUCE_WG);
@@ -269,9 +311,12 @@ impl Render {
             tiles_size: self.tiles_size,
             segments_size: self.segments_size,
             ptcl_size: self.ptcl_size,
+            flags,
+            tolerance: quality.tolerance.max(RenderQuality::MIN_TOLERANCE),
             layout: packed.layout,
         };
         // println!("{:?}", config);
+        self.binning_size = config.binning_size;
         let scene_buf = ResourceProxy::Buf(recording.upload("scene", packed.data));
         let config_buf =
             ResourceProxy::Buf(recording.upload_uniform("config", bytemuck::bytes_of(&config)));
@@ -511,7 +556,6 @@ impl Render {
                 ptcl_buf,
             ],
         );
-        recording.free_resource(scene_buf);
         recording.free_resource(draw_monoid_buf);
         recording.free_resource(bin_header_buf);
         recording.free_resource(path_buf);
@@ -520,11 +564,13 @@ impl Render {
         self.height_in_tiles = config.height_in_tiles;
         self.fine = Some(FineResources {
             config_buf,
+            scene_buf,
             bump_buf,
             tile_buf,
             segments_buf,
             ptcl_buf,
             gradient_image,
+            image_atlas,
             info_bin_data_buf,
             out_image,
         });
@@ -549,9 +595,12 @@ impl Render {
                 fine.ptcl_buf,
                 fine.gradient_image,
                 fine.info_bin_data_buf,
+                fine.scene_buf,
+                ResourceProxy::Image(fine.image_atlas),
             ],
         );
         recording.free_resource(fine.config_buf);
+        recording.free_resource(fine.scene_buf);
         recording.free_resource(fine.tile_buf);
         recording.free_resource(fine.segments_buf);
         recording.free_resource(fine.ptcl_buf);
@@ -567,7 +616,35 @@ impl Render {
         self.fine.as_ref().unwrap().out_image
     }
 
+    /// Get the image atlas, which must be bound as an external resource.
+    pub fn image_atlas(&self) -> ImageProxy {
+        self.fine.as_ref().unwrap().image_atlas
+    }
+
     pub fn bump_buf(&self) -> BufProxy {
         *self.fine.as_ref().unwrap().bump_buf.as_buf().unwrap()
     }
+
+    /// Returns an event for each buffer whose capacity was exceeded,
+    /// according to the contents of the bump buffer read back after coarse
+    /// rasterization.
+    pub fn overflows(&self, bump: &[u8]) -> Vec<CapacityEvent> {
+        let bump: BumpAllocators = bytemuck::pod_read_unaligned(&bump[..BUMP_SIZE as usize]);
+        [
+            ("binning", bump.binning, self.binning_size),
+            ("ptcl", bump.ptcl, self.ptcl_size),
+            ("tiles", bump.tile, self.tiles_size),
+            ("segments", bump.segments, self.segments_size),
+        ]
+        .into_iter()
+        .filter(|(_, required, capacity)| required > capacity)
+        .map(
+            |(buffer, required, capacity)| CapacityEvent::BufferOverflow {
+                buffer,
+                required,
+                capacity,
+            },
+        )
+        .collect()
+    }
 }
@@ -14,15 +14,38 @@
 //
 // Also licensed under MIT license, at your choice.
 
-use peniko::kurbo::{Affine, Rect, Shape};
-use peniko::{BlendMode, BrushRef, Fill, Stroke};
+use std::sync::{Arc, Mutex};
 
-use crate::encoding::{Encoding, Transform};
+use peniko::kurbo::{Affine, Circle, Ellipse, Insets, Point, Rect, RoundedRect, Shape, Vec2};
+use peniko::{BlendMode, Brush, BrushRef, Color, ColorStop, Compose, Fill, Image, Mix, Stroke};
+
+use crate::budget::SceneBudget;
+use crate::encoding::resource::{
+    AsyncImage, BrushHandle, CustomResource, Placeholder, ResourceUsage,
+};
+use crate::encoding::{
+    dash_path, Backdrop, Compression, DrawEllipse, DrawProcedural, DrawRoundedRect, DrawShadow,
+    DrawTag, Encoding, EncodingMark, ImageSampling, IncompatibleEncoding, Instance, MergeOffsets,
+    MeshPatchData, PathEncoder, Transform, YuvMatrix, YuvRange, DRAW_FLAG_ADDITIVE,
+    MAX_PALETTE_SIZE,
+};
+use crate::filter::ColorMatrix;
+use crate::image::Orientation;
+use crate::ink::{stroke_outline, varying_outline, InkStroke};
+use crate::mesh::MeshGradient;
+use crate::nine_slice::fit;
+use crate::pattern::Pattern;
+use crate::procedural::ProceduralBrush;
+use crate::snap;
 
 /// Encoded definition of a scene and associated resources.
 #[derive(Default)]
 pub struct Scene {
     data: Encoding,
+    budget: Option<SceneBudget>,
+    /// The scene with its instanced fragments expanded, computed when it is
+    /// first rendered and kept until the draws change.
+    expanded: Mutex<Option<Arc<Scene>>>,
 }
 
 impl Scene {
@@ -31,50 +54,416 @@ impl Scene {
         Self::default()
     }
 
+    /// Creates a scene from encoded streams.
+    pub(crate) fn from_encoding(data: Encoding) -> Self {
+        Self {
+            data,
+            budget: None,
+            expanded: Mutex::default(),
+        }
+    }
+
+    /// Creates a scene that draws a fragment.
+    ///
+    /// Fragments are encoded without the leading identity transform and fill
+    /// linewidth of a scene, so their encoding can't be rendered directly.
+    pub(crate) fn from_fragment(fragment: &SceneFragment) -> Self {
+        let mut scene = Self::new();
+        let mut builder = SceneBuilder::for_scene(&mut scene);
+        builder.append(fragment, None);
+        builder.finish();
+        scene
+    }
+
+    /// Returns the scene with its instanced fragments expanded, or `None` if
+    /// it has none. The expansion is shared by the renders of the scene until
+    /// it is rebuilt.
+    pub(crate) fn expanded(&self) -> Option<Arc<Scene>> {
+        if !self.data.has_instances() {
+            return None;
+        }
+        let mut expanded = self.expanded.lock().unwrap();
+        let expanded = expanded.get_or_insert_with(|| {
+            let data = self.data.expand_instances().unwrap_or_default();
+            Arc::new(Scene::from_encoding(data))
+        });
+        Some(expanded.clone())
+    }
+
+    /// Discards the expansion of the instanced fragments after the draws of
+    /// the scene changed.
+    fn invalidate_expanded(&mut self) {
+        *self.expanded.get_mut().unwrap() = None;
+    }
+
+    /// Copies the brushes, palette and visibility of the scene to its
+    /// expansion after they changed, or discards the expansion if it is in
+    /// use.
+    fn sync_expanded(&mut self) {
+        let expanded = self.expanded.get_mut().unwrap();
+        if let Some(scene) = expanded {
+            match Arc::get_mut(scene) {
+                Some(scene) => {
+                    scene.data.brushes.clone_from(&self.data.brushes);
+                    scene.data.palette.clone_from(&self.data.palette);
+                    scene
+                        .data
+                        .hidden_groups
+                        .clone_from(&self.data.hidden_groups);
+                    scene
+                        .data
+                        .retired_stops
+                        .clone_from(&self.data.retired_stops);
+                }
+                None => *expanded = None,
+            }
+        }
+    }
+
     /// Returns the raw encoded scene data streams.
     pub fn data(&self) -> &Encoding {
         &self.data
     }
+
+    /// Sets a budget that the scene is checked against each time it is
+    /// built. This is intended for catching regressions in scene
+    /// construction during development.
+    pub fn set_budget(&mut self, budget: Option<SceneBudget>) {
+        self.budget = budget;
+    }
+
+    /// Adds a brush to the brush table of the scene and returns a handle
+    /// that can be used to draw with it.
+    ///
+    /// The brush table is retained when the scene is rebuilt.
+    pub fn add_brush(&mut self, brush: impl Into<Brush>) -> BrushHandle {
+        let handle = self.data.add_brush(brush.into());
+        self.sync_expanded();
+        handle
+    }
+
+    /// Replaces the brush for the specified handle. All draws that use the
+    /// handle will be rendered with the new brush without requiring the
+    /// scene to be rebuilt.
+    pub fn set_brush(&mut self, handle: BrushHandle, brush: impl Into<Brush>) {
+        if let Some(slot) = self.data.brushes.get_mut(handle.0 as usize) {
+            *slot = brush.into();
+        }
+        self.sync_expanded();
+    }
+
+    /// Replaces only the color stops of the gradient for the specified
+    /// handle, for example to animate its colors. The ramps of previous
+    /// stops are reused so the gradient cache does not grow while animating.
+    ///
+    /// Returns false if the handle does not refer to a gradient.
+    pub fn set_gradient_stops(&mut self, handle: BrushHandle, stops: &[ColorStop]) -> bool {
+        let changed = self.data.set_gradient_stops(handle, stops);
+        self.sync_expanded();
+        changed
+    }
+
+    /// Returns the brush for the specified handle.
+    pub fn brush(&self, handle: BrushHandle) -> Option<&Brush> {
+        self.data.brushes.get(handle.0 as usize)
+    }
+
+    /// Sets the palette of the scene.
+    ///
+    /// Draws that reference a palette slot are colored with the palette entry
+    /// at the time of rendering, so changing the palette recolors them, even
+    /// when they come from cached fragments, without rebuilding the scene.
+    /// Slots beyond the end of the palette are transparent. Colors beyond
+    /// [`MAX_PALETTE_SIZE`] are ignored.
+    pub fn set_palette(&mut self, colors: &[Color]) {
+        let len = colors.len().min(MAX_PALETTE_SIZE as usize);
+        self.data.palette.clear();
+        self.data.palette.extend_from_slice(&colors[..len]);
+        self.sync_expanded();
+    }
+
+    /// Sets the color of a single palette slot, growing the palette with
+    /// transparent entries if needed. Slots of [`MAX_PALETTE_SIZE`] and
+    /// beyond are ignored.
+    pub fn set_palette_color(&mut self, slot: u32, color: Color) {
+        if slot >= MAX_PALETTE_SIZE {
+            return;
+        }
+        let slot = slot as usize;
+        if slot >= self.data.palette.len() {
+            self.data.palette.resize(slot + 1, Color::rgba8(0, 0, 0, 0));
+        }
+        self.data.palette[slot] = color;
+        self.sync_expanded();
+    }
+
+    /// Returns the palette of the scene.
+    pub fn palette(&self) -> &[Color] {
+        &self.data.palette
+    }
+
+    /// Removes all brushes from the brush table, invalidating all handles.
+    pub fn clear_brushes(&mut self) {
+        self.data.brushes.clear();
+        self.sync_expanded();
+    }
+
+    /// Appends the draws of another scene along with its brushes, palette
+    /// and visibility groups, sharing gradients with identical stops.
+    ///
+    /// This is intended for composing documents from components whose
+    /// scenes are cached independently. The brush handles, palette slots
+    /// and visibility groups of `other` are moved by the returned offsets in
+    /// this scene. See [`Encoding::merge`] for the details.
+    pub fn append_scene(&mut self, other: &Scene) -> MergeOffsets {
+        let offsets = self.data.merge(&other.data);
+        self.invalidate_expanded();
+        offsets
+    }
+
+    /// Returns the images and gradients referenced by the scene along with
+    /// their sizes.
+    ///
+    /// This can be used to manage the lifetimes of assets or to prepare them
+    /// ahead of the frame that first renders the scene.
+    pub fn resources(&self) -> ResourceUsage {
+        self.data.resources()
+    }
+
+    /// Returns a scene that renders the coverage of the geometry in this
+    /// scene as an alpha mask, for consumers that composite the content
+    /// themselves. Layers and clips are preserved.
+    pub fn coverage_mask(&self) -> Scene {
+        Scene::from_encoding(self.data.coverage_mask())
+    }
+
+    /// Sets whether the draws in a visibility group are hidden.
+    ///
+    /// Hidden draws remain encoded and are skipped by the pipeline, so
+    /// toggling visibility does not require the scene to be rebuilt. Hiding
+    /// a layer also hides its contents. See
+    /// [`SceneBuilder::set_visibility_group`].
+    pub fn set_hidden(&mut self, group: u32, hidden: bool) {
+        self.data.set_group_hidden(group, hidden);
+        self.sync_expanded();
+    }
+
+    /// Returns true if the draws in a visibility group are hidden.
+    pub fn is_hidden(&self, group: u32) -> bool {
+        self.data.is_group_hidden(group)
+    }
+
+    /// Makes all visibility groups visible.
+    pub fn show_all(&mut self) {
+        self.data.hidden_groups.clear();
+        self.sync_expanded();
+    }
 }
 
 /// Encoded definition of a scene fragment and associated resources.
-#[derive(Default)]
+///
+/// A fragment owns its draws and is `Send + Sync + 'static`, so it can be
+/// recorded on a worker thread, stored in a cache and played back into any
+/// builder with [`SceneBuilder::append`], independently of the lifetime of
+/// the scene that it is drawn into.
+#[derive(Clone, Default)]
 pub struct SceneFragment {
     data: Encoding,
 }
 
+// Fragments are handed between threads.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<SceneFragment>();
+};
+
 impl SceneFragment {
     /// Creates a new scene fragment.
     pub fn new() -> Self {
         Self::default()
     }
 
+    /// Records the draws made by `f` into a new fragment.
+    ///
+    /// Brush handles refer to the brush table of the scene that the fragment
+    /// is appended to.
+    pub fn record(f: impl FnOnce(&mut SceneBuilder)) -> Self {
+        let mut fragment = Self::new();
+        let mut builder = SceneBuilder::for_fragment(&mut fragment);
+        f(&mut builder);
+        builder.finish();
+        fragment
+    }
+
     /// Returns true if the fragment does not contain any paths.
     pub fn is_empty(&self) -> bool {
         self.data.is_empty()
     }
 
+    /// Returns an estimate of the memory used by the fragment in bytes.
+    pub fn heap_size(&self) -> usize {
+        self.data.heap_size()
+    }
+
+    /// Appends the segments of a shape to the last path in the fragment,
+    /// keeping its style and brush.
+    ///
+    /// This allows extending a path between frames without rebuilding the
+    /// fragment. Returns the bounding box of the appended segments, which is
+    /// the region of the fragment that needs to be redrawn, or `None` if
+    /// nothing was appended.
+    pub fn extend_last_path(&mut self, shape: &impl Shape) -> Option<Rect> {
+        match self.data.extend_last_path(shape) {
+            Some(n_segments) if n_segments != 0 => Some(shape.bounding_box()),
+            _ => None,
+        }
+    }
+
+    /// Returns the encoded data streams of the fragment.
+    pub(crate) fn data(&self) -> &Encoding {
+        &self.data
+    }
+
+    /// Returns the images and gradients referenced by the fragment. Brush
+    /// handles are resolved by the scene that the fragment is appended to
+    /// and are not included.
+    pub fn resources(&self) -> ResourceUsage {
+        self.data.resources()
+    }
+
+    /// Returns a fragment that renders the coverage of the geometry in this
+    /// fragment as an alpha mask.
+    ///
+    /// Appending the masks of individual fragments to a scene and rendering
+    /// it to an auxiliary target produces per-element masks.
+    pub fn coverage_mask(&self) -> SceneFragment {
+        SceneFragment {
+            data: self.data.coverage_mask(),
+        }
+    }
+
+    /// Serializes the fragment. Returns `None` if the fragment references
+    /// images, brushes by handle or custom resources.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        self.data.to_bytes()
+    }
+
+    /// Deserializes a fragment produced by [`to_bytes`](Self::to_bytes).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, IncompatibleEncoding> {
+        Ok(Self {
+            data: Encoding::from_bytes(bytes)?,
+        })
+    }
+
+    /// Serializes and compresses the fragment, for caching large scenes on
+    /// disk. Returns `None` under the same conditions as
+    /// [`to_bytes`](Self::to_bytes).
+    pub fn to_compressed_bytes(&self, compression: Compression) -> Option<Vec<u8>> {
+        self.data.to_compressed_bytes(compression)
+    }
+
+    /// Deserializes a fragment produced by [`to_bytes`](Self::to_bytes) or
+    /// [`to_compressed_bytes`](Self::to_compressed_bytes) from a reader,
+    /// decompressing it as it is read.
+    pub fn from_reader(reader: impl std::io::Read) -> Result<Self, IncompatibleEncoding> {
+        Ok(Self {
+            data: Encoding::from_reader(reader)?,
+        })
+    }
+
     /// Returns the the entire sequence of points in the scene fragment.
     pub fn points(&self) -> &[[f32; 2]] {
-        if self.is_empty() {
-            &[]
-        } else {
-            bytemuck::cast_slice(&self.data.path_data)
-        }
+        encoding_points(&self.data)
+    }
+}
+
+/// Returns the control points of the paths of an encoding.
+fn encoding_points(encoding: &Encoding) -> &[[f32; 2]] {
+    if encoding.is_empty() {
+        &[]
+    } else {
+        bytemuck::cast_slice(&encoding.path_data)
     }
 }
 
 /// Builder for constructing a scene or scene fragment.
 pub struct SceneBuilder<'a> {
     scene: &'a mut Encoding,
+    budget: Option<&'a SceneBudget>,
     layer_depth: u32,
+    max_layer_depth: u32,
+    /// Device space bounds of the active rounded clips, outside of which
+    /// draws are culled.
+    rounded_clips: Vec<Rect>,
+    scissors: Vec<Rect>,
+    transforms: Vec<Affine>,
+    saved: Vec<SavedState>,
+    /// Layer depths at which mask layers were pushed, whose content layer
+    /// is popped along with the group layer.
+    mask_layers: Vec<u32>,
+    /// Layer depths at which pixel aligned layers were pushed, along with
+    /// the size of the transform stack before their transform was pushed.
+    aligned_layers: Vec<(u32, usize)>,
+    /// Open layers that are removed when they are popped if their content
+    /// is trivial.
+    flat_layers: Vec<FlatLayer>,
+    /// Index and device space bounding box of the most recent draw object
+    /// that was tested against the clips.
+    last_draw: Option<(usize, Rect)>,
+    pixel_snapping: bool,
+}
+
+/// Channel of a mask that determines the opacity of the masked content.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum MaskMode {
+    /// The alpha of the mask, as for the `mask-type: alpha` CSS property.
+    #[default]
+    Alpha,
+    /// The luminance of the mask multiplied by its alpha, as for SVG masks.
+    Luminance,
+}
+
+/// Sizes of the transform, clip and layer stacks at a call to
+/// [`SceneBuilder::save`].
+#[derive(Copy, Clone)]
+struct SavedState {
+    n_transforms: usize,
+    n_rounded_clips: usize,
+    n_scissors: usize,
+    layer_depth: u32,
+}
+
+/// Layer with a rectangular shape that is composited with source-over and
+/// full opacity, which is equivalent to drawing its content directly if the
+/// content is a single draw object inside the rectangle.
+///
+/// Layers of rounded clips are also equivalent to their content if all of it
+/// is composited with source-over within the region that is not affected by
+/// the rounded corners.
+#[derive(Copy, Clone)]
+struct FlatLayer {
+    layer_depth: u32,
+    /// Streams before the path of the layer.
+    start: EncodingMark,
+    /// Streams after the begin clip command of the layer.
+    end: EncodingMark,
+    /// Bounds of the layer in device space.
+    bounds: Rect,
+    /// For rounded clips, the conservative region in device space that is
+    /// not affected by the rounded corners.
+    inner: Option<Rect>,
+    /// True if the content of a rounded clip requires its layer.
+    is_clipping: bool,
 }
 
 impl<'a> SceneBuilder<'a> {
     /// Creates a new builder for filling a scene. Any current content in the scene
     /// will be cleared.
     pub fn for_scene(scene: &'a mut Scene) -> Self {
-        Self::new(&mut scene.data, false)
+        scene.invalidate_expanded();
+        let mut builder = Self::new(&mut scene.data, false);
+        builder.budget = scene.budget.as_ref();
+        builder
     }
 
     /// Creates a new builder for filling a scene fragment. Any current content in
@@ -88,10 +477,86 @@ impl<'a> SceneBuilder<'a> {
         scene.reset(is_fragment);
         Self {
             scene,
+            budget: None,
             layer_depth: 0,
+            max_layer_depth: 0,
+            rounded_clips: vec![],
+            scissors: vec![],
+            transforms: vec![],
+            saved: vec![],
+            mask_layers: vec![],
+            aligned_layers: vec![],
+            flat_layers: vec![],
+            last_draw: None,
+            pixel_snapping: false,
         }
     }
 
+    /// Saves the current transform, clips and layers, in the manner of the
+    /// `save` method of the Canvas 2D API.
+    ///
+    /// A matching [`restore`](Self::restore) pops everything that was pushed
+    /// since, so code ported from Canvas 2D or Skia does not have to balance
+    /// each push with a pop.
+    pub fn save(&mut self) {
+        self.saved.push(SavedState {
+            n_transforms: self.transforms.len(),
+            n_rounded_clips: self.rounded_clips.len(),
+            n_scissors: self.scissors.len(),
+            layer_depth: self.layer_depth,
+        });
+    }
+
+    /// Restores the state saved by the most recent [`save`](Self::save),
+    /// popping the transforms, clips and layers that were pushed since. Does
+    /// nothing if there is no saved state.
+    pub fn restore(&mut self) {
+        let state = match self.saved.pop() {
+            Some(state) => state,
+            None => return,
+        };
+        self.transforms.truncate(state.n_transforms);
+        // The layers of rounded clips are popped along with the other
+        // layers.
+        self.rounded_clips.truncate(state.n_rounded_clips);
+        while self.layer_depth > state.layer_depth {
+            self.pop_layer();
+        }
+        if self.scissors.len() > state.n_scissors {
+            self.scissors.truncate(state.n_scissors);
+            let rect = self.scissors.last().map(|rect| scissor_to_f32(*rect));
+            self.scene.encode_scissor(rect);
+        }
+    }
+
+    /// Intersects the current clip with a shape until the next
+    /// [`restore`](Self::restore), in the manner of the `clip` method of the
+    /// Canvas 2D API.
+    pub fn clip(&mut self, style: Fill, transform: Affine, shape: &impl Shape) {
+        self.push_layer_with_fill(style, Mix::Clip, 1.0, transform, shape);
+    }
+
+    /// Pushes a transform that is applied to all subsequent draws, layers and
+    /// clips, in addition to their own transforms, until the matching
+    /// [`pop_transform`](Self::pop_transform).
+    ///
+    /// Nested transforms are composed, so the transform of a draw is the
+    /// product of the pushed transforms from the outermost to the innermost
+    /// followed by the transform passed to the draw.
+    pub fn push_transform(&mut self, transform: Affine) {
+        self.transforms.push(self.transform() * transform);
+    }
+
+    /// Pops the most recent transform.
+    pub fn pop_transform(&mut self) {
+        self.transforms.pop();
+    }
+
+    /// Returns the composition of the pushed transforms.
+    pub fn transform(&self) -> Affine {
+        self.transforms.last().copied().unwrap_or(Affine::IDENTITY)
+    }
+
     /// Pushes a new layer bound by the specifed shape and composed with
     /// previous layers using the specified blend mode.
     pub fn push_layer(
@@ -101,28 +566,437 @@ impl<'a> SceneBuilder<'a> {
         transform: Affine,
         shape: &impl Shape,
     ) {
-        let blend = blend.into();
+        self.push_layer_with_fill(Fill::NonZero, blend, alpha, transform, shape);
+    }
+
+    /// Pushes a new layer bound by the specified shape, which is filled with
+    /// the specified rule, as for the SVG `clip-rule` property.
+    pub fn push_layer_with_fill(
+        &mut self,
+        style: Fill,
+        blend: impl Into<BlendMode>,
+        alpha: f32,
+        transform: Affine,
+        shape: &impl Shape,
+    ) {
+        let transform = self.transform() * transform;
+        self.encode_layer(style, blend.into(), alpha, transform, shape);
+    }
+
+    /// Encodes a layer with a transform that the transform stack has already
+    /// been applied to.
+    fn encode_layer(
+        &mut self,
+        style: Fill,
+        blend: BlendMode,
+        alpha: f32,
+        transform: Affine,
+        shape: &impl Shape,
+    ) {
+        self.clip_content(transform.transform_rect_bbox(shape.bounding_box()));
+        if !is_src_over_mode(blend) {
+            self.keep_current_clip_layer();
+        }
+        let start = self.scene.mark();
+        self.encode_layer_path(style, transform, shape);
+        self.scene
+            .encode_begin_clip_with_fill(blend, alpha.clamp(0.0, 1.0), style);
+        self.layer_depth += 1;
+        self.max_layer_depth = self.max_layer_depth.max(self.layer_depth);
+        let [_, b, c, _, _, _] = transform.as_coeffs();
+        let is_src_over = is_src_over_mode(blend) && alpha >= 1.0;
+        if let Some(rect) = shape
+            .as_rect()
+            .filter(|_| is_src_over && b == 0.0 && c == 0.0)
+        {
+            self.flat_layers.push(FlatLayer {
+                layer_depth: self.layer_depth,
+                start,
+                end: self.scene.mark(),
+                bounds: transform.transform_rect_bbox(rect),
+                inner: None,
+                is_clipping: false,
+            });
+        }
+    }
+
+    /// Removes the current layer from the encoding if it is a flat layer
+    /// whose content is empty or a single draw object within its bounds.
+    ///
+    /// User interface toolkits tend to push a layer for every widget, and
+    /// removing the trivial ones saves the blend stack space and the
+    /// compositing of their tiles.
+    fn flatten_layer(&mut self) -> bool {
+        let layer = match self.flat_layers.last() {
+            Some(layer) if layer.layer_depth == self.layer_depth => *layer,
+            _ => return false,
+        };
+        self.flat_layers.pop();
+        let end = &layer.end;
+        let is_flat = self.scene.instances.len() == end.instances
+            && match layer.inner {
+                Some(_) => !layer.is_clipping,
+                None => self.scene.n_clips == end.n_clips && self.is_trivial_content(&layer),
+            };
+        if is_flat {
+            self.scene.remove_range(&layer.start, end);
+            // The content of rounded clips remains and moves to the start.
+            let n_removed = end.draw_tags - layer.start.draw_tags;
+            self.last_draw = self
+                .last_draw
+                .filter(|(ix, _)| layer.inner.is_some() && *ix >= end.draw_tags)
+                .map(|(ix, bbox)| (ix - n_removed, bbox));
+        }
+        is_flat
+    }
+
+    /// Returns true if the content of a layer is empty or a single draw
+    /// object inside its bounds.
+    fn is_trivial_content(&self, layer: &FlatLayer) -> bool {
+        let end = &layer.end;
+        match self.scene.draw_tags.len() - end.draw_tags {
+            0 => true,
+            // Only a draw that is composited with source-over can be moved
+            // onto the backdrop. Erasers and additive draws apply to the
+            // transparent layer rather than to the backdrop.
+            1 => {
+                matches!(self.last_draw, Some((ix, bbox))
+                    if ix == end.draw_tags && contains(layer.bounds, bbox))
+                    && self.scene.draw_tags[end.draw_tags] != DrawTag::ERASE
+                    && self.draw_flags_at(end.draw_tags) == 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the draw flags of the draw object at the given index.
+    fn draw_flags_at(&self, draw_ix: usize) -> u32 {
+        self.scene
+            .draw_flags
+            .iter()
+            .rev()
+            .find(|(ix, _)| *ix as usize <= draw_ix)
+            .map_or(0, |(_, flags)| *flags)
+    }
+
+    /// Encodes the path that bounds a layer.
+    fn encode_layer_path(&mut self, style: Fill, transform: Affine, shape: &impl Shape) {
         self.scene
             .encode_transform(Transform::from_kurbo(&transform));
-        self.scene.encode_linewidth(-1.0);
+        self.scene.encode_linewidth(match style {
+            Fill::NonZero => -1.0,
+            Fill::EvenOdd => -2.0,
+        });
         if !self.scene.encode_shape(shape, true) {
             // If the layer shape is invalid, encode a valid empty path. This suppresses
             // all drawing until the layer is popped.
             self.scene
                 .encode_shape(&Rect::new(0.0, 0.0, 0.0, 0.0), true);
         }
-        self.scene.encode_begin_clip(blend, alpha.clamp(0.0, 1.0));
+    }
+
+    /// Pushes a new layer whose content is drawn with the translation of
+    /// the current transform rounded to whole device pixels.
+    ///
+    /// This keeps text and icons in scrolled or moving containers at the
+    /// same position relative to the pixel grid, so they do not shimmer
+    /// when the container is moved by fractional amounts, at the cost of
+    /// the content moving in steps of whole pixels. Content that should
+    /// move smoothly, such as animations, belongs in a regular layer.
+    ///
+    /// The rounded transform applies to the shape of the layer and to all
+    /// draws until the layer is popped, including the transforms pushed
+    /// within it.
+    pub fn push_pixel_aligned_layer(
+        &mut self,
+        blend: impl Into<BlendMode>,
+        alpha: f32,
+        transform: Affine,
+        shape: &impl Shape,
+    ) {
+        let n_transforms = self.transforms.len();
+        let aligned = snap::snap_translation(self.transform());
+        self.transforms.push(aligned);
+        self.encode_layer(
+            Fill::NonZero,
+            blend.into(),
+            alpha,
+            aligned * transform,
+            shape,
+        );
+        self.aligned_layers.push((self.layer_depth, n_transforms));
+    }
+
+    /// Pushes a new layer whose colors are transformed by a color matrix
+    /// when it is popped, for example to gray out disabled controls.
+    ///
+    /// The matrix is applied to the unpremultiplied colors of the content
+    /// of the layer before the group alpha and the blend mode. Offsets in
+    /// the matrix also affect the transparent parts of the layer within
+    /// `shape`, as for the filter region of `feColorMatrix`.
+    pub fn push_color_matrix_layer(
+        &mut self,
+        matrix: &ColorMatrix,
+        blend: impl Into<BlendMode>,
+        alpha: f32,
+        transform: Affine,
+        shape: &impl Shape,
+    ) {
+        let transform = self.transform() * transform;
+        let blend = blend.into();
+        self.clip_content(transform.transform_rect_bbox(shape.bounding_box()));
+        if !is_src_over_mode(blend) {
+            self.keep_current_clip_layer();
+        }
+        self.encode_layer_path(Fill::NonZero, transform, shape);
+        self.scene.encode_begin_color_matrix_clip(
+            blend,
+            alpha.clamp(0.0, 1.0),
+            Fill::NonZero,
+            &matrix.0,
+        );
         self.layer_depth += 1;
+        self.max_layer_depth = self.max_layer_depth.max(self.layer_depth);
+    }
+
+    /// Pushes a new layer bound by the outline of a stroke of the specified
+    /// shape, for example for progress rings and outlined reveal effects.
+    pub fn push_stroke_layer(
+        &mut self,
+        style: &Stroke,
+        blend: impl Into<BlendMode>,
+        alpha: f32,
+        transform: Affine,
+        shape: &impl Shape,
+    ) {
+        let outline = stroke_outline(style, shape);
+        self.push_layer_with_fill(Fill::NonZero, blend, alpha, transform, &outline);
+    }
+
+    /// Pushes a layer whose content is masked by the content of a fragment,
+    /// such as a gradient or an image, for soft masks. The layer is bound by
+    /// `shape` and popped with [`pop_layer`](Self::pop_layer).
+    ///
+    /// The mask is drawn with `mask_transform` in addition to `transform`,
+    /// and is transparent outside of the content of the fragment.
+    pub fn push_mask_layer(
+        &mut self,
+        mode: MaskMode,
+        alpha: f32,
+        transform: Affine,
+        shape: &impl Shape,
+        mask: &SceneFragment,
+        mask_transform: Option<Affine>,
+    ) {
+        let transform = self.transform() * transform;
+        // The group holds the mask, which the content is then composited
+        // into with source-in, so that it takes the opacity of the mask.
+        self.encode_layer(Fill::NonZero, BlendMode::default(), alpha, transform, shape);
+        let mask_transform = transform * mask_transform.unwrap_or(Affine::IDENTITY);
+        let mask_transform = Some(Transform::from_kurbo(&mask_transform));
+        match mode {
+            MaskMode::Alpha => self.scene.append(&mask.data, &mask_transform),
+            MaskMode::Luminance => {
+                self.encode_layer_path(Fill::NonZero, transform, shape);
+                self.scene.encode_begin_luminance_clip(Fill::NonZero);
+                self.scene.append(&mask.data, &mask_transform);
+                self.scene.encode_end_clip();
+            }
+        }
+        let src_in = BlendMode::new(Mix::Normal, Compose::SrcIn);
+        self.encode_layer(Fill::NonZero, src_in, 1.0, transform, shape);
+        self.mask_layers.push(self.layer_depth);
+    }
+
+    /// Pushes a layer that is composited over a blurred copy of the content
+    /// drawn beneath it, as for the CSS `backdrop-filter: blur()` property,
+    /// for frosted glass panels. The layer is bound by `shape` and popped
+    /// with [`pop_layer`](Self::pop_layer).
+    ///
+    /// The fine stage composites each tile in isolation, so the backdrop is
+    /// rendered and blurred in separate passes before the scene, which costs
+    /// another render of the content beneath each backdrop layer. The
+    /// standard deviation of the blur is in the coordinate space of the
+    /// shape. Backdrop layers in fragments only draw their content.
+    pub fn push_backdrop_layer(
+        &mut self,
+        std_dev: f32,
+        alpha: f32,
+        transform: Affine,
+        shape: &impl Shape,
+    ) {
+        let transform = self.transform() * transform;
+        let std_dev = std_dev.max(0.0) * transform.determinant().abs().sqrt() as f32;
+        let extent = (3.0 * std_dev).ceil() as f64;
+        let bounds = transform
+            .transform_rect_bbox(shape.bounding_box())
+            .inflate(extent, extent)
+            .expand();
+        // The backdrop is rendered with the enclosing layers, so the layers
+        // of rounded clips are kept.
+        self.keep_clip_layers();
+        let image = AsyncImage::new(Placeholder::Skip);
+        let backdrop = Backdrop {
+            mark: self.scene.mark(),
+            open_layers: self.layer_depth,
+            bounds,
+            std_dev,
+            image: image.clone(),
+        };
+        self.scene.backdrops.push(backdrop);
+        self.encode_layer(Fill::NonZero, Mix::Normal.into(), alpha, transform, shape);
+        // The blurred backdrop is drawn in scene coordinates.
+        self.scene
+            .encode_transform(Transform::from_kurbo(&Affine::IDENTITY));
+        self.scene.encode_linewidth(-1.0);
+        if self.scene.encode_shape(&bounds, true) {
+            let brush_transform = Affine::translate(bounds.origin().to_vec2());
+            self.scene
+                .encode_transform(Transform::from_kurbo(&brush_transform));
+            self.scene.swap_last_path_tags();
+            self.scene.encode_async_image(&image, 1.0);
+        }
     }
 
     /// Pops the current layer.
     pub fn pop_layer(&mut self) {
         if self.layer_depth > 0 {
-            self.scene.encode_end_clip();
+            if self.mask_layers.last() == Some(&self.layer_depth) {
+                self.mask_layers.pop();
+                self.scene.encode_end_clip();
+                self.layer_depth -= 1;
+            }
+            if let Some(&(depth, n_transforms)) = self.aligned_layers.last() {
+                if depth == self.layer_depth {
+                    self.aligned_layers.pop();
+                    self.transforms.truncate(n_transforms);
+                }
+            }
+            if !self.flatten_layer() {
+                self.scene.encode_end_clip();
+            }
             self.layer_depth -= 1;
         }
     }
 
+    /// Assigns subsequent draws to a visibility group, or to no group if
+    /// `group` is `None`.
+    ///
+    /// Groups do not nest: each draw belongs to at most one group. Draws in
+    /// an appended fragment keep the groups assigned while building the
+    /// fragment and otherwise take the current group.
+    pub fn set_visibility_group(&mut self, group: Option<u32>) {
+        self.scene.encode_visibility_group(group);
+    }
+
+    /// Sets whether subsequent draws are added to the content beneath them
+    /// instead of being composited with source-over.
+    ///
+    /// The result is clamped as for [`Compose::PlusLighter`], but unlike a
+    /// layer with that mode, each draw is composited directly, without a
+    /// blend group, so large numbers of overlapping particles or glows stay
+    /// cheap. Clips and layers are not affected.
+    pub fn set_additive_blending(&mut self, enabled: bool) {
+        let flags = self.scene.draw_flags();
+        self.scene.encode_draw_flags(if enabled {
+            flags | DRAW_FLAG_ADDITIVE
+        } else {
+            flags & !DRAW_FLAG_ADDITIVE
+        });
+    }
+
+    /// Sets whether subsequent draws of axis-aligned geometry are snapped to
+    /// device pixels.
+    ///
+    /// While enabled, the edges of filled rectangles are moved to pixel
+    /// boundaries, and stroked rectangles and horizontal or vertical lines
+    /// are moved so that the stroke covers whole pixels, with its width
+    /// rounded to a whole number of pixels. This keeps thin separators and
+    /// borders in user interfaces crisp at fractional positions and scales.
+    /// Other shapes and draws with rotated or skewed transforms are not
+    /// affected. See the [`snap`](crate::snap) module for the underlying
+    /// functions.
+    pub fn set_pixel_snapping(&mut self, enabled: bool) {
+        self.pixel_snapping = enabled;
+    }
+
+    /// Returns true if pixel snapping is enabled.
+    pub fn pixel_snapping(&self) -> bool {
+        self.pixel_snapping
+    }
+
+    /// Pushes a clip to a rounded rectangle.
+    ///
+    /// This is optimized for the common case of UI containers where most of
+    /// the content is either entirely inside or entirely outside of the clip.
+    /// Draws outside of the clip are culled, and the clip is encoded as a
+    /// layer that spans all of its content but is removed when the clip is
+    /// popped if the content did not need it. The layer is kept if any of
+    /// the content intersects the rounded corners or the boundary, or is not
+    /// composited with source-over, such as erasers, so the content behaves
+    /// as in a regular clip layer.
+    ///
+    /// Every call must be balanced with [`pop_rounded_clip`](Self::pop_rounded_clip).
+    pub fn push_rounded_clip(&mut self, transform: Affine, rect: &RoundedRect) {
+        let transform = self.transform() * transform;
+        let outer = transform.transform_rect_bbox(rect.rect());
+        let start = self.scene.mark();
+        self.encode_layer(Fill::NonZero, Mix::Clip.into(), 1.0, transform, rect);
+        self.rounded_clips.push(outer);
+        let [a, b, c, d, _, _] = transform.as_coeffs();
+        if b != 0.0 || c != 0.0 {
+            return;
+        }
+        let radii = rect.radii();
+        let radius = radii
+            .top_left
+            .max(radii.top_right)
+            .max(radii.bottom_right)
+            .max(radii.bottom_left);
+        self.flat_layers.push(FlatLayer {
+            layer_depth: self.layer_depth,
+            start,
+            end: self.scene.mark(),
+            bounds: outer,
+            inner: Some(outer.inset(-radius * a.abs().max(d.abs()))),
+            is_clipping: false,
+        });
+    }
+
+    /// Pops the most recent rounded rectangle clip.
+    pub fn pop_rounded_clip(&mut self) {
+        if self.rounded_clips.pop().is_some() {
+            self.pop_layer();
+        }
+    }
+
+    /// Pushes a scissor rectangle that limits subsequent draws.
+    ///
+    /// Unlike a clip layer, a scissor is applied to each draw individually
+    /// and is much cheaper to render, which makes it suitable for clipping
+    /// rows of lists and tables. The rectangle is transformed to device
+    /// space, so rotated or skewed transforms result in the bounding box of
+    /// the transformed rectangle. Nested scissors are intersected. Draws
+    /// that are entirely outside of the scissor are culled. Layers are not
+    /// affected by the scissor.
+    ///
+    /// Every call must be balanced with [`pop_scissor`](Self::pop_scissor).
+    pub fn push_scissor(&mut self, transform: Affine, rect: Rect) {
+        let mut rect = (self.transform() * transform).transform_rect_bbox(rect);
+        if let Some(parent) = self.scissors.last() {
+            rect = rect.intersect(*parent);
+        }
+        self.scissors.push(rect);
+        self.scene.encode_scissor(Some(scissor_to_f32(rect)));
+    }
+
+    /// Pops the most recent scissor rectangle.
+    pub fn pop_scissor(&mut self) {
+        self.scissors.pop();
+        let rect = self.scissors.last().map(|rect| scissor_to_f32(*rect));
+        self.scene.encode_scissor(rect);
+    }
+
     /// Fills a shape using the specified style and brush.
     pub fn fill<'b>(
         &mut self,
@@ -132,54 +1006,1026 @@ impl<'a> SceneBuilder<'a> {
         brush_transform: Option<Affine>,
         shape: &impl Shape,
     ) {
-        self.scene
-            .encode_transform(Transform::from_kurbo(&transform));
-        self.scene.encode_linewidth(match style {
-            Fill::NonZero => -1.0,
-            Fill::EvenOdd => -2.0,
+        self.fill_with(style, transform, brush_transform, shape, |scene| {
+            scene.encode_brush(brush, 1.0)
         });
-        if self.scene.encode_shape(shape, true) {
-            if let Some(brush_transform) = brush_transform {
-                self.scene
-                    .encode_transform(Transform::from_kurbo(&(transform * brush_transform)));
-                self.scene.swap_last_path_tags();
-                self.scene.encode_brush(brush, 1.0);
-            } else {
-                self.scene.encode_brush(brush, 1.0);
-            }
-        }
     }
 
-    /// Strokes a shape using the specified style and brush.
-    pub fn stroke<'b>(
+    /// Fills a shape using the specified style and a brush from the brush
+    /// table of the scene.
+    pub fn fill_handle(
         &mut self,
-        style: &Stroke,
+        style: Fill,
         transform: Affine,
-        brush: impl Into<BrushRef<'b>>,
+        brush: BrushHandle,
         brush_transform: Option<Affine>,
         shape: &impl Shape,
     ) {
-        self.scene
-            .encode_transform(Transform::from_kurbo(&transform));
-        self.scene.encode_linewidth(style.width);
-        if self.scene.encode_shape(shape, false) {
-            if let Some(brush_transform) = brush_transform {
-                self.scene
-                    .encode_transform(Transform::from_kurbo(&(transform * brush_transform)));
-                self.scene.swap_last_path_tags();
-                self.scene.encode_brush(brush, 1.0);
-            } else {
-                self.scene.encode_brush(brush, 1.0);
-            }
-        }
+        self.fill_with(style, transform, brush_transform, shape, |scene| {
+            scene.encode_brush_handle(brush, 1.0)
+        });
     }
 
-    /// Appends a fragment to the scene.
-    pub fn append(&mut self, fragment: &SceneFragment, transform: Option<Affine>) {
-        self.scene.append(
-            &fragment.data,
-            &transform.map(|xform| Transform::from_kurbo(&xform)),
-        );
+    /// Fills a shape using the specified style and an asynchronously loaded
+    /// image, drawing its placeholder until the image is available.
+    pub fn fill_async_image(
+        &mut self,
+        style: Fill,
+        transform: Affine,
+        image: &AsyncImage,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+    ) {
+        self.fill_with(style, transform, brush_transform, shape, |scene| {
+            scene.encode_async_image(image, 1.0)
+        });
+    }
+
+    /// Fills a shape with an image brush that is sampled with the specified
+    /// filter, for example nearest neighbor sampling for pixel art.
+    pub fn fill_image(
+        &mut self,
+        style: Fill,
+        transform: Affine,
+        image: &Image,
+        sampling: ImageSampling,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+    ) {
+        self.fill_with(style, transform, brush_transform, shape, |scene| {
+            scene.encode_image_with_sampling(image, 1.0, sampling)
+        });
+    }
+
+    /// Fills a shape with a pattern that repeats a vector motif. Draws
+    /// nothing if the pattern has not been
+    /// [prepared](crate::pattern::Pattern::prepare).
+    pub fn fill_pattern(
+        &mut self,
+        style: Fill,
+        transform: Affine,
+        pattern: &Pattern,
+        shape: &impl Shape,
+    ) {
+        pattern.fill(self, style, transform, shape);
+    }
+
+    /// Fills a shape with an image holding packed YUV samples, such as a
+    /// video frame produced by [`yuv::pack_yuv`](crate::yuv::pack_yuv) or
+    /// [`YuvPacker`](crate::yuv::YuvPacker), converting them to RGB with
+    /// the specified matrix and range.
+    #[allow(clippy::too_many_arguments)]
+    pub fn fill_yuv_image(
+        &mut self,
+        style: Fill,
+        transform: Affine,
+        image: &Image,
+        matrix: YuvMatrix,
+        range: YuvRange,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+    ) {
+        self.fill_with(style, transform, brush_transform, shape, |scene| {
+            scene.encode_yuv_image(image, 1.0, matrix, range)
+        });
+    }
+
+    /// Draws an image in the specified orientation with its top left corner at
+    /// the origin, so that images with an EXIF orientation can be displayed
+    /// without uploading a rotated copy.
+    pub fn draw_image(&mut self, image: &Image, orientation: Orientation, transform: Affine) {
+        let (width, height) = orientation.oriented_size(image.width, image.height);
+        let rect = Rect::new(0.0, 0.0, width as f64, height as f64);
+        let brush_transform = orientation.transform(image.width as f64, image.height as f64);
+        self.fill(
+            Fill::NonZero,
+            transform,
+            BrushRef::Image(image),
+            Some(brush_transform),
+            &rect,
+        );
+    }
+
+    /// Draws an image split into nine regions by `insets`, in texels,
+    /// stretched to fill `rect`.
+    ///
+    /// The corners keep their size in texels, the edges are stretched along
+    /// one axis and the center along both. The insets are scaled down when
+    /// the rectangle is too small to hold them. Each region is sampled as an
+    /// image of its own that is extended by repeating its edge texels, so
+    /// filtering does not bleed texels of neighboring regions across the
+    /// seams.
+    pub fn draw_image_nine_slice(
+        &mut self,
+        image: &Image,
+        insets: Insets,
+        rect: Rect,
+        transform: Affine,
+    ) {
+        let texel = |inset: f64, len: u32| inset.round().clamp(0.0, len as f64) as u32;
+        let (width, height) = (image.width, image.height);
+        let left = texel(insets.x0, width);
+        let right = texel(insets.x1, width - left);
+        let top = texel(insets.y0, height);
+        let bottom = texel(insets.y1, height - top);
+        let src_x = [0, left, width - right, width];
+        let src_y = [0, top, height - bottom, height];
+        let (dst_left, dst_right) = fit(left as f64, right as f64, rect.width());
+        let (dst_top, dst_bottom) = fit(top as f64, bottom as f64, rect.height());
+        let dst_x = [rect.x0, rect.x0 + dst_left, rect.x1 - dst_right, rect.x1];
+        let dst_y = [rect.y0, rect.y0 + dst_top, rect.y1 - dst_bottom, rect.y1];
+        for j in 0..3 {
+            for i in 0..3 {
+                let origin = [src_x[i], src_y[j]];
+                let size = [src_x[i + 1] - src_x[i], src_y[j + 1] - src_y[j]];
+                let dst = Rect::new(dst_x[i], dst_y[j], dst_x[i + 1], dst_y[j + 1]);
+                if size[0] == 0 || size[1] == 0 || dst.width() <= 0.0 || dst.height() <= 0.0 {
+                    continue;
+                }
+                let brush_transform = Affine::translate(dst.origin().to_vec2())
+                    * Affine::scale_non_uniform(
+                        dst.width() / size[0] as f64,
+                        dst.height() / size[1] as f64,
+                    );
+                self.fill_with(
+                    Fill::NonZero,
+                    transform,
+                    Some(brush_transform),
+                    &dst,
+                    |scene| {
+                        scene.encode_image_region(image, 1.0, ImageSampling::Linear, origin, size)
+                    },
+                );
+            }
+        }
+    }
+
+    /// Fills a rounded rectangle with the specified brush.
+    ///
+    /// With a solid color, the rounded rectangle is encoded as a single draw
+    /// whose path is its bounding rectangle, padded by a device pixel, and
+    /// the coverage of the corners is computed analytically when the scene
+    /// is rendered rather than from flattened arcs. Other brushes fill the
+    /// outline of the rounded rectangle.
+    pub fn fill_rrect<'b>(
+        &mut self,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+        rect: &RoundedRect,
+    ) {
+        let color = match brush.into() {
+            BrushRef::Solid(color) => color,
+            brush => return self.fill(Fill::NonZero, transform, brush, None, rect),
+        };
+        let pad = match min_scale(self.transform() * transform) {
+            Some(scale) => 1.0 / scale,
+            None => return,
+        };
+        let bounds = rect.rect();
+        let radii = rect.radii();
+        let draw = DrawRoundedRect::new(
+            color,
+            bounds.width() as f32,
+            bounds.height() as f32,
+            [
+                radii.top_left as f32,
+                radii.top_right as f32,
+                radii.bottom_right as f32,
+                radii.bottom_left as f32,
+            ],
+        );
+        self.fill_with(
+            Fill::NonZero,
+            transform,
+            Some(Affine::translate(bounds.center().to_vec2())),
+            &bounds.inflate(pad, pad),
+            |scene| scene.encode_rounded_rect(draw),
+        );
+    }
+
+    /// Fills a circle with the specified brush.
+    ///
+    /// With a solid color, the circle is encoded as a single draw with the
+    /// center and radius of the circle, and its coverage is computed exactly
+    /// when the scene is rendered. This avoids flattening the circle into
+    /// curves, which dominates the time to encode scenes such as scatter
+    /// plots. Other brushes fill the outline of the circle.
+    pub fn fill_circle<'b>(
+        &mut self,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+        circle: &Circle,
+    ) {
+        match brush.into() {
+            BrushRef::Solid(color) => {
+                let radii = Vec2::new(circle.radius, circle.radius);
+                self.encode_ellipse(transform, color, circle.center, radii);
+            }
+            brush => self.fill(Fill::NonZero, transform, brush, None, circle),
+        }
+    }
+
+    /// Fills an ellipse with the specified brush.
+    ///
+    /// This is drawn in the same way as [`fill_circle`](Self::fill_circle).
+    pub fn fill_ellipse<'b>(
+        &mut self,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+        ellipse: &Ellipse,
+    ) {
+        match brush.into() {
+            BrushRef::Solid(color) => {
+                let center = ellipse.center();
+                let rotation = ellipse.rotation();
+                // The encoded ellipse is axis aligned, so the rotation is
+                // moved to the transform.
+                let transform = if rotation != 0.0 {
+                    transform
+                        * Affine::translate(center.to_vec2())
+                        * Affine::rotate(rotation)
+                        * Affine::translate(-center.to_vec2())
+                } else {
+                    transform
+                };
+                self.encode_ellipse(transform, color, center, ellipse.radii());
+            }
+            brush => self.fill(Fill::NonZero, transform, brush, None, ellipse),
+        }
+    }
+
+    /// Encodes an axis aligned ellipse whose path is its bounding rectangle,
+    /// padded by a device pixel.
+    fn encode_ellipse(&mut self, transform: Affine, color: Color, center: Point, radii: Vec2) {
+        let pad = match min_scale(self.transform() * transform) {
+            Some(scale) => 1.0 / scale,
+            None => return,
+        };
+        let radii = Vec2::new(radii.x.abs(), radii.y.abs());
+        let bounds = Rect::from_center_size(center, radii * 2.0).inflate(pad, pad);
+        let draw = DrawEllipse::new(
+            color,
+            [center.x as f32, center.y as f32],
+            [radii.x as f32, radii.y as f32],
+        );
+        self.fill_with(Fill::NonZero, transform, None, &bounds, |scene| {
+            scene.encode_ellipse(draw)
+        });
+    }
+
+    /// Draws the shadow of a shape, displaced by `offset` and blurred like a
+    /// CSS box shadow, where `blur_radius` is twice the standard deviation
+    /// of the Gaussian.
+    ///
+    /// Rectangles, circles and rounded rectangles with uniform radii are
+    /// blurred analytically with a single draw. The shadows of other shapes
+    /// are computed from the distance to their outline, which is exact for
+    /// straight edges and slightly rounds off sharp corners. The shadow is
+    /// not clipped by the shape, so it is drawn before the shape.
+    pub fn draw_shadow(
+        &mut self,
+        shape: &impl Shape,
+        offset: Vec2,
+        blur_radius: f64,
+        color: Color,
+    ) {
+        let std_dev = (blur_radius * 0.5).max(0.0);
+        let extent = 3.0 * std_dev + 1.0;
+        let rounded = shape
+            .as_rect()
+            .map(|rect| RoundedRect::from_rect(rect, 0.0))
+            .or_else(|| shape.as_rounded_rect())
+            .or_else(|| {
+                shape
+                    .as_circle()
+                    .map(|circle| RoundedRect::from_rect(circle.bounding_box(), circle.radius))
+            });
+        if let Some(rounded) = rounded {
+            if let Some(radius) = rounded.radii().as_single_radius() {
+                let rect = rounded.rect() + offset;
+                let shadow = DrawShadow::new(
+                    color,
+                    rect.width() as f32,
+                    rect.height() as f32,
+                    radius as f32,
+                    std_dev as f32,
+                );
+                self.fill_with(
+                    Fill::NonZero,
+                    Affine::IDENTITY,
+                    Some(Affine::translate(rect.center().to_vec2())),
+                    &rect.inflate(extent, extent),
+                    |scene| scene.encode_shadow(shadow),
+                );
+                return;
+            }
+        }
+        // The blurred coverage of a shape is approximately 1 - g inside and g
+        // outside, where g is the complementary error function of the
+        // distance to the outline. The fill is combined with
+        // a stroke of the band using xor, which yields exactly that. Xor only
+        // yields the coverage for opaque colors, so the alpha of the color
+        // is applied once to the enclosing layer.
+        let transform = Affine::translate(offset);
+        let bbox = (shape.bounding_box() + offset).inflate(extent, extent);
+        let alpha = color.a as f32 / 255.0;
+        let color = Color { a: 255, ..color };
+        self.push_layer(Mix::Normal, alpha, Affine::IDENTITY, &bbox);
+        self.fill(Fill::NonZero, transform, color, None, shape);
+        if std_dev > 0.0 {
+            let band = Stroke::new(6.0 * std_dev as f32);
+            self.push_layer(
+                BlendMode::new(Mix::Normal, Compose::Xor),
+                1.0,
+                Affine::IDENTITY,
+                &bbox,
+            );
+            self.stroke_with(&band, transform, None, shape, |scene| {
+                scene.encode_shadow(DrawShadow::new(color, 0.0, 0.0, 0.0, 0.0))
+            });
+            self.pop_layer();
+        }
+        self.pop_layer();
+    }
+
+    /// Fills a shape using the specified style and the brush that a custom
+    /// resource resolves to when the scene is rendered.
+    pub fn fill_custom(
+        &mut self,
+        style: Fill,
+        transform: Affine,
+        resource: &Arc<dyn CustomResource>,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+    ) {
+        self.fill_with(style, transform, brush_transform, shape, |scene| {
+            scene.encode_custom(resource.clone(), 1.0)
+        });
+    }
+
+    /// Fills a shape using the specified style and the color in a palette slot.
+    pub fn fill_palette(&mut self, style: Fill, transform: Affine, slot: u32, shape: &impl Shape) {
+        self.fill_with(style, transform, None, shape, |scene| {
+            scene.encode_palette_color(slot)
+        });
+    }
+
+    /// Fills a shape using the specified style and procedural brush.
+    pub fn fill_procedural(
+        &mut self,
+        style: Fill,
+        transform: Affine,
+        brush: &ProceduralBrush,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+    ) {
+        self.fill_with(style, transform, brush_transform, shape, |scene| {
+            scene.encode_procedural(DrawProcedural::new(brush))
+        });
+    }
+
+    /// Common logic for fills where `encode_brush` is responsible for encoding
+    /// the brush once the path has been encoded.
+    fn fill_with(
+        &mut self,
+        style: Fill,
+        transform: Affine,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+        encode_brush: impl FnOnce(&mut Encoding),
+    ) {
+        let transform = self.transform() * transform;
+        match shape.as_rect().filter(|_| self.pixel_snapping) {
+            Some(rect) => {
+                let rect = snap::snap_rect(rect, transform);
+                self.encode_fill(style, transform, brush_transform, &rect, encode_brush);
+            }
+            None => self.encode_fill(style, transform, brush_transform, shape, encode_brush),
+        }
+    }
+
+    /// Encodes a fill with a transform that includes the pushed transforms.
+    fn encode_fill(
+        &mut self,
+        style: Fill,
+        transform: Affine,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+        encode_brush: impl FnOnce(&mut Encoding),
+    ) {
+        let bbox = transform.transform_rect_bbox(shape.bounding_box());
+        if !self.begin_draw(bbox) {
+            return;
+        }
+        self.scene
+            .encode_transform(Transform::from_kurbo(&transform));
+        self.scene.encode_linewidth(match style {
+            Fill::NonZero => -1.0,
+            Fill::EvenOdd => -2.0,
+        });
+        if self.scene.encode_shape(shape, true) {
+            if let Some(brush_transform) = brush_transform {
+                self.scene
+                    .encode_transform(Transform::from_kurbo(&(transform * brush_transform)));
+                self.scene.swap_last_path_tags();
+            }
+            encode_brush(self.scene);
+        }
+    }
+
+    /// Strokes a shape using the specified style and brush.
+    ///
+    /// The dash pattern of the style, if any, is applied when the shape is
+    /// encoded, with lengths in the coordinate space of the shape.
+    pub fn stroke<'b>(
+        &mut self,
+        style: &Stroke,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+    ) {
+        self.stroke_with(style, transform, brush_transform, shape, |scene| {
+            scene.encode_brush(brush, 1.0)
+        });
+    }
+
+    /// Strokes a shape using the specified style and a brush from the brush
+    /// table of the scene.
+    pub fn stroke_handle(
+        &mut self,
+        style: &Stroke,
+        transform: Affine,
+        brush: BrushHandle,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+    ) {
+        self.stroke_with(style, transform, brush_transform, shape, |scene| {
+            scene.encode_brush_handle(brush, 1.0)
+        });
+    }
+
+    /// Strokes a shape using the specified style and the color in a palette
+    /// slot.
+    pub fn stroke_palette(
+        &mut self,
+        style: &Stroke,
+        transform: Affine,
+        slot: u32,
+        shape: &impl Shape,
+    ) {
+        self.stroke_with(style, transform, None, shape, |scene| {
+            scene.encode_palette_color(slot)
+        });
+    }
+
+    /// Fills a shape and then strokes it on top of the fill.
+    ///
+    /// This is equivalent to calling [`fill`](Self::fill) followed by
+    /// [`stroke`](Self::stroke) but encodes the geometry of the shape only
+    /// once when all of its subpaths are closed and the stroke is not
+    /// dashed. With [pixel snapping](Self::set_pixel_snapping), rectangles
+    /// and lines are snapped like they are by those methods.
+    pub fn draw<'b, 'c>(
+        &mut self,
+        style: Fill,
+        transform: Affine,
+        fill_brush: impl Into<BrushRef<'b>>,
+        stroke: &Stroke,
+        stroke_brush: impl Into<BrushRef<'c>>,
+        shape: &impl Shape,
+    ) {
+        if self.pixel_snapping && (shape.as_rect().is_some() || shape.as_line().is_some()) {
+            // The fill and the stroke are snapped to different geometry.
+            self.fill(style, transform, fill_brush, None, shape);
+            self.stroke(stroke, transform, stroke_brush, None, shape);
+            return;
+        }
+        let transform = self.transform() * transform;
+        let [a, b, c, d, _, _] = transform.as_coeffs();
+        let scale = a.abs().max(b.abs()).max(c.abs()).max(d.abs());
+        let bbox = transform
+            .transform_rect_bbox(shape.bounding_box())
+            .inset(stroke.width as f64 * scale * 2.0);
+        if !self.begin_draw(bbox) {
+            return;
+        }
+        self.scene
+            .encode_transform(Transform::from_kurbo(&transform));
+        self.scene.encode_linewidth(match style {
+            Fill::NonZero => -1.0,
+            Fill::EvenOdd => -2.0,
+        });
+        let range = self.scene.encode_fill_shape(shape);
+        if range.is_some() {
+            self.scene.encode_brush(fill_brush, 1.0);
+        }
+        self.scene.encode_linewidth(stroke.width);
+        let dashes = dash_path(
+            shape.path_elements(0.1),
+            stroke.dash_offset,
+            &stroke.dash_pattern,
+        );
+        let encoded = match (range, dashes) {
+            (_, Some(dashes)) => self.scene.encode_shape(&dashes, false),
+            (Some(range), None) if range.closed => {
+                self.scene.encode_path_copy(&range);
+                true
+            }
+            _ => self.scene.encode_shape(shape, false),
+        };
+        if encoded {
+            self.scene.encode_brush(stroke_brush, 1.0);
+        }
+    }
+
+    /// Strokes line segments through a sequence of points in a single draw.
+    ///
+    /// Points with non-finite coordinates leave a gap in the line. This is
+    /// intended for plotting large numbers of samples: the points are copied
+    /// directly into the encoding without going through [`Shape`].
+    pub fn stroke_polyline<'b>(
+        &mut self,
+        style: &Stroke,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+        points: &[[f32; 2]],
+    ) {
+        let transform = self.transform() * transform;
+        let bbox = self.bulk_bbox(transform, style.width as f64, points);
+        self.draw_bulk(bbox, transform, style.width, false, brush, |encoder| {
+            encoder.polyline(points)
+        });
+    }
+
+    /// Draws a disk with the given diameter centered at each point in a
+    /// single draw.
+    ///
+    /// The disks are not scaled by the transform, which makes this suitable
+    /// for scatter plots where the transform maps data to device space.
+    pub fn draw_points<'b>(
+        &mut self,
+        diameter: f32,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+        points: &[[f32; 2]],
+    ) {
+        let transform = self.transform() * transform;
+        let bbox = self.bulk_bbox(transform, diameter as f64, points);
+        // Each point is a stroked segment that is short enough to be
+        // indistinguishable from a round dot. The segments are encoded in
+        // device space so that the width is not affected by the transform.
+        let to_device = |[x, y]: [f32; 2]| {
+            let p = transform * Point::new(x as f64, y as f64);
+            [p.x as f32, p.y as f32]
+        };
+        self.draw_bulk(bbox, Affine::IDENTITY, diameter, false, brush, |encoder| {
+            for point in points {
+                let [x, y] = to_device(*point);
+                if x.is_finite() && y.is_finite() {
+                    encoder.move_to(x, y);
+                    encoder.line_to(x + x.abs().max(1.0) * 1e-6, y);
+                }
+            }
+        });
+    }
+
+    /// Fills the band between two series that share x coordinates, such as
+    /// a confidence interval, in a single draw.
+    ///
+    /// The series are truncated to the shortest of the three slices.
+    pub fn fill_band<'b>(
+        &mut self,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+        xs: &[f32],
+        lows: &[f32],
+        highs: &[f32],
+    ) {
+        let transform = self.transform() * transform;
+        let n = xs.len().min(lows.len()).min(highs.len());
+        let mut points = Vec::with_capacity(n * 2);
+        points.extend((0..n).map(|i| [xs[i], highs[i]]));
+        points.extend((0..n).rev().map(|i| [xs[i], lows[i]]));
+        let bbox = self.bulk_bbox(transform, 0.0, &points);
+        self.draw_bulk(bbox, transform, -1.0, true, brush, |encoder| {
+            encoder.polyline(&points)
+        });
+    }
+
+    /// Returns the device space bounding box of a sequence of points for
+    /// culling, or an empty rectangle if nothing could cull the draw.
+    fn bulk_bbox(&self, transform: Affine, width: f64, points: &[[f32; 2]]) -> Rect {
+        if self.rounded_clips.is_empty() && self.scissors.is_empty() {
+            return Rect::ZERO;
+        }
+        let bbox = points
+            .iter()
+            .filter(|[x, y]| x.is_finite() && y.is_finite())
+            .fold(None, |bbox: Option<Rect>, [x, y]| {
+                let point = Point::new(*x as f64, *y as f64);
+                Some(bbox.map_or(Rect::from_points(point, point), |bbox| bbox.union_pt(point)))
+            })
+            .unwrap_or(Rect::ZERO);
+        transform.transform_rect_bbox(bbox).inset(width)
+    }
+
+    /// Common logic for the bulk draws where `encode_path` encodes the
+    /// segments of a single path.
+    fn draw_bulk<'b>(
+        &mut self,
+        bbox: Rect,
+        transform: Affine,
+        linewidth: f32,
+        is_fill: bool,
+        brush: impl Into<BrushRef<'b>>,
+        encode_path: impl FnOnce(&mut PathEncoder),
+    ) {
+        if !self.begin_draw(bbox) {
+            return;
+        }
+        self.scene
+            .encode_transform(Transform::from_kurbo(&transform));
+        self.scene.encode_linewidth(linewidth);
+        let mut encoder = self.scene.encode_path(is_fill);
+        encode_path(&mut encoder);
+        if encoder.finish(true) != 0 {
+            self.scene.encode_brush(brush, 1.0);
+        }
+    }
+
+    /// Erases previously drawn content of the current layer inside a shape.
+    ///
+    /// This is equivalent to drawing with a destination-out blend but does not
+    /// require an additional layer. The `alpha` parameter controls the strength
+    /// of the eraser where 1.0 removes the content entirely.
+    pub fn erase(&mut self, style: Fill, transform: Affine, alpha: f32, shape: &impl Shape) {
+        self.keep_current_clip_layer();
+        self.fill_with(style, transform, None, shape, |scene| {
+            scene.encode_erase(alpha.clamp(0.0, 1.0))
+        });
+    }
+
+    /// Erases previously drawn content of the current layer along the stroke
+    /// of a shape.
+    ///
+    /// See [`erase`](Self::erase) for details.
+    pub fn erase_stroke(
+        &mut self,
+        style: &Stroke,
+        transform: Affine,
+        alpha: f32,
+        shape: &impl Shape,
+    ) {
+        self.keep_current_clip_layer();
+        self.stroke_with(style, transform, None, shape, |scene| {
+            scene.encode_erase(alpha.clamp(0.0, 1.0))
+        });
+    }
+
+    /// Common logic for strokes where `encode_brush` is responsible for encoding
+    /// the brush once the path has been encoded.
+    fn stroke_with(
+        &mut self,
+        style: &Stroke,
+        transform: Affine,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+        encode_brush: impl FnOnce(&mut Encoding),
+    ) {
+        let transform = self.transform() * transform;
+        if self.pixel_snapping {
+            let width = style.width as f64;
+            if let Some(rect) = shape.as_rect() {
+                let rect = snap::snap_stroke_rect(rect, width, transform);
+                let style = snap_stroke_style(style, transform);
+                self.encode_stroke(&style, transform, brush_transform, &rect, encode_brush);
+                return;
+            }
+            if let Some(line) = shape.as_line() {
+                let line = snap::snap_stroke_line(line, width, transform);
+                let style = snap_stroke_style(style, transform);
+                self.encode_stroke(&style, transform, brush_transform, &line, encode_brush);
+                return;
+            }
+        }
+        self.encode_stroke(style, transform, brush_transform, shape, encode_brush);
+    }
+
+    /// Encodes a stroke with a transform that includes the pushed
+    /// transforms.
+    fn encode_stroke(
+        &mut self,
+        style: &Stroke,
+        transform: Affine,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+        encode_brush: impl FnOnce(&mut Encoding),
+    ) {
+        // Generous enough to account for miter joins.
+        let [a, b, c, d, _, _] = transform.as_coeffs();
+        let scale = a.abs().max(b.abs()).max(c.abs()).max(d.abs());
+        let bbox = transform
+            .transform_rect_bbox(shape.bounding_box())
+            .inset(style.width as f64 * scale * 2.0);
+        if !self.begin_draw(bbox) {
+            return;
+        }
+        self.scene
+            .encode_transform(Transform::from_kurbo(&transform));
+        self.scene.encode_linewidth(style.width);
+        let encoded = match dash_path(
+            shape.path_elements(0.1),
+            style.dash_offset,
+            &style.dash_pattern,
+        ) {
+            Some(dashes) => self.scene.encode_shape(&dashes, false),
+            None => self.scene.encode_shape(shape, false),
+        };
+        if encoded {
+            if let Some(brush_transform) = brush_transform {
+                self.scene
+                    .encode_transform(Transform::from_kurbo(&(transform * brush_transform)));
+                self.scene.swap_last_path_tags();
+            }
+            encode_brush(self.scene);
+        }
+    }
+
+    /// Tests a draw with the specified device space bounding box against the
+    /// active scissor and rounded clips, keeping the layers of the rounded
+    /// clips whose content it affects.
+    ///
+    /// Returns false if the draw is entirely clipped out.
+    fn begin_draw(&mut self, bbox: Rect) -> bool {
+        self.last_draw = None;
+        if let Some(scissor) = self.scissors.last() {
+            if !overlaps(*scissor, bbox) {
+                return false;
+            }
+        }
+        if !self.rounded_clips.iter().all(|clip| overlaps(*clip, bbox)) {
+            return false;
+        }
+        self.clip_content(bbox);
+        // Additive draws apply to the layer of the clip.
+        if self.scene.draw_flags() != 0 {
+            self.keep_current_clip_layer();
+        }
+        self.last_draw = Some((self.scene.draw_tags.len(), bbox));
+        true
+    }
+
+    /// Keeps the layers of the enclosing rounded clips if content with the
+    /// specified device space bounding box is not inside the region that is
+    /// unaffected by their corners.
+    fn clip_content(&mut self, bbox: Rect) {
+        for layer in &mut self.flat_layers {
+            if let Some(inner) = layer.inner {
+                layer.is_clipping |= !contains(inner, bbox);
+            }
+        }
+    }
+
+    /// Keeps the layer of the current rounded clip, if any, for content
+    /// that is not composited with source-over and so applies to the layer
+    /// rather than to the content beneath it.
+    fn keep_current_clip_layer(&mut self) {
+        let depth = self.layer_depth;
+        if let Some(layer) = self
+            .flat_layers
+            .last_mut()
+            .filter(|layer| layer.layer_depth == depth && layer.inner.is_some())
+        {
+            layer.is_clipping = true;
+        }
+    }
+
+    /// Keeps the layers of all enclosing rounded clips.
+    fn keep_clip_layers(&mut self) {
+        for layer in &mut self.flat_layers {
+            layer.is_clipping = true;
+        }
+    }
+
+    /// Strokes a polyline whose width varies along its length, given as pairs
+    /// of a point and the width of the stroke at that point, for example to
+    /// apply stylus pressure. Joins and caps are round.
+    pub fn stroke_varying<'b>(
+        &mut self,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+        brush_transform: Option<Affine>,
+        points: impl IntoIterator<Item = (Point, f64)>,
+    ) {
+        self.fill(
+            Fill::NonZero,
+            transform,
+            brush,
+            brush_transform,
+            &varying_outline(points),
+        );
+    }
+
+    /// Draws a variable width ink stroke using the specified brush.
+    pub fn draw_ink<'b>(
+        &mut self,
+        transform: Affine,
+        brush: impl Into<BrushRef<'b>>,
+        brush_transform: Option<Affine>,
+        stroke: &InkStroke,
+    ) {
+        self.fill(
+            Fill::NonZero,
+            transform,
+            brush,
+            brush_transform,
+            stroke.outline(),
+        );
+    }
+
+    /// Fills the area covered by a mesh gradient.
+    ///
+    /// Each patch is encoded as a separate draw object bounded by its outline
+    /// and the color is evaluated per pixel during fine rasterization.
+    pub fn fill_mesh_gradient(&mut self, transform: Affine, mesh: &MeshGradient) {
+        let transform = self.transform() * transform;
+        let bbox = mesh
+            .patches
+            .iter()
+            .map(|patch| transform.transform_rect_bbox(patch.boundary().bounding_box()))
+            .reduce(|a, b| a.union(b));
+        if !bbox.map_or(false, |bbox| self.begin_draw(bbox)) {
+            return;
+        }
+        self.scene
+            .encode_transform(Transform::from_kurbo(&transform));
+        self.scene.encode_linewidth(-1.0);
+        for patch in &mesh.patches {
+            if self.scene.encode_shape(&patch.boundary(), true) {
+                self.scene.encode_mesh_patch(MeshPatchData::new(patch));
+            }
+        }
+    }
+
+    /// Appends a fragment to the scene.
+    pub fn append(&mut self, fragment: &SceneFragment, transform: Option<Affine>) {
+        self.append_encoding(&fragment.data, transform);
+    }
+
+    /// Appends the encoding of a fragment or scene.
+    fn append_encoding(&mut self, encoding: &Encoding, transform: Option<Affine>) {
+        let transform = match self.transforms.last() {
+            Some(current) => Some(*current * transform.unwrap_or(Affine::IDENTITY)),
+            None => transform,
+        };
+        if !self.rounded_clips.is_empty() {
+            // The bounds only cover the control points, so strokes in the
+            // fragment may extend slightly past them.
+            let bbox = encoding_points(encoding)
+                .iter()
+                .fold(None, |bbox: Option<Rect>, [x, y]| {
+                    let point = Point::new(*x as f64, *y as f64);
+                    Some(bbox.map_or(Rect::from_points(point, point), |bbox| bbox.union_pt(point)))
+                });
+            let bbox = match bbox {
+                Some(bbox) => bbox,
+                None => return,
+            };
+            let bbox = transform
+                .unwrap_or(Affine::IDENTITY)
+                .transform_rect_bbox(bbox);
+            // Fragments that appear to be outside are clipped rather than
+            // culled since the bounds are not exact. The content of layers
+            // and instances in the fragment is not inspected.
+            let is_src_over = encoding.draw_flags.is_empty()
+                && encoding.n_clips == 0
+                && !encoding.has_instances()
+                && !encoding.draw_tags.contains(&DrawTag::ERASE);
+            if !self.begin_draw(bbox.inset(1.0)) {
+                self.clip_content(bbox.inset(1.0));
+            }
+            if !is_src_over {
+                self.keep_current_clip_layer();
+            }
+        }
+        self.scene.append(
+            encoding,
+            &transform.map(|xform| Transform::from_kurbo(&xform)),
+        );
+    }
+
+    /// Appends a cross-fade from one fragment to another, where `t` ranges
+    /// from 0, which draws only `from`, to 1, which draws only `to`.
+    ///
+    /// Both fragments are drawn with the transform into layers bound by the
+    /// shape, and mixed on the GPU as `from * (1 - t) + to * t` before the
+    /// result is composited, which is the usual transition between two
+    /// screens. Partially transparent content fades correctly, unlike
+    /// drawing `to` over `from` with increasing alpha.
+    pub fn append_crossfade(
+        &mut self,
+        from: &SceneFragment,
+        to: &SceneFragment,
+        t: f32,
+        transform: Affine,
+        bounds: &impl Shape,
+    ) {
+        self.append_crossfade_with(
+            t,
+            transform,
+            bounds,
+            |builder| builder.append_encoding(&from.data, Some(transform)),
+            |builder| builder.append_encoding(&to.data, Some(transform)),
+        );
+    }
+
+    /// Appends a cross-fade between two scenes, merging their brushes,
+    /// palettes, visibility groups and backdrops as
+    /// [`Scene::append_scene`] does.
+    pub(crate) fn append_crossfade_scenes(
+        &mut self,
+        from: &Scene,
+        to: &Scene,
+        t: f32,
+        bounds: &impl Shape,
+    ) {
+        self.append_crossfade_with(
+            t,
+            Affine::IDENTITY,
+            bounds,
+            |builder| {
+                builder.scene.merge(&from.data);
+            },
+            |builder| {
+                builder.scene.merge(&to.data);
+            },
+        );
+    }
+
+    /// Appends a cross-fade between the content drawn by `from` and `to`.
+    fn append_crossfade_with(
+        &mut self,
+        t: f32,
+        transform: Affine,
+        bounds: &impl Shape,
+        from: impl FnOnce(&mut Self),
+        to: impl FnOnce(&mut Self),
+    ) {
+        let t = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) };
+        if t == 0.0 {
+            from(self);
+            return;
+        }
+        if t == 1.0 {
+            to(self);
+            return;
+        }
+        // The group isolates the mix from the backdrop, so that the sum of
+        // the layers is composited with source-over.
+        self.push_layer(Mix::Normal, 1.0, transform, bounds);
+        self.push_layer(Mix::Normal, 1.0 - t, transform, bounds);
+        from(self);
+        self.pop_layer();
+        self.push_layer(
+            BlendMode::new(Mix::Normal, Compose::PlusLighter),
+            t,
+            transform,
+            bounds,
+        );
+        to(self);
+        self.pop_layer();
+        self.pop_layer();
+    }
+
+    /// Draws many instances of a fragment, each with its own transform and
+    /// optionally a color that replaces the brushes of the fragment.
+    ///
+    /// The scene stores the fragment only once along with the parameters of
+    /// the instances, which keeps building and appending the scene cheap for
+    /// particle systems and markers in plots. The instances are expanded on
+    /// the CPU when the scene is first rendered after it changed, so the
+    /// uploaded scene still grows with the number of instances.
+    pub fn draw_instances(
+        &mut self,
+        fragment: &SceneFragment,
+        instances: impl IntoIterator<Item = (Affine, Option<Color>)>,
+    ) {
+        let current = self.transform();
+        let instances: Vec<Instance> = instances
+            .into_iter()
+            .map(|(transform, color)| Instance {
+                transform: Transform::from_kurbo(&(current * transform)),
+                color,
+            })
+            .collect();
+        if instances.is_empty() {
+            return;
+        }
+        // The instances are not culled individually.
+        self.keep_clip_layers();
+        self.scene
+            .encode_instances(Arc::new(fragment.data.clone()), instances);
     }
 
     /// Completes construction and finalizes the underlying scene.
@@ -187,5 +2033,148 @@ impl<'a> SceneBuilder<'a> {
         for _ in 0..self.layer_depth {
             self.scene.encode_end_clip();
         }
+        if let Some(budget) = self.budget {
+            budget.check(self.scene, self.max_layer_depth);
+        }
+    }
+}
+
+/// Returns a copy of a stroke style with the width snapped to whole device
+/// pixels.
+fn snap_stroke_style(style: &Stroke, transform: Affine) -> Stroke {
+    let mut style = style.clone();
+    style.width = snap::snap_stroke_width(style.width as f64, transform) as f32;
+    style
+}
+
+/// Returns the smallest factor by which a transform scales lengths, or `None`
+/// if it collapses them.
+fn min_scale(transform: Affine) -> Option<f64> {
+    let [a, b, c, d, _, _] = transform.as_coeffs();
+    // Smallest singular value of the linear part.
+    let sum = a * a + b * b + c * c + d * d;
+    let det = a * d - b * c;
+    let scale = (0.5 * (sum - (sum * sum - 4.0 * det * det).max(0.0).sqrt())).sqrt();
+    (scale.is_finite() && scale > 0.0).then_some(scale)
+}
+
+fn scissor_to_f32(rect: Rect) -> [f32; 4] {
+    [rect.x0, rect.y0, rect.x1, rect.y1].map(|x| x as f32)
+}
+
+/// Returns true if `outer` entirely contains `inner`.
+fn contains(outer: Rect, inner: Rect) -> bool {
+    inner.x0 >= outer.x0 && inner.y0 >= outer.y0 && inner.x1 <= outer.x1 && inner.y1 <= outer.y1
+}
+
+/// Returns true if the interiors of two rectangles intersect.
+fn overlaps(a: Rect, b: Rect) -> bool {
+    b.x0 < a.x1 && b.x1 > a.x0 && b.y0 < a.y1 && b.y1 > a.y0
+}
+
+/// Returns true if a blend mode composites with source-over.
+fn is_src_over_mode(blend: BlendMode) -> bool {
+    blend.compose == Compose::SrcOver && matches!(blend.mix, Mix::Normal | Mix::Clip)
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::kurbo::{Affine, Rect, Vec2};
+    use peniko::{Color, Fill, Stroke};
+
+    use super::{Scene, SceneBuilder, SceneFragment};
+    use crate::encoding::{DrawShadow, DrawTag, MAX_PALETTE_SIZE};
+
+    fn draw(builder: &mut SceneBuilder) {
+        let rect = Rect::new(0.0, 0.0, 10.0, 10.0);
+        builder.fill(
+            Fill::NonZero,
+            Affine::translate((5.0, 5.0)) * Affine::rotate(0.5),
+            Color::rgb8(255, 0, 0),
+            None,
+            &rect,
+        );
+        builder.stroke(
+            &Stroke::new(2.0),
+            Affine::scale(2.0),
+            Color::rgb8(0, 0, 255),
+            None,
+            &rect,
+        );
+    }
+
+    #[test]
+    fn fragment_scene_matches_direct_scene() {
+        let fragment = SceneFragment::record(draw);
+        let mut direct = Scene::new();
+        let mut builder = SceneBuilder::for_scene(&mut direct);
+        draw(&mut builder);
+        builder.finish();
+        let scene = Scene::from_fragment(&fragment);
+        let (scene, direct) = (scene.data(), direct.data());
+        assert!(scene.transforms == direct.transforms);
+        assert_eq!(scene.linewidths, direct.linewidths);
+        assert!(scene.path_tags == direct.path_tags);
+        assert!(scene.draw_tags == direct.draw_tags);
+        // The leading entries are the ones that the fragment lacks.
+        assert_eq!(scene.linewidths[0], -1.0);
+    }
+
+    #[test]
+    fn coverage_mask_keeps_shadows() {
+        let mut scene = Scene::new();
+        let mut builder = SceneBuilder::for_scene(&mut scene);
+        builder.draw_shadow(
+            &Rect::new(0.0, 0.0, 20.0, 10.0),
+            Vec2::new(2.0, 2.0),
+            4.0,
+            Color::rgba8(0, 0, 0, 128),
+        );
+        builder.finish();
+        let mask = scene.coverage_mask();
+        let mask = mask.data();
+        assert!(mask.draw_tags == [DrawTag::SHADOW]);
+        let shadow: DrawShadow = bytemuck::pod_read_unaligned(&mask.draw_data);
+        assert_eq!(
+            shadow.color,
+            Color::rgba8(255, 255, 255, 255).to_premul_u32()
+        );
+        assert_eq!((shadow.width, shadow.height), (20.0, 10.0));
+        assert_eq!(shadow.std_dev, 2.0);
+    }
+
+    #[test]
+    fn appended_instances_stay_instanced() {
+        let marker = SceneFragment::record(|builder| {
+            builder.fill(
+                Fill::NonZero,
+                Affine::IDENTITY,
+                Color::rgb8(0, 0, 0),
+                None,
+                &Rect::new(0.0, 0.0, 1.0, 1.0),
+            );
+        });
+        let instances = (0..100).map(|i| (Affine::translate((i as f64, 0.0)), None));
+        let plot = SceneFragment::record(|builder| builder.draw_instances(&marker, instances));
+        let mut scene = Scene::new();
+        let mut builder = SceneBuilder::for_scene(&mut scene);
+        builder.append(&plot, Some(Affine::scale(2.0)));
+        builder.finish();
+        assert!(scene.data().has_instances());
+        assert!(scene.data().path_tags.len() < 10);
+        let expanded = scene.expanded().unwrap();
+        assert!(!expanded.data().has_instances());
+        assert_eq!(expanded.data().n_paths, 100);
+        assert!(expanded.data().is_consistent());
+    }
+
+    #[test]
+    fn ignores_palette_slots_beyond_maximum() {
+        let mut scene = Scene::new();
+        scene.set_palette_color(u32::MAX, Color::rgb8(255, 0, 0));
+        scene.set_palette_color(MAX_PALETTE_SIZE, Color::rgb8(255, 0, 0));
+        assert!(scene.palette().is_empty());
+        scene.set_palette_color(MAX_PALETTE_SIZE - 1, Color::rgb8(255, 0, 0));
+        assert_eq!(scene.palette().len(), MAX_PALETTE_SIZE as usize);
     }
 }
@@ -0,0 +1,331 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Scrollable regions with cached content.
+
+use peniko::kurbo::{Affine, Vec2};
+use wgpu::{CommandEncoder, Device, Queue, Texture, TextureView};
+
+use crate::{Renderer, Result, Scene, SceneBuilder, SceneFragment};
+
+/// Cached rendering of scrollable content.
+///
+/// The content is rendered into a texture that is larger than the viewport
+/// by a margin on each side. Scrolling within the margin only changes the
+/// offset at which the cache is composited and scrolling past it shifts the
+/// existing content and renders only the newly exposed bands.
+pub struct ScrollRegion {
+    width: u32,
+    height: u32,
+    margin: u32,
+    caches: [CacheTexture; 2],
+    current: usize,
+    /// Position of the cache in content space or `None` if the cache is not
+    /// valid.
+    origin: Option<(i32, i32)>,
+    /// Position of the viewport in content space.
+    offset: (i32, i32),
+}
+
+impl ScrollRegion {
+    /// Creates a new scroll region with the specified viewport size and
+    /// number of pixels to cache on each side of the viewport.
+    pub fn new(device: &Device, width: u32, height: u32, margin: u32) -> Self {
+        let cache_width = width + 2 * margin;
+        let cache_height = height + 2 * margin;
+        Self {
+            width,
+            height,
+            margin,
+            caches: [
+                CacheTexture::new(device, cache_width, cache_height),
+                CacheTexture::new(device, cache_width, cache_height),
+            ],
+            current: 0,
+            origin: None,
+            offset: (0, 0),
+        }
+    }
+
+    /// Returns the size of the viewport.
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Resizes the viewport. This invalidates the cached content.
+    pub fn resize(&mut self, device: &Device, width: u32, height: u32) {
+        if width != self.width || height != self.height {
+            *self = Self::new(device, width, height, self.margin);
+        }
+    }
+
+    /// Discards the cached content. This must be called when the content
+    /// changes.
+    pub fn invalidate(&mut self) {
+        self.origin = None;
+    }
+
+    /// Scrolls the viewport to the specified offset in content space, which
+    /// is rounded to whole pixels, and renders any content that is not
+    /// already cached.
+    pub fn update(
+        &mut self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &Queue,
+        content: &SceneFragment,
+        offset: Vec2,
+    ) -> Result<()> {
+        let offset = (offset.x.round() as i32, offset.y.round() as i32);
+        self.offset = offset;
+        let viewport = IntRect::new(offset, self.width, self.height);
+        let cache_width = self.width + 2 * self.margin;
+        let cache_height = self.height + 2 * self.margin;
+        if let Some(origin) = self.origin {
+            if IntRect::new(origin, cache_width, cache_height).contains(&viewport) {
+                return Ok(());
+            }
+        }
+        let margin = self.margin as i32;
+        let new_origin = (offset.0 - margin, offset.1 - margin);
+        let new_rect = IntRect::new(new_origin, cache_width, cache_height);
+        let target = 1 - self.current;
+        let overlap = self
+            .origin
+            .map(|origin| IntRect::new(origin, cache_width, cache_height).intersect(&new_rect))
+            .filter(|overlap| !overlap.is_empty());
+        let bands = if let Some(overlap) = overlap {
+            let old_origin = self.origin.unwrap();
+            let mut encoder =
+                device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            copy_texture(
+                &mut encoder,
+                &self.caches[self.current].texture,
+                overlap.offset_from(old_origin),
+                &self.caches[target].texture,
+                overlap.offset_from(new_origin),
+                overlap.size(),
+            );
+            queue.submit(Some(encoder.finish()));
+            new_rect.subtract(&overlap)
+        } else {
+            vec![new_rect]
+        };
+        for band in bands {
+            self.render_band(renderer, device, queue, content, &band, target, new_origin)?;
+        }
+        self.current = target;
+        self.origin = Some(new_origin);
+        Ok(())
+    }
+
+    /// Records a copy of the visible portion of the cached content into the
+    /// target texture at the specified position.
+    ///
+    /// The target is assumed to have been created with the
+    /// [wgpu::TextureFormat::Rgba8Unorm] format and the
+    /// [wgpu::TextureUsages::COPY_DST] flag set. Since the copy replaces the
+    /// content of the target, it should happen after the rest of the scene
+    /// has been rendered.
+    pub fn composite(&self, encoder: &mut CommandEncoder, target: &Texture, x: u32, y: u32) {
+        if let Some(origin) = self.origin {
+            let viewport = IntRect::new(self.offset, self.width, self.height);
+            copy_texture(
+                encoder,
+                &self.caches[self.current].texture,
+                viewport.offset_from(origin),
+                target,
+                (x, y),
+                (self.width, self.height),
+            );
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn render_band(
+        &self,
+        renderer: &mut Renderer,
+        device: &Device,
+        queue: &Queue,
+        content: &SceneFragment,
+        band: &IntRect,
+        target: usize,
+        origin: (i32, i32),
+    ) -> Result<()> {
+        let (width, height) = band.size();
+        let mut scene = Scene::new();
+        let mut builder = SceneBuilder::for_scene(&mut scene);
+        builder.append(
+            content,
+            Some(Affine::translate((-band.x0 as f64, -band.y0 as f64))),
+        );
+        builder.finish();
+        let band_texture = CacheTexture::new(device, width, height);
+        renderer.render_to_texture(device, queue, &scene, &band_texture.view, width, height)?;
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        copy_texture(
+            &mut encoder,
+            &band_texture.texture,
+            (0, 0),
+            &self.caches[target].texture,
+            band.offset_from(origin),
+            (width, height),
+        );
+        queue.submit(Some(encoder.finish()));
+        Ok(())
+    }
+}
+
+struct CacheTexture {
+    texture: Texture,
+    view: TextureView,
+}
+
+impl CacheTexture {
+    fn new(device: &Device, width: u32, height: u32) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("scroll cache"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsages::STORAGE_BINDING
+                | wgpu::TextureUsages::COPY_SRC
+                | wgpu::TextureUsages::COPY_DST,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        Self { texture, view }
+    }
+}
+
+fn copy_texture(
+    encoder: &mut CommandEncoder,
+    src: &Texture,
+    src_origin: (u32, u32),
+    dst: &Texture,
+    dst_origin: (u32, u32),
+    size: (u32, u32),
+) {
+    encoder.copy_texture_to_texture(
+        wgpu::ImageCopyTexture {
+            texture: src,
+            mip_level: 0,
+            origin: wgpu::Origin3d {
+                x: src_origin.0,
+                y: src_origin.1,
+                z: 0,
+            },
+            aspect: wgpu::TextureAspect::All,
+        },
+        wgpu::ImageCopyTexture {
+            texture: dst,
+            mip_level: 0,
+            origin: wgpu::Origin3d {
+                x: dst_origin.0,
+                y: dst_origin.1,
+                z: 0,
+            },
+            aspect: wgpu::TextureAspect::All,
+        },
+        wgpu::Extent3d {
+            width: size.0,
+            height: size.1,
+            depth_or_array_layers: 1,
+        },
+    );
+}
+
+/// Pixel aligned rectangle in content space.
+#[derive(Copy, Clone, Debug)]
+struct IntRect {
+    x0: i32,
+    y0: i32,
+    x1: i32,
+    y1: i32,
+}
+
+impl IntRect {
+    fn new(origin: (i32, i32), width: u32, height: u32) -> Self {
+        Self {
+            x0: origin.0,
+            y0: origin.1,
+            x1: origin.0 + width as i32,
+            y1: origin.1 + height as i32,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.x0 >= self.x1 || self.y0 >= self.y1
+    }
+
+    fn size(&self) -> (u32, u32) {
+        ((self.x1 - self.x0) as u32, (self.y1 - self.y0) as u32)
+    }
+
+    fn offset_from(&self, origin: (i32, i32)) -> (u32, u32) {
+        ((self.x0 - origin.0) as u32, (self.y0 - origin.1) as u32)
+    }
+
+    fn contains(&self, other: &Self) -> bool {
+        other.x0 >= self.x0 && other.y0 >= self.y0 && other.x1 <= self.x1 && other.y1 <= self.y1
+    }
+
+    fn intersect(&self, other: &Self) -> Self {
+        Self {
+            x0: self.x0.max(other.x0),
+            y0: self.y0.max(other.y0),
+            x1: self.x1.min(other.x1),
+            y1: self.y1.min(other.y1),
+        }
+    }
+
+    /// Returns the bands of this rectangle that are not covered by `inner`,
+    /// which must be contained within it.
+    fn subtract(&self, inner: &Self) -> Vec<Self> {
+        let bands = [
+            // Top and bottom span the full width.
+            Self {
+                y1: inner.y0,
+                ..*self
+            },
+            Self {
+                y0: inner.y1,
+                ..*self
+            },
+            // Left and right span the height of the inner rectangle.
+            Self {
+                y0: inner.y0,
+                x1: inner.x0,
+                y1: inner.y1,
+                ..*self
+            },
+            Self {
+                x0: inner.x1,
+                y0: inner.y0,
+                y1: inner.y1,
+                ..*self
+            },
+        ];
+        bands.into_iter().filter(|band| !band.is_empty()).collect()
+    }
+}
@@ -18,42 +18,177 @@
 
 mod preprocess;
 
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 
 use wgpu::Device;
 
-use crate::engine::{BindType, Engine, Error, ImageFormat, ShaderId};
+use crate::engine::{storage_buffers, BindType, Engine, Error, ImageFormat, ShaderId};
 
+// Workgroup sizes and tile dimensions are constants in the WGSL sources and
+// must match these values. They are not pipeline-overridable constants
+// because wgpu 0.15 does not support WGSL `override` declarations; once the
+// wgpu dependency supports them, they can be passed when the pipelines are
+// created instead of being fixed in the sources.
 pub const PATHTAG_REDUCE_WG: u32 = 256;
 pub const PATH_BBOX_WG: u32 = 256;
 pub const PATH_COARSE_WG: u32 = 256;
 pub const PATH_DRAWOBJ_WG: u32 = 256;
 pub const CLIP_REDUCE_WG: u32 = 256;
 
-macro_rules! shader {
-    ($name:expr) => {&{
-        let shader = include_str!(concat!(
-            env!("CARGO_MANIFEST_DIR"),
-            "/shader/",
-            $name,
-            ".wgsl"
-        ));
-        #[cfg(feature = "hot_reload")]
-        let shader = std::fs::read_to_string(concat!(
-            env!("CARGO_MANIFEST_DIR"),
-            "/shader/",
-            $name,
-            ".wgsl"
-        ))
-        .unwrap_or_else(|e| {
-            eprintln!(
-                "Failed to read shader {name}, error falling back to version at compilation time. Error: {e:?}",
-                name = $name
-            );
-            shader.to_string()
-        });
-        shader
-    }};
+/// WGSL sources of the shaders.
+///
+/// By default the sources are embedded in the binary. Applications that care
+/// about binary size, such as those targeting wasm or mobile, can disable the
+/// `embedded_shaders` feature and provide the sources at runtime instead,
+/// for example by fetching the files of the `shader` directory of the crate.
+/// The shaders are compiled from WGSL by wgpu for the backend in use, so no
+/// backend specific artifacts are shipped either way.
+#[derive(Clone, Default)]
+pub struct ShaderSources {
+    shaders: HashMap<String, Cow<'static, str>>,
+    shared: HashMap<String, Cow<'static, str>>,
+}
+
+impl ShaderSources {
+    /// Creates an empty set of sources.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the sources embedded in the binary.
+    ///
+    /// With the `hot_reload` feature, the shaders are read from the source
+    /// tree of the crate when possible.
+    #[cfg(feature = "embedded_shaders")]
+    pub fn embedded() -> Self {
+        macro_rules! shader {
+            ($name:expr) => {
+                (
+                    $name,
+                    include_str!(concat!(
+                        env!("CARGO_MANIFEST_DIR"),
+                        "/shader/",
+                        $name,
+                        ".wgsl"
+                    )),
+                )
+            };
+        }
+        macro_rules! shared_shader {
+            ($name:expr) => {
+                (
+                    $name,
+                    include_str!(concat!("../shader/shared/", $name, ".wgsl")),
+                )
+            };
+        }
+        let shaders = [
+            shader!("backdrop"),
+            shader!("backdrop_dyn"),
+            shader!("bbox_clear"),
+            shader!("binning"),
+            shader!("clip_leaf"),
+            shader!("clip_reduce"),
+            shader!("coarse"),
+            shader!("draw_leaf"),
+            shader!("draw_reduce"),
+            shader!("fine"),
+            shader!("path_coarse"),
+            shader!("path_coarse_full"),
+            shader!("pathseg"),
+            shader!("pathtag_reduce"),
+            shader!("pathtag_reduce2"),
+            shader!("pathtag_scan"),
+            shader!("pathtag_scan1"),
+            shader!("tile_alloc"),
+        ];
+        let shared = [
+            shared_shader!("bbox"),
+            shared_shader!("blend"),
+            shared_shader!("bump"),
+            shared_shader!("clip"),
+            shared_shader!("config"),
+            shared_shader!("cubic"),
+            shared_shader!("drawtag"),
+            shared_shader!("pathtag"),
+            shared_shader!("ptcl"),
+            shared_shader!("segment"),
+            shared_shader!("tile"),
+        ];
+        let mut sources = Self::new();
+        for (name, source) in shaders {
+            #[cfg(feature = "hot_reload")]
+            let source: Cow<'static, str> = match std::fs::read_to_string(format!(
+                "{}/shader/{name}.wgsl",
+                env!("CARGO_MANIFEST_DIR")
+            )) {
+                Ok(source) => source.into(),
+                Err(e) => {
+                    eprintln!(
+                        "Failed to read shader {name}, error falling back to version at compilation time. Error: {e:?}"
+                    );
+                    source.into()
+                }
+            };
+            sources.insert(name, source);
+        }
+        for (name, source) in shared {
+            sources.insert_shared(name, source);
+        }
+        sources
+    }
+
+    /// Reads the sources from a directory with the layout of the `shader`
+    /// directory of the crate, i.e. with the shared modules in a `shared`
+    /// subdirectory.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_dir(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        fn read_dir(
+            path: &std::path::Path,
+            map: &mut HashMap<String, Cow<'static, str>>,
+        ) -> std::io::Result<()> {
+            for entry in std::fs::read_dir(path)? {
+                let path = entry?.path();
+                if path.extension().map_or(false, |ext| ext == "wgsl") {
+                    if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                        map.insert(name.to_string(), std::fs::read_to_string(&path)?.into());
+                    }
+                }
+            }
+            Ok(())
+        }
+        let path = path.as_ref();
+        let mut sources = Self::new();
+        read_dir(path, &mut sources.shaders)?;
+        read_dir(&path.join("shared"), &mut sources.shared)?;
+        Ok(sources)
+    }
+
+    /// Adds the source of a shader, named after its file without the
+    /// extension.
+    pub fn insert(&mut self, name: impl Into<String>, source: impl Into<Cow<'static, str>>) {
+        self.shaders.insert(name.into(), source.into());
+    }
+
+    /// Adds the source of a shared module that shaders can import.
+    pub fn insert_shared(&mut self, name: impl Into<String>, source: impl Into<Cow<'static, str>>) {
+        self.shared.insert(name.into(), source.into());
+    }
+
+    fn get(&self, name: &str) -> Result<&str, Error> {
+        self.shaders
+            .get(name)
+            .map(|source| source.as_ref())
+            .ok_or_else(|| format!("missing source for shader {name}").into())
+    }
+
+    fn imports(&self) -> HashMap<&str, &str> {
+        self.shared
+            .iter()
+            .map(|(name, source)| (name.as_str(), source.as_ref()))
+            .collect()
+    }
 }
 
 pub struct Shaders {
@@ -64,6 +199,24 @@ pub struct Shaders {
     pub fine: ShaderId,
 }
 
+/// Bindings of the coarse shader, which binds the most storage buffers of
+/// the full pipeline.
+const COARSE_BINDINGS: &[BindType] = &[
+    BindType::Uniform,
+    BindType::BufReadOnly,
+    BindType::BufReadOnly,
+    BindType::BufReadOnly,
+    BindType::BufReadOnly,
+    BindType::BufReadOnly,
+    BindType::BufReadOnly,
+    BindType::Buffer,
+    BindType::Buffer,
+];
+
+/// Number of storage buffers per shader stage that the full pipeline
+/// requires.
+pub const MAX_STORAGE_BUFFERS: u32 = storage_buffers(COARSE_BINDINGS);
+
 // Shaders for the full pipeline
 pub struct FullShaders {
     pub pathtag_reduce: ShaderId,
@@ -85,22 +238,23 @@ pub struct FullShaders {
     pub fine: ShaderId,
 }
 
-pub fn init_shaders(device: &Device, engine: &mut Engine) -> Result<Shaders, Error> {
-    let imports = SHARED_SHADERS
-        .iter()
-        .copied()
-        .collect::<std::collections::HashMap<_, _>>();
+pub fn init_shaders(
+    device: &Device,
+    engine: &mut Engine,
+    sources: &ShaderSources,
+) -> Result<Shaders, Error> {
+    let imports = sources.imports();
     let empty = HashSet::new();
     let pathtag_reduce = engine.add_shader(
         device,
         "pathtag_reduce",
-        preprocess::preprocess(shader!("pathtag_reduce"), &empty, &imports).into(),
+        preprocess::preprocess(sources.get("pathtag_reduce")?, &empty, &imports).into(),
         &[BindType::Uniform, BindType::BufReadOnly, BindType::Buffer],
     )?;
     let pathtag_scan = engine.add_shader(
         device,
         "pathtag_scan",
-        preprocess::preprocess(shader!("pathtag_scan"), &empty, &imports).into(),
+        preprocess::preprocess(sources.get("pathtag_scan")?, &empty, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
@@ -114,7 +268,7 @@ pub fn init_shaders(device: &Device, engine: &mut Engine) -> Result<Shaders, Err
     let path_coarse = engine.add_shader(
         device,
         "path_coarse",
-        preprocess::preprocess(shader!("path_coarse"), &path_coarse_config, &imports).into(),
+        preprocess::preprocess(sources.get("path_coarse")?, &path_coarse_config, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
@@ -126,13 +280,13 @@ pub fn init_shaders(device: &Device, engine: &mut Engine) -> Result<Shaders, Err
     let backdrop = engine.add_shader(
         device,
         "backdrop",
-        preprocess::preprocess(shader!("backdrop"), &empty, &imports).into(),
+        preprocess::preprocess(sources.get("backdrop")?, &empty, &imports).into(),
         &[BindType::Uniform, BindType::Buffer],
     )?;
     let fine = engine.add_shader(
         device,
         "fine",
-        preprocess::preprocess(shader!("fine"), &empty, &imports).into(),
+        preprocess::preprocess(sources.get("fine")?, &empty, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
@@ -149,11 +303,12 @@ pub fn init_shaders(device: &Device, engine: &mut Engine) -> Result<Shaders, Err
     })
 }
 
-pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders, Error> {
-    let imports = SHARED_SHADERS
-        .iter()
-        .copied()
-        .collect::<std::collections::HashMap<_, _>>();
+pub fn full_shaders(
+    device: &Device,
+    engine: &mut Engine,
+    sources: &ShaderSources,
+) -> Result<FullShaders, Error> {
+    let imports = sources.imports();
     let empty = HashSet::new();
     let mut full_config = HashSet::new();
     full_config.insert("full".into());
@@ -168,19 +323,19 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
     let pathtag_reduce = engine.add_shader(
         device,
         "pathtag_reduce",
-        preprocess::preprocess(shader!("pathtag_reduce"), &full_config, &imports).into(),
+        preprocess::preprocess(sources.get("pathtag_reduce")?, &full_config, &imports).into(),
         &[BindType::Uniform, BindType::BufReadOnly, BindType::Buffer],
     )?;
     let pathtag_reduce2 = engine.add_shader(
         device,
         "pathtag_reduce2",
-        preprocess::preprocess(shader!("pathtag_reduce2"), &full_config, &imports).into(),
+        preprocess::preprocess(sources.get("pathtag_reduce2")?, &full_config, &imports).into(),
         &[BindType::BufReadOnly, BindType::Buffer],
     )?;
     let pathtag_scan1 = engine.add_shader(
         device,
         "pathtag_scan1",
-        preprocess::preprocess(shader!("pathtag_scan1"), &full_config, &imports).into(),
+        preprocess::preprocess(sources.get("pathtag_scan1")?, &full_config, &imports).into(),
         &[
             BindType::BufReadOnly,
             BindType::BufReadOnly,
@@ -190,7 +345,7 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
     let pathtag_scan = engine.add_shader(
         device,
         "pathtag_scan",
-        preprocess::preprocess(shader!("pathtag_scan"), &small_config, &imports).into(),
+        preprocess::preprocess(sources.get("pathtag_scan")?, &small_config, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
@@ -201,7 +356,7 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
     let pathtag_scan_large = engine.add_shader(
         device,
         "pathtag_scan",
-        preprocess::preprocess(shader!("pathtag_scan"), &full_config, &imports).into(),
+        preprocess::preprocess(sources.get("pathtag_scan")?, &full_config, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
@@ -212,13 +367,13 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
     let bbox_clear = engine.add_shader(
         device,
         "bbox_clear",
-        preprocess::preprocess(shader!("bbox_clear"), &empty, &imports).into(),
+        preprocess::preprocess(sources.get("bbox_clear")?, &empty, &imports).into(),
         &[BindType::Uniform, BindType::Buffer],
     )?;
     let pathseg = engine.add_shader(
         device,
         "pathseg",
-        preprocess::preprocess(shader!("pathseg"), &full_config, &imports).into(),
+        preprocess::preprocess(sources.get("pathseg")?, &full_config, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
@@ -230,18 +385,18 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
     let draw_reduce = engine.add_shader(
         device,
         "draw_reduce",
-        preprocess::preprocess(shader!("draw_reduce"), &empty, &imports).into(),
+        preprocess::preprocess(sources.get("draw_reduce")?, &empty, &imports).into(),
         &[BindType::Uniform, BindType::BufReadOnly, BindType::Buffer],
     )?;
     let draw_leaf = engine.add_shader(
         device,
         "draw_leaf",
-        preprocess::preprocess(shader!("draw_leaf"), &empty, &imports).into(),
+        preprocess::preprocess(sources.get("draw_leaf")?, &empty, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
             BindType::BufReadOnly,
-            BindType::BufReadOnly,
+            BindType::Buffer,
             BindType::Buffer,
             BindType::Buffer,
             BindType::Buffer,
@@ -250,7 +405,7 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
     let clip_reduce = engine.add_shader(
         device,
         "clip_reduce",
-        preprocess::preprocess(shader!("clip_reduce"), &empty, &imports).into(),
+        preprocess::preprocess(sources.get("clip_reduce")?, &empty, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
@@ -262,7 +417,7 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
     let clip_leaf = engine.add_shader(
         device,
         "clip_leaf",
-        preprocess::preprocess(shader!("clip_leaf"), &empty, &imports).into(),
+        preprocess::preprocess(sources.get("clip_leaf")?, &empty, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
@@ -276,7 +431,7 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
     let binning = engine.add_shader(
         device,
         "binning",
-        preprocess::preprocess(shader!("binning"), &empty, &imports).into(),
+        preprocess::preprocess(sources.get("binning")?, &empty, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
@@ -291,7 +446,7 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
     let tile_alloc = engine.add_shader(
         device,
         "tile_alloc",
-        preprocess::preprocess(shader!("tile_alloc"), &uniform, &imports).into(),
+        preprocess::preprocess(sources.get("tile_alloc")?, &uniform, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
@@ -305,7 +460,7 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
     let path_coarse = engine.add_shader(
         device,
         "path_coarse_full",
-        preprocess::preprocess(shader!("path_coarse_full"), &full_config, &imports).into(),
+        preprocess::preprocess(sources.get("path_coarse_full")?, &full_config, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
@@ -320,29 +475,19 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
     let backdrop = engine.add_shader(
         device,
         "backdrop_dyn",
-        preprocess::preprocess(shader!("backdrop_dyn"), &empty, &imports).into(),
+        preprocess::preprocess(sources.get("backdrop_dyn")?, &empty, &imports).into(),
         &[BindType::Uniform, BindType::BufReadOnly, BindType::Buffer],
     )?;
     let coarse = engine.add_shader(
         device,
         "coarse",
-        preprocess::preprocess(shader!("coarse"), &uniform, &imports).into(),
-        &[
-            BindType::Uniform,
-            BindType::BufReadOnly,
-            BindType::BufReadOnly,
-            BindType::BufReadOnly,
-            BindType::BufReadOnly,
-            BindType::BufReadOnly,
-            BindType::BufReadOnly,
-            BindType::Buffer,
-            BindType::Buffer,
-        ],
+        preprocess::preprocess(sources.get("coarse")?, &uniform, &imports).into(),
+        COARSE_BINDINGS,
     )?;
     let fine = engine.add_shader(
         device,
         "fine",
-        preprocess::preprocess(shader!("fine"), &full_config, &imports).into(),
+        preprocess::preprocess(sources.get("fine")?, &full_config, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
@@ -351,6 +496,8 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
             BindType::BufReadOnly,
             BindType::ImageRead(ImageFormat::Rgba8),
             BindType::BufReadOnly,
+            BindType::BufReadOnly,
+            BindType::ImageRead(ImageFormat::Rgba8),
         ],
     )?;
     Ok(FullShaders {
@@ -373,26 +520,3 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
         fine,
     })
 }
-
-macro_rules! shared_shader {
-    ($name:expr) => {
-        (
-            $name,
-            include_str!(concat!("../shader/shared/", $name, ".wgsl")),
-        )
-    };
-}
-
-const SHARED_SHADERS: &[(&str, &str)] = &[
-    shared_shader!("bbox"),
-    shared_shader!("blend"),
-    shared_shader!("bump"),
-    shared_shader!("clip"),
-    shared_shader!("config"),
-    shared_shader!("cubic"),
-    shared_shader!("drawtag"),
-    shared_shader!("pathtag"),
-    shared_shader!("ptcl"),
-    shared_shader!("segment"),
-    shared_shader!("tile"),
-];
@@ -0,0 +1,149 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Pixel snapping of axis-aligned geometry.
+//!
+//! Antialiasing spreads an edge that falls between two pixels over both of
+//! them, so a one pixel wide separator whose center is on a pixel boundary
+//! renders as two pixels of half intensity. These functions move the edges
+//! of rectangles and axis-aligned strokes so that they cover whole device
+//! pixels under a transform. Coordinates are returned in the space of the
+//! shape, so the results are drawn with the same transform.
+//!
+//! Only transforms that preserve the axes, composed of scales and
+//! translations, are snapped. Geometry under rotated or skewed transforms
+//! is returned unchanged.
+//!
+//! [`SceneBuilder::set_pixel_snapping`](crate::SceneBuilder::set_pixel_snapping)
+//! applies the functions to subsequent fills of rectangles and strokes of
+//! rectangles and axis-aligned lines, and
+//! [`SceneBuilder::push_pixel_aligned_layer`](crate::SceneBuilder::push_pixel_aligned_layer)
+//! snaps the translation of everything drawn in a layer with
+//! [`snap_translation`].
+
+use peniko::kurbo::{Affine, Line, Rect};
+
+/// Snaps the edges of a rectangle to the nearest device pixel boundaries.
+pub fn snap_rect(rect: Rect, transform: Affine) -> Rect {
+    let [a, _, _, d, e, f] = match axis_aligned(transform) {
+        Some(coeffs) => coeffs,
+        None => return rect,
+    };
+    Rect::new(
+        snap_boundary(rect.x0, a, e),
+        snap_boundary(rect.y0, d, f),
+        snap_boundary(rect.x1, a, e),
+        snap_boundary(rect.y1, d, f),
+    )
+}
+
+/// Rounds the translation of a transform to whole device pixels.
+///
+/// Content that is drawn with the result keeps the same position relative
+/// to the pixel grid when the transform is moved by fractional amounts, so
+/// it does not shimmer while it is scrolled. Unlike the other functions,
+/// this applies to any transform.
+pub fn snap_translation(transform: Affine) -> Affine {
+    let [a, b, c, d, e, f] = transform.as_coeffs();
+    Affine::new([a, b, c, d, e.round(), f.round()])
+}
+
+/// Rounds a stroke width to a whole number of device pixels, and to at
+/// least one pixel.
+///
+/// The width is only snapped if the transform scales both axes by the same
+/// amount, as a stroke has a single width.
+pub fn snap_stroke_width(width: f64, transform: Affine) -> f64 {
+    match axis_aligned(transform) {
+        Some([a, _, _, d, _, _]) if a.abs() == d.abs() && width > 0.0 => {
+            device_width(width, a) / a.abs()
+        }
+        _ => width,
+    }
+}
+
+/// Snaps a horizontal or vertical line that is stroked with the specified
+/// width so that the stroke covers whole device pixels.
+///
+/// The line is moved to pixel centers if the stroke is an odd number of
+/// pixels wide and to pixel boundaries otherwise. The ends of the line are
+/// snapped to pixel boundaries, which keeps butt caps crisp. Lines that are
+/// not axis-aligned are returned unchanged.
+pub fn snap_stroke_line(line: Line, width: f64, transform: Affine) -> Line {
+    let [a, _, _, d, e, f] = match axis_aligned(transform) {
+        Some(coeffs) => coeffs,
+        None => return line,
+    };
+    let (p0, p1) = (line.p0, line.p1);
+    if p0.x == p1.x {
+        let x = snap_stroke_edge(p0.x, width, a, e);
+        Line::new(
+            (x, snap_boundary(p0.y, d, f)),
+            (x, snap_boundary(p1.y, d, f)),
+        )
+    } else if p0.y == p1.y {
+        let y = snap_stroke_edge(p0.y, width, d, f);
+        Line::new(
+            (snap_boundary(p0.x, a, e), y),
+            (snap_boundary(p1.x, a, e), y),
+        )
+    } else {
+        line
+    }
+}
+
+/// Snaps the edges of a rectangle that is stroked with the specified width
+/// so that each side of the stroke covers whole device pixels.
+pub fn snap_stroke_rect(rect: Rect, width: f64, transform: Affine) -> Rect {
+    let [a, _, _, d, e, f] = match axis_aligned(transform) {
+        Some(coeffs) => coeffs,
+        None => return rect,
+    };
+    Rect::new(
+        snap_stroke_edge(rect.x0, width, a, e),
+        snap_stroke_edge(rect.y0, width, d, f),
+        snap_stroke_edge(rect.x1, width, a, e),
+        snap_stroke_edge(rect.y1, width, d, f),
+    )
+}
+
+/// Returns the coefficients of the transform if it preserves the axes and
+/// is invertible.
+fn axis_aligned(transform: Affine) -> Option<[f64; 6]> {
+    let coeffs = transform.as_coeffs();
+    let [a, b, c, d, _, _] = coeffs;
+    (b == 0.0 && c == 0.0 && a != 0.0 && d != 0.0).then_some(coeffs)
+}
+
+/// Returns the width of a stroke in whole device pixels along an axis with
+/// the specified scale.
+fn device_width(width: f64, scale: f64) -> f64 {
+    (width * scale.abs()).round().max(1.0)
+}
+
+/// Snaps a coordinate along an axis with the specified scale and offset to
+/// the nearest pixel boundary.
+fn snap_boundary(v: f64, scale: f64, offset: f64) -> f64 {
+    ((v * scale + offset).round() - offset) / scale
+}
+
+/// Snaps the center line of a stroke along an axis so that the stroke
+/// starts on a pixel boundary.
+fn snap_stroke_edge(v: f64, width: f64, scale: f64, offset: f64) -> f64 {
+    let half = device_width(width, scale) * 0.5;
+    let device = v * scale + offset;
+    ((device - half).round() + half - offset) / scale
+}
@@ -0,0 +1,422 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Geometry of shaped text for carets and selections.
+//!
+//! Vello does not shape text, so these helpers work from the clusters
+//! reported by a shaper along with the grapheme boundaries of the text,
+//! which allows editors to draw carets and selections that agree with the
+//! rendered glyphs without a second text engine.
+//!
+//! Lines containing text of both directions are laid out with
+//! [`layout_line`], which reorders the shaped runs of a line for display
+//! given the embedding levels computed by a bidi implementation.
+
+use std::ops::Range;
+
+use peniko::kurbo::{Line, Rect};
+
+use crate::glyph::{PositionedGlyph, Presentation};
+
+/// Cluster of glyphs produced by shaping that maps to a range of the text.
+#[derive(Clone, Debug)]
+pub struct Cluster {
+    /// Range of the text in bytes covered by the cluster.
+    pub text_range: Range<usize>,
+    /// Position of the left edge of the cluster.
+    pub x: f64,
+    /// Advance width of the cluster.
+    pub advance: f64,
+    /// True if the cluster is right-to-left.
+    pub rtl: bool,
+}
+
+/// Caret and selection geometry for a shaped run of text.
+///
+/// Clusters that contain several graphemes, such as ligatures, are divided
+/// evenly between their graphemes so that the caret can be placed inside
+/// them.
+#[derive(Clone, Debug)]
+pub struct RunGeometry {
+    clusters: Vec<Cluster>,
+    boundaries: Vec<usize>,
+    top: f64,
+    bottom: f64,
+}
+
+impl RunGeometry {
+    /// Creates the geometry for a run from its clusters in any order, the
+    /// byte offsets of the grapheme boundaries of the text and the vertical
+    /// extent of the line.
+    pub fn new(
+        clusters: impl IntoIterator<Item = Cluster>,
+        grapheme_boundaries: impl IntoIterator<Item = usize>,
+        top: f64,
+        bottom: f64,
+    ) -> Self {
+        let mut clusters: Vec<Cluster> = clusters.into_iter().collect();
+        clusters.sort_by(|a, b| a.x.total_cmp(&b.x));
+        let mut boundaries: Vec<usize> = grapheme_boundaries.into_iter().collect();
+        boundaries.sort_unstable();
+        boundaries.dedup();
+        Self {
+            clusters,
+            boundaries,
+            top,
+            bottom,
+        }
+    }
+
+    /// Returns the clusters in visual order.
+    pub fn clusters(&self) -> &[Cluster] {
+        &self.clusters
+    }
+
+    /// Returns the caret for the text offset, or `None` if the offset is not
+    /// in the run.
+    ///
+    /// An offset at the boundary of two clusters is placed at the trailing
+    /// edge of the preceding cluster in logical order only when no cluster
+    /// starts at the offset.
+    pub fn caret(&self, offset: usize) -> Option<Line> {
+        let x = self
+            .clusters
+            .iter()
+            .find(|c| c.text_range.contains(&offset))
+            .or_else(|| self.clusters.iter().find(|c| c.text_range.end == offset))
+            .map(|cluster| self.x_in_cluster(cluster, offset))?;
+        Some(Line::new((x, self.top), (x, self.bottom)))
+    }
+
+    /// Returns the rectangles covering a range of the text, merged where
+    /// they touch. Bidirectional text can produce several rectangles.
+    pub fn selection(&self, range: Range<usize>) -> Vec<Rect> {
+        let mut rects: Vec<Rect> = vec![];
+        for cluster in &self.clusters {
+            let start = range.start.max(cluster.text_range.start);
+            let end = range.end.min(cluster.text_range.end);
+            if start >= end {
+                continue;
+            }
+            let x0 = self.x_in_cluster(cluster, start);
+            let x1 = self.x_in_cluster(cluster, end);
+            let (x0, x1) = (x0.min(x1), x0.max(x1));
+            match rects.last_mut() {
+                Some(last) if (last.x1 - x0).abs() < 1e-6 => last.x1 = x1,
+                _ => rects.push(Rect::new(x0, self.top, x1, self.bottom)),
+            }
+        }
+        rects
+    }
+
+    /// Returns the text offset of the grapheme boundary closest to the
+    /// horizontal position.
+    pub fn hit_test(&self, x: f64) -> usize {
+        let cluster = match self
+            .clusters
+            .iter()
+            .find(|c| x < c.x + c.advance)
+            .or_else(|| self.clusters.last())
+        {
+            Some(cluster) => cluster,
+            None => return 0,
+        };
+        self.cluster_boundaries(cluster)
+            .min_by(|a, b| {
+                let da = (self.x_in_cluster(cluster, *a) - x).abs();
+                let db = (self.x_in_cluster(cluster, *b) - x).abs();
+                da.total_cmp(&db)
+            })
+            .unwrap_or(cluster.text_range.start)
+    }
+
+    /// Returns the grapheme boundaries in a cluster, including its ends.
+    fn cluster_boundaries<'a>(&'a self, cluster: &'a Cluster) -> impl Iterator<Item = usize> + 'a {
+        let range = &cluster.text_range;
+        std::iter::once(range.start)
+            .chain(
+                self.boundaries
+                    .iter()
+                    .copied()
+                    .filter(move |b| *b > range.start && *b < range.end),
+            )
+            .chain(std::iter::once(range.end))
+    }
+
+    /// Returns the horizontal position of a text offset within a cluster.
+    fn x_in_cluster(&self, cluster: &Cluster, offset: usize) -> f64 {
+        let boundaries: Vec<usize> = self.cluster_boundaries(cluster).collect();
+        // Offsets between boundaries snap to the preceding boundary.
+        let index = boundaries.iter().rposition(|b| *b <= offset).unwrap_or(0);
+        let fraction = index as f64 / (boundaries.len() - 1).max(1) as f64;
+        if cluster.rtl {
+            cluster.x + cluster.advance * (1.0 - fraction)
+        } else {
+            cluster.x + cluster.advance * fraction
+        }
+    }
+}
+
+/// Glyph produced by shaping.
+#[derive(Copy, Clone, Debug)]
+pub struct ShapedGlyph {
+    /// Glyph identifier.
+    pub gid: u16,
+    /// Byte offset of the start of the cluster that the glyph belongs to.
+    pub cluster: usize,
+    /// Horizontal advance of the glyph.
+    pub advance: f32,
+    /// Horizontal offset of the glyph from its pen position.
+    pub x_offset: f32,
+    /// Vertical offset of the glyph from the baseline, increasing upwards.
+    pub y_offset: f32,
+}
+
+/// Run of shaped glyphs with a single direction and embedding level.
+#[derive(Clone, Debug)]
+pub struct ShapedRun {
+    /// Bidi embedding level of the run. Odd levels are right-to-left.
+    pub level: u8,
+    /// Range of the text in bytes covered by the run.
+    pub text_range: Range<usize>,
+    /// Glyphs of the run in logical order.
+    pub glyphs: Vec<ShapedGlyph>,
+}
+
+impl ShapedRun {
+    /// Returns true if the run is right-to-left.
+    pub fn is_rtl(&self) -> bool {
+        self.level & 1 != 0
+    }
+}
+
+/// Returns the indices of runs with the given embedding levels in visual
+/// order, from left to right.
+///
+/// This implements rule L2 of the Unicode bidirectional algorithm: from the
+/// highest level down to the lowest odd level, every maximal sequence of
+/// runs at that level or higher is reversed.
+pub fn visual_order(levels: &[u8]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..levels.len()).collect();
+    let highest = levels.iter().copied().max().unwrap_or(0);
+    let lowest_odd = levels.iter().copied().filter(|l| l & 1 != 0).min();
+    let lowest_odd = match lowest_odd {
+        Some(level) => level,
+        None => return order,
+    };
+    for level in (lowest_odd..=highest).rev() {
+        let mut i = 0;
+        while i < order.len() {
+            if levels[order[i]] < level {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < order.len() && levels[order[i]] >= level {
+                i += 1;
+            }
+            order[start..i].reverse();
+        }
+    }
+    order
+}
+
+/// Lays out a line of shaped runs given in logical order, starting at the
+/// pen position `(x, y)`.
+///
+/// Returns the glyphs in visual order, ready for
+/// [`TextBlob::new`](crate::glyph::TextBlob::new), along with the caret and
+/// selection geometry of the line. The grapheme boundaries and vertical
+/// extent are passed to [`RunGeometry::new`].
+///
+/// Each [emoji sequence](emoji_sequences) of the text is treated as a single
+/// cluster with the [presentation](presentation) it requests, and invisible
+/// components of sequences that the font could not combine are dropped.
+pub fn layout_line(
+    text: &str,
+    runs: &[ShapedRun],
+    x: f32,
+    y: f32,
+    grapheme_boundaries: impl IntoIterator<Item = usize>,
+    top: f64,
+    bottom: f64,
+) -> (Vec<PositionedGlyph>, RunGeometry) {
+    let sequences = emoji_sequences(text);
+    let sequence_at = |offset: usize| sequences.iter().find(|seq| seq.contains(&offset));
+    let levels: Vec<u8> = runs.iter().map(|run| run.level).collect();
+    let mut glyphs = vec![];
+    let mut clusters: Vec<Cluster> = vec![];
+    let mut pen = x;
+    for index in visual_order(&levels) {
+        let run = &runs[index];
+        let rtl = run.is_rtl();
+        let n_glyphs = run.glyphs.len();
+        for i in 0..n_glyphs {
+            let i = if rtl { n_glyphs - 1 - i } else { i };
+            let glyph = &run.glyphs[i];
+            let sequence = sequence_at(glyph.cluster);
+            let text_range = match sequence {
+                Some(sequence) => sequence.clone(),
+                None => glyph.cluster..cluster_end(run, i),
+            };
+            // Unmapped joiners and selectors are shaped to zero width .notdef
+            // glyphs.
+            let invisible = sequence.is_some() && glyph.gid == 0 && glyph.advance == 0.0;
+            if !invisible {
+                glyphs.push(PositionedGlyph {
+                    gid: glyph.gid,
+                    x: pen + glyph.x_offset,
+                    y: y - glyph.y_offset,
+                    presentation: text
+                        .get(text_range.clone())
+                        .map(presentation)
+                        .unwrap_or_default(),
+                });
+            }
+            match clusters.last_mut() {
+                // Glyphs of a cluster are adjacent in visual order.
+                Some(last) if last.text_range.start == text_range.start && last.rtl == rtl => {
+                    last.advance += glyph.advance as f64;
+                }
+                _ => clusters.push(Cluster {
+                    text_range,
+                    x: pen as f64,
+                    advance: glyph.advance as f64,
+                    rtl,
+                }),
+            }
+            pen += glyph.advance;
+        }
+    }
+    let grapheme_boundaries = grapheme_boundaries.into_iter().filter(|offset| {
+        !sequences
+            .iter()
+            .any(|seq| seq.start < *offset && *offset < seq.end)
+    });
+    let geometry = RunGeometry::new(clusters, grapheme_boundaries, top, bottom);
+    (glyphs, geometry)
+}
+
+/// Returns the byte ranges of the emoji sequences in the text.
+///
+/// A sequence is an emoji followed by variation selectors, skin tone
+/// modifiers, a combining keycap or tag characters, optionally joined to
+/// further emoji with U+200D ZERO WIDTH JOINER, or a pair of regional
+/// indicators forming a flag. Shapers should be given each sequence as a
+/// single cluster so that fonts can substitute the composed glyph.
+pub fn emoji_sequences(text: &str) -> Vec<Range<usize>> {
+    let mut sequences = vec![];
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if !is_emoji_base(c) {
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        let mut flag = is_regional_indicator(c);
+        while let Some(&(offset, next)) = chars.peek() {
+            if next == ZWJ {
+                // The joiner only forms a sequence if an emoji follows it.
+                let mut lookahead = chars.clone();
+                lookahead.next();
+                match lookahead.next() {
+                    Some((offset, joined)) if is_emoji_base(joined) => {
+                        chars = lookahead;
+                        end = offset + joined.len_utf8();
+                        flag = false;
+                        continue;
+                    }
+                    _ => break,
+                }
+            }
+            let modifies = is_emoji_modifier(next) || (flag && is_regional_indicator(next));
+            if !modifies {
+                break;
+            }
+            chars.next();
+            end = offset + next.len_utf8();
+            flag = false;
+        }
+        if end > start + c.len_utf8() {
+            sequences.push(start..end);
+        }
+    }
+    sequences
+}
+
+/// Returns the presentation requested by a cluster or emoji sequence.
+pub fn presentation(cluster: &str) -> Presentation {
+    let mut result = Presentation::Default;
+    for c in cluster.chars() {
+        match c {
+            TEXT_SELECTOR => return Presentation::Text,
+            EMOJI_SELECTOR | ZWJ | KEYCAP => result = Presentation::Emoji,
+            c if is_skin_tone(c) || is_tag(c) || is_regional_indicator(c) => {
+                result = Presentation::Emoji
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+const ZWJ: char = '\u{200d}';
+const TEXT_SELECTOR: char = '\u{fe0e}';
+const EMOJI_SELECTOR: char = '\u{fe0f}';
+const KEYCAP: char = '\u{20e3}';
+
+fn is_emoji_base(c: char) -> bool {
+    matches!(
+        c,
+        '#' | '*'
+            | '0'..='9'
+            | '\u{a9}'
+            | '\u{ae}'
+            | '\u{203c}'..='\u{2bff}'
+            | '\u{3030}'
+            | '\u{303d}'
+            | '\u{3297}'
+            | '\u{3299}'
+            | '\u{1f000}'..='\u{1faff}'
+    )
+}
+
+fn is_emoji_modifier(c: char) -> bool {
+    matches!(c, TEXT_SELECTOR | EMOJI_SELECTOR | KEYCAP) || is_skin_tone(c) || is_tag(c)
+}
+
+fn is_skin_tone(c: char) -> bool {
+    matches!(c, '\u{1f3fb}'..='\u{1f3ff}')
+}
+
+fn is_tag(c: char) -> bool {
+    matches!(c, '\u{e0020}'..='\u{e007f}')
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    matches!(c, '\u{1f1e6}'..='\u{1f1ff}')
+}
+
+/// Returns the end of the cluster of the glyph at `index` in logical order,
+/// which is the start of the next cluster in the run.
+fn cluster_end(run: &ShapedRun, index: usize) -> usize {
+    let start = run.glyphs[index].cluster;
+    run.glyphs
+        .iter()
+        .map(|glyph| glyph.cluster)
+        .filter(|cluster| *cluster > start)
+        .min()
+        .unwrap_or(run.text_range.end)
+}
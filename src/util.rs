@@ -17,12 +17,16 @@
 //! Simple helpers for managing wgpu state and surfaces.
 
 use std::future::Future;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
 
 use super::Result;
 
 use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
 use wgpu::{
-    Adapter, Device, Instance, Limits, Queue, RequestAdapterOptions, Surface, SurfaceConfiguration,
+    Adapter, Device, Instance, Queue, RequestAdapterOptions, Surface, SurfaceConfiguration,
+    SurfaceTexture,
 };
 
 /// Simple render context that maintains wgpu state for rendering the pipeline.
@@ -37,6 +41,13 @@ pub struct DeviceHandle {
     pub queue: Queue,
 }
 
+impl DeviceHandle {
+    /// Returns information about the adapter of the device.
+    pub fn adapter_info(&self) -> wgpu::AdapterInfo {
+        self.adapter.get_info()
+    }
+}
+
 impl RenderContext {
     pub fn new() -> Result<Self> {
         let instance = Instance::new(wgpu::InstanceDescriptor {
@@ -71,7 +82,193 @@ impl RenderContext {
             surface,
             config,
             dev_id,
+            latency: PresentLatency::Standard,
+            lost: false,
+        }
+    }
+
+    /// Acquires the next texture of the surface.
+    ///
+    /// Outdated surfaces, for example after a change of display mode, are
+    /// reconfigured transparently. If the surface was lost, for example
+    /// because its output was disconnected, it is marked as lost and
+    /// [`SurfaceError::Lost`] is returned, and the application should
+    /// recreate it with [`recreate_surface`](Self::recreate_surface) once the
+    /// window is visible again.
+    pub fn acquire_texture(
+        &self,
+        surface: &mut RenderSurface,
+    ) -> std::result::Result<SurfaceTexture, SurfaceError> {
+        if surface.lost {
+            return Err(SurfaceError::Lost);
+        }
+        match surface.surface.get_current_texture() {
+            Ok(texture) => return Ok(texture),
+            Err(wgpu::SurfaceError::Timeout) => return Err(SurfaceError::Timeout),
+            Err(wgpu::SurfaceError::OutOfMemory) => return Err(SurfaceError::OutOfMemory),
+            Err(wgpu::SurfaceError::Outdated) | Err(wgpu::SurfaceError::Lost) => {}
+        }
+        // Try once more with a fresh configuration.
+        surface
+            .surface
+            .configure(&self.devices[surface.dev_id].device, &surface.config);
+        match surface.surface.get_current_texture() {
+            Ok(texture) => Ok(texture),
+            Err(wgpu::SurfaceError::Timeout) | Err(wgpu::SurfaceError::Outdated) => {
+                Err(SurfaceError::Timeout)
+            }
+            Err(wgpu::SurfaceError::OutOfMemory) => Err(SurfaceError::OutOfMemory),
+            Err(wgpu::SurfaceError::Lost) => {
+                surface.lost = true;
+                Err(SurfaceError::Lost)
+            }
+        }
+    }
+
+    /// Recreates a lost surface for the window, keeping its configuration.
+    ///
+    /// The surface may move to a different device if the window is now shown
+    /// on an output driven by another adapter. Returns true if the device
+    /// changed, in which case any [`Renderer`](crate::Renderer) for the
+    /// surface must be migrated with
+    /// [`Renderer::migrate`](crate::Renderer::migrate).
+    ///
+    /// Returns an error if the window has no surface or no device can present
+    /// to it yet, in which case the surface is still lost and this can be
+    /// called again later.
+    pub async fn recreate_surface<W>(
+        &mut self,
+        surface: &mut RenderSurface,
+        window: &W,
+    ) -> Result<bool>
+    where
+        W: HasRawWindowHandle + HasRawDisplayHandle,
+    {
+        let new_surface = unsafe { self.instance.create_surface(window) }?;
+        let dev_id = self
+            .device(Some(&new_surface))
+            .await
+            .ok_or("no device is compatible with the surface")?;
+        new_surface.configure(&self.devices[dev_id].device, &surface.config);
+        let changed = dev_id != surface.dev_id;
+        surface.surface = new_surface;
+        surface.dev_id = dev_id;
+        surface.lost = false;
+        Ok(changed)
+    }
+
+    /// Sets the presentation latency mode of the surface.
+    ///
+    /// Modes that are not supported by the platform fall back to the closest
+    /// supported alternative, ending with [`wgpu::PresentMode::Fifo`] which is
+    /// always available.
+    pub fn set_present_latency(&self, surface: &mut RenderSurface, latency: PresentLatency) {
+        let adapter = &self.devices[surface.dev_id].adapter;
+        let supported = surface.surface.get_capabilities(adapter).present_modes;
+        let preferred: &[wgpu::PresentMode] = match latency {
+            PresentLatency::Standard => &[],
+            PresentLatency::Low => &[wgpu::PresentMode::Mailbox, wgpu::PresentMode::FifoRelaxed],
+            PresentLatency::Lowest => &[
+                wgpu::PresentMode::Immediate,
+                wgpu::PresentMode::Mailbox,
+                wgpu::PresentMode::FifoRelaxed,
+            ],
+        };
+        surface.config.present_mode = preferred
+            .iter()
+            .copied()
+            .find(|mode| supported.contains(mode))
+            .unwrap_or(wgpu::PresentMode::Fifo);
+        surface.latency = latency;
+        surface
+            .surface
+            .configure(&self.devices[surface.dev_id].device, &surface.config);
+    }
+
+    /// Returns information about all adapters that are available to the
+    /// context, in the order used by [`device_for_adapter`](Self::device_for_adapter).
+    ///
+    /// Adapters cannot be enumerated on the web, so this and the methods
+    /// that select an adapter by index are not available on wasm.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn enumerate_adapters(&self) -> Vec<wgpu::AdapterInfo> {
+        self.instance
+            .enumerate_adapters(wgpu::Backends::PRIMARY)
+            .map(|adapter| adapter.get_info())
+            .collect()
+    }
+
+    /// Finds or creates a device handle id for the adapter at the given index
+    /// of [`enumerate_adapters`](Self::enumerate_adapters).
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn device_for_adapter(&mut self, adapter_ix: usize) -> Option<usize> {
+        let adapter = self
+            .instance
+            .enumerate_adapters(wgpu::Backends::PRIMARY)
+            .nth(adapter_ix)?;
+        let info = adapter.get_info();
+        if let Some(dev_id) = self
+            .devices
+            .iter()
+            .position(|d| same_adapter(&d.adapter.get_info(), &info))
+        {
+            return Some(dev_id);
+        }
+        self.request_device(adapter).await
+    }
+
+    /// Moves the surface to a device on the adapter at the given index of
+    /// [`enumerate_adapters`](Self::enumerate_adapters), for example when a
+    /// window moves to a monitor driven by another GPU.
+    ///
+    /// Returns the new device handle id, or `None` if the adapter does not
+    /// support the surface, in which case the surface is unchanged. Any
+    /// [`Renderer`](crate::Renderer) for the surface must be migrated to the
+    /// new device with [`Renderer::migrate`](crate::Renderer::migrate).
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn migrate_surface(
+        &mut self,
+        surface: &mut RenderSurface,
+        adapter_ix: usize,
+    ) -> Option<usize> {
+        let dev_id = self.device_for_adapter(adapter_ix).await?;
+        if !self.devices[dev_id]
+            .adapter
+            .is_surface_supported(&surface.surface)
+        {
+            return None;
+        }
+        surface.dev_id = dev_id;
+        surface
+            .surface
+            .configure(&self.devices[dev_id].device, &surface.config);
+        Some(dev_id)
+    }
+
+    /// Finds or creates a device handle id on the software adapter, for
+    /// systems without 3D acceleration.
+    ///
+    /// Such devices usually cannot present to a surface directly; frames can
+    /// be read back and presented through shared memory with
+    /// [`FrameReadback`](crate::interop::FrameReadback).
+    pub async fn fallback_device(&mut self) -> Option<usize> {
+        let adapter = self
+            .instance
+            .request_adapter(&RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::LowPower,
+                force_fallback_adapter: true,
+                compatible_surface: None,
+            })
+            .await?;
+        let info = adapter.get_info();
+        if let Some(dev_id) = self
+            .devices
+            .iter()
+            .position(|d| same_adapter(&d.adapter.get_info(), &info))
+        {
+            return Some(dev_id);
         }
+        self.request_device(adapter).await
     }
 
     /// Resizes the surface to the new dimensions.
@@ -108,14 +305,26 @@ impl RenderContext {
             compatible_surface,
         )
         .await?;
+        self.request_device(adapter).await
+    }
+
+    /// Creates a device handle id for the adapter.
+    async fn request_device(&mut self, adapter: Adapter) -> Option<usize> {
         let features = adapter.features();
-        let limits = Limits::default();
+        // The limits of the adapter are requested as they are. The renderer
+        // adapts to the texture and buffer sizes and reports an error when
+        // it is created if the device cannot run the pipeline, rather than
+        // the request failing on adapters below the default limits.
+        let limits = adapter.limits();
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: None,
                     features: features
-                        & (wgpu::Features::TIMESTAMP_QUERY | wgpu::Features::CLEAR_TEXTURE),
+                        & (wgpu::Features::TIMESTAMP_QUERY
+                            | wgpu::Features::CLEAR_TEXTURE
+                            | wgpu::Features::TEXTURE_COMPRESSION_BC
+                            | wgpu::Features::TEXTURE_COMPRESSION_ASTC_LDR),
                     limits,
                 },
                 None,
@@ -132,11 +341,198 @@ impl RenderContext {
     }
 }
 
+/// Returns true if the two infos describe the same adapter.
+fn same_adapter(a: &wgpu::AdapterInfo, b: &wgpu::AdapterInfo) -> bool {
+    a.vendor == b.vendor && a.device == b.device && a.backend == b.backend && a.name == b.name
+}
+
 /// Combination of surface and its configuration.
 pub struct RenderSurface {
     pub surface: Surface,
     pub config: SurfaceConfiguration,
     pub dev_id: usize,
+    /// Requested presentation latency mode. Use
+    /// [`RenderContext::set_present_latency`] to change this.
+    pub latency: PresentLatency,
+    lost: bool,
+}
+
+impl RenderSurface {
+    /// Returns true if the surface was lost and must be recreated with
+    /// [`RenderContext::recreate_surface`].
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+}
+
+/// Error returned when a surface texture cannot be acquired.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SurfaceError {
+    /// No texture became available in time. The frame should be skipped.
+    Timeout,
+    /// The surface was lost, for example because its output was
+    /// disconnected, and must be recreated.
+    Lost,
+    /// There is not enough memory to acquire a texture.
+    OutOfMemory,
+}
+
+impl std::fmt::Display for SurfaceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "timed out acquiring surface texture"),
+            Self::Lost => write!(f, "surface was lost"),
+            Self::OutOfMemory => write!(f, "out of memory acquiring surface texture"),
+        }
+    }
+}
+
+impl std::error::Error for SurfaceError {}
+
+/// Presentation latency mode of a surface.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum PresentLatency {
+    /// Frames are presented in sync with the display refresh.
+    #[default]
+    Standard,
+    /// Frames replace any pending frame so that presentation only waits for
+    /// the next refresh. This avoids tearing where supported.
+    Low,
+    /// Frames are presented immediately, which may tear. Intended for
+    /// latency sensitive content such as handwriting.
+    Lowest,
+}
+
+/// Coalesces redraw requests so that the window is redrawn at most once per
+/// display refresh.
+///
+/// Call [`invalidate`](Self::invalidate) whenever content changes and request
+/// a redraw from the windowing system when it returns true. On the redraw
+/// event, draw only if [`begin_frame`](Self::begin_frame) returns true. When
+/// the display is ready for another frame, which is signaled by a frame
+/// callback on Wayland or can be approximated with
+/// [`next_refresh`](Self::next_refresh) elsewhere, call
+/// [`frame_done`](Self::frame_done) and request a redraw if it returns true.
+///
+/// On the web, where `std::time::Instant` is not available, the scheduler
+/// does not track time, so [`frame_done`](Self::frame_done) should be called
+/// from an animation frame callback.
+#[derive(Clone, Debug)]
+pub struct RedrawScheduler {
+    state: RedrawState,
+    dirty: bool,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    refresh_interval: Duration,
+    #[cfg(not(target_arch = "wasm32"))]
+    last_frame: Option<Instant>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum RedrawState {
+    /// Nothing is scheduled.
+    Idle,
+    /// A redraw was requested and has not started.
+    Requested,
+    /// A frame was drawn and the display has not refreshed since.
+    Waiting,
+}
+
+impl RedrawScheduler {
+    /// Creates a new scheduler for a display with the given refresh
+    /// interval.
+    pub fn new(refresh_interval: Duration) -> Self {
+        Self {
+            state: RedrawState::Idle,
+            dirty: false,
+            refresh_interval,
+            #[cfg(not(target_arch = "wasm32"))]
+            last_frame: None,
+        }
+    }
+
+    /// Sets the refresh interval of the display, for example after the
+    /// window moves to another monitor.
+    pub fn set_refresh_interval(&mut self, refresh_interval: Duration) {
+        self.refresh_interval = refresh_interval;
+    }
+
+    /// Marks the content as changed. Returns true if the caller should
+    /// request a redraw; further invalidations are coalesced until the next
+    /// frame.
+    pub fn invalidate(&mut self) -> bool {
+        self.dirty = true;
+        if self.state == RedrawState::Idle {
+            self.state = RedrawState::Requested;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns true if there are changes that have not been drawn.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Called on a redraw event. Returns true if a frame should be drawn.
+    pub fn begin_frame(&mut self) -> bool {
+        if !self.dirty {
+            if self.state == RedrawState::Requested {
+                self.state = RedrawState::Idle;
+            }
+            return false;
+        }
+        self.dirty = false;
+        self.state = RedrawState::Waiting;
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.last_frame = Some(Instant::now());
+        }
+        true
+    }
+
+    /// Called when the display is ready for another frame. Returns true if
+    /// the caller should request a redraw for changes made in the meantime.
+    pub fn frame_done(&mut self) -> bool {
+        if self.state != RedrawState::Waiting {
+            return false;
+        }
+        if self.dirty {
+            self.state = RedrawState::Requested;
+            true
+        } else {
+            self.state = RedrawState::Idle;
+            false
+        }
+    }
+
+    /// Returns the time of the next display refresh after the last frame
+    /// while waiting for it, for use as a wake up deadline when frame
+    /// callbacks are not available.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn next_refresh(&self) -> Option<Instant> {
+        match (self.state, self.last_frame) {
+            (RedrawState::Waiting, Some(last_frame)) => Some(last_frame + self.refresh_interval),
+            _ => None,
+        }
+    }
+
+    /// Calls [`frame_done`](Self::frame_done) if the refresh interval has
+    /// passed since the last frame. Returns true if the caller should
+    /// request a redraw.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn poll(&mut self, now: Instant) -> bool {
+        match self.next_refresh() {
+            Some(deadline) if now >= deadline => self.frame_done(),
+            _ => false,
+        }
+    }
+}
+
+impl Default for RedrawScheduler {
+    fn default() -> Self {
+        Self::new(Duration::from_micros(16_667))
+    }
 }
 
 struct NullWake;
@@ -162,3 +558,32 @@ pub fn block_on_wgpu<F: Future>(device: &Device, mut fut: F) -> F::Output {
         }
     }
 }
+
+/// Block on a future, polling the device as needed, for at most `timeout`.
+///
+/// Returns `None` if the future is not ready when the timeout elapses, for
+/// example because a submission that it awaits never completes. Unlike
+/// [`block_on_wgpu`], the device is polled without blocking, with short
+/// sleeps in between, so this is not available on the web.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn block_on_wgpu_timeout<F: Future>(
+    device: &Device,
+    mut fut: F,
+    timeout: Duration,
+) -> Option<F::Output> {
+    let waker = std::task::Waker::from(std::sync::Arc::new(NullWake));
+    let mut context = std::task::Context::from_waker(&waker);
+    // Same logic as `pin_mut!` macro from `pin_utils`.
+    let mut fut = unsafe { std::pin::Pin::new_unchecked(&mut fut) };
+    let start = Instant::now();
+    loop {
+        if let std::task::Poll::Ready(item) = fut.as_mut().poll(&mut context) {
+            return Some(item);
+        }
+        if start.elapsed() >= timeout {
+            return None;
+        }
+        device.poll(wgpu::Maintain::Poll);
+        std::thread::sleep(crate::watchdog::POLL_INTERVAL);
+    }
+}
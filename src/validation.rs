@@ -0,0 +1,128 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Capture of wgpu validation errors with the context of the render.
+//!
+//! By default, wgpu reports validation errors to the uncaptured error
+//! handler of the device, which only knows that a compute pass failed.
+//! With [`Renderer::set_validation_capture`](crate::Renderer::set_validation_capture),
+//! the renderer pushes error scopes around its submissions, and returns the
+//! errors from the render as a [`ValidationError`] that names the stage of
+//! the pipeline and carries statistics of the scene.
+//!
+//! Error scopes are resolved by blocking on the device, which is immediate
+//! on native backends. On the web they would never resolve, so errors are
+//! left to the uncaptured error handler there.
+
+use std::fmt;
+
+use crate::encoding::Encoding;
+
+/// Granularity at which validation errors are captured.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum ValidationCapture {
+    /// Errors are reported to the uncaptured error handler of the device.
+    #[default]
+    Off,
+    /// One error scope is pushed around each submission, which is cheap but
+    /// does not identify the stage.
+    Submission,
+    /// An error scope is also pushed around each dispatch, so that errors
+    /// name the stage of the pipeline that caused them.
+    Dispatch,
+}
+
+/// Statistics of the scene whose render caused an error.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct SceneStats {
+    /// Width of the target in pixels.
+    pub width: u32,
+    /// Height of the target in pixels.
+    pub height: u32,
+    /// Number of paths, including clips.
+    pub n_paths: u32,
+    /// Number of path segments.
+    pub n_path_segments: u32,
+    /// Number of draw objects.
+    pub n_draw_objects: u32,
+    /// Number of clips and layers.
+    pub n_clips: u32,
+    /// Number of transforms.
+    pub n_transforms: u32,
+    /// Size of the encoding in bytes.
+    pub encoding_bytes: usize,
+}
+
+impl SceneStats {
+    /// Computes the statistics of an encoding rendered at the given size.
+    pub fn new(encoding: &Encoding, width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            n_paths: encoding.n_paths,
+            n_path_segments: encoding.n_path_segments,
+            n_draw_objects: encoding.draw_tags.len() as u32,
+            n_clips: encoding.n_clips,
+            n_transforms: encoding.transforms.len() as u32,
+            encoding_bytes: encoding.heap_size(),
+        }
+    }
+}
+
+impl fmt::Display for SceneStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} target, {} paths, {} segments, {} draw objects, {} clips, {} transforms, {} bytes",
+            self.width,
+            self.height,
+            self.n_paths,
+            self.n_path_segments,
+            self.n_draw_objects,
+            self.n_clips,
+            self.n_transforms,
+            self.encoding_bytes
+        )
+    }
+}
+
+/// A wgpu validation error raised while rendering a scene.
+#[derive(Debug)]
+pub struct ValidationError {
+    /// Label of the shader whose dispatch caused the error, if errors are
+    /// captured for each dispatch and the error was raised by one.
+    pub stage: Option<&'static str>,
+    /// Statistics of the scene that was rendered.
+    pub stats: SceneStats,
+    /// The error reported by wgpu.
+    pub error: wgpu::Error,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.stage {
+            Some(stage) => write!(f, "validation error in stage {stage}")?,
+            None => write!(f, "validation error in submission")?,
+        }
+        write!(f, " ({}): {}", self.stats, self.error)
+    }
+}
+
+impl std::error::Error for ValidationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
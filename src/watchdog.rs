@@ -0,0 +1,83 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Detection of GPU work that does not complete.
+//!
+//! The renderer blocks on the GPU in a few places: when frames are paced
+//! with [`FramePacing::Block`](crate::FramePacing::Block), when a render is
+//! read back with [`Renderer::render_to_image`](crate::Renderer::render_to_image)
+//! and when validation errors are captured. A driver bug or a runaway
+//! submission makes these waits block forever, which freezes the host
+//! application.
+//!
+//! With [`Renderer::set_watchdog`](crate::Renderer::set_watchdog), these
+//! waits give up after a timeout and the render fails with a [`GpuHang`]
+//! error. The renderer then stops submitting work, and renders fail with
+//! the same error until the hung work completes. If it never does, the
+//! application can recover by creating a new device and moving the
+//! renderer to it with [`Renderer::migrate`](crate::Renderer::migrate).
+//! The renderer does not recreate the device itself, since the adapter and
+//! the surfaces that depend on the device are owned by the application.
+//!
+//! The watchdog polls the device without blocking, so it is not available
+//! on the web, where the renderer never blocks.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use wgpu::Device;
+
+/// Interval at which the device is polled while waiting with a timeout.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Error returned when GPU work does not complete within the timeout of the
+/// watchdog.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GpuHang {
+    /// What the renderer was waiting for.
+    pub operation: &'static str,
+    /// The timeout that elapsed.
+    pub timeout: Duration,
+}
+
+impl fmt::Display for GpuHang {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GPU work did not complete within {:?} while waiting for {}",
+            self.timeout, self.operation
+        )
+    }
+}
+
+impl std::error::Error for GpuHang {}
+
+/// Polls the device until the flag is set, for at most `timeout`. Returns
+/// false if the timeout elapsed.
+pub(crate) fn wait_for_flag(device: &Device, flag: &AtomicBool, timeout: Duration) -> bool {
+    let start = Instant::now();
+    loop {
+        device.poll(wgpu::Maintain::Poll);
+        if flag.load(Ordering::Acquire) {
+            return true;
+        }
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright 2023 The vello authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Brushes for planar YUV video frames.
+//!
+//! Decoded frames are packed into an image whose red, green and blue
+//! channels hold the Y, U and V samples of each pixel, with the chroma
+//! planes upsampled to full resolution. The fine shader converts the samples
+//! to RGB when the image is sampled, so a frame is drawn with
+//! [`SceneBuilder::fill_yuv_image`](crate::SceneBuilder::fill_yuv_image)
+//! without a separate conversion pass.
+//!
+//! Frames in host memory are packed with [`pack_yuv`]. Frames that are
+//! decoded into textures are packed on the GPU with [`YuvPacker`] into a
+//! texture registered with
+//! [`Renderer::register_texture`](crate::Renderer::register_texture).
+
+use peniko::{Format, Image};
+use wgpu::util::DeviceExt;
+use wgpu::{CommandEncoder, Device, TextureFormat, TextureView};
+
+pub use crate::encoding::{YuvMatrix, YuvRange};
+
+/// Layout of the planes of a YUV frame with 4:2:0 chroma subsampling.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum YuvFormat {
+    /// A Y plane followed by separate U and V planes at half resolution.
+    I420,
+    /// A Y plane followed by a single plane of interleaved U and V samples at
+    /// half resolution.
+    Nv12,
+}
+
+impl YuvFormat {
+    /// Returns the number of planes of the format.
+    pub fn planes(self) -> usize {
+        match self {
+            Self::I420 => 3,
+            Self::Nv12 => 2,
+        }
+    }
+}
+
+/// Packs the tightly packed planes of a frame in host memory into an image
+/// for [`SceneBuilder::fill_yuv_image`](crate::SceneBuilder::fill_yuv_image).
+///
+/// The chroma planes have a width and height of half the frame, rounded up.
+///
+/// # Panics
+///
+/// Panics if the number of planes does not match the format or a plane is
+/// smaller than its size.
+pub fn pack_yuv(format: YuvFormat, planes: &[&[u8]], width: u32, height: u32) -> Image {
+    assert_eq!(planes.len(), format.planes(), "wrong number of planes");
+    let (w, h) = (width as usize, height as usize);
+    let (cw, ch) = ((w + 1) / 2, (h + 1) / 2);
+    assert!(planes[0].len() >= w * h, "luma plane is too small");
+    match format {
+        YuvFormat::I420 => assert!(
+            planes[1].len() >= cw * ch && planes[2].len() >= cw * ch,
+            "chroma plane is too small"
+        ),
+        YuvFormat::Nv12 => assert!(planes[1].len() >= cw * ch * 2, "chroma plane is too small"),
+    }
+    let mut data = Vec::with_capacity(w * h * 4);
+    for y in 0..h {
+        for x in 0..w {
+            let c = (y / 2) * cw + x / 2;
+            let (u, v) = match format {
+                YuvFormat::I420 => (planes[1][c], planes[2][c]),
+                YuvFormat::Nv12 => (planes[1][c * 2], planes[1][c * 2 + 1]),
+            };
+            data.extend_from_slice(&[planes[0][y * w + x], u, v, 255]);
+        }
+    }
+    Image::new(data.into(), Format::Rgba8, width, height)
+}
+
+/// Packs the planes of frames decoded into textures on the GPU.
+///
+/// The Y plane and the U and V planes of an I420 frame are `R8Unorm`
+/// textures and the UV plane of an NV12 frame is an `Rg8Unorm` texture. The
+/// target is an `Rgba8Unorm` texture with the `RENDER_ATTACHMENT` and
+/// `COPY_SRC` usages and the size of the frame, which is registered once
+/// with [`Renderer::register_texture`](crate::Renderer::register_texture)
+/// and packed again for every frame.
+pub struct YuvPacker {
+    bind_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl YuvPacker {
+    /// Creates the packing pipeline.
+    pub fn new(device: &Device) -> Self {
+        const SHADERS: &str = r#"
+            @vertex
+            fn vs_main(@builtin(vertex_index) ix: u32) -> @builtin(position) vec4<f32> {
+                // Generate a full screen triangle in NDCs
+                let vertex = vec2(f32(ix / 2u) * 4.0 - 1.0, f32(ix % 2u) * 4.0 - 1.0);
+                return vec4(vertex, 0.0, 1.0);
+            }
+
+            @group(0) @binding(0)
+            var y_plane: texture_2d<f32>;
+
+            @group(0) @binding(1)
+            var u_plane: texture_2d<f32>;
+
+            @group(0) @binding(2)
+            var v_plane: texture_2d<f32>;
+
+            // Nonzero if the U and V samples are interleaved in u_plane.
+            @group(0) @binding(3)
+            var<uniform> interleaved: u32;
+
+            @fragment
+            fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+                let xy = vec2<i32>(position.xy);
+                let cxy = min(xy / 2, vec2<i32>(textureDimensions(u_plane)) - 1);
+                let y = textureLoad(y_plane, xy, 0).r;
+                let c = textureLoad(u_plane, cxy, 0);
+                var uv = vec2(c.r, textureLoad(v_plane, cxy, 0).r);
+                if interleaved != 0u {
+                    uv = c.rg;
+                }
+                return vec4(y, uv, 1.0);
+            }
+        "#;
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("yuv packing shaders"),
+            source: wgpu::ShaderSource::Wgsl(SHADERS.into()),
+        });
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            binding,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let bind_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                texture_entry(0),
+                texture_entry(1),
+                texture_entry(2),
+                wgpu::BindGroupLayoutEntry {
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    binding: 3,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&bind_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: TextureFormat::Rgba8Unorm,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+        Self {
+            bind_layout,
+            pipeline,
+        }
+    }
+
+    /// Records a pass that packs the planes of a frame into the target.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of planes does not match the format.
+    pub fn pack(
+        &self,
+        device: &Device,
+        encoder: &mut CommandEncoder,
+        format: YuvFormat,
+        planes: &[&TextureView],
+        target: &TextureView,
+    ) {
+        assert_eq!(planes.len(), format.planes(), "wrong number of planes");
+        let interleaved = (format == YuvFormat::Nv12) as u32;
+        let uniform = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::bytes_of(&interleaved),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        // The V plane of an NV12 frame is unused but must still be bound.
+        let v_plane = planes.get(2).copied().unwrap_or(planes[1]);
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.bind_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(planes[0]),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(planes[1]),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(v_plane),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: uniform.as_entire_binding(),
+                },
+            ],
+        });
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("yuv packing"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}